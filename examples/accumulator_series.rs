@@ -0,0 +1,104 @@
+//! Times a 100-point accumulator series over a 2M-block history, read from the checkpoints and
+//! by replaying the events up to every point, for a growing number of events:
+//!
+//! ```sh
+//! cargo run --release --example accumulator_series -- 1000 10000 100000
+//! ```
+
+use ethers::{
+    core::types::{Address, U64},
+    utils::parse_ether,
+};
+use oprtc_calculator::prelude::*;
+use std::{env, time::Instant};
+
+const HISTORY_BLOCKS: u64 = 2_000_000;
+const POINTS: usize = 100;
+
+fn main() {
+    let counts: Vec<u64> = env::args()
+        .skip(1)
+        .map(|count| count.parse().expect("event count"))
+        .collect();
+    let counts = if counts.is_empty() {
+        vec![1_000, 10_000, 100_000]
+    } else {
+        counts
+    };
+
+    let from_block = U64::from(BLOCK_CONTRACT_DEPLOYED);
+    let to_block = from_block + HISTORY_BLOCKS;
+    for count in counts {
+        let events = history(count);
+        let blocks = series_blocks(from_block, to_block, POINTS, CHECKPOINT_BLOCKS);
+
+        let mut state = GlobalState::new();
+        state.set_accumulator_interval(Some(CHECKPOINT_BLOCKS));
+        state.process_events(events.clone()).unwrap();
+        let started = Instant::now();
+        let series: Vec<Accumulator> = blocks
+            .iter()
+            .map(|block| state.accumulator_at(*block).expect("checkpointed block"))
+            .collect();
+        let from_checkpoints = started.elapsed();
+
+        let started = Instant::now();
+        for (block, point) in blocks.iter().zip(&series) {
+            let mut replay = GlobalState::new();
+            replay
+                .process_events(
+                    events
+                        .iter()
+                        .filter(|evt| evt.block_number() <= *block)
+                        .cloned()
+                        .collect(),
+                )
+                .unwrap();
+            assert_eq!(replay.total_emission(*block), point.emission_to_date);
+        }
+        let by_replay = started.elapsed();
+
+        println!(
+            "{} events: {} points from checkpoints in {:?}, by replay in {:?}",
+            count,
+            series.len(),
+            from_checkpoints,
+            by_replay
+        );
+    }
+}
+
+/// `count` events evenly spread over the history: a holder staked throughout, and a hundred
+/// others depositing and withdrawing in turn.
+fn history(count: u64) -> Vec<Event> {
+    let one = Shares(parse_ether("1").unwrap());
+    let block = |i: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + i * HISTORY_BLOCKS / count);
+    let mut events = vec![Event::Deposit(Deposit {
+        address: Address::from_low_u64_be(0xfee),
+        shares: one,
+        block_number: block(0),
+        transaction_index: 0,
+        log_index: 0,
+    })];
+    events.extend((1..count).map(|i| {
+        let address = Address::from_low_u64_be((i - 1) / 2 % 100 + 1);
+        if i % 2 == 1 {
+            Event::Deposit(Deposit {
+                address,
+                shares: one,
+                block_number: block(i),
+                transaction_index: 0,
+                log_index: 1,
+            })
+        } else {
+            Event::Withdrawal(Withdraw {
+                address,
+                shares: one,
+                block_number: block(i),
+                transaction_index: 0,
+                log_index: 1,
+            })
+        }
+    }));
+    events
+}
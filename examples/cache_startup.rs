@@ -0,0 +1,98 @@
+//! Times reading a large synthetic cache in each format, and seeking to its last blocks in
+//! the binary one:
+//!
+//! ```sh
+//! cargo run --release --example cache_startup -- 2000000
+//! ```
+
+use ethers::core::types::{Address, U256, U64};
+use eyre::Result;
+use oprtc_calculator::prelude::*;
+use std::{env, fs, time::Instant};
+
+fn main() -> Result<()> {
+    let count: u64 = env::args()
+        .nth(1)
+        .map_or(1_000_000, |count| count.parse().expect("event count"));
+    let from_block = BLOCK_CONTRACT_DEPLOYED;
+    let to_block = from_block + count / 4;
+
+    // four events a block, rotating through a thousand holders
+    let mut cache = EventCache::new(
+        Address::from_low_u64_be(0x7a),
+        1,
+        from_block,
+        to_block,
+        CHUNK_SIZE,
+    );
+    cache.completed_chunks = chunk_grid(from_block, to_block, CHUNK_SIZE)
+        .into_iter()
+        .map(|(start, _)| start)
+        .collect();
+    cache.events = (0..count)
+        .map(|i| {
+            let holder = Address::from_low_u64_be(i % 1_000 + 1);
+            let block_number = U64::from(from_block + i / 4);
+            let shares = U256::from(i + 1) * U256::exp10(15);
+            match i % 4 {
+                0 | 1 => Event::Deposit(Deposit {
+                    address: holder,
+                    shares: Shares(shares),
+                    block_number,
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                2 => Event::Transfer(Transfer {
+                    from: holder,
+                    to: Address::from_low_u64_be(i % 997 + 1),
+                    shares: Shares(shares),
+                    block_number,
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                _ => Event::Withdrawal(Withdraw {
+                    address: holder,
+                    shares: Shares(shares),
+                    block_number,
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            }
+        })
+        .collect();
+    cache.logs = cache.events.len();
+
+    let dir = env::temp_dir().join(format!("oprtc-cache-startup-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let json = dir.join("cache.json");
+    let binary = dir.join("cache.bin");
+    cache.save_as(&json, CacheFormat::Json)?;
+    cache.save_as(&binary, CacheFormat::Binary)?;
+
+    for (name, path) in [("json", &json), ("binary", &binary)] {
+        let started = Instant::now();
+        let loaded = EventCache::load(path)?;
+        let elapsed = started.elapsed();
+        assert_eq!(loaded.events.len(), cache.events.len());
+        println!(
+            "{}: {} events, {} MB, read in {:?}",
+            name,
+            loaded.events.len(),
+            fs::metadata(path)?.len() / 1_000_000,
+            elapsed
+        );
+    }
+
+    let last_day = to_block - 7_200;
+    let started = Instant::now();
+    let tail = EventCache::load_after(&binary, last_day)?;
+    println!(
+        "binary: {} events after block {} read in {:?}",
+        tail.events.len(),
+        last_day,
+        started.elapsed()
+    );
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
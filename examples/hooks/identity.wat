@@ -0,0 +1,29 @@
+;; The smallest post-processing hook: returns the rewards list unchanged. A real hook parses
+;; the JSON input, adjusts the rows and writes its output JSON to memory it owns. See the
+;; `hooks` module docs for the ABI.
+(module
+  (memory (export "memory") 1)
+
+  ;; bump allocator, growing the memory as needed
+  (global $next (mut i32) (i32.const 1024))
+
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local $end i32)
+    (local.set $ptr (global.get $next))
+    (local.set $end (i32.add (local.get $ptr) (local.get $len)))
+    (if (i32.gt_u (local.get $end) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (drop
+          (memory.grow
+            (i32.sub
+              (i32.div_u (i32.add (local.get $end) (i32.const 65535)) (i32.const 65536))
+              (memory.size))))))
+    (global.set $next (local.get $end))
+    (local.get $ptr))
+
+  ;; the output is the input, in place
+  (func (export "process") (param $ptr i32) (param $len i32) (result i64)
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+      (i64.extend_i32_u (local.get $len)))))
@@ -0,0 +1,56 @@
+//! Replays an events journal, one JSON-encoded event per line as written next to a proof, and
+//! prints the rewards at a block as JSON:
+//!
+//! ```sh
+//! cargo run --example json_report -- events.jsonl 17600000
+//! ```
+
+use ethers::core::types::U64;
+use eyre::{eyre, Result};
+use oprtc_calculator::prelude::*;
+use serde_json::json;
+use std::{env, fs::File, io::BufReader};
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let (Some(path), Some(block)) = (args.next(), args.next()) else {
+        return Err(eyre!("usage: json_report <events.jsonl> <block>"));
+    };
+    let block = U64::from(block.parse::<u64>()?);
+
+    let events = read_journal(BufReader::new(File::open(path)?))?;
+    let mut state = GlobalState::new();
+    state.try_process_events(events)?;
+
+    let report = rewards_report(
+        &state,
+        block,
+        &Annotations::default(),
+        None,
+        (&SubAccounts::default(), &Checkpoints::new()),
+        false,
+    );
+
+    // amounts as decimal wei strings, which JSON numbers can't hold
+    let rows: Vec<_> = report
+        .rows
+        .iter()
+        .map(|row| {
+            json!({
+                "address": row.label,
+                "rewards": row.rewards.to_string(),
+                "pct": report.pct(row.rewards),
+            })
+        })
+        .collect();
+    let json = json!({
+        "block": block.as_u64(),
+        "total_rewards_expected": report.total_rewards_expected.to_string(),
+        "total_rewards_given": report.total_rewards_given.to_string(),
+        "report_hash": format!("{:?}", report.hash()),
+        "rows": rows,
+    });
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
@@ -0,0 +1,131 @@
+//! Embeds the rewards oracle in a tokio service. An in-memory chain stands in for the provider,
+//! so this runs offline:
+//!
+//! ```sh
+//! cargo run --example oracle
+//! ```
+
+use async_trait::async_trait;
+use ethers::{
+    core::{
+        abi::{encode, Token},
+        types::{Address, Bytes, Filter, Log, ValueOrArray, H256, U256, U64},
+    },
+    utils::{format_ether, keccak256, parse_ether},
+};
+use oprtc_calculator::{prelude::*, Result};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+/// The vault's logs and a head mined by hand. Clones share both.
+#[derive(Clone, Default)]
+struct MemoryChain {
+    logs: Arc<Mutex<Vec<Log>>>,
+    head: Arc<AtomicU64>,
+}
+
+impl MemoryChain {
+    fn deposit(&self, owner: Address, shares: U256, block_number: u64) {
+        let signature = H256::from(keccak256("Deposit(address,address,uint256,uint256)"));
+        self.logs.lock().unwrap().push(Log {
+            address: VAULT.parse().unwrap(),
+            topics: vec![signature, H256::from(owner), H256::from(owner)],
+            data: Bytes::from(encode(&[Token::Uint(shares), Token::Uint(shares)])),
+            block_number: Some(U64::from(block_number)),
+            ..Default::default()
+        });
+        self.head.fetch_max(block_number, Ordering::Relaxed);
+    }
+
+    fn mine(&self, blocks: u64) {
+        self.head.fetch_add(blocks, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl LogSource for MemoryChain {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        let topic0 = match &filter.topics[0] {
+            Some(ValueOrArray::Value(Some(topic))) => Some(*topic),
+            _ => None,
+        };
+        let from_block = filter.get_from_block().unwrap_or_default();
+        let to_block = filter.get_to_block().unwrap_or(U64::MAX);
+
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|log| topic0.is_none() || log.topics.first() == topic0.as_ref())
+            .filter(|log| {
+                log.block_number
+                    .is_some_and(|block| from_block <= block && block <= to_block)
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl HeadSource for MemoryChain {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok(self.head.load(Ordering::Relaxed))
+    }
+
+    async fn safe_block(&self) -> Result<u64> {
+        self.latest_block().await
+    }
+
+    async fn finalized_block(&self) -> Result<u64> {
+        self.latest_block().await
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let bob = Address::from_low_u64_be(0xb0b);
+    let chain = MemoryChain::default();
+    chain.deposit(bob, parse_ether("1").unwrap(), BLOCK_CONTRACT_DEPLOYED);
+    chain.mine(100);
+
+    let mut config = OracleConfig::new(VAULT.parse().unwrap());
+    config.ttl = Duration::from_millis(200);
+    // `StalePolicy::ServeStale` answers at once past the TTL, refreshing in the background
+    config.policy = StalePolicy::Wait;
+    let oracle = RewardsOracle::new(chain.clone(), config.clone());
+
+    // request handlers each hold a clone, sharing one cache
+    let mut handlers = vec![];
+    for _ in 0..3 {
+        let oracle = oracle.clone();
+        handlers.push(tokio::spawn(async move { oracle.rewards_of(bob).await }));
+    }
+    for handler in handlers {
+        let view = handler.await.expect("the handler should not panic")?;
+        println!(
+            "bob: {} rewards at block {}",
+            format_ether(view.rewards),
+            view.block
+        );
+    }
+
+    chain.mine(50);
+    tokio::time::sleep(config.ttl).await;
+    let view = oracle.rewards_of(bob).await?;
+    println!(
+        "bob: {} rewards at block {} after the TTL",
+        format_ether(view.rewards),
+        view.block
+    );
+    println!("{:?}", oracle.metrics());
+
+    Ok(())
+}
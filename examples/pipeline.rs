@@ -0,0 +1,128 @@
+//! Times fetching and replaying a history chunk by chunk against the pipelined stages, on an
+//! in-memory chain that answers every request after a fixed latency:
+//!
+//! ```sh
+//! cargo run --release --example pipeline
+//! ```
+
+use async_trait::async_trait;
+use ethers::{
+    core::{
+        abi::{encode, Token},
+        types::{Address, Bytes, Filter, Log, ValueOrArray, H256, U256, U64},
+    },
+    utils::{keccak256, parse_ether},
+};
+use oprtc_calculator::{prelude::*, Result};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+const CHUNKS: u64 = 40;
+const LATENCY: Duration = Duration::from_millis(25);
+
+/// The vault's logs, served after `LATENCY` like a remote provider.
+#[derive(Clone)]
+struct SlowChain {
+    logs: Arc<Vec<Log>>,
+}
+
+impl SlowChain {
+    /// A deposit and a transfer every 50 blocks across the range.
+    fn new(from_block: u64, to_block: u64) -> SlowChain {
+        let vault = VAULT.parse().unwrap();
+        let log = |event: &str, topics: &[Address], words: &[U256], block_number: u64| Log {
+            address: vault,
+            topics: std::iter::once(H256::from(keccak256(event)))
+                .chain(topics.iter().map(|topic| H256::from(*topic)))
+                .collect(),
+            data: Bytes::from(encode(
+                &words
+                    .iter()
+                    .map(|word| Token::Uint(*word))
+                    .collect::<Vec<_>>(),
+            )),
+            block_number: Some(U64::from(block_number)),
+            ..Default::default()
+        };
+
+        let one = parse_ether("1").unwrap();
+        let mut logs = vec![];
+        for (i, block_number) in (from_block..=to_block).step_by(50).enumerate() {
+            let owner = Address::from_low_u64_be(i as u64 % 64 + 1);
+            let recipient = Address::from_low_u64_be(i as u64 % 64 + 2);
+            logs.push(log(
+                "Deposit(address,address,uint256,uint256)",
+                &[owner, owner],
+                &[one, one],
+                block_number,
+            ));
+            logs.push(log(
+                "Transfer(address,address,uint256)",
+                &[owner, recipient],
+                &[one / 2],
+                block_number,
+            ));
+        }
+        SlowChain {
+            logs: Arc::new(logs),
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for SlowChain {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        tokio::time::sleep(LATENCY).await;
+        let topic0 = match &filter.topics[0] {
+            Some(ValueOrArray::Value(Some(topic))) => Some(*topic),
+            _ => None,
+        };
+        let from_block = filter.get_from_block().unwrap_or_default();
+        let to_block = filter.get_to_block().unwrap_or(U64::MAX);
+
+        Ok(self
+            .logs
+            .iter()
+            .filter(|log| topic0.is_none() || log.topics.first() == topic0.as_ref())
+            .filter(|log| {
+                log.block_number
+                    .is_some_and(|block| from_block <= block && block <= to_block)
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let from_block = BLOCK_CONTRACT_DEPLOYED;
+    let to_block = from_block + CHUNKS * CHUNK_SIZE - 1;
+    let chain = SlowChain::new(from_block, to_block);
+    let flavor = VaultFlavor::oprtc_v1();
+    let vault = VAULT.parse().unwrap();
+    let chunks = chunk_grid(from_block, to_block, CHUNK_SIZE);
+
+    // the stages one after the other, a chunk at a time
+    let started = Instant::now();
+    let mut serial = GlobalState::new();
+    for (start, end) in &chunks {
+        let decoded = fetch_events(&chain, &flavor, vault, *start, *end).await?;
+        serial.try_process_events(decoded.strict()?)?;
+    }
+    println!("serial: {} chunks in {:?}", chunks.len(), started.elapsed());
+
+    for depth in [1, 4, 16] {
+        let mut pipelined = GlobalState::new();
+        let timings = fetch_pipelined(&chain, &flavor, vault, &chunks, depth, |decoded| {
+            pipelined.try_process_events(decoded.strict()?)
+        })
+        .await?;
+        assert_eq!(pipelined.state_hash(), serial.state_hash());
+        println!("depth {}: {}", depth, timings);
+    }
+
+    Ok(())
+}
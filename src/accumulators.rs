@@ -0,0 +1,172 @@
+//! Checkpoints of the global accumulators every so many blocks, so the accumulators at a past
+//! block take a binary search and a short preview instead of a replay of the events before it.
+
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use ethers::core::types::{U256, U64};
+use serde::{Deserialize, Serialize};
+
+/// Blocks between checkpoints unless given.
+pub const CHECKPOINT_BLOCKS: u64 = 1_000;
+
+/// The global accumulators as a preview at `block_number` gives them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Accumulator {
+    pub block_number: U64,
+    /// Rewards per share, scaled by 1e18
+    #[serde(serialize_with = "serialize_u256")]
+    pub total_rewards_per_share: U256,
+    /// Shares earning rewards
+    #[serde(serialize_with = "serialize_u256")]
+    pub total_shares_staked: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub emission_to_date: U256,
+}
+
+/// The accumulators at a multiple of the interval, with what previews the blocks after it up
+/// to the next event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub block_number: U64,
+    pub total_rewards_per_share: U256,
+    pub total_shares_staked: U256,
+    pub emission_to_date: U256,
+    /// Block the accumulator was last folded at, and its value there
+    pub accounted_block: U64,
+    pub accounted_rewards_per_share: U256,
+    /// Block of the first event after `block_number`, up to which the shares hold
+    pub next_event_block: U64,
+}
+
+impl Checkpoint {
+    pub fn accumulator(&self) -> Accumulator {
+        Accumulator {
+            block_number: self.block_number,
+            total_rewards_per_share: self.total_rewards_per_share,
+            total_shares_staked: self.total_shares_staked,
+            emission_to_date: self.emission_to_date,
+        }
+    }
+}
+
+/// Checkpoints at every multiple of `interval` the state moved past, in block order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Checkpoints {
+    interval: u64,
+    checkpoints: Vec<Checkpoint>,
+    /// Block of the last event, from which the state itself previews every block
+    live_from: U64,
+}
+
+impl Checkpoints {
+    pub fn new(interval: u64, live_from: U64) -> Checkpoints {
+        Checkpoints {
+            interval,
+            checkpoints: vec![],
+            live_from,
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn live_from(&self) -> U64 {
+        self.live_from
+    }
+
+    /// The multiples of the interval an event at `block_number` moves the state past.
+    pub fn due_before(&self, block_number: U64) -> impl Iterator<Item = U64> {
+        let first = self.live_from.as_u64().div_ceil(self.interval) * self.interval;
+        (first..block_number.as_u64())
+            .step_by(self.interval as usize)
+            .map(U64::from)
+    }
+
+    /// Adds the checkpoints taken before an event at `block_number`.
+    pub fn record(&mut self, checkpoints: Vec<Checkpoint>, block_number: U64) {
+        self.checkpoints.extend(checkpoints);
+        self.live_from = block_number;
+    }
+
+    /// The last checkpoint at or before `block_number`.
+    pub fn at_or_before(&self, block_number: U64) -> Option<&Checkpoint> {
+        let after = self
+            .checkpoints
+            .partition_point(|checkpoint| checkpoint.block_number <= block_number);
+        after.checked_sub(1).map(|i| &self.checkpoints[i])
+    }
+}
+
+/// `points` blocks evenly spread over `(from_block, to_block]`, each but the last rounded down
+/// to a multiple of `interval` so that a checkpoint holds its accumulators.
+pub fn series_blocks(from_block: U64, to_block: U64, points: usize, interval: u64) -> Vec<U64> {
+    let span = (to_block.saturating_sub(from_block)).as_u64();
+    let mut blocks: Vec<U64> = (1..points as u64)
+        .map(|i| from_block.as_u64() + span * i / points as u64)
+        .map(|block| U64::from(block - block % interval))
+        .filter(|block| *block > from_block)
+        .collect();
+    blocks.push(to_block);
+    blocks.dedup();
+    blocks
+}
+
+pub fn render_series(
+    series: &[Accumulator],
+    format: OutputFormat,
+    freshness: &Freshness,
+) -> String {
+    let columns = [
+        "block_number",
+        "total_rewards_per_share",
+        "total_shares_staked",
+        "emission_to_date",
+    ];
+
+    let cells = |point: &Accumulator| {
+        [
+            point.block_number.to_string(),
+            point.total_rewards_per_share.to_string(),
+            point.total_shares_staked.to_string(),
+            point.emission_to_date.to_string(),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(series, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for point in series {
+                out += &(cells(point).join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for point in series {
+                out += &format!("| {} |\n", cells(point).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_series_points_over_checkpoint_blocks() {
+        let blocks = series_blocks(U64::from(1_500), U64::from(10_250), 4, 1_000);
+        let blocks: Vec<u64> = blocks.iter().map(U64::as_u64).collect();
+        assert_eq!(blocks, vec![3_000, 5_000, 8_000, 10_250]);
+
+        // points closer than the interval collapse into one
+        let blocks = series_blocks(U64::from(1_500), U64::from(2_100), 10, 1_000);
+        assert_eq!(blocks, vec![U64::from(2_000), U64::from(2_100)]);
+    }
+}
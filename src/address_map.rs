@@ -0,0 +1,377 @@
+use crate::error::{bail, ensure, Error, Result};
+use crate::report::{Report, WithholdReason};
+use crate::stdio;
+use ethers::{core::types::Address, utils::to_checksum};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+/// Chain a rewards export is mirrored to, each with its own address format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum ExportChain {
+    /// An EVM L2, addresses EIP-55 checksummed
+    L2,
+    /// A Cosmos chain, addresses bech32
+    Cosmos,
+}
+
+impl ExportChain {
+    fn parse(s: &str) -> Option<ExportChain> {
+        match s {
+            "l2" => Some(ExportChain::L2),
+            "cosmos" => Some(ExportChain::Cosmos),
+            _ => None,
+        }
+    }
+
+    /// Checks that `address` is well formed on this chain.
+    pub fn validate(self, address: &str) -> Result<(), String> {
+        match self {
+            ExportChain::L2 => {
+                let parsed: Address = address
+                    .parse()
+                    .map_err(|_| format!("`{}` is not an EVM address", address))?;
+                let checksummed = to_checksum(&parsed, None);
+                if address != checksummed {
+                    return Err(format!(
+                        "`{}` is not EIP-55 checksummed, expected `{}`",
+                        address, checksummed
+                    ));
+                }
+                Ok(())
+            }
+            ExportChain::Cosmos => {
+                let (_, data) = decode_bech32(address)?;
+                if data.len() != 20 && data.len() != 32 {
+                    return Err(format!(
+                        "`{}` holds {} bytes, expected a 20 or 32 byte account",
+                        address,
+                        data.len()
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExportChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportChain::L2 => "l2",
+            ExportChain::Cosmos => "cosmos",
+        })
+    }
+}
+
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn bech32_polymod(values: impl Iterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = (checksum & 0x1ffffff) << 5 ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+/// Decodes a BIP-173 bech32 string into its human-readable part and data bytes.
+fn decode_bech32(s: &str) -> Result<(String, Vec<u8>), String> {
+    let invalid = |why: &str| format!("`{}` is not bech32: {}", s, why);
+    if s.len() < 8 || s.len() > 90 {
+        return Err(invalid("wrong length"));
+    }
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(invalid("mixed case"));
+    }
+    let s_lower = s.to_ascii_lowercase();
+    let (hrp, data) = s_lower
+        .rsplit_once('1')
+        .ok_or_else(|| invalid("no separator"))?;
+    if hrp.is_empty() || data.len() < 6 || !hrp.bytes().all(|b| (33..=126).contains(&b)) {
+        return Err(invalid("malformed human-readable part or data"));
+    }
+
+    let values = data
+        .bytes()
+        .map(|b| BECH32_CHARSET.iter().position(|c| *c == b).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| invalid("character outside the charset"))?;
+    let expanded = hrp
+        .bytes()
+        .map(|b| b >> 5)
+        .chain([0])
+        .chain(hrp.bytes().map(|b| b & 31));
+    if bech32_polymod(expanded.chain(values.iter().copied())) != 1 {
+        return Err(invalid("bad checksum"));
+    }
+
+    // regroup the 5-bit values, checksum left out, into bytes
+    let mut bytes = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+    for value in &values[..values.len() - 6] {
+        acc = acc << 5 | *value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return Err(invalid("non-zero padding"));
+    }
+
+    Ok((hrp.to_string(), bytes))
+}
+
+/// Addresses rewards are paid to on other chains, read from a CSV file:
+///
+/// ```csv
+/// source_address,mapped_address,chain
+/// 0x0000000000000000000000000000000000000B0b,0x1111000000000000000000000000000000001C1C,l2
+/// 0x0000000000000000000000000000000000000B0b,cosmos1qypqxpq9qcrsszg2pvxq6rs0zqg3yyc5lzv7xu,cosmos
+/// ```
+#[derive(Debug, Default)]
+pub struct AddressMap {
+    mapped: HashMap<(Address, ExportChain), String>,
+}
+
+impl AddressMap {
+    pub fn parse(contents: &str) -> Result<AddressMap> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+        ensure!(
+            lines.next().map(|(_, header)| header.trim())
+                == Some("source_address,mapped_address,chain"),
+            Error::config,
+            "the address map must start with the header source_address,mapped_address,chain"
+        );
+
+        let mut mapped = HashMap::new();
+        for (i, line) in lines {
+            let line_number = i + 1;
+            let fields: Vec<_> = line.split(',').map(str::trim).collect();
+            let [source, target, chain] = fields[..] else {
+                bail!(
+                    Error::config,
+                    "line {} has {} fields, expected 3",
+                    line_number,
+                    fields.len()
+                );
+            };
+            let source: Address = source.parse().map_err(|_| {
+                Error::config(format!(
+                    "line {}: `{}` is not an address",
+                    line_number, source
+                ))
+            })?;
+            let chain = ExportChain::parse(chain).ok_or_else(|| {
+                Error::config(format!(
+                    "line {}: unknown chain `{}`, expected l2 or cosmos",
+                    line_number, chain
+                ))
+            })?;
+            chain
+                .validate(target)
+                .map_err(|err| Error::config(format!("line {}: {}", line_number, err)))?;
+            ensure!(
+                mapped.insert((source, chain), target.to_string()).is_none(),
+                Error::config,
+                "line {}: {:?} is mapped twice for {}",
+                line_number,
+                source,
+                chain
+            );
+        }
+
+        Ok(AddressMap { mapped })
+    }
+
+    pub fn load(path: &Path) -> Result<AddressMap> {
+        let contents = stdio::read_to_string(path).map_err(|err| {
+            Error::io(
+                format!("failed to read address map {}", path.display()),
+                err,
+            )
+        })?;
+
+        AddressMap::parse(&contents).map_err(|err| {
+            Error::config(format!("invalid address map {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn get(&self, address: Address, chain: ExportChain) -> Option<&str> {
+        self.mapped.get(&(address, chain)).map(String::as_str)
+    }
+}
+
+/// Rewrites the address of every row for `chain`, keeping sub-account suffixes, and moves rows
+/// whose address has no mapping to the report's withheld bucket.
+pub fn map_report(report: &mut Report, map: &AddressMap, chain: ExportChain) -> Result<()> {
+    let mut rows = vec![];
+    let mut seen = HashSet::new();
+
+    for mut row in std::mem::take(&mut report.rows) {
+        let (address, suffix) = match row.label.split_once('#') {
+            Some((address, name)) => (address, format!("#{}", name)),
+            None => (row.label.as_str(), String::new()),
+        };
+        let address: Address = address
+            .parse()
+            .map_err(|_| Error::config(format!("can't map the label {}", row.label)))?;
+
+        match map.get(address, chain) {
+            Some(mapped) => {
+                row.label = format!("{}{}", mapped, suffix);
+                ensure!(
+                    seen.insert(row.label.clone()),
+                    Error::config,
+                    "several rows map to {} on {}",
+                    row.label,
+                    chain
+                );
+                rows.push(row);
+            }
+            None => {
+                row.reason = Some(WithholdReason::Unmapped);
+                report.withheld.push(row);
+            }
+        }
+    }
+
+    report.rows = rows;
+    report.export_chain = Some(chain);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ReportRow;
+    use crate::rounding::Rounding;
+    use ethers::{core::types::U256, utils::parse_ether};
+
+    const COSMOS_BOB: &str = "cosmos1qypqxpq9qcrsszg2pvxq6rs0zqg3yyc5lzv7xu";
+
+    #[test]
+    fn validates_bech32_and_checksummed_addresses() {
+        // BIP-173 vectors
+        assert_eq!(
+            decode_bech32("A12UEL5L").unwrap(),
+            ("a".to_string(), vec![])
+        );
+        assert!(decode_bech32("abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw").is_ok());
+        assert!(
+            decode_bech32("split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w").is_ok()
+        );
+        for invalid in [
+            "an84characterslonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1569pvx",
+            "li1dgmt3",
+            "pzry9x0s0muk",
+            "1pzry9x0s0muk",
+            "x1b4n0q5v",
+            "A1G7SGD8",
+            "a12UEL5L",
+        ] {
+            assert!(decode_bech32(invalid).is_err(), "{} decoded", invalid);
+        }
+
+        assert_eq!(
+            decode_bech32(COSMOS_BOB).unwrap(),
+            ("cosmos".to_string(), (1..=20).collect())
+        );
+        let cosmos = ExportChain::Cosmos;
+        assert!(cosmos.validate(COSMOS_BOB).is_ok());
+        assert!(cosmos
+            .validate("osmo14w46h2at4w46h2at4w46h2at4w46h2at4w46h2at4w46h2at4w4sqaazhc")
+            .is_ok());
+        let typo = COSMOS_BOB.replace("lzv7xu", "lzv7xv");
+        assert!(cosmos.validate(&typo).unwrap_err().contains("bad checksum"));
+        // a valid checksum over a 1 byte payload is no account
+        assert!(cosmos.validate("A12UEL5L").is_err());
+
+        let l2 = ExportChain::L2;
+        assert!(l2
+            .validate("0x0000000000000000000000000000000000000B0b")
+            .is_ok());
+        assert!(l2
+            .validate("0x0000000000000000000000000000000000000b0b")
+            .unwrap_err()
+            .contains("not EIP-55"));
+        assert!(l2.validate(COSMOS_BOB).is_err());
+    }
+
+    #[test]
+    fn withholds_rows_without_a_mapping() {
+        let map = AddressMap::parse(&format!(
+            "source_address,mapped_address,chain\n\
+             0x0000000000000000000000000000000000000B0b,{COSMOS_BOB},cosmos\n\
+             0x00000000000000000000000000000000000A11cE,0x0000000000000000000000000000000000000B0b,l2\n"
+        ))
+        .unwrap();
+        let row = |label: &str, rewards: &str| ReportRow {
+            label: label.to_string(),
+            rewards: parse_ether(rewards).unwrap(),
+            bonus: U256::zero(),
+            note: None,
+            reason: None,
+        };
+        let mut report = Report {
+            total_rewards_expected: parse_ether("100").unwrap(),
+            total_rewards_given: parse_ether("100").unwrap(),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 18,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            rows: vec![
+                row("0x0000000000000000000000000000000000000b0b#a", "50"),
+                row("0x00000000000000000000000000000000000a11ce", "30"),
+                row("0x0000000000000000000000000000000000000b0b#b", "20"),
+            ],
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+        };
+
+        map_report(&mut report, &map, ExportChain::Cosmos).unwrap();
+
+        let labels: Vec<_> = report.rows.iter().map(|row| row.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            [format!("{COSMOS_BOB}#a"), format!("{COSMOS_BOB}#b")]
+        );
+        assert_eq!(
+            report.withheld,
+            [ReportRow {
+                reason: Some(WithholdReason::Unmapped),
+                ..row("0x00000000000000000000000000000000000a11ce", "30")
+            }]
+        );
+        assert_eq!(report.total_rewards_given, parse_ether("100").unwrap());
+        let rendered = report.to_string();
+        assert!(rendered.contains("withheld (1 unmapped)"));
+        assert!(rendered.contains("exported for cosmos"));
+
+        let twice = "source_address,mapped_address,chain\n\
+                     0x0000000000000000000000000000000000000B0b,0x0000000000000000000000000000000000000B0b,l2\n\
+                     0x0000000000000000000000000000000000000b0b,0x00000000000000000000000000000000000A11cE,l2\n";
+        assert!(AddressMap::parse(twice).is_err());
+        let lowercase = "source_address,mapped_address,chain\n\
+                         0x0000000000000000000000000000000000000B0b,0x0000000000000000000000000000000000000b0b,l2\n";
+        assert!(AddressMap::parse(lowercase).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::error::{Error, Result};
+use crate::stdio;
+use ethers::core::types::Address;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+use toml_edit::{value, Array, Document, Item, Table};
+
+/// Free-text note and tags operations keeps about an address.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Annotation {
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Annotations file, a TOML table per address:
+///
+/// ```toml
+/// ["0x00000000000000000000000000000000000A11cE"]
+/// note = "old treasury"
+/// tags = ["treasury"]
+/// ```
+#[derive(Debug, Default)]
+pub struct Annotations {
+    entries: HashMap<Address, Annotation>,
+}
+
+impl Annotations {
+    pub fn parse(contents: &str) -> Result<Annotations> {
+        let tables: BTreeMap<String, Annotation> =
+            toml::from_str(contents).map_err(|err| Error::config(err.to_string()))?;
+        let mut entries = HashMap::new();
+
+        for (key, annotation) in tables {
+            let address = key
+                .parse::<Address>()
+                .map_err(|_| Error::config(format!("`{}` is not an address", key)))?;
+            entries.insert(address, annotation);
+        }
+
+        Ok(Annotations { entries })
+    }
+
+    pub fn load(path: &Path) -> Result<Annotations> {
+        let contents = stdio::read_to_string(path).map_err(|err| {
+            Error::io(
+                format!("failed to read annotations {}", path.display()),
+                err,
+            )
+        })?;
+
+        Annotations::parse(&contents).map_err(|err| {
+            Error::config(format!("invalid annotations {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn get(&self, address: Address) -> Option<&Annotation> {
+        self.entries.get(&address)
+    }
+
+    pub fn has_tag(&self, address: Address, tag: &str) -> bool {
+        self.get(address)
+            .is_some_and(|annotation| annotation.tags.iter().any(|t| t == tag))
+    }
+}
+
+/// Sets the note of `address` and adds `tags` to it, leaving comments and the layout of the
+/// rest of the document untouched.
+pub fn annotate(
+    contents: &str,
+    address: Address,
+    note: Option<&str>,
+    tags: &[String],
+) -> Result<String> {
+    let mut doc: Document = contents
+        .parse()
+        .map_err(|err: toml_edit::TomlError| Error::config(err.to_string()))?;
+
+    // the address may already be listed with a different casing
+    let key = doc
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .find(|key| key.parse::<Address>().ok() == Some(address))
+        .unwrap_or_else(|| format!("{:?}", address));
+
+    let table = doc
+        .as_table_mut()
+        .entry(&key)
+        .or_insert(Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| Error::config(format!("`{}` is not a table", key)))?;
+
+    if let Some(note) = note {
+        table["note"] = value(note);
+    }
+
+    if !tags.is_empty() {
+        let existing = table
+            .entry("tags")
+            .or_insert(value(Array::new()))
+            .as_array_mut()
+            .ok_or_else(|| Error::config(format!("tags of `{}` are not a list", key)))?;
+
+        for tag in tags {
+            if !existing.iter().any(|t| t.as_str() == Some(tag.as_str())) {
+                existing.push(tag.as_str());
+            }
+        }
+    }
+
+    Ok(doc.to_string())
+}
+
+/// Writes through `stdio::replace`, so a crash never leaves a truncated annotations file.
+pub fn save(path: &Path, contents: &str) -> Result<()> {
+    stdio::replace(path, contents.as_bytes()).map_err(|err| {
+        Error::io(
+            format!("failed to write annotations {}", path.display()),
+            err,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    const NOTES: &str = r#"# kept by operations
+["0x0000000000000000000000000000000000000B0b"]
+note = "old treasury" # moved in 2023
+tags = ["treasury"]
+"#;
+
+    #[test]
+    fn parses_notes_and_tags() {
+        let annotations = Annotations::parse(NOTES).unwrap();
+        let bob = BOB.parse().unwrap();
+
+        assert_eq!(
+            annotations.get(bob),
+            Some(&Annotation {
+                note: Some("old treasury".to_string()),
+                tags: vec!["treasury".to_string()],
+            })
+        );
+        assert_eq!(annotations.get(ALICE.parse().unwrap()), None);
+
+        let err = Annotations::parse("[treasury]\nnote = \"x\"\n").unwrap_err();
+        assert!(err.to_string().contains("not an address"));
+    }
+
+    #[test]
+    fn annotating_merges_into_existing_entries_and_keeps_comments() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+
+        let contents = annotate(NOTES, bob, None, &["treasury".into(), "multisig".into()]).unwrap();
+        let contents = annotate(
+            &contents,
+            alice,
+            Some("contacted support about missing rewards on 2024-03-02"),
+            &[],
+        )
+        .unwrap();
+
+        assert!(contents.starts_with("# kept by operations\n"));
+        assert!(contents.contains("# moved in 2023"));
+
+        let annotations = Annotations::parse(&contents).unwrap();
+        assert_eq!(
+            annotations.get(bob).unwrap().tags,
+            vec!["treasury".to_string(), "multisig".to_string()]
+        );
+        assert_eq!(
+            annotations.get(bob).unwrap().note.as_deref(),
+            Some("old treasury")
+        );
+        assert!(annotations.get(alice).unwrap().tags.is_empty());
+    }
+
+    #[test]
+    fn filters_by_tag() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+
+        let contents = annotate(NOTES, alice, None, &["retail".into()]).unwrap();
+        let annotations = Annotations::parse(&contents).unwrap();
+
+        let treasury: Vec<_> = [bob, alice]
+            .into_iter()
+            .filter(|address| annotations.has_tag(*address, "treasury"))
+            .collect();
+        assert_eq!(treasury, vec![bob]);
+        assert!(annotations.has_tag(alice, "retail"));
+    }
+}
@@ -0,0 +1,438 @@
+//! The binary cache format: a header, then one fixed-width record per event in block order.
+//! Files are memory-mapped rather than read, and records decoded straight from the map, so a
+//! run can seek to a block by binary search without touching the events before it.
+//!
+//! Every number is little-endian. The header holds, in order:
+//!
+//! | bytes | field |
+//! |---|---|
+//! | 8 | magic, `OPRTCEVC` |
+//! | 2 | format version |
+//! | 2 | byte-order mark, `0xfeff` |
+//! | 2 | record width |
+//! | 2 | zero |
+//! | 20 | vault |
+//! | 8 each | chain id, from block, to block, chunk size, logs, undecodable logs |
+//! | 8 | completed chunks `c` |
+//! | 8 | events `n` |
+//! | 8 × `c` | first block of every completed chunk |
+//!
+//! and a record the block (8), the kind (1: deposit 0, withdrawal 1, transfer 2), the account
+//! or sender (20), the receiver of a transfer or zero (20), the shares (32), the transaction
+//! index (8) and the log index (8). Version 1 records end at the shares, their events reading
+//! back with zero indices.
+
+use crate::cache::EventCache;
+use crate::error::{ensure, Error, Result};
+use crate::state::{Deposit, Event, Transfer, Withdraw};
+use crate::units::Shares;
+use ethers::core::types::{Address, U256, U64};
+use std::io::{self, Write};
+
+pub(crate) const MAGIC: &[u8; 8] = b"OPRTCEVC";
+pub(crate) const VERSION: u16 = 2;
+const BYTE_ORDER_MARK: u16 = 0xfeff;
+const RECORD_WIDTH: usize = V1_RECORD_WIDTH + 8 + 8;
+/// Records of version 1 files, without the transaction and log indices
+const V1_RECORD_WIDTH: usize = 8 + 1 + 20 + 20 + 32;
+/// Header bytes before the completed chunks
+const FIXED_HEADER: usize = 8 + 2 + 2 + 2 + 2 + 20 + 6 * 8 + 8 + 8;
+
+const DEPOSIT: u8 = 0;
+const WITHDRAWAL: u8 = 1;
+const TRANSFER: u8 = 2;
+
+pub(crate) fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Writes `cache` to `writer`, the header and then one record per event.
+pub(crate) fn encode<W: Write>(cache: &EventCache, mut writer: W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&BYTE_ORDER_MARK.to_le_bytes())?;
+    writer.write_all(&(RECORD_WIDTH as u16).to_le_bytes())?;
+    writer.write_all(&[0; 2])?;
+    writer.write_all(cache.vault.as_bytes())?;
+    for number in [
+        cache.chain_id,
+        cache.from_block,
+        cache.to_block,
+        cache.chunk_size,
+        cache.logs as u64,
+        cache.undecodable_logs as u64,
+        cache.completed_chunks.len() as u64,
+        cache.events.len() as u64,
+    ] {
+        writer.write_all(&number.to_le_bytes())?;
+    }
+    for start in &cache.completed_chunks {
+        writer.write_all(&start.to_le_bytes())?;
+    }
+
+    let mut record = [0; RECORD_WIDTH];
+    for evt in &cache.events {
+        let (kind, address, to) = match evt {
+            Event::Deposit(deposit) => (DEPOSIT, deposit.address, Address::zero()),
+            Event::Withdrawal(withdraw) => (WITHDRAWAL, withdraw.address, Address::zero()),
+            Event::Transfer(transfer) => (TRANSFER, transfer.from, transfer.to),
+        };
+        record[..8].copy_from_slice(&evt.block_number().as_u64().to_le_bytes());
+        record[8] = kind;
+        record[9..29].copy_from_slice(address.as_bytes());
+        record[29..49].copy_from_slice(to.as_bytes());
+        evt.shares().0.to_little_endian(&mut record[49..81]);
+        let (_, transaction_index, log_index) = evt.position();
+        record[81..89].copy_from_slice(&transaction_index.to_le_bytes());
+        record[89..].copy_from_slice(&log_index.to_le_bytes());
+        writer.write_all(&record)?;
+    }
+
+    writer.flush()
+}
+
+/// Reads the fields of the header in order, failing on a file too short to hold them.
+struct Header<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Header<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let field = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or_else(|| Error::decode("the header is truncated"))?;
+        self.at += len;
+        Ok(field)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Decodes a cache from the bytes of a binary cache file, keeping only the events past
+/// `after` when given. The events before it are skipped by binary search, never decoded.
+pub(crate) fn decode(bytes: &[u8], after: Option<u64>) -> Result<EventCache> {
+    let mut header = Header { bytes, at: 0 };
+    ensure!(
+        header.take(8)? == MAGIC,
+        Error::decode,
+        "not a binary cache"
+    );
+    let version = header.u16()?;
+    ensure!(
+        version == 1 || version == VERSION,
+        Error::decode,
+        "format version {} is not supported, this build reads version {}",
+        version,
+        VERSION
+    );
+    ensure!(
+        header.u16()? == BYTE_ORDER_MARK,
+        Error::decode,
+        "written big-endian, the format is little-endian"
+    );
+    let expected = if version == 1 {
+        V1_RECORD_WIDTH
+    } else {
+        RECORD_WIDTH
+    };
+    let width = header.u16()? as usize;
+    ensure!(
+        width == expected,
+        Error::decode,
+        "records are {} bytes wide, expected {}",
+        width,
+        expected
+    );
+    header.take(2)?;
+
+    let mut cache = EventCache::new(
+        Address::from_slice(header.take(20)?),
+        header.u64()?,
+        header.u64()?,
+        header.u64()?,
+        header.u64()?,
+    );
+    cache.logs = header.u64()? as usize;
+    cache.undecodable_logs = header.u64()? as usize;
+    let chunks = header.u64()? as usize;
+    let count = header.u64()? as usize;
+    cache.completed_chunks = (0..chunks).map(|_| header.u64()).collect::<Result<_>>()?;
+    debug_assert_eq!(FIXED_HEADER + chunks * 8, header.at);
+
+    let records = &bytes[header.at..];
+    ensure!(
+        records.len() == count * width,
+        Error::decode,
+        "{} bytes of records for {} events, the file is truncated or has trailing bytes",
+        records.len(),
+        count
+    );
+    let record = |i: usize| &records[i * width..(i + 1) * width];
+    let block = |i: usize| u64::from_le_bytes(record(i)[..8].try_into().unwrap());
+
+    let first = match after {
+        Some(after) => partition_point(count, |i| block(i) <= after),
+        None => 0,
+    };
+    cache.events = (first..count)
+        .map(|i| decode_record(record(i)))
+        .collect::<Result<_>>()?;
+
+    Ok(cache)
+}
+
+/// The first of `0..count` for which `before` is false, `before` holding for a prefix.
+fn partition_point(count: usize, before: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if before(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+fn decode_record(record: &[u8]) -> Result<Event> {
+    let block_number = U64::from(u64::from_le_bytes(record[..8].try_into().unwrap()));
+    let address = Address::from_slice(&record[9..29]);
+    let to = Address::from_slice(&record[29..49]);
+    let shares = U256::from_little_endian(&record[49..81]);
+    let index = |at: usize| {
+        record
+            .get(at..at + 8)
+            .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let (transaction_index, log_index) = (index(81), index(89));
+
+    Ok(match record[8] {
+        DEPOSIT => Event::Deposit(Deposit {
+            address,
+            shares: Shares(shares),
+            block_number,
+            transaction_index,
+            log_index,
+        }),
+        WITHDRAWAL => Event::Withdrawal(Withdraw {
+            address,
+            shares: Shares(shares),
+            block_number,
+            transaction_index,
+            log_index,
+        }),
+        TRANSFER => Event::Transfer(Transfer {
+            from: address,
+            to,
+            shares: Shares(shares),
+            block_number,
+            transaction_index,
+            log_index,
+        }),
+        kind => {
+            return Err(Error::decode(format!(
+                "record at block {} has unknown kind {}",
+                block_number, kind
+            )))
+        }
+    })
+}
+
+/// A read-only view of a whole file, memory-mapped on unix and read elsewhere.
+pub(crate) use map::Mmap;
+
+#[cfg(unix)]
+mod map {
+    use std::{fs::File, io, ops::Deref, os::unix::io::AsRawFd, ptr, slice};
+
+    pub(crate) struct Mmap {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    impl Mmap {
+        pub(crate) fn open(file: &File) -> io::Result<Mmap> {
+            let len = file.metadata()?.len() as usize;
+            if len == 0 {
+                return Ok(Mmap {
+                    ptr: ptr::null_mut(),
+                    len,
+                });
+            }
+
+            // caches are replaced by renaming a new file over them and never written in place,
+            // so the mapped file doesn't change under the map
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mmap { ptr, len })
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            if self.len == 0 {
+                return &[];
+            }
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                unsafe { libc::munmap(self.ptr, self.len) };
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod map {
+    use std::{
+        fs::File,
+        io::{self, Read},
+        ops::Deref,
+    };
+
+    pub(crate) struct Mmap(Vec<u8>);
+
+    impl Mmap {
+        pub(crate) fn open(mut file: &File) -> io::Result<Mmap> {
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes)?;
+            Ok(Mmap(bytes))
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::parse_ether;
+
+    fn cache() -> EventCache {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        let mut cache = EventCache::new(Address::from_low_u64_be(0x7a), 1, 100, 199, 50);
+        cache.completed_chunks = vec![100, 150];
+        cache.events = vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(U256::MAX),
+                block_number: U64::from(100),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number: U64::from(150),
+                transaction_index: 4,
+                log_index: 11,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: alice,
+                shares: Shares(one),
+                block_number: U64::from(150),
+                transaction_index: 4,
+                log_index: 12,
+            }),
+        ];
+        cache.logs = 3;
+        cache
+    }
+
+    #[test]
+    fn rejects_other_versions_byte_orders_and_truncated_files() {
+        let mut bytes = vec![];
+        encode(&cache(), &mut bytes).unwrap();
+        assert_eq!(bytes.len(), FIXED_HEADER + 2 * 8 + 3 * RECORD_WIDTH);
+        assert_eq!(decode(&bytes, None).unwrap(), cache());
+        assert_eq!(
+            decode(&bytes, Some(149)).unwrap().events,
+            cache().events[1..]
+        );
+
+        let error = |bytes: &[u8]| decode(bytes, None).unwrap_err().to_string();
+
+        let mut newer = bytes.clone();
+        newer[8..10].copy_from_slice(&3u16.to_le_bytes());
+        assert!(error(&newer).contains("version 3 is not supported"));
+
+        let mut big_endian = bytes.clone();
+        big_endian[10..12].copy_from_slice(&BYTE_ORDER_MARK.to_be_bytes());
+        assert!(error(&big_endian).contains("big-endian"));
+
+        assert!(error(&bytes[..bytes.len() - 1]).contains("truncated"));
+        assert!(error(&bytes[..40]).contains("header is truncated"));
+
+        let mut unknown_kind = bytes.clone();
+        let last = bytes.len() - RECORD_WIDTH;
+        unknown_kind[last + 8] = 7;
+        assert!(error(&unknown_kind).contains("unknown kind 7"));
+    }
+
+    #[test]
+    fn reads_version_1_files_without_indices() {
+        let mut bytes = vec![];
+        encode(&cache(), &mut bytes).unwrap();
+        let header = FIXED_HEADER + 2 * 8;
+        let mut v1 = bytes[..header].to_vec();
+        v1[8..10].copy_from_slice(&1u16.to_le_bytes());
+        v1[12..14].copy_from_slice(&(V1_RECORD_WIDTH as u16).to_le_bytes());
+        for record in bytes[header..].chunks(RECORD_WIDTH) {
+            v1.extend_from_slice(&record[..V1_RECORD_WIDTH]);
+        }
+
+        let decoded = decode(&v1, None).unwrap();
+        assert_eq!(decoded.events.len(), 3);
+        assert!(decoded
+            .events
+            .iter()
+            .all(|evt| evt.position().1 == 0 && evt.position().2 == 0));
+        assert_eq!(
+            decoded
+                .events
+                .iter()
+                .map(Event::block_number)
+                .collect::<Vec<_>>(),
+            cache()
+                .events
+                .iter()
+                .map(Event::block_number)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::state::{Event, GlobalState, StateError};
+use ethers::core::types::{Address, U256, U64};
+use std::{collections::BTreeSet, fmt};
+
+/// The first block after which two replays disagree, with the events each side applied in it
+/// and both states right after it.
+#[derive(Debug)]
+pub struct FirstDivergence {
+    pub block_number: U64,
+    pub left_events: Vec<Event>,
+    pub right_events: Vec<Event>,
+    pub left: GlobalState,
+    pub right: GlobalState,
+}
+
+/// Replays both event streams, sorted by block, in lockstep from the given states and compares
+/// the state hashes after every block either stream has events in. The states may be configured
+/// differently to compare two settings on the same events. Fails on the first event either
+/// state can't apply.
+pub fn bisect(
+    left: &[Event],
+    right: &[Event],
+    mut left_state: GlobalState,
+    mut right_state: GlobalState,
+) -> Result<Option<FirstDivergence>, StateError> {
+    let blocks: BTreeSet<U64> = left.iter().chain(right).map(Event::block_number).collect();
+    // each block's events lead what is left of a sorted stream
+    let take_block = |rest: &mut &[Event], block_number: U64| -> Vec<Event> {
+        let len = rest
+            .iter()
+            .take_while(|evt| evt.block_number() == block_number)
+            .count();
+        let (events, tail) = rest.split_at(len);
+        *rest = tail;
+        events.to_vec()
+    };
+
+    let (mut left_rest, mut right_rest) = (left, right);
+
+    for block_number in blocks {
+        let left_events = take_block(&mut left_rest, block_number);
+        let right_events = take_block(&mut right_rest, block_number);
+
+        left_state.process_events(left_events.clone())?;
+        right_state.process_events(right_events.clone())?;
+
+        if left_state.state_hash() != right_state.state_hash() {
+            return Ok(Some(FirstDivergence {
+                block_number,
+                left_events,
+                right_events,
+                left: left_state,
+                right: right_state,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+impl fmt::Display for FirstDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "first divergence at block {}", self.block_number)?;
+        for (side, events) in [("left", &self.left_events), ("right", &self.right_events)] {
+            writeln!(f, "{} events:", side)?;
+            for evt in events {
+                writeln!(f, "  {:?}", evt)?;
+            }
+        }
+
+        let mut row = |field: &str, left: U256, right: U256| {
+            let marker = if left == right { " " } else { "!" };
+            writeln!(f, "{} {:<24} {:>40} {:>40}", marker, field, left, right)
+        };
+        row(
+            "total shares",
+            self.left.total_shares_staked(),
+            self.right.total_shares_staked(),
+        )?;
+        row(
+            "rewards per share",
+            self.left.total_rewards_per_share(),
+            self.right.total_rewards_per_share(),
+        )?;
+
+        let addresses: BTreeSet<Address> = self
+            .left
+            .users()
+            .chain(self.right.users())
+            .copied()
+            .collect();
+        for address in addresses {
+            let (left_shares, right_shares) =
+                (self.left.shares_of(address), self.right.shares_of(address));
+            let (left_rewards, right_rewards) = (
+                self.left.preview_user_rewards(address, self.block_number),
+                self.right.preview_user_rewards(address, self.block_number),
+            );
+            if left_shares != right_shares || left_rewards != right_rewards {
+                row(&format!("{:?} shares", address), left_shares, right_shares)?;
+                row(
+                    &format!("{:?} rewards", address),
+                    left_rewards,
+                    right_rewards,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Transfer, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    fn stream(len: u64) -> Vec<Event> {
+        let address = |i: u64| Address::from_low_u64_be(0x1000 + i % 17);
+        (0..len)
+            .map(|i| {
+                let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + i / 3);
+                if i % 5 == 4 {
+                    // from the address that deposited just before, so it never overdraws
+                    Event::Transfer(Transfer {
+                        from: address(i - 1),
+                        to: address(i + 1),
+                        shares: Shares(U256::from(i)),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    })
+                } else {
+                    Event::Deposit(Deposit {
+                        address: address(i),
+                        shares: Shares(parse_ether("1").unwrap() + i),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_block_of_a_single_altered_event() {
+        let left = stream(3_000);
+        let mut right = left.clone();
+        let Event::Deposit(deposit) = &mut right[2_500] else {
+            panic!("event 2500 is a deposit");
+        };
+        deposit.shares += Shares(U256::one());
+        let altered = deposit.block_number;
+
+        let divergence = bisect(&left, &right, GlobalState::new(), GlobalState::new())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(divergence.block_number, altered);
+        assert_eq!(divergence.left_events.len(), 3);
+        assert_ne!(divergence.left_events, divergence.right_events);
+        let report = divergence.to_string();
+        assert!(report.contains(&format!("first divergence at block {}", altered)));
+        assert!(report.contains("! total shares"));
+
+        assert!(bisect(&left, &left, GlobalState::new(), GlobalState::new())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,611 @@
+use crate::binary_cache::{self, Mmap};
+use crate::chunks::ChunkStore;
+use crate::error::{bail, ensure, Error, Result};
+use crate::fetch::{chunk_grid, decode_logs, fetch_chunks, Decoded, LogSource};
+use crate::flavor::VaultFlavor;
+use crate::state::Event;
+use crate::stdio::{self, ensure_not_binary, is_stdio};
+use ethers::{
+    core::types::{Address, H256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io, path::Path};
+
+/// How a cache is written. Reads tell the formats apart by their first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CacheFormat {
+    /// One JSON document, readable by other tools
+    #[value(alias = "jsonl")]
+    Json,
+    /// Fixed-width records, memory-mapped when read, see `binary_cache`
+    Binary,
+}
+
+/// Decoded vault events for a block range, along with enough metadata to tell which vault and
+/// chain they came from and which chunks of the range have been fetched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct EventCache {
+    pub vault: Address,
+    pub chain_id: u64,
+    pub from_block: u64,
+    pub to_block: u64,
+    pub chunk_size: u64,
+    /// First block of every chunk fetched so far
+    pub completed_chunks: Vec<u64>,
+    pub events: Vec<Event>,
+    /// Vault logs fetched so far
+    #[serde(default)]
+    pub logs: usize,
+    /// Logs among them that couldn't be decoded and are missing from `events`
+    #[serde(default)]
+    pub undecodable_logs: usize,
+}
+
+impl EventCache {
+    pub fn new(
+        vault: Address,
+        chain_id: u64,
+        from_block: u64,
+        to_block: u64,
+        chunk_size: u64,
+    ) -> EventCache {
+        EventCache {
+            vault,
+            chain_id,
+            from_block,
+            to_block,
+            chunk_size,
+            completed_chunks: vec![],
+            events: vec![],
+            logs: 0,
+            undecodable_logs: 0,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        chunk_grid(self.from_block, self.to_block, self.chunk_size)
+            .iter()
+            .all(|(start, _)| self.completed_chunks.contains(start))
+    }
+
+    /// Fetches every chunk of the range that isn't marked complete yet, `concurrency` at a time.
+    pub async fn fill<S: LogSource + Clone + 'static>(
+        &mut self,
+        source: &S,
+        flavor: &VaultFlavor,
+        concurrency: usize,
+    ) -> Result<()> {
+        let pending = self.pending();
+        let fetched = fetch_chunks(source, flavor, self.vault, &pending, concurrency).await?;
+        self.complete(&pending, fetched);
+
+        Ok(())
+    }
+
+    /// Like `fill`, decoding the logs `chunks` holds and fetching into it only the chunks it
+    /// lacks, so an interrupted fill resumes without refetching.
+    pub async fn fill_from<S: LogSource + Clone + 'static>(
+        &mut self,
+        chunks: &mut ChunkStore,
+        source: &S,
+        flavor: &VaultFlavor,
+        concurrency: usize,
+    ) -> Result<()> {
+        let meta = chunks.meta();
+        ensure!(
+            meta.vault == self.vault
+                && meta.chain_id == self.chain_id
+                && meta.chunk_size == self.chunk_size,
+            Error::config,
+            "chunks of {:?} on chain {} by {} blocks can't fill the {}",
+            meta.vault,
+            meta.chain_id,
+            meta.chunk_size,
+            self.describe()
+        );
+
+        let pending = self.pending();
+        let logs = chunks.fetch(source, flavor, &pending, concurrency).await?;
+        let fetched = logs.iter().map(|logs| decode_logs(flavor, logs)).collect();
+        self.complete(&pending, fetched);
+
+        Ok(())
+    }
+
+    fn pending(&self) -> Vec<(u64, u64)> {
+        chunk_grid(self.from_block, self.to_block, self.chunk_size)
+            .into_iter()
+            .filter(|(start, _)| !self.completed_chunks.contains(start))
+            .collect()
+    }
+
+    fn complete(&mut self, chunks: &[(u64, u64)], fetched: Vec<Decoded>) {
+        self.completed_chunks
+            .extend(chunks.iter().map(|(start, _)| *start));
+        self.completed_chunks.sort_unstable();
+        self.append(fetched);
+    }
+
+    /// Extends a complete cache up to `block`, fetching only the blocks after `to_block`.
+    pub async fn sync_to<S: LogSource + Clone + 'static>(
+        &mut self,
+        source: &S,
+        flavor: &VaultFlavor,
+        block: u64,
+        concurrency: usize,
+    ) -> Result<()> {
+        ensure!(
+            self.is_complete(),
+            Error::config,
+            "{} has unfetched chunks",
+            self.describe()
+        );
+        ensure!(
+            block >= self.to_block,
+            Error::config,
+            "{} is already past block {}",
+            self.describe(),
+            block
+        );
+
+        let pending = chunk_grid(self.to_block + 1, block, self.chunk_size);
+        let fetched = fetch_chunks(source, flavor, self.vault, &pending, concurrency).await?;
+
+        // the old last chunk may have ended mid-grid, the extended range is whole either way
+        self.to_block = block;
+        self.completed_chunks = chunk_grid(self.from_block, block, self.chunk_size)
+            .into_iter()
+            .map(|(start, _)| start)
+            .collect();
+        self.append(fetched);
+
+        Ok(())
+    }
+
+    /// Reads the cache at `path` to sync further, failing when it can't be resumed: unreadable,
+    /// partly fetched, or fetched for another vault, chain or first block.
+    pub fn load_resumable(
+        path: &Path,
+        vault: Address,
+        chain_id: u64,
+        from_block: u64,
+    ) -> Result<EventCache> {
+        let cache = EventCache::load(path)?;
+        cache.ensure_vault(vault)?;
+        ensure!(
+            cache.chain_id == chain_id && cache.from_block == from_block,
+            Error::config,
+            "{} was fetched on chain {} from block {}, expected chain {} from block {}",
+            cache.describe(),
+            cache.chain_id,
+            cache.from_block,
+            chain_id,
+            from_block
+        );
+        ensure!(
+            cache.chunk_size > 0 && cache.is_complete(),
+            Error::config,
+            "{} has unfetched chunks",
+            cache.describe()
+        );
+        Ok(cache)
+    }
+
+    fn append(&mut self, fetched: Vec<Decoded>) {
+        let mut unconfirmed = 0;
+        for decoded in fetched {
+            unconfirmed += decoded.unconfirmed;
+            self.events.extend(decoded.events);
+            self.logs += decoded.logs;
+            self.undecodable_logs += decoded.undecodable;
+        }
+
+        self.events.sort_by_key(|evt| evt.position());
+        if unconfirmed > 0 {
+            eprintln!("unconfirmed logs ignored: {}", unconfirmed);
+        }
+    }
+
+    /// Reads a cache in either format.
+    pub fn load(path: &Path) -> Result<EventCache> {
+        EventCache::read(path, None)
+    }
+
+    /// Reads a cache with only the events past `block`. Binary caches seek to them without
+    /// decoding the ones before.
+    pub fn load_after(path: &Path, block: u64) -> Result<EventCache> {
+        EventCache::read(path, Some(block))
+    }
+
+    fn read(path: &Path, after: Option<u64>) -> Result<EventCache> {
+        let io_error = |err| Error::io(format!("failed to read cache {}", path.display()), err);
+        let parse_error = || Error::decode(format!("failed to parse cache {}", path.display()));
+        let piped;
+        let map;
+        let bytes: &[u8] = if is_stdio(path) {
+            piped = stdio::read(path).map_err(io_error)?;
+            ensure_not_binary(&piped)?;
+            &piped
+        } else {
+            map = File::open(path)
+                .and_then(|file| Mmap::open(&file))
+                .map_err(io_error)?;
+            &map
+        };
+
+        if binary_cache::is_binary(bytes) {
+            return binary_cache::decode(bytes, after).map_err(|err| parse_error().caused_by(err));
+        }
+        let mut cache: EventCache =
+            serde_json::from_slice(bytes).map_err(|err| parse_error().caused_by(err))?;
+        if let Some(block) = after {
+            cache
+                .events
+                .retain(|evt| evt.block_number().as_u64() > block);
+        }
+        Ok(cache)
+    }
+
+    /// Writes the cache as JSON, see `save_as`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.save_as(path, CacheFormat::Json)
+    }
+
+    /// Writes the cache through `stdio::replace`, so a crash never leaves a truncated cache, or
+    /// to standard output for `-`.
+    pub fn save_as(&self, path: &Path, format: CacheFormat) -> Result<()> {
+        let mut bytes = vec![];
+        let encoded = match format {
+            CacheFormat::Json => serde_json::to_writer(&mut bytes, self).map_err(io::Error::from),
+            CacheFormat::Binary => binary_cache::encode(self, &mut bytes),
+        };
+        encoded
+            .and_then(|()| stdio::replace(path, &bytes))
+            .map_err(|err| Error::io(format!("failed to write cache {}", path.display()), err))
+    }
+
+    pub fn hash(&self) -> H256 {
+        H256::from(keccak256(
+            serde_json::to_vec(self).expect("cache should serialize"),
+        ))
+    }
+
+    /// Combines shards covering adjacent block ranges into a single cache. Every shard must be
+    /// complete and describe the same vault, chain and chunk grid. Logs a provider returned
+    /// outside of a shard's own range are dropped, so seams aren't counted twice.
+    pub fn merge(mut shards: Vec<EventCache>) -> Result<EventCache> {
+        shards.sort_by_key(|shard| shard.from_block);
+
+        let mut shards = shards.into_iter();
+        let first = shards
+            .next()
+            .ok_or_else(|| Error::config("no caches to merge"))?;
+        ensure!(
+            first.is_complete(),
+            Error::config,
+            "{} has unfetched chunks",
+            first.describe()
+        );
+
+        let mut merged = EventCache::new(
+            first.vault,
+            first.chain_id,
+            first.from_block,
+            first.to_block,
+            first.chunk_size,
+        );
+        merged.completed_chunks = first.completed_chunks.clone();
+        merged.events = first.events_in_range();
+        merged.logs = first.logs;
+        merged.undecodable_logs = first.undecodable_logs;
+
+        for shard in shards {
+            ensure!(
+                shard.vault == merged.vault && shard.chain_id == merged.chain_id,
+                Error::config,
+                "{} is for vault {:?} on chain {}, expected vault {:?} on chain {}",
+                shard.describe(),
+                shard.vault,
+                shard.chain_id,
+                merged.vault,
+                merged.chain_id
+            );
+            ensure!(
+                shard.chunk_size == merged.chunk_size,
+                Error::config,
+                "{} uses chunk size {}, expected {}",
+                shard.describe(),
+                shard.chunk_size,
+                merged.chunk_size
+            );
+            ensure!(
+                shard.is_complete(),
+                Error::config,
+                "{} has unfetched chunks",
+                shard.describe()
+            );
+
+            if shard.from_block <= merged.to_block {
+                bail!(
+                    Error::config,
+                    "{} overlaps the blocks up to {} already covered",
+                    shard.describe(),
+                    merged.to_block
+                );
+            }
+            if shard.from_block > merged.to_block + 1 {
+                bail!(
+                    Error::config,
+                    "blocks {}..={} are not covered by any cache",
+                    merged.to_block + 1,
+                    shard.from_block - 1
+                );
+            }
+
+            merged.to_block = shard.to_block;
+            merged
+                .completed_chunks
+                .extend(shard.completed_chunks.iter().copied());
+            merged.events.extend(shard.events_in_range());
+            merged.logs += shard.logs;
+            merged.undecodable_logs += shard.undecodable_logs;
+        }
+
+        Ok(merged)
+    }
+
+    /// Fails unless the cache was fetched for `vault`. Its events no longer carry the address
+    /// of the contract that emitted them, so this is the only check left.
+    pub fn ensure_vault(&self, vault: Address) -> Result<()> {
+        ensure!(
+            self.vault == vault,
+            Error::config,
+            "{} was fetched for {:?}, not for the vault {:?}",
+            self.describe(),
+            self.vault,
+            vault
+        );
+        Ok(())
+    }
+
+    fn events_in_range(&self) -> Vec<Event> {
+        self.events
+            .iter()
+            .filter(|evt| {
+                let block_number = evt.block_number().as_u64();
+                block_number >= self.from_block && block_number <= self.to_block
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn describe(&self) -> String {
+        format!("cache for blocks {}..={}", self.from_block, self.to_block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::mock::{deposit_log, transfer_log, withdraw_log, MockSource};
+    use crate::fetch::{shard_range, Shard};
+    use crate::state::BLOCK_CONTRACT_DEPLOYED;
+    use ethers::utils::parse_ether;
+    use std::fs;
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    const FROM: u64 = BLOCK_CONTRACT_DEPLOYED;
+    const TO: u64 = BLOCK_CONTRACT_DEPLOYED + 5_000;
+    const CHUNK: u64 = 1_000;
+
+    fn source() -> MockSource {
+        let bob = BOB.parse().unwrap();
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+
+        MockSource {
+            logs: vec![
+                deposit_log(bob, one * 4, FROM),
+                deposit_log(alice, one, FROM + 336),
+                transfer_log(bob, alice, one, FROM + 1_336),
+                // sits on a chunk boundary of the aligned grid
+                deposit_log(alice, one * 2, 17_566_000),
+                withdraw_log(bob, one, 17_566_000),
+                transfer_log(alice, bob, one, FROM + 3_900),
+                withdraw_log(alice, one, TO),
+            ],
+        }
+    }
+
+    fn empty_cache(from_block: u64, to_block: u64) -> EventCache {
+        EventCache::new(VAULT.parse().unwrap(), 1, from_block, to_block, CHUNK)
+    }
+
+    async fn fetch_shards(count: usize) -> Vec<EventCache> {
+        let mut shards = vec![];
+
+        for index in 1..=count {
+            let (start, end) = shard_range(FROM, TO, CHUNK, Shard { index, count }).unwrap();
+            let mut shard = empty_cache(start, end);
+            shard
+                .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+                .await
+                .unwrap();
+            shards.push(shard);
+        }
+
+        shards
+    }
+
+    #[tokio::test]
+    async fn merged_shards_match_unsharded_fetch() {
+        let mut unsharded = empty_cache(FROM, TO);
+        unsharded
+            .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+        assert!(unsharded.is_complete());
+
+        let mut shards = fetch_shards(3).await;
+        assert_eq!(shards[0].from_block, FROM);
+        assert_eq!(shards[1].from_block % CHUNK, 0);
+
+        shards.reverse();
+        let merged = EventCache::merge(shards).unwrap();
+
+        assert_eq!(merged.hash(), unsharded.hash());
+    }
+
+    #[tokio::test]
+    async fn merge_drops_logs_leaking_across_a_seam() {
+        let mut unsharded = empty_cache(FROM, TO);
+        unsharded
+            .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+
+        let mut shards = fetch_shards(2).await;
+        let leaked = shards[0].events.last().unwrap().clone();
+        shards[1].events.insert(0, leaked);
+
+        let merged = EventCache::merge(shards).unwrap();
+
+        assert_eq!(merged.hash(), unsharded.hash());
+    }
+
+    #[tokio::test]
+    async fn fill_keeps_only_logs_of_the_vault() {
+        let mut source = source();
+        let mut reward_token = transfer_log(
+            BOB.parse().unwrap(),
+            VAULT.parse().unwrap(),
+            parse_ether("1000").unwrap(),
+            FROM + 10,
+        );
+        reward_token.address = Address::from_low_u64_be(0x7e);
+        source.logs.push(reward_token);
+
+        let mut cache = empty_cache(FROM, TO);
+        cache
+            .fill(&source, &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+        assert_eq!(cache.logs, 7);
+        assert_eq!(cache.events.len(), 7);
+
+        assert!(cache.ensure_vault(VAULT.parse().unwrap()).is_ok());
+        let other = cache.ensure_vault(Address::from_low_u64_be(0x7e));
+        assert!(other.unwrap_err().to_string().contains("not for the vault"));
+    }
+
+    #[tokio::test]
+    async fn syncing_fetches_only_the_new_blocks() {
+        let mut full = empty_cache(FROM, TO);
+        full.fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+
+        // ends mid-chunk, right before the events on the grid boundary
+        let mut synced = empty_cache(FROM, 17_565_999);
+        synced
+            .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+        // refetching any of the synced blocks would count their events twice
+        synced
+            .sync_to(&source(), &VaultFlavor::oprtc_v1(), TO, 2)
+            .await
+            .unwrap();
+
+        assert!(synced.is_complete());
+        assert_eq!(synced.hash(), full.hash());
+    }
+
+    #[tokio::test]
+    async fn only_whole_caches_of_the_vault_resume() {
+        let vault = VAULT.parse().unwrap();
+        let dir = std::env::temp_dir().join(format!("oprtc-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.json");
+
+        let mut cache = empty_cache(FROM, TO);
+        cache
+            .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+        cache.save(&path).unwrap();
+        assert!(EventCache::load_resumable(&path, vault, 1, FROM).is_ok());
+        assert!(EventCache::load_resumable(&path, vault, 10, FROM).is_err());
+
+        cache.completed_chunks.pop();
+        cache.save(&path).unwrap();
+        let partial = EventCache::load_resumable(&path, vault, 1, FROM).unwrap_err();
+        assert!(partial.to_string().contains("unfetched chunks"));
+
+        fs::write(&path, &fs::read(&path).unwrap()[..100]).unwrap();
+        let truncated = EventCache::load_resumable(&path, vault, 1, FROM).unwrap_err();
+        assert!(truncated.to_string().contains("failed to parse"));
+    }
+
+    #[tokio::test]
+    async fn merge_rejects_gaps_and_overlaps() {
+        let mut shards = fetch_shards(3).await;
+        let middle = shards.remove(1);
+
+        let gap = EventCache::merge(shards.clone()).unwrap_err();
+        assert!(gap.to_string().contains("not covered"));
+
+        shards.push(middle.clone());
+        shards.push(middle);
+        let overlap = EventCache::merge(shards).unwrap_err();
+        assert!(overlap.to_string().contains("overlaps"));
+    }
+
+    #[tokio::test]
+    async fn binary_and_json_caches_round_trip() {
+        let mut cache = empty_cache(FROM, TO);
+        cache
+            .fill(&source(), &VaultFlavor::oprtc_v1(), 2)
+            .await
+            .unwrap();
+        cache.undecodable_logs = 1;
+
+        let dir = std::env::temp_dir().join(format!("oprtc-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("cache.json");
+        let binary = dir.join("cache.bin");
+
+        cache.save(&json).unwrap();
+        EventCache::load(&json)
+            .unwrap()
+            .save_as(&binary, CacheFormat::Binary)
+            .unwrap();
+        let converted = EventCache::load(&binary).unwrap();
+        assert_eq!(converted, cache);
+        assert_eq!(converted.hash(), cache.hash());
+
+        // and back, byte for byte
+        let back = dir.join("back.json");
+        converted.save_as(&back, CacheFormat::Json).unwrap();
+        assert_eq!(fs::read(&back).unwrap(), fs::read(&json).unwrap());
+
+        // seeking lands on the same events in both formats, boundaries included
+        for block in [0, FROM, 17_566_000, FROM + 3_900, TO] {
+            let after = EventCache::load_after(&binary, block).unwrap();
+            assert_eq!(after, EventCache::load_after(&json, block).unwrap());
+            assert!(after
+                .events
+                .iter()
+                .all(|evt| evt.block_number().as_u64() > block));
+        }
+        assert!(EventCache::load_after(&binary, TO)
+            .unwrap()
+            .events
+            .is_empty());
+    }
+}
@@ -0,0 +1,37 @@
+use crate::rpc::Client;
+use ethers::{
+    core::{
+        abi::{encode, Token},
+        types::{Address, BlockId, Bytes, TransactionRequest, U256, U64},
+    },
+    providers::Middleware,
+    utils::id,
+};
+use eyre::{ensure, Result};
+
+/// Calls a view function returning a single `uint256`, pinned to `block_number`.
+pub async fn call_uint(
+    client: &Client,
+    to: Address,
+    signature: &str,
+    args: &[Token],
+    block_number: U64,
+) -> Result<U256> {
+    let mut calldata = id(signature).to_vec();
+    calldata.extend(encode(args));
+
+    let tx = TransactionRequest::new()
+        .to(to)
+        .data(Bytes::from(calldata))
+        .into();
+
+    let output = client.call(&tx, Some(BlockId::from(block_number))).await?;
+    ensure!(
+        output.len() == 32,
+        "unexpected {} output at block {}",
+        signature,
+        block_number
+    );
+
+    Ok(U256::from_big_endian(&output))
+}
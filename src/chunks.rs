@@ -0,0 +1,368 @@
+//! Undecoded vault logs kept on disk chunk by chunk, so a backfill picks up where it stopped,
+//! even under a newer build or another decode configuration. Only what the logs themselves
+//! depend on is recorded with them: the vault, chain, events fetched and chunk grid. Layouts,
+//! emission and accounting settings are free to change, as every read decodes the logs again.
+
+use crate::error::{ensure, Error, Result};
+use crate::fetch::{fetch_log_chunks, LogSource};
+use crate::flavor::VaultFlavor;
+use crate::stdio;
+use ethers::{
+    core::types::{Address, Log, H256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Version of the chunk files and their metadata, bumped only when a build can no longer read
+/// the chunks an older one wrote. Independent of the snapshot and report schemas.
+pub const CHUNK_SCHEMA_VERSION: u32 = 1;
+
+const META_FILE: &str = "meta.json";
+
+/// What the logs of a chunk depend on. Keys a newer build adds are ignored when read, so the
+/// chunks are only fetched again when one of these changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ChunkMeta {
+    pub schema: u32,
+    pub vault: Address,
+    pub chain_id: u64,
+    /// topic0 of every event fetched, sorted
+    pub topics: Vec<H256>,
+    pub chunk_size: u64,
+}
+
+impl ChunkMeta {
+    pub fn new(vault: Address, chain_id: u64, flavor: &VaultFlavor, chunk_size: u64) -> ChunkMeta {
+        ChunkMeta {
+            schema: CHUNK_SCHEMA_VERSION,
+            vault,
+            chain_id,
+            topics: topics(flavor),
+            chunk_size,
+        }
+    }
+
+    /// Why chunks fetched under `self` can't stand in for those `wanted` describes.
+    fn mismatch(&self, wanted: &ChunkMeta) -> Option<String> {
+        if self.schema != wanted.schema {
+            Some(format!(
+                "chunk schema {}, this build reads {}",
+                self.schema, wanted.schema
+            ))
+        } else if self.vault != wanted.vault || self.chain_id != wanted.chain_id {
+            Some(format!(
+                "fetched for {:?} on chain {}",
+                self.vault, self.chain_id
+            ))
+        } else if self.topics != wanted.topics {
+            Some("fetched for another set of events".to_string())
+        } else if self.chunk_size != wanted.chunk_size {
+            Some(format!("fetched in chunks of {} blocks", self.chunk_size))
+        } else {
+            None
+        }
+    }
+}
+
+fn topics(flavor: &VaultFlavor) -> Vec<H256> {
+    let mut topics: Vec<H256> = flavor
+        .signatures()
+        .into_iter()
+        .map(|signature| H256::from(keccak256(signature)))
+        .collect();
+    topics.sort();
+    topics
+}
+
+/// A directory holding `meta.json` and a `<start>-<end>.json` file of logs for every chunk
+/// fetched so far.
+#[derive(Debug)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    meta: ChunkMeta,
+    /// Last block of the chunk on disk starting at each block
+    chunks: BTreeMap<u64, u64>,
+}
+
+impl ChunkStore {
+    /// Opens the chunks in `dir` for `meta`, creating the directory when there is none. Chunks
+    /// fetched under other metadata, or without readable metadata, are deleted.
+    pub fn open(dir: &Path, meta: ChunkMeta) -> Result<ChunkStore> {
+        let io_error = |err| Error::io(format!("failed to open chunks in {}", dir.display()), err);
+        fs::create_dir_all(dir).map_err(io_error)?;
+
+        let meta_path = dir.join(META_FILE);
+        let stale = match fs::read(&meta_path) {
+            Ok(bytes) => match serde_json::from_slice::<ChunkMeta>(&bytes) {
+                Ok(stored) => stored.mismatch(&meta),
+                Err(err) => Some(format!("unreadable {}: {}", META_FILE, err)),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Some(format!("no {}", META_FILE)),
+            Err(err) => return Err(io_error(err)),
+        };
+
+        let mut chunks = BTreeMap::new();
+        let mut discarded = 0;
+        for entry in fs::read_dir(dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let Some((start, end)) = chunk_range(&path) else {
+                continue;
+            };
+            if stale.is_some() {
+                fs::remove_file(&path).map_err(io_error)?;
+                discarded += 1;
+            } else {
+                chunks.insert(start, end);
+            }
+        }
+
+        if let Some(reason) = stale {
+            if discarded > 0 {
+                eprintln!(
+                    "discarded {} chunks in {}: {}",
+                    discarded,
+                    dir.display(),
+                    reason
+                );
+            }
+            let json = serde_json::to_vec_pretty(&meta).expect("chunk metadata should serialize");
+            stdio::replace(&meta_path, &json).map_err(io_error)?;
+        }
+
+        Ok(ChunkStore {
+            dir: dir.to_path_buf(),
+            meta,
+            chunks,
+        })
+    }
+
+    pub fn meta(&self) -> &ChunkMeta {
+        &self.meta
+    }
+
+    /// The chunks of `chunks` no chunk on disk covers, which `fetch` requests.
+    pub fn missing(&self, chunks: &[(u64, u64)]) -> Vec<(u64, u64)> {
+        chunks
+            .iter()
+            .copied()
+            .filter(|(start, end)| self.chunks.get(start).is_none_or(|stored| stored < end))
+            .collect()
+    }
+
+    /// The logs of every chunk in the order given, read from disk where a chunk covers it and
+    /// fetched `concurrency` at a time otherwise. Fetched chunks are saved as they arrive,
+    /// except those holding logs not yet in a block.
+    pub async fn fetch<S: LogSource + Clone + 'static>(
+        &mut self,
+        source: &S,
+        flavor: &VaultFlavor,
+        chunks: &[(u64, u64)],
+        concurrency: usize,
+    ) -> Result<Vec<Vec<Log>>> {
+        ensure!(
+            topics(flavor) == self.meta.topics,
+            Error::config,
+            "flavor {} fetches other events than the chunks in {}",
+            flavor.name,
+            self.dir.display()
+        );
+
+        let missing = self.missing(chunks);
+        let mut fetched = BTreeMap::new();
+        fetch_log_chunks(
+            source,
+            flavor,
+            self.meta.vault,
+            &missing,
+            concurrency,
+            |i, logs| {
+                if logs.iter().all(|log| log.block_number.is_some()) {
+                    self.save(missing[i], &logs)?;
+                }
+                fetched.insert(missing[i].0, logs);
+                Ok(())
+            },
+        )
+        .await?;
+
+        chunks
+            .iter()
+            .map(|&(start, end)| match fetched.remove(&start) {
+                Some(logs) => Ok(logs),
+                None => self.read(start, end),
+            })
+            .collect()
+    }
+
+    fn path(&self, start: u64, end: u64) -> PathBuf {
+        self.dir.join(format!("{}-{}.json", start, end))
+    }
+
+    fn save(&mut self, (start, end): (u64, u64), logs: &[Log]) -> Result<()> {
+        let path = self.path(start, end);
+        let json = serde_json::to_vec(logs).expect("logs should serialize");
+        stdio::replace(&path, &json)
+            .map_err(|err| Error::io(format!("failed to write chunk {}", path.display()), err))?;
+        // a chunk ending at an older head is superseded by the whole one
+        if let Some(stored) = self
+            .chunks
+            .insert(start, end)
+            .filter(|stored| *stored != end)
+        {
+            let _ = fs::remove_file(self.path(start, stored));
+        }
+        Ok(())
+    }
+
+    /// The logs of the chunk on disk starting at `start`, up to `end`.
+    fn read(&self, start: u64, end: u64) -> Result<Vec<Log>> {
+        let path = self.path(start, self.chunks[&start]);
+        let bytes = fs::read(&path)
+            .map_err(|err| Error::io(format!("failed to read chunk {}", path.display()), err))?;
+        let mut logs: Vec<Log> = serde_json::from_slice(&bytes).map_err(|err| {
+            Error::decode(format!("failed to parse chunk {}", path.display())).caused_by(err)
+        })?;
+        logs.retain(|log| log.block_number.is_some_and(|block| block.as_u64() <= end));
+        Ok(logs)
+    }
+}
+
+/// The block range a chunk file is named after, `None` for other files.
+fn chunk_range(path: &Path) -> Option<(u64, u64)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".json")?;
+    let (start, end) = name.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::mock::{custom_log, deposit_log, transfer_log, MockSource, VAULT};
+    use crate::fetch::{chunk_grid, decode_logs};
+    use crate::flavor::{EventKind, WITHDRAW_EVENT};
+    use crate::state::{Event, BLOCK_CONTRACT_DEPLOYED};
+    use async_trait::async_trait;
+    use ethers::{core::types::Filter, utils::parse_ether};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const FROM: u64 = BLOCK_CONTRACT_DEPLOYED;
+    const TO: u64 = BLOCK_CONTRACT_DEPLOYED + 5_000;
+    const CHUNK: u64 = 1_000;
+
+    /// Counts the requests reaching a `MockSource`.
+    #[derive(Clone)]
+    struct CountingSource {
+        source: MockSource,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LogSource for CountingSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.source.fetch_logs(filter).await
+        }
+    }
+
+    fn source() -> CountingSource {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        CountingSource {
+            source: MockSource {
+                logs: vec![
+                    deposit_log(bob, one * 4, FROM),
+                    transfer_log(bob, alice, one, FROM + 1_336),
+                    // two shares for three assets
+                    custom_log(
+                        WITHDRAW_EVENT,
+                        &[bob, bob, bob],
+                        &[one * 3, one * 2],
+                        FROM + 3_900,
+                    ),
+                ],
+            },
+            requests: Arc::default(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("oprtc-chunks-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn fetch(dir: &Path, source: &CountingSource, flavor: &VaultFlavor) -> Vec<Event> {
+        let meta = ChunkMeta::new(VAULT.parse().unwrap(), 1, flavor, CHUNK);
+        let mut store = ChunkStore::open(dir, meta).unwrap();
+        let chunks = chunk_grid(FROM, TO, CHUNK);
+        let logs = store.fetch(source, flavor, &chunks, 2).await.unwrap();
+        decode_logs(flavor, &logs.concat()).events
+    }
+
+    #[tokio::test]
+    async fn decode_changes_reuse_chunks_and_topic_changes_refetch_them() {
+        let dir = temp_dir("decode");
+        let source = source();
+        let flavor = VaultFlavor::erc4626();
+        let first = fetch(&dir, &source, &flavor).await;
+        let requests = source.requests.load(Ordering::SeqCst);
+        assert_eq!(first.len(), 3);
+
+        // reading the withdrawn assets instead of shares only changes how the logs decode
+        let mut reworded = flavor.clone();
+        let withdraw = reworded
+            .events
+            .iter_mut()
+            .find(|layout| layout.kind == EventKind::Withdraw)
+            .unwrap();
+        withdraw.shares_word = 0;
+        let redecoded = fetch(&dir, &source, &reworded).await;
+        assert_eq!(source.requests.load(Ordering::SeqCst), requests);
+        assert_eq!(redecoded.len(), 3);
+        assert_ne!(redecoded, first);
+
+        // leaving transfers out changes what gets fetched
+        let mut untransferred = flavor.clone();
+        untransferred
+            .events
+            .retain(|layout| layout.kind != EventKind::Transfer);
+        let refetched = fetch(&dir, &source, &untransferred).await;
+        assert!(source.requests.load(Ordering::SeqCst) > requests);
+        assert_eq!(refetched.len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_metadata_keys_of_newer_builds() {
+        let dir = temp_dir("meta");
+        let source = source();
+        let flavor = VaultFlavor::erc4626();
+        fetch(&dir, &source, &flavor).await;
+        let requests = source.requests.load(Ordering::SeqCst);
+
+        let meta_path = dir.join(META_FILE);
+        let mut meta: serde_json::Value =
+            serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+        meta["finality_depth"] = 64.into();
+        fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+        assert_eq!(fetch(&dir, &source, &flavor).await.len(), 3);
+        assert_eq!(source.requests.load(Ordering::SeqCst), requests);
+        // the newer build's key is left in place
+        assert!(fs::read_to_string(&meta_path)
+            .unwrap()
+            .contains("finality_depth"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use crate::error::{ensure, Error, Result};
+use crate::fetch::{chunk_grid, fetch_range, LogSource, CHUNK_SIZE};
+use ethers::{
+    core::types::{Address, Filter, Log, I256, U256, U64},
+    utils::keccak256,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// `MerkleDistributor` payout event, none of its fields indexed.
+pub const CLAIMED_EVENT: &str = "Claimed(uint256,address,uint256)";
+
+/// A distributor contract that paid the rewards of one past epoch.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Distributor {
+    pub address: Address,
+    /// Label of the epoch the distributor paid, e.g. `2023-Q3`
+    pub epoch: String,
+    /// First block to look for claims in
+    pub deployed_block: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DistributorsFile {
+    distributor: Vec<Distributor>,
+}
+
+/// Distributor contracts through which past epochs were paid.
+///
+/// ```toml
+/// [[distributor]]
+/// address = "0x0000000000000000000000000000000000D15700"
+/// epoch = "2023-Q3"
+/// deployed_block = 18000000
+/// ```
+#[derive(Debug, Default)]
+pub struct Distributors {
+    pub distributors: Vec<Distributor>,
+}
+
+impl Distributors {
+    pub fn parse(contents: &str) -> Result<Distributors> {
+        let file: DistributorsFile =
+            toml::from_str(contents).map_err(|err| Error::config(err.to_string()))?;
+
+        let mut seen = HashSet::new();
+        for distributor in &file.distributor {
+            ensure!(
+                seen.insert(distributor.address),
+                Error::config,
+                "distributor {:?} is listed twice",
+                distributor.address
+            );
+        }
+
+        Ok(Distributors {
+            distributors: file.distributor,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Distributors> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            Error::io(
+                format!("failed to read distributors {}", path.display()),
+                err,
+            )
+        })?;
+
+        Distributors::parse(&contents).map_err(|err| {
+            Error::config(format!("invalid distributors {}", path.display())).caused_by(err)
+        })
+    }
+}
+
+/// One `Claimed` payout.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claim {
+    pub distributor: Address,
+    pub index: U256,
+    pub account: Address,
+    pub amount: U256,
+    pub block_number: U64,
+}
+
+pub fn decode_claim(log: &Log) -> Result<Claim> {
+    let word = |i: usize| log.data.get(i * 32..(i + 1) * 32);
+    let (Some(index), Some(account), Some(amount)) = (word(0), word(1), word(2)) else {
+        return Err(Error::decode(format!(
+            "{} log has {} bytes of data, expected 96",
+            CLAIMED_EVENT,
+            log.data.len()
+        )));
+    };
+    let block_number = log
+        .block_number
+        .ok_or_else(|| Error::decode(format!("{} log has no block number", CLAIMED_EVENT)))?;
+
+    Ok(Claim {
+        distributor: log.address,
+        index: U256::from(index),
+        account: Address::from_slice(&account[12..]),
+        amount: U256::from(amount),
+        block_number,
+    })
+}
+
+/// Fetches every claim paid by `distributor` up to `to_block`, a chunk of the grid at a time.
+/// Logs of other contracts are dropped, like those of `fetch_events`.
+pub async fn fetch_claims<S: LogSource>(
+    source: &S,
+    distributor: &Distributor,
+    to_block: u64,
+) -> Result<Vec<Claim>> {
+    let mut claims = vec![];
+
+    for (start, end) in chunk_grid(distributor.deployed_block, to_block, CHUNK_SIZE) {
+        let filter = Filter::new()
+            .address(distributor.address)
+            .event(CLAIMED_EVENT)
+            .from_block(start)
+            .to_block(end);
+
+        for log in fetch_range(source, &filter).await? {
+            if log.address == distributor.address
+                && log.topics.first() == Some(&keccak256(CLAIMED_EVENT).into())
+            {
+                claims.push(decode_claim(&log)?);
+            }
+        }
+    }
+
+    Ok(claims)
+}
+
+/// What is still owed to an address: its lifetime rewards less everything already claimed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outstanding {
+    pub address: Address,
+    pub earned: U256,
+    pub paid: U256,
+    /// `paid` split by the epoch label of the distributor that paid it
+    pub paid_by_epoch: BTreeMap<String, U256>,
+    /// `earned - paid`, negative when the address was overpaid
+    pub outstanding: I256,
+}
+
+impl Outstanding {
+    pub fn is_overpaid(&self) -> bool {
+        self.outstanding.is_negative()
+    }
+}
+
+/// Nets `claims` against the lifetime rewards of each address, largest outstanding first.
+/// Addresses that claimed without having earned anything show up overpaid.
+pub fn outstanding(
+    earned: &[(Address, U256)],
+    claims: &[Claim],
+    distributors: &Distributors,
+) -> Vec<Outstanding> {
+    let epochs: BTreeMap<Address, &str> = distributors
+        .distributors
+        .iter()
+        .map(|distributor| (distributor.address, distributor.epoch.as_str()))
+        .collect();
+
+    let mut rows: BTreeMap<Address, Outstanding> = earned
+        .iter()
+        .map(|&(address, earned)| {
+            let row = Outstanding {
+                address,
+                earned,
+                paid: U256::zero(),
+                paid_by_epoch: BTreeMap::new(),
+                outstanding: I256::zero(),
+            };
+            (address, row)
+        })
+        .collect();
+
+    for claim in claims {
+        let row = rows.entry(claim.account).or_insert_with(|| Outstanding {
+            address: claim.account,
+            earned: U256::zero(),
+            paid: U256::zero(),
+            paid_by_epoch: BTreeMap::new(),
+            outstanding: I256::zero(),
+        });
+        row.paid += claim.amount;
+        let epoch = epochs.get(&claim.distributor).copied().unwrap_or("unknown");
+        *row.paid_by_epoch.entry(epoch.to_string()).or_default() += claim.amount;
+    }
+
+    let mut rows: Vec<_> = rows
+        .into_values()
+        .map(|mut row| {
+            row.outstanding = I256::from_raw(row.earned) - I256::from_raw(row.paid);
+            row
+        })
+        .collect();
+    rows.sort_by_key(|row| (std::cmp::Reverse(row.outstanding), row.address));
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::mock::MockSource;
+    use ethers::{
+        core::abi::{encode, Token},
+        utils::parse_ether,
+    };
+
+    const Q3: &str = "0x0000000000000000000000000000000000D15700";
+    const Q4: &str = "0x0000000000000000000000000000000000D15701";
+
+    fn claimed_log(distributor: &str, index: u64, account: Address, amount: U256) -> Log {
+        Log {
+            address: distributor.parse().unwrap(),
+            topics: vec![keccak256(CLAIMED_EVENT).into()],
+            data: encode(&[
+                Token::Uint(U256::from(index)),
+                Token::Address(account),
+                Token::Uint(amount),
+            ])
+            .into(),
+            block_number: Some(U64::from(18_000_000 + index)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn nets_claims_of_every_epoch_against_lifetime_rewards() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let carol = Address::from_low_u64_be(0xca201);
+        let ether = |amount: &str| parse_ether(amount).unwrap();
+
+        let distributors = Distributors::parse(&format!(
+            "[[distributor]]\naddress = \"{Q3}\"\nepoch = \"2023-Q3\"\ndeployed_block = 18000000\n\n\
+             [[distributor]]\naddress = \"{Q4}\"\nepoch = \"2023-Q4\"\ndeployed_block = 18000000\n"
+        ))
+        .unwrap();
+        // the mock ignores the address filter, so each fetch also sees the other epoch's logs
+        let source = MockSource {
+            logs: vec![
+                claimed_log(Q3, 0, bob, ether("30")),
+                claimed_log(Q3, 1, alice, ether("50")),
+                claimed_log(Q4, 0, bob, ether("20")),
+                claimed_log(Q4, 1, carol, ether("1")),
+            ],
+        };
+
+        let mut claims = vec![];
+        for distributor in &distributors.distributors {
+            claims.extend(
+                fetch_claims(&source, distributor, 18_000_100)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(claims.len(), 4);
+
+        let earned = [(bob, ether("100")), (alice, ether("40"))];
+        let rows = outstanding(&earned, &claims, &distributors);
+
+        assert_eq!(rows[0].address, bob);
+        assert_eq!(rows[0].paid, ether("50"));
+        assert_eq!(
+            rows[0].paid_by_epoch,
+            BTreeMap::from([
+                ("2023-Q3".to_string(), ether("30")),
+                ("2023-Q4".to_string(), ether("20"))
+            ])
+        );
+        assert_eq!(rows[0].outstanding, I256::from_raw(ether("50")));
+        assert!(!rows[0].is_overpaid());
+
+        let overpaid: Vec<_> = rows.iter().filter(|row| row.is_overpaid()).collect();
+        assert_eq!(overpaid.len(), 2);
+        assert_eq!(overpaid[0].address, carol);
+        assert_eq!(overpaid[0].outstanding, -I256::from_raw(ether("1")));
+        assert_eq!(overpaid[1].address, alice);
+        assert_eq!(overpaid[1].outstanding, -I256::from_raw(ether("10")));
+    }
+
+    #[test]
+    fn rejects_truncated_claims_and_repeated_distributors() {
+        let mut log = claimed_log(Q3, 0, Address::zero(), U256::one());
+        log.data = log.data[..64].to_vec().into();
+        assert!(matches!(decode_claim(&log), Err(Error::Decode { .. })));
+
+        let repeated = format!(
+            "[[distributor]]\naddress = \"{Q3}\"\nepoch = \"a\"\ndeployed_block = 1\n\n\
+             [[distributor]]\naddress = \"{Q3}\"\nepoch = \"b\"\ndeployed_block = 1\n"
+        );
+        assert!(Distributors::parse(&repeated).is_err());
+    }
+}
@@ -0,0 +1,2430 @@
+use crate::accumulators::{render_series, series_blocks, CHECKPOINT_BLOCKS};
+use crate::address_map::{map_report, AddressMap, ExportChain};
+use crate::annotations::{self, annotate, Annotations};
+use crate::bisect::bisect;
+use crate::cache::{CacheFormat, EventCache};
+use crate::calls::call_uint;
+use crate::chunks::{ChunkMeta, ChunkStore};
+use crate::claims::{fetch_claims, outstanding, Distributors};
+use crate::clock::TokioClock;
+use crate::cohorts::{build_cohorts, render_cohorts, Bucket};
+use crate::context::EvaluationContext;
+use crate::dilution::{build_dilution, render_dilution};
+use crate::doctor::{diagnose, render, Status};
+use crate::dormancy::{
+    activity_blocks, build_dormancy, render_dormancy, withhold_dormant, DormancyRow, DORMANCY_DAYS,
+};
+use crate::error::Error;
+use crate::exact::{Ledger, EXACT_HOLDER_WARNING};
+use crate::fetch::{
+    chunk_grid, fetch_chunks, shard_range, ClientSource, Decoded, FetchMode, Shard, CHUNK_SIZE,
+};
+use crate::flavor::VaultFlavor;
+use crate::freshness::{parse_max_staleness, Freshness};
+use crate::help::help_json;
+use crate::hooks::{apply_hook, HookPolicy};
+use crate::html::{render_html, RunMetadata};
+use crate::index::{index_path, EventIndex};
+use crate::journal::{read_journal, write_journal};
+use crate::lineage::{ancestry, EmbeddedLineage, Input, Lineage, Origin, RunId};
+use crate::lock::{FileLock, LockMode};
+use crate::loyalty::{parse_budget, LoyaltyBonus, MIN_AGE_BLOCKS};
+use crate::merge::{parse_named_url, ConflictPolicy, MergedSource};
+use crate::observer::{InvariantWarning, StateObserver};
+use crate::output::{format_ether_rounded, versioned_json, OutputFormat, ReportFormat};
+use crate::payout::{parse_min_payout, pay_epoch, Rollover};
+use crate::pipeline::{fetch_pipelined, PipelineTimings};
+use crate::proof::{Proof, ProofConfig, CHECKPOINT_INTERVAL};
+use crate::quality::{unbacked_events, DataQuality};
+use crate::quarantine::{
+    quarantine_rows, render_quarantine, unknown_sender_burst, QuarantineSummary,
+};
+use crate::reconcile::{fetch_balances, largest_holders, reconcile_balances};
+use crate::report::{rewards_report, write_rewards_csv, Report};
+use crate::rounding::Rounding;
+use crate::rpc::{Client, Instrumented, ProviderStats, RetryPolicy};
+use crate::sanity::{sanity_check, PoolShares, RewardsFile, SanityBounds};
+use crate::scenario::run_scenarios;
+use crate::signing::{sign_file, signing_key_from_env, verify_file};
+use crate::simulate::{simulate_claims, ClaimSimulator, MerkleFile};
+use crate::snapshot::{load_signing_key, parse_public_key, ReplaySettings, Snapshot};
+use crate::state::{
+    AccrualPolicy, Deposit, Emission, Event, GlobalState, SelfHeldShares, Withdraw,
+    BLOCK_CONTRACT_DEPLOYED,
+};
+use crate::stdio::{self, is_stdio, parse_events, EventsInput, InputFormat};
+use crate::subaccounts::SubAccounts;
+use crate::timestamps::TimestampCache;
+use crate::top_ups::TopUps;
+use crate::utilization::{render_utilization, utilization_series, worst_bucket, BUCKET_SIZE};
+use crate::verify::{LiveState, Verifier};
+use crate::watch::Watcher;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use ethers::{
+    core::{
+        k256::ecdsa::VerifyingKey,
+        types::{Address, I256, U256, U64},
+    },
+    providers::{Http, Provider},
+};
+use eyre::{bail, ensure, Result, WrapErr};
+use num_rational::BigRational;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+const HTTP_URL: &str = "https://rpc.flashbots.net";
+const LENDING_VAULT_ADDRESS: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+const CHAIN_ID: u64 = 1;
+
+#[derive(Parser)]
+#[command(about = "Computes per-user rewards for the OPRTC lending vault")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print every command and flag with its kind, default and environment variable as JSON
+    /// and exit
+    #[arg(long)]
+    help_json: bool,
+
+    /// JSON-RPC endpoint of the chain the vault is deployed on. Repeat it, or separate URLs
+    /// with commas, to fail over to the next endpoint while one keeps failing
+    #[arg(
+        long,
+        global = true,
+        env = "OPRTC_RPC_URL",
+        value_delimiter = ',',
+        default_value = HTTP_URL
+    )]
+    rpc_url: Vec<String>,
+
+    /// Address of the vault to compute rewards for
+    #[arg(long, global = true, default_value = LENDING_VAULT_ADDRESS)]
+    vault: Address,
+
+    /// Block the vault was deployed at, from which events are fetched and rewards emitted
+    #[arg(long, global = true, default_value_t = BLOCK_CONTRACT_DEPLOYED)]
+    from_block: u64,
+
+    /// Skip deposits that are withdrawn again by the same address in the same block
+    #[arg(long, global = true)]
+    net_same_block: bool,
+
+    /// Replay the events of each block as one net delta per address, much faster for addresses
+    /// with many events a block. Needs block-start accrual
+    #[arg(long, global = true)]
+    coalesce: bool,
+
+    /// When a block's emission accrues: `block-start`, before any of its events like the
+    /// vault, or `per-event`, which reproduces reports from before the option
+    #[arg(long, global = true, value_enum, default_value_t = AccrualPolicy::BlockStart)]
+    accrual_policy: AccrualPolicy,
+
+    /// Blocks received shares must stay staked before they earn, each deposit or transfer in
+    /// maturing on its own. Shares leave oldest first and forfeit if they leave before maturing
+    #[arg(long, global = true)]
+    min_staking_blocks: Option<u64>,
+
+    /// Blocks between the accumulator checkpoints `series` reads and snapshots keep, 0 to keep
+    /// none. Taken under per-block emission only
+    #[arg(long, global = true, default_value_t = CHECKPOINT_BLOCKS)]
+    accumulator_interval: u64,
+
+    /// Event layout of the vault: `erc4626`, `oprtc-v1` or a path to a TOML flavor file
+    #[arg(long, global = true, default_value = "oprtc-v1")]
+    flavor: VaultFlavor,
+
+    /// Report rewards as of the last event, leaving out emissions accrued since then
+    #[arg(long, global = true)]
+    no_pending: bool,
+
+    /// Warn about events moving more than this multiple of the shares staked before them
+    #[arg(long, global = true)]
+    max_share_multiple: Option<u64>,
+
+    /// Blocks per `eth_getLogs` request. Ranges a provider still refuses are halved
+    #[arg(long, global = true, default_value_t = CHUNK_SIZE)]
+    chunk_size: u64,
+
+    /// Attempts at a request failing with a timeout, rate limit or server error, waiting twice
+    /// as long before each retry
+    #[arg(long, global = true, default_value_t = RetryPolicy::default().max_attempts)]
+    rpc_max_attempts: usize,
+
+    /// Number of block-range chunks fetched at the same time
+    #[arg(long, global = true, default_value_t = 4)]
+    concurrency: usize,
+
+    /// Fetch, decode and collect chunks as overlapping stages, with up to this many chunks
+    /// between being requested and collected, instead of fetching `--concurrency` at a time
+    #[arg(long, global = true)]
+    pipeline_depth: Option<usize>,
+
+    /// How logs are requested: `eth_getLogs`, or a persistent filter read with `eth_getFilterLogs`
+    #[arg(long, global = true, value_enum, default_value_t = FetchMode::Logs)]
+    fetch_mode: FetchMode,
+
+    /// How shares the vault holds of itself, such as minted fees, are rewarded
+    #[arg(long, global = true, value_enum, default_value_t = SelfHeldShares::Exclude)]
+    self_held_shares: SelfHeldShares,
+
+    /// Fractional digits of ether amounts printed to the console, rounded half up outside the
+    /// rewards report
+    #[arg(long, global = true, default_value_t = 18)]
+    decimals: usize,
+
+    /// How the rewards report rounds amounts to --decimals: `floor`, `half-even` or `ceil`. The
+    /// listed amounts always add up to their total rounded the same way
+    #[arg(long, global = true, value_enum, default_value_t = Rounding::Floor)]
+    rounding: Rounding,
+
+    /// Skip undecodable logs and withdrawals of shares never received, then report a
+    /// data-quality summary
+    #[arg(long, global = true)]
+    best_effort: bool,
+
+    /// Skip what --best-effort skips, write every skipped event with the reason to this file
+    /// for review and mark the rewards report approximate
+    #[arg(long, global = true)]
+    quarantine: Option<PathBuf>,
+
+    /// Format of the quarantine file
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Json)]
+    quarantine_format: OutputFormat,
+
+    /// Also fetch logs from this provider, as `name=url`, merging them with those of the default
+    /// provider, named `rpc`, and reporting where the merged logs came from
+    #[arg(long, global = true, value_parser = parse_named_url)]
+    cross_check_rpc: Vec<(String, String)>,
+
+    /// What to do when merged providers disagree on a log: `fail` or `prefer-source:<name>`
+    #[arg(long, global = true, default_value = "fail")]
+    on_conflict: ConflictPolicy,
+
+    /// Format of the caches fetch, merge-caches and cache convert write. Reads take either
+    #[arg(long, global = true, value_enum, default_value_t = CacheFormat::Json)]
+    cache_format: CacheFormat,
+
+    /// Seconds to wait for another process holding a cache lock before giving up
+    #[arg(long, global = true, default_value_t = 0)]
+    lock_timeout: u64,
+
+    /// Fail with exit code 8 when the data is further behind the chain head than this, e.g.
+    /// `1h`, `90m` or `2d`. Fails offline, where the head is unknown
+    #[arg(long, global = true, value_parser = parse_max_staleness)]
+    max_staleness: Option<Duration>,
+
+    /// Environment variable holding a hex secp256k1 private key to sign every written report,
+    /// CSV, merkle file, quarantine, proof, journal and rollover with, into a detached `.sig` file
+    /// next to it. Standard output is left unsigned
+    #[arg(long, global = true)]
+    sign_key_env: Option<String>,
+
+    /// Read events from a complete cache file instead of fetching them, or from a JSON cache
+    /// or events journal on standard input for `-`
+    #[arg(long, visible_alias = "events-file", conflicts_with = "stdin")]
+    cache: Option<PathBuf>,
+
+    /// Directory of the event caches runs without `--cache` sync incrementally, one per vault
+    /// and chain
+    #[arg(long, default_value = ".oprtc-cache")]
+    cache_dir: PathBuf,
+
+    /// Fetch the whole event history instead of syncing the cache in `--cache-dir`
+    #[arg(long, conflicts_with = "cache")]
+    no_cache: bool,
+
+    /// What `-` inputs hold, guessed from the first document when `auto`
+    #[arg(long, global = true, value_enum, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+
+    /// Evaluate at this block instead of the current head, which must be the last block of
+    /// `--cache` when reading one
+    #[arg(long)]
+    at_block: Option<u64>,
+
+    /// Format of the rewards report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+    format: ReportFormat,
+
+    /// Where to write the rewards report, `-` for standard output
+    #[arg(long, default_value = "-")]
+    output: PathBuf,
+
+    /// Also write every payee's shares and rewards as CSV to this file, `-` for standard
+    /// output
+    #[arg(long)]
+    csv: Option<PathBuf>,
+
+    /// Also write a merkle-distributor claims file of every payee's rewards to this file, `-`
+    /// for standard output
+    #[arg(long)]
+    merkle_out: Option<PathBuf>,
+
+    /// Reproducible rewards report pinned to `--at-block`, followed by its hash for attestation
+    #[arg(long, requires = "at_block")]
+    audit_mode: bool,
+
+    /// Read a JSON-lines events journal from standard input instead of fetching events
+    #[arg(long)]
+    stdin: bool,
+
+    /// Resume from a snapshot written by `snapshot`, keeping only the events after its block
+    /// and refusing to evaluate before it
+    #[arg(long, conflicts_with = "cache")]
+    snapshot: Option<PathBuf>,
+
+    /// Hex secp256k1 public key `--snapshot` must be signed with
+    #[arg(long, value_parser = parse_public_key, requires = "snapshot")]
+    snapshot_pubkey: Option<VerifyingKey>,
+
+    /// TOML file of per-address notes and tags shown next to reported addresses
+    #[arg(long, global = true)]
+    annotations: Option<PathBuf>,
+
+    /// Print every event involving this address and exit without running the accounting
+    #[arg(long)]
+    address_events: Option<Address>,
+
+    /// TOML file splitting addresses into sub-accounts with time-ranged weights
+    #[arg(long, global = true)]
+    sub_accounts: Option<PathBuf>,
+
+    /// Only list addresses carrying this annotation tag in the rewards report
+    #[arg(long, requires = "annotations")]
+    filter_tag: Option<String>,
+
+    /// Ether of a loyalty bonus split over the share-blocks held longer than
+    /// --loyalty-min-age, listed next to the rewards
+    #[arg(long, value_parser = parse_budget)]
+    loyalty_budget: Option<U256>,
+
+    /// Blocks shares must be held before they earn the loyalty bonus, 90 days by default
+    #[arg(long, default_value_t = MIN_AGE_BLOCKS, requires = "loyalty_budget")]
+    loyalty_min_age: u64,
+
+    /// Settle rewards in exact fractions, flooring each holder's to wei only at the end, and
+    /// report what the integer accounting rounded away. Slow for many holders
+    #[arg(long)]
+    exact: bool,
+
+    /// Withhold the rewards of payees dormant for more than this many days, 365 without a
+    /// value, listing them as withheld for escrow
+    #[arg(long, num_args = 0..=1, default_missing_value = "365")]
+    withhold_dormant: Option<u64>,
+
+    /// Withhold payouts below this many ether, carrying them into the next epoch
+    #[arg(long, value_parser = parse_min_payout, requires = "rollover_out")]
+    min_payout: Option<U256>,
+
+    /// Rollover written by the previous epoch, whose carried amounts are paid first
+    #[arg(long, requires = "min_payout")]
+    rollover_in: Option<PathBuf>,
+
+    /// Where to write the rollover for the next epoch
+    #[arg(long, requires = "min_payout")]
+    rollover_out: Option<PathBuf>,
+
+    /// WASM module adjusting the rewards list before it is reported, see `examples/hooks`.
+    /// Needs a build with the `wasm-hooks` feature
+    #[arg(long)]
+    hook: Option<PathBuf>,
+
+    /// Accept labels the hook adds to the rewards list
+    #[arg(long, requires = "hook")]
+    hook_allow_new_labels: bool,
+
+    /// Basis points by which the hook may raise the total rewards
+    #[arg(long, requires = "hook", default_value_t = 0)]
+    hook_max_increase_bps: u32,
+
+    /// CSV file of source_address,mapped_address,chain rows giving payees' addresses on other
+    /// chains
+    #[arg(long, requires = "export_chain")]
+    address_map: Option<PathBuf>,
+
+    /// Report rewards at the mapped addresses for this chain, withholding unmapped payees
+    #[arg(long, requires = "address_map")]
+    export_chain: Option<ExportChain>,
+
+    /// Run the `sanity` checks on the rewards report and refuse to write payouts while they
+    /// raise red flags
+    #[arg(long)]
+    strict: bool,
+
+    /// Write the payouts of a --strict run despite its red flags
+    #[arg(long, requires = "strict")]
+    acknowledge_flags: bool,
+
+    /// Rewards JSON of an earlier run, as `sanity --save` writes, to flag rewards that changed
+    /// by more than --max-change-pct since
+    #[arg(long, global = true)]
+    previous: Option<PathBuf>,
+
+    /// Flag addresses earning more than this percentage of the total emission
+    #[arg(long, global = true, default_value_t = 25.0)]
+    max_emission_pct: f64,
+
+    /// Flag addresses whose rewards changed by more than this percentage since --previous
+    #[arg(long, global = true, default_value_t = 50.0)]
+    max_change_pct: f64,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Group holders by the period of their first share receipt and report retention
+    Cohorts {
+        #[arg(long, value_enum, default_value_t = Bucket::Weekly)]
+        bucket: Bucket,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+        format: OutputFormat,
+    },
+    /// List payees owed rewards with no deposit, withdrawal or transfer for longer than the
+    /// threshold, by the evaluation block's timestamp
+    Dormancy {
+        #[arg(long, default_value_t = DORMANCY_DAYS)]
+        threshold_days: u64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Markdown)]
+        format: OutputFormat,
+    },
+    /// Replay without each of the addresses with the most rewards and report how much more
+    /// everyone else would have earned
+    Dilution {
+        /// Number of addresses to replay without, by rewards
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Markdown)]
+        format: OutputFormat,
+    },
+    /// The global accumulators at evenly spaced blocks up to the evaluation block, read from
+    /// the checkpoints of --accumulator-interval
+    Series {
+        /// Number of blocks, each but the last rounded down to a checkpoint
+        #[arg(long, default_value_t = 100)]
+        points: usize,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+        format: OutputFormat,
+    },
+    /// Share of each bucket's emission credited to staked shares
+    Utilization {
+        /// Blocks per bucket, aligned to multiples of it
+        #[arg(long, default_value_t = BUCKET_SIZE)]
+        bucket: u64,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+        format: OutputFormat,
+    },
+    /// Fetch vault events into a cache file, optionally as one shard of a larger backfill
+    Fetch {
+        /// Cache file to write
+        #[arg(long)]
+        cache: PathBuf,
+        /// Last block to fetch, defaults to the current head
+        #[arg(long)]
+        to_block: Option<u64>,
+        /// Fetch only the i-th of n contiguous slices of the chunk grid, e.g. `2/3`
+        #[arg(long)]
+        shard: Option<Shard>,
+        /// Keep the raw logs of every chunk in this directory and fetch only the chunks it
+        /// lacks, so an interrupted backfill resumes, even after an upgrade or a layout change
+        #[arg(long)]
+        chunks_dir: Option<PathBuf>,
+    },
+    /// Check the accounting state against a clean fetch and replay of the vault history
+    Verify {
+        /// Repeat the check every N hours instead of exiting after one pass
+        #[arg(long)]
+        every_hours: Option<u64>,
+        /// Replace the in-memory state with the clean replay when they diverge
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Keep the rewards up to date as blocks are mined, printing them whenever new events land
+    Watch {
+        /// Seconds between polls of the chain head
+        #[arg(long, default_value_t = 12)]
+        interval: u64,
+    },
+    /// Print a completion script for the shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Combine cache shards covering adjacent block ranges into a single cache
+    MergeCaches {
+        out: PathBuf,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Compare reconstructed shares against on-chain `balanceOf` for a sample of holders
+    Reconcile {
+        /// Number of largest holders to check
+        #[arg(long, default_value_t = 20)]
+        sample: usize,
+        /// Extra addresses to check besides the sampled holders
+        #[arg(long)]
+        address: Vec<Address>,
+    },
+    /// Set the note or add tags for an address in the `--annotations` file
+    Annotate {
+        #[arg(long)]
+        address: Address,
+        #[arg(long)]
+        note: Option<String>,
+        #[arg(long)]
+        tag: Vec<String>,
+    },
+    /// Write a proof of the distribution that `verify-proof` can check offline
+    Prove {
+        #[arg(long)]
+        output: PathBuf,
+        /// Also write the events the proof commits to as a JSON-lines journal
+        #[arg(long)]
+        events: Option<PathBuf>,
+    },
+    /// Run declarative accounting scenarios
+    Scenario {
+        #[command(subcommand)]
+        command: ScenarioCommand,
+    },
+    /// Work with cache files
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Replay an events journal and check it reproduces every claim of a proof
+    VerifyProof {
+        proof: PathBuf,
+        /// Journal in canonical order, replayed as it is read so it needn't fit in memory
+        #[arg(long)]
+        events: PathBuf,
+        /// Read the whole journal and sort it before replaying, for journals out of order
+        #[arg(long)]
+        sort_first: bool,
+    },
+    /// Check the detached `.sig` file of an output written with `--sign-key-env`
+    VerifySignature {
+        path: PathBuf,
+        /// Hex secp256k1 public key the output must be signed with
+        #[arg(long, value_parser = parse_public_key)]
+        pubkey: VerifyingKey,
+    },
+    /// Print the runs and files an output was built from, following the `lineage` field of JSON
+    /// outputs and the `.lineage.json` file written next to the others
+    Lineage {
+        artifact: PathBuf,
+        /// Directory to find the inputs in by hash, the artifact's own by default
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Net the claims already paid through past distributors against lifetime rewards, giving
+    /// what the next distribution owes
+    Outstanding {
+        /// TOML file listing the distributor contract of each past epoch
+        #[arg(long)]
+        distributors: PathBuf,
+    },
+    /// Write the accounting state at the evaluation block for `--snapshot` runs to resume from
+    Snapshot {
+        #[arg(long)]
+        output: PathBuf,
+        /// File holding the hex secp256k1 private key to sign the snapshot with
+        #[arg(long)]
+        key: Option<PathBuf>,
+    },
+    /// Replay two event histories in lockstep and report the first block after which they
+    /// disagree
+    Bisect {
+        /// Cache file, or `.jsonl` events journal
+        left: PathBuf,
+        /// Defaults to `left`, to compare two settings on the same events
+        right: Option<PathBuf>,
+        /// Replay the right side with `--net-same-block` flipped
+        #[arg(long)]
+        right_flip_net_same_block: bool,
+    },
+    /// Dry-run the claim of every leaf of a merkle file against a deployed distributor
+    SimulateClaims {
+        #[arg(long)]
+        distributor: Address,
+        /// Claims file in the format of Uniswap's merkle-distributor generator
+        #[arg(long)]
+        merkle_file: PathBuf,
+        /// RPC URL of a fork to simulate against instead, such as a local `anvil --fork-url`
+        /// with the distributor funded
+        #[arg(long)]
+        fork: Option<String>,
+    },
+    /// Flag addresses whose rewards are implausible: more than their shares could have earned,
+    /// more than --max-emission-pct of the emission, or more than --max-change-pct away from
+    /// --previous
+    Sanity {
+        /// Also write the rewards checked as JSON, for a later run's --previous
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+    /// Check the provider, vault and cache for common misconfigurations
+    Doctor {
+        /// Cache file to check as well
+        #[arg(long)]
+        cache: Option<PathBuf>,
+        /// Print the checks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ScenarioCommand {
+    /// Run a scenario file, or every `.toml` scenario in a directory
+    Run { path: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Rewrite a cache in --cache-format
+    Convert { input: PathBuf, output: PathBuf },
+}
+
+/// Parses the command line and runs the command, the whole of the `oprtc_calculator` binary.
+pub async fn run() -> Result<()> {
+    let mut cli = Cli::parse();
+    if cli.help_json {
+        println!("{:#}", help_json(&Cli::command()));
+        return Ok(());
+    }
+    if let Some(Command::Completions { shell }) = cli.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(());
+    }
+    cli.best_effort |= cli.quarantine.is_some();
+    ensure!(
+        cli.rpc_max_attempts > 0,
+        "--rpc-max-attempts must be at least 1"
+    );
+    let retry = RetryPolicy {
+        max_attempts: cli.rpc_max_attempts,
+        ..RetryPolicy::default()
+    };
+
+    let mut endpoints = cli
+        .rpc_url
+        .iter()
+        .map(|url| {
+            let http = Http::from_str(url)
+                .wrap_err_with(|| format!("--rpc-url {} is not a valid URL", url))?;
+            Ok((url, http))
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter();
+    let (url, http) = endpoints.next().expect("--rpc-url has a default");
+    let transport = endpoints
+        .fold(Instrumented::new(url, http), |transport, (url, http)| {
+            transport.with_fallback(url, http)
+        })
+        .with_retry(retry);
+    let client = Arc::new(Provider::new(transport.clone()));
+    let mut sources = vec![(
+        "rpc".to_string(),
+        ClientSource::new(client.as_ref().clone(), cli.fetch_mode),
+    )];
+    for (name, url) in &cli.cross_check_rpc {
+        let client = Provider::new(Instrumented::new(name, Http::from_str(url)?).with_retry(retry));
+        sources.push((name.clone(), ClientSource::new(client, cli.fetch_mode)));
+    }
+    let source = MergedSource::new(sources, cli.on_conflict.clone())?;
+
+    if cli.audit_mode {
+        ensure!(
+            cli.command.is_none(),
+            "--audit-mode only applies to the rewards report"
+        );
+    }
+    if cli.exact {
+        ensure!(
+            cli.command.is_none(),
+            "--exact only applies to the rewards report"
+        );
+        ensure!(
+            cli.sub_accounts.is_none(),
+            "--exact settles plain shares per address, --sub-accounts is not supported"
+        );
+    }
+    if cli.format != ReportFormat::Text {
+        ensure!(
+            cli.command.is_none(),
+            "--format only applies to the rewards report, commands take their own"
+        );
+        ensure!(
+            !cli.audit_mode,
+            "--audit-mode appends the hash to the text report, use --format text"
+        );
+    }
+    if cli.withhold_dormant.is_some() {
+        ensure!(
+            cli.command.is_none(),
+            "--withhold-dormant only applies to the rewards report"
+        );
+    }
+    if cli.loyalty_budget.is_some() {
+        ensure!(
+            cli.sub_accounts.is_none(),
+            "the loyalty bonus ages shares per address, --sub-accounts is not supported"
+        );
+        ensure!(
+            cli.min_payout.is_none(),
+            "the loyalty bonus is not rolled over, --min-payout is not supported"
+        );
+    }
+
+    ensure!(cli.chunk_size > 0, "--chunk-size must be at least 1 block");
+    if let Some(at_block) = cli.at_block {
+        ensure!(
+            at_block >= cli.from_block,
+            "--at-block {} is before --from-block {}",
+            at_block,
+            cli.from_block
+        );
+    }
+
+    let piped: Vec<&Path> = stdin_paths(&cli)
+        .into_iter()
+        .filter(|path| is_stdio(path))
+        .collect();
+    ensure!(
+        piped.len() + usize::from(cli.stdin) <= 1,
+        "standard input can only be read once, {} inputs are `-`{}",
+        piped.len(),
+        if cli.stdin { " besides --stdin" } else { "" }
+    );
+    if !is_stdio(&cli.output) {
+        ensure!(
+            cli.command.is_none(),
+            "--output only applies to the rewards report, commands take their own"
+        );
+    }
+    if let Some(csv) = &cli.csv {
+        ensure!(
+            cli.command.is_none(),
+            "--csv only applies to the rewards report"
+        );
+        ensure!(
+            !is_stdio(csv) || !is_stdio(&cli.output),
+            "--csv and --output can't both be standard output"
+        );
+    }
+    if let Some(merkle_out) = &cli.merkle_out {
+        ensure!(
+            cli.command.is_none(),
+            "--merkle-out only applies to the rewards report"
+        );
+        ensure!(
+            !is_stdio(merkle_out)
+                || (!is_stdio(&cli.output) && !cli.csv.as_deref().is_some_and(is_stdio)),
+            "--merkle-out can't share standard output with the report or --csv"
+        );
+    }
+
+    // resolved up front so a missing key fails the run before anything is written
+    let signing_key = cli
+        .sign_key_env
+        .as_deref()
+        .map(signing_key_from_env)
+        .transpose()?;
+    let sign = |path: &Path| -> Result<()> {
+        if let Some(key) = signing_key.as_ref().filter(|_| !is_stdio(path)) {
+            let sig_path = sign_file(path, key)?;
+            eprintln!("signed {} into {}", path.display(), sig_path.display());
+        }
+        Ok(())
+    };
+    // every written file records the run and the files it was read from, see `lineage`
+    let run_id = RunId::generate();
+    let mut lineage_inputs: Vec<PathBuf> = stdin_paths(&cli)
+        .into_iter()
+        .chain(
+            [&cli.snapshot, &cli.rollover_in, &cli.previous, &cli.hook]
+                .into_iter()
+                .flatten()
+                .map(PathBuf::as_path),
+        )
+        .map(Path::to_path_buf)
+        .collect();
+
+    let vault = cli.vault;
+    let lock_timeout = Duration::from_secs(cli.lock_timeout);
+    let mut pipeline: Option<PipelineTimings> = None;
+
+    match cli.command {
+        Some(Command::Fetch {
+            cache,
+            to_block,
+            shard,
+            chunks_dir,
+        }) => {
+            if shard.is_some() && to_block.is_none() {
+                bail!("--shard requires --to-block so every worker partitions the same range");
+            }
+            let ctx = EvaluationContext::resolve(&client, to_block).await?;
+            let to_block = ctx.block.as_u64();
+            ensure!(
+                to_block >= cli.from_block,
+                "block {} is before --from-block {}",
+                to_block,
+                cli.from_block
+            );
+            let (from_block, to_block) = match shard {
+                Some(shard) => shard_range(cli.from_block, to_block, cli.chunk_size, shard)?,
+                None => (cli.from_block, to_block),
+            };
+            let _lock = lock(&cache, LockMode::Exclusive, lock_timeout)?;
+            let mut event_cache =
+                EventCache::new(vault, ctx.chain_id, from_block, to_block, cli.chunk_size);
+            match &chunks_dir {
+                Some(dir) => {
+                    let meta = ChunkMeta::new(vault, ctx.chain_id, &cli.flavor, cli.chunk_size);
+                    let mut chunks = ChunkStore::open(dir, meta)?;
+                    let grid = chunk_grid(from_block, to_block, cli.chunk_size);
+                    eprintln!(
+                        "{} of {} chunks already in {}",
+                        grid.len() - chunks.missing(&grid).len(),
+                        grid.len(),
+                        dir.display()
+                    );
+                    event_cache
+                        .fill_from(&mut chunks, &source, &cli.flavor, cli.concurrency)
+                        .await?;
+                }
+                None => {
+                    event_cache
+                        .fill(&source, &cli.flavor, cli.concurrency)
+                        .await?
+                }
+            }
+            if source.is_merging() {
+                eprintln!("{}", source.provenance());
+            }
+            event_cache.save_as(&cache, cli.cache_format)?;
+            record_lineage(&cache, run_id, &lineage_inputs)?;
+            save_index(&event_cache, &cache)?;
+            print_cache_summary(&event_cache, &cache);
+        }
+        Some(Command::Annotate { address, note, tag }) => {
+            let Some(path) = &cli.annotations else {
+                bail!("annotate requires --annotations");
+            };
+
+            ensure!(
+                !is_stdio(path),
+                "annotate rewrites --annotations in place, it can't be `-`"
+            );
+            let _lock = lock(path, LockMode::Exclusive, lock_timeout)?;
+            let contents = match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err.into()),
+            };
+            annotations::save(path, &annotate(&contents, address, note.as_deref(), &tag)?)?;
+        }
+        Some(Command::VerifyProof {
+            proof,
+            events,
+            sort_first,
+        }) => {
+            let proof = Proof::load(&proof)?;
+            let events = if sort_first {
+                let mut events = read_journal(stdio::read(&events)?.as_slice())?;
+                events.sort_by_key(Event::position);
+                proof
+                    .verify(&events)
+                    .map(|()| events.len())
+                    .map_err(Error::from)
+            } else {
+                proof.verify_journal(stdio::open(&events)?)
+            }
+            .wrap_err("proof verification failed")?;
+            println!(
+                "proof verified: {} events, {} checkpoints, {} amounts",
+                events,
+                proof.checkpoints.len(),
+                proof.amounts.len()
+            );
+            return Ok(());
+        }
+        Some(Command::VerifySignature { path, pubkey }) => {
+            verify_file(&path, &pubkey)
+                .wrap_err_with(|| format!("{} failed verification", path.display()))?;
+            println!("signature of {} verified", path.display());
+            return Ok(());
+        }
+        Some(Command::Lineage { artifact, dir }) => {
+            let dir = dir.unwrap_or_else(|| match artifact.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            });
+            let (lineage, ancestors) = ancestry(&artifact, &dir)?;
+            println!("{}  run {}", artifact.display(), lineage.run_id);
+            for ancestor in ancestors {
+                let indent = "  ".repeat(ancestor.depth + 1);
+                match ancestor.origin {
+                    Origin::Artifact { path, run_id } => {
+                        println!("{}{}  run {}", indent, path.display(), run_id)
+                    }
+                    Origin::Source { path } => println!("{}{}  no lineage", indent, path.display()),
+                    Origin::Cycle { path } => {
+                        println!("{}{}  cycle, not followed", indent, path.display())
+                    }
+                    Origin::Missing => println!(
+                        "{}{}  missing from {} ({:?})",
+                        indent,
+                        ancestor.input.path.display(),
+                        dir.display(),
+                        ancestor.input.hash
+                    ),
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Bisect {
+            left,
+            right,
+            right_flip_net_same_block,
+        }) => {
+            let (left_events, left_horizon) = load_events(&left, cli.input_format, cli.from_block)?;
+            let (right_events, right_horizon) = match &right {
+                Some(path) => load_events(path, cli.input_format, cli.from_block)?,
+                None => (left_events.clone(), left_horizon),
+            };
+            // both histories are read from files, so only the horizon they share is known
+            let freshness = Freshness::offline(left_horizon.min(right_horizon));
+            println!("{}", freshness);
+            if let Some(max_staleness) = cli.max_staleness {
+                freshness.ensure_within(max_staleness)?;
+            }
+            ensure!(
+                !(cli.net_same_block || right_flip_net_same_block)
+                    || cli.accrual_policy == AccrualPolicy::BlockStart,
+                "--net-same-block needs --accrual-policy block-start, per-event accrual sees every \
+                 event"
+            );
+            let new_state = |net_same_block| {
+                let mut state = GlobalState::deployed_at(cli.from_block);
+                state.set_same_block_netting(net_same_block);
+                state.set_accrual_policy(cli.accrual_policy);
+                state.set_best_effort(cli.best_effort);
+                state
+            };
+
+            match bisect(
+                &left_events,
+                &right_events,
+                new_state(cli.net_same_block),
+                new_state(cli.net_same_block != right_flip_net_same_block),
+            )? {
+                Some(divergence) => print!("{}", divergence),
+                None => println!(
+                    "the replays agree after every block, {} and {} events",
+                    left_events.len(),
+                    right_events.len()
+                ),
+            }
+            return Ok(());
+        }
+        Some(Command::SimulateClaims {
+            distributor,
+            merkle_file,
+            fork,
+        }) => {
+            let file = MerkleFile::load(&merkle_file)?;
+            let client = match &fork {
+                Some(url) => {
+                    Provider::new(Instrumented::new(url, Http::from_str(url)?).with_retry(retry))
+                }
+                None => client.as_ref().clone(),
+            };
+            let ctx = EvaluationContext::resolve(&client, None).await?;
+
+            let root = client.merkle_root(distributor, ctx.block).await?;
+            if root != file.merkle_root {
+                eprintln!(
+                    "warning: the distributor's root {:?} isn't the file's {:?}, claims will revert",
+                    root, file.merkle_root
+                );
+            }
+
+            let results =
+                simulate_claims(&client, distributor, &file, ctx.block, cli.concurrency).await?;
+            let mut total_gas = U256::zero();
+            let mut failed = 0;
+            for result in &results {
+                match &result.outcome {
+                    Ok(gas) => {
+                        total_gas += *gas;
+                        println!(
+                            "leaf {} {:?} {}: pass, gas {}",
+                            result.index,
+                            result.account,
+                            format_ether_rounded(result.amount, cli.decimals),
+                            gas
+                        );
+                    }
+                    Err(reason) => {
+                        failed += 1;
+                        println!(
+                            "leaf {} {:?} {}: FAIL, {}",
+                            result.index,
+                            result.account,
+                            format_ether_rounded(result.amount, cli.decimals),
+                            reason
+                        );
+                    }
+                }
+            }
+
+            let passed = results.len() - failed;
+            println!(
+                "{} of {} claims would succeed at block {}, {} gas in total, {} per claim",
+                passed,
+                results.len(),
+                ctx.block,
+                total_gas,
+                total_gas / U256::from(passed.max(1))
+            );
+            ensure!(failed == 0, "{} claims would fail", failed);
+            return Ok(());
+        }
+        Some(Command::Doctor { cache, json }) => {
+            let ctx = EvaluationContext::resolve(&client, None).await?;
+            let checks = diagnose(
+                &client,
+                &ctx,
+                vault,
+                &cli.flavor,
+                CHAIN_ID,
+                cli.from_block,
+                cache.as_deref(),
+            )
+            .await?;
+
+            if json {
+                println!("{}", versioned_json(&checks));
+            } else {
+                print!("{}", render(&checks, std::io::stdout().is_terminal()));
+            }
+            ensure!(
+                checks.iter().all(|check| check.status != Status::Fail),
+                "doctor found problems"
+            );
+            return Ok(());
+        }
+        Some(Command::Scenario {
+            command: ScenarioCommand::Run { path },
+        }) => {
+            let outcomes = run_scenarios(&path)?;
+            let failed = outcomes
+                .iter()
+                .filter(|(_, outcome)| outcome.is_err())
+                .count();
+
+            for (file, outcome) in &outcomes {
+                match outcome {
+                    Ok(()) => println!("PASS {}", file.display()),
+                    Err(err) => println!("FAIL {}: {:#}", file.display(), err),
+                }
+            }
+            ensure!(
+                failed == 0,
+                "{} of {} scenarios failed",
+                failed,
+                outcomes.len()
+            );
+            return Ok(());
+        }
+        Some(Command::Cache {
+            command: CacheCommand::Convert { input, output },
+        }) => {
+            let _input_lock = lock(&input, LockMode::Shared, lock_timeout)?;
+            let _output_lock = lock(&output, LockMode::Exclusive, lock_timeout)?;
+            let event_cache = EventCache::load(&input)?;
+            event_cache.save_as(&output, cli.cache_format)?;
+            record_lineage(&output, run_id, &lineage_inputs)?;
+            save_index(&event_cache, &output)?;
+            print_cache_summary(&event_cache, &output);
+        }
+        Some(Command::MergeCaches { out, inputs }) => {
+            let _out_lock = lock(&out, LockMode::Exclusive, lock_timeout)?;
+            let _input_locks: Vec<_> = inputs
+                .iter()
+                .map(|path| lock(path, LockMode::Shared, lock_timeout))
+                .collect::<Result<_>>()?;
+            let shards = inputs
+                .iter()
+                .map(|path| EventCache::load(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            let merged = EventCache::merge(shards)?;
+            merged.save_as(&out, cli.cache_format)?;
+            record_lineage(&out, run_id, &lineage_inputs)?;
+            save_index(&merged, &out)?;
+            print_cache_summary(&merged, &out);
+        }
+        command => {
+            let window = match &cli.snapshot {
+                Some(path) => {
+                    let window = Snapshot::load(path)?;
+                    window.verify(cli.snapshot_pubkey.as_ref())?;
+                    ensure!(
+                        window.vault == vault,
+                        "{} is a snapshot of {:?}, not of the vault {:?}",
+                        path.display(),
+                        window.vault,
+                        vault
+                    );
+                    ensure!(
+                        !matches!(
+                            command,
+                            Some(Command::Cohorts { .. })
+                                | Some(Command::Dilution { .. })
+                                | Some(Command::Dormancy { .. })
+                                | Some(Command::Verify { .. })
+                                | Some(Command::Prove { .. })
+                                | Some(Command::Sanity { .. })
+                        ) && cli.address_events.is_none()
+                            && cli.sub_accounts.is_none()
+                            && cli.loyalty_budget.is_none()
+                            && cli.withhold_dormant.is_none()
+                            && !cli.exact
+                            && !cli.strict,
+                        "cohorts, dilution, dormancy, verify, prove, sanity, --address-events, \
+                         --sub-accounts, --loyalty-budget, --withhold-dormant, --exact and --strict need \
+                         the full event history, which --snapshot runs don't keep"
+                    );
+                    Some(window)
+                }
+                None => None,
+            };
+            let from_block = window
+                .as_ref()
+                .map_or(cli.from_block, |window| window.block_number.as_u64() + 1);
+
+            // `--cache -` takes either a cache or a journal, `--stdin` only a journal
+            let (piped_cache, journal) = match &cli.cache {
+                Some(path) if is_stdio(path) => {
+                    match parse_events(&stdio::read(path)?, cli.input_format)? {
+                        EventsInput::Cache(event_cache) => (Some(event_cache), None),
+                        EventsInput::Journal(events) => (None, Some(events)),
+                    }
+                }
+                None if cli.stdin => (None, Some(read_journal(std::io::stdin().lock())?)),
+                _ => (None, None),
+            };
+            let journal_input = journal.is_some();
+            let (mut decoded, ctx) = match (&cli.cache, journal) {
+                (_, Some(events)) => {
+                    let decoded = Decoded {
+                        events,
+                        ..Decoded::default()
+                    };
+                    (
+                        decoded,
+                        EvaluationContext::resolve(&client, cli.at_block).await?,
+                    )
+                }
+                (Some(path), None) => {
+                    let _lock = lock(path, LockMode::Shared, lock_timeout)?;
+                    let event_cache = match piped_cache {
+                        Some(event_cache) => event_cache,
+                        None => EventCache::load(path)?,
+                    };
+                    event_cache.ensure_vault(vault)?;
+                    ensure!(
+                        event_cache.is_complete(),
+                        "{} has unfetched chunks",
+                        path.display()
+                    );
+                    if let Some(at_block) = cli.at_block {
+                        ensure!(
+                            at_block == event_cache.to_block,
+                            "{} ends at block {}, not at --at-block {}",
+                            path.display(),
+                            event_cache.to_block,
+                            at_block
+                        );
+                    }
+                    let ctx =
+                        EvaluationContext::resolve(&client, Some(event_cache.to_block)).await?;
+                    ensure!(
+                        ctx.chain_id == event_cache.chain_id,
+                        "{} was fetched on chain {}, the provider is on chain {}",
+                        path.display(),
+                        event_cache.chain_id,
+                        ctx.chain_id
+                    );
+                    let decoded = Decoded {
+                        events: event_cache.events,
+                        logs: event_cache.logs,
+                        undecodable: event_cache.undecodable_logs,
+                        ..Decoded::default()
+                    };
+                    (decoded, ctx)
+                }
+                (None, None) => {
+                    let ctx = EvaluationContext::resolve(&client, cli.at_block).await?;
+                    // snapshot runs only need the blocks after it, pipelined ones fetch as they go
+                    let cache_path = (!cli.no_cache
+                        && cli.pipeline_depth.is_none()
+                        && window.is_none())
+                    .then(|| {
+                        cli.cache_dir
+                            .join(format!("{:?}-{}.cache", vault, ctx.chain_id))
+                    });
+                    let mut _lock = None;
+                    let cached = match &cache_path {
+                        Some(path) => {
+                            std::fs::create_dir_all(&cli.cache_dir).wrap_err_with(|| {
+                                format!("failed to create {}", cli.cache_dir.display())
+                            })?;
+                            _lock = lock(path, LockMode::Exclusive, lock_timeout)?;
+                            cache_to_sync(path, vault, &ctx, from_block, cli.chunk_size)
+                        }
+                        None => None,
+                    };
+                    let decoded = match (cache_path, cached) {
+                        (Some(path), Some(mut event_cache)) => {
+                            if event_cache.is_complete() {
+                                let synced_from = event_cache.to_block + 1;
+                                event_cache
+                                    .sync_to(
+                                        &source,
+                                        &cli.flavor,
+                                        ctx.block.as_u64(),
+                                        cli.concurrency,
+                                    )
+                                    .await?;
+                                eprintln!("synced {} from block {}", path.display(), synced_from);
+                            } else {
+                                event_cache
+                                    .fill(&source, &cli.flavor, cli.concurrency)
+                                    .await?;
+                                eprintln!("fetched the event history into {}", path.display());
+                            }
+                            event_cache.save_as(&path, cli.cache_format)?;
+                            lineage_inputs.push(path);
+                            Decoded {
+                                events: event_cache.events,
+                                logs: event_cache.logs,
+                                undecodable: event_cache.undecodable_logs,
+                                ..Decoded::default()
+                            }
+                        }
+                        _ => {
+                            let chunks = chunk_grid(from_block, ctx.block.as_u64(), cli.chunk_size);
+                            let chunks = match cli.pipeline_depth {
+                                Some(depth) => {
+                                    let mut fetched = vec![];
+                                    let timings = fetch_pipelined(
+                                        &source,
+                                        &cli.flavor,
+                                        vault,
+                                        &chunks,
+                                        depth,
+                                        |chunk| {
+                                            fetched.push(chunk);
+                                            Ok(())
+                                        },
+                                    )
+                                    .await?;
+                                    pipeline = Some(timings);
+                                    fetched
+                                }
+                                None => {
+                                    fetch_chunks(
+                                        &source,
+                                        &cli.flavor,
+                                        vault,
+                                        &chunks,
+                                        cli.concurrency,
+                                    )
+                                    .await?
+                                }
+                            };
+                            let decoded = Decoded::concat(chunks);
+                            if decoded.unconfirmed > 0 {
+                                eprintln!("unconfirmed logs ignored: {}", decoded.unconfirmed);
+                            }
+                            decoded
+                        }
+                    };
+                    if source.is_merging() {
+                        eprintln!("{}", source.provenance());
+                    }
+                    (decoded, ctx)
+                }
+            };
+            ensure!(
+                ctx.block >= U64::from(cli.from_block),
+                "block {} is before --from-block {}",
+                ctx.block,
+                cli.from_block
+            );
+            if let Some(window) = &window {
+                window.ensure_covers(ctx.block)?;
+                ensure!(
+                    window.chain_id == ctx.chain_id,
+                    "the snapshot was taken on chain {}, the provider is on chain {}",
+                    window.chain_id,
+                    ctx.chain_id
+                );
+                decoded
+                    .events
+                    .retain(|evt| evt.block_number() > window.block_number);
+            } else {
+                warn_unbacked(&decoded.events, vault);
+            }
+            let (logs, undecodable_logs) = (decoded.logs, decoded.undecodable);
+            let first_error = decoded.first_error.clone();
+            let all_events = if cli.best_effort {
+                decoded.events
+            } else {
+                decoded.strict()?
+            };
+            // journals are written after the conversion, already in shares
+            let all_events = match &cli.flavor.rebase {
+                Some(rebase) if !journal_input => {
+                    let series = rebase
+                        .index_series(&source, cli.from_block, ctx.block.as_u64())
+                        .await?;
+                    let normalized = rebase.normalize(all_events, &series, vault);
+                    eprintln!(
+                        "converted rebased amounts to shares, {} wei of dust rounded off",
+                        normalized.dust
+                    );
+                    normalized.events
+                }
+                _ => all_events,
+            };
+            if !cli.flavor.segments.is_empty() {
+                for (name, count) in cli.flavor.layout_counts(&all_events) {
+                    eprintln!("decoded {} events under {}", count, name);
+                }
+            }
+            eprintln!(
+                "evaluating at block {} (timestamp {}) on chain {}",
+                ctx.block, ctx.timestamp, ctx.chain_id
+            );
+            let head = if (cli.cache.is_none() || journal_input) && cli.at_block.is_none() {
+                ctx
+            } else {
+                EvaluationContext::resolve(&client, None).await?
+            };
+            let freshness = Freshness::online(&ctx, &head);
+            eprintln!("{}", freshness);
+            if let Some(max_staleness) = cli.max_staleness {
+                freshness.ensure_within(max_staleness)?;
+            }
+
+            if let Some(address) = cli.address_events {
+                let index = match &cli.cache {
+                    Some(path) if !is_stdio(path) => EventIndex::for_cache(path, &all_events),
+                    _ => EventIndex::build(&all_events),
+                };
+                eprintln!(
+                    "address index: {} addresses, ~{} bytes",
+                    index.addresses(),
+                    index.memory_bytes()
+                );
+                return print_address_events(
+                    index.events_for(&all_events, address).collect(),
+                    address,
+                    cli.decimals,
+                );
+            }
+
+            let mut global_state = match &window {
+                Some(window) => GlobalState::resume(window.state.clone()),
+                None => GlobalState::deployed_at(cli.from_block),
+            };
+            ensure!(
+                global_state.deployed_block() == U64::from(cli.from_block),
+                "the snapshot is of a vault deployed at block {}, not at --from-block {}",
+                global_state.deployed_block(),
+                cli.from_block
+            );
+            global_state.set_same_block_netting(cli.net_same_block);
+            global_state.set_coalescing(cli.coalesce);
+            global_state.set_accrual_policy(cli.accrual_policy);
+            global_state.set_max_share_multiple(cli.max_share_multiple);
+            global_state.set_self_held_shares(vault, cli.self_held_shares);
+            global_state.set_best_effort(cli.best_effort);
+            global_state.set_distribution_tracking(true);
+            match &window {
+                Some(_) => ensure!(
+                    global_state.min_staking_blocks() == cli.min_staking_blocks,
+                    "the snapshot was taken with --min-staking-blocks {:?}, not {:?}",
+                    global_state.min_staking_blocks(),
+                    cli.min_staking_blocks
+                ),
+                None => global_state.set_min_staking_blocks(cli.min_staking_blocks),
+            }
+            if cli.min_staking_blocks.is_some() {
+                ensure!(
+                    !cli.coalesce && !cli.net_same_block,
+                    "--coalesce and --net-same-block skip share receipts, which \
+                     --min-staking-blocks matures one by one"
+                );
+                ensure!(
+                    !cli.exact && !matches!(command, Some(Command::Prove { .. })),
+                    "--exact and proofs settle every staked share, --min-staking-blocks is not \
+                     supported"
+                );
+            }
+
+            if matches!(command, Some(Command::Prove { .. })) {
+                ensure!(
+                    !cli.best_effort,
+                    "proofs replay every event, --best-effort is not supported"
+                );
+                ensure!(
+                    !cli.coalesce,
+                    "proofs checkpoint every event, --coalesce is not supported"
+                );
+                global_state.set_checkpoint_interval(Some(CHECKPOINT_INTERVAL));
+            }
+            ensure!(
+                !cli.coalesce || cli.accrual_policy == AccrualPolicy::BlockStart,
+                "--coalesce needs --accrual-policy block-start, per-event accrual sees every event"
+            );
+            ensure!(
+                !cli.net_same_block || cli.accrual_policy == AccrualPolicy::BlockStart,
+                "--net-same-block needs --accrual-policy block-start, per-event accrual sees every \
+                 event"
+            );
+            ensure!(
+                cli.accrual_policy == AccrualPolicy::BlockStart
+                    || !matches!(command, Some(Command::Verify { .. })),
+                "verify replays with block-start accrual, --accrual-policy per-event is not supported"
+            );
+            ensure!(
+                cli.flavor.rebase.is_none()
+                    || !matches!(
+                        command,
+                        Some(Command::Verify { .. }) | Some(Command::Prove { .. })
+                    ),
+                "verify and prove replay the vault's raw amounts, rebasing flavors are not supported"
+            );
+
+            let sub_accounts = match &cli.sub_accounts {
+                Some(path) => SubAccounts::load(path)?,
+                None => SubAccounts::default(),
+            };
+
+            let emission = cli.flavor.emission()?;
+            let settings = ReplaySettings {
+                net_same_block: cli.net_same_block,
+                emission,
+                accrual_policy: cli.accrual_policy,
+            };
+            if let Some(window) = &window {
+                ensure!(
+                    window.settings == settings,
+                    "the snapshot was taken with {:?}, this run uses {:?}",
+                    window.settings,
+                    settings
+                );
+            }
+            if emission != Emission::PerBlock {
+                ensure!(
+                    !matches!(
+                        command,
+                        Some(Command::Verify { .. }) | Some(Command::Prove { .. })
+                    ),
+                    "verify and prove only replay per-block emission"
+                );
+                ensure!(
+                    sub_accounts.boundaries().all(|block| block <= ctx.block),
+                    "sub-account ranges change past the evaluation block {}",
+                    ctx.block
+                );
+
+                let top_ups = match &cli.flavor.top_ups {
+                    Some(config) => {
+                        config
+                            .fetch(&source, vault, cli.from_block, ctx.block.as_u64())
+                            .await?
+                    }
+                    None => vec![],
+                };
+                let mut timestamps = TimestampCache::new();
+                timestamps.insert(ctx.block, ctx.timestamp);
+                let blocks = all_events
+                    .iter()
+                    .map(|evt| evt.block_number())
+                    .chain([global_state.deployed_block()])
+                    .chain(sub_accounts.boundaries())
+                    .chain(top_ups.iter().map(|top_up| top_up.block_number));
+                timestamps.fetch(&client, &ctx, blocks).await?;
+                if emission == Emission::TopUps {
+                    eprintln!("streaming {} top-ups", top_ups.len());
+                    global_state.set_top_ups(TopUps::new(&top_ups, &timestamps)?);
+                }
+                global_state.set_emission(emission, timestamps);
+            }
+            // checkpoints under per-second emission would need every checkpoint's timestamp
+            let accumulator_interval = (cli.accumulator_interval > 0
+                && emission == Emission::PerBlock)
+                .then_some(cli.accumulator_interval);
+            match global_state.accumulator_interval() {
+                Some(interval) if window.is_some() => ensure!(
+                    Some(interval) == accumulator_interval,
+                    "the snapshot has accumulator checkpoints every {} blocks, not {:?}",
+                    interval,
+                    accumulator_interval
+                ),
+                _ => global_state.set_accumulator_interval(accumulator_interval),
+            }
+            if matches!(command, Some(Command::Series { .. })) {
+                ensure!(
+                    accumulator_interval.is_some(),
+                    "series reads accumulator checkpoints, which need per-block emission and a \
+                     non-zero --accumulator-interval"
+                );
+            }
+
+            let warnings = WarningLog {
+                max_share_multiple: cli.max_share_multiple.unwrap_or_default(),
+            };
+            if cli.exact {
+                let holders: HashSet<Address> = all_events
+                    .iter()
+                    .flat_map(|evt| match evt {
+                        Event::Deposit(deposit) => vec![deposit.address],
+                        Event::Withdrawal(withdraw) => vec![withdraw.address],
+                        Event::Transfer(transfer) => vec![transfer.from, transfer.to],
+                    })
+                    .collect();
+                if holders.len() > EXACT_HOLDER_WARNING {
+                    eprintln!(
+                        "warning: --exact keeps a growing fraction per holder, {} holders will \
+                         take long and a lot of memory",
+                        holders.len()
+                    );
+                }
+            }
+            let mut observers = (
+                (warnings, PoolShares::deployed_at(cli.from_block)),
+                (
+                    cli.loyalty_budget
+                        .map(|_| LoyaltyBonus::new(cli.loyalty_min_age)),
+                    cli.exact.then(Ledger::<BigRational>::new),
+                ),
+            );
+            // counterfactual replays start from the same settings
+            let fresh_state = global_state.clone();
+            let checkpoints =
+                sub_accounts.replay_with(&mut global_state, all_events.clone(), &mut observers)?;
+            let ((_, pool_shares), (loyalty, exact)) = observers;
+
+            if cli.net_same_block || cli.coalesce {
+                eprintln!("user record updates: {}", global_state.record_updates());
+            }
+            if cli.coalesce {
+                eprintln!(
+                    "coalesced {} events into {} per-address deltas",
+                    global_state.coalesced_events(),
+                    global_state.coalesced().len()
+                );
+            }
+
+            let quarantine = global_state.quarantined();
+            if let Some(burst) = unknown_sender_burst(quarantine, &cli.flavor.boundaries()) {
+                eprintln!("{}", burst);
+            }
+            if let Some(path) = &cli.quarantine {
+                let rows = quarantine_rows(quarantine, undecodable_logs, first_error.as_deref());
+                std::fs::write(
+                    path,
+                    render_quarantine(&rows, cli.quarantine_format, &freshness),
+                )?;
+                sign(path)?;
+                record_lineage(path, run_id, &lineage_inputs)?;
+                eprintln!(
+                    "quarantined {} events and {} undecodable logs to {}",
+                    quarantine.len(),
+                    undecodable_logs,
+                    path.display()
+                );
+            }
+
+            let quality = if cli.best_effort {
+                let supply = call_uint(&client, vault, "totalSupply()", &[], ctx.block).await;
+                Some(DataQuality {
+                    logs: logs.max(all_events.len() + undecodable_logs),
+                    undecodable_logs,
+                    skipped_events: global_state.skipped_events().len(),
+                    supply_delta: supply.ok().map(|supply| {
+                        I256::from_raw(global_state.total_shares_staked()) - I256::from_raw(supply)
+                    }),
+                })
+            } else {
+                None
+            };
+
+            let report_block = if cli.no_pending {
+                global_state.last_accounted_block()
+            } else {
+                ctx.block
+            };
+
+            let utilization_bucket = match command {
+                Some(Command::Utilization { bucket, .. }) => bucket,
+                _ => BUCKET_SIZE,
+            };
+            ensure!(utilization_bucket > 0, "--bucket must be at least 1 block");
+            let utilization = utilization_series(&global_state, report_block, utilization_bucket);
+            if let Some(worst) = worst_bucket(&utilization) {
+                eprintln!(
+                    "lowest emission utilization: {:.4}% over blocks {}-{}",
+                    worst.ratio() * 100.0,
+                    worst.from_block,
+                    worst.to_block
+                );
+            }
+            if let Some(reconciliation) = global_state.top_up_reconciliation(report_block) {
+                eprintln!("{}", reconciliation);
+            }
+
+            match command {
+                Some(Command::Utilization { format, .. }) => {
+                    print!("{}", render_utilization(&utilization, format, &freshness));
+                }
+                Some(Command::Series { points, format }) => {
+                    ensure!(points > 0, "--points must be at least 1");
+                    let interval = global_state
+                        .accumulator_interval()
+                        .expect("series runs with checkpoints");
+                    let blocks = series_blocks(
+                        global_state.deployed_block(),
+                        report_block,
+                        points,
+                        interval,
+                    );
+                    // blocks before the checkpoints of a snapshot that had none are left out
+                    let series: Vec<_> = blocks
+                        .into_iter()
+                        .filter_map(|block| global_state.accumulator_at(block))
+                        .collect();
+                    print!("{}", render_series(&series, format, &freshness));
+                }
+                Some(Command::Cohorts { bucket, format }) => {
+                    let first_blocks: Vec<U64> = global_state
+                        .users()
+                        .filter_map(|address| global_state.first_deposit_block(*address))
+                        .collect();
+
+                    let mut timestamps = TimestampCache::new();
+                    timestamps.fetch(&client, &ctx, first_blocks).await?;
+
+                    let rows = build_cohorts(
+                        &all_events,
+                        &global_state,
+                        &timestamps,
+                        bucket,
+                        report_block,
+                    )?;
+                    print!("{}", render_cohorts(&rows, format, &freshness));
+                }
+                Some(Command::Dormancy {
+                    threshold_days,
+                    format,
+                }) => {
+                    let rows =
+                        find_dormant(&client, &ctx, &global_state, threshold_days, report_block)
+                            .await?;
+                    print!("{}", render_dormancy(&rows, format, &freshness));
+                }
+                Some(Command::Dilution { top, format }) => {
+                    let rows = build_dilution(
+                        &all_events,
+                        &global_state,
+                        &fresh_state,
+                        top,
+                        report_block,
+                    )?;
+                    print!("{}", render_dilution(&rows, format, &freshness));
+                }
+                Some(Command::Verify {
+                    every_hours,
+                    repair,
+                }) => {
+                    let live = Arc::new(RwLock::new(LiveState {
+                        state: global_state,
+                        synced_block: ctx.block.as_u64(),
+                    }));
+                    let verifier = Verifier::new(
+                        source.clone(),
+                        cli.flavor.clone(),
+                        vault,
+                        cli.from_block,
+                        live,
+                        repair,
+                        Arc::new(TokioClock),
+                    );
+
+                    match every_hours {
+                        Some(hours) => verifier.run(Duration::from_secs(hours * 3600)).await,
+                        None => {
+                            if let Some(divergence) = verifier.verify_once().await? {
+                                bail!(
+                                    "state diverged at {:?}, last touched at block {}",
+                                    divergence.address,
+                                    divergence.block_number
+                                );
+                            }
+                            println!("state matches a clean replay");
+                        }
+                    }
+                }
+                Some(Command::Watch { interval }) => {
+                    ensure!(interval > 0, "--interval must be at least 1 second");
+                    ensure!(
+                        emission == Emission::PerBlock && cli.flavor.rebase.is_none(),
+                        "watch applies new events by share and block, per-second emission and \
+                         rebasing flavors are not supported"
+                    );
+                    let print = |live: &LiveState| {
+                        let block_number = U64::from(live.synced_block);
+                        println!("rewards at block {}", block_number);
+                        for (address, rewards) in live.state.get_user_rewards(block_number) {
+                            println!(
+                                "{:?} {}",
+                                address,
+                                format_ether_rounded(rewards, cli.decimals)
+                            );
+                        }
+                    };
+                    let live = LiveState {
+                        state: global_state,
+                        synced_block: ctx.block.as_u64(),
+                    };
+                    print(&live);
+                    let watcher = Watcher::new(
+                        source.clone(),
+                        client.as_ref().clone(),
+                        cli.flavor.clone(),
+                        vault,
+                        Arc::new(RwLock::new(live)),
+                        Arc::new(TokioClock),
+                    );
+                    watcher.run(Duration::from_secs(interval), print).await;
+                }
+                Some(Command::Reconcile { sample, address }) => {
+                    let mut addresses = largest_holders(&global_state, sample);
+                    for extra in address {
+                        if !addresses.contains(&extra) {
+                            addresses.push(extra);
+                        }
+                    }
+
+                    let balances = fetch_balances(&client, &ctx, vault, &addresses).await?;
+                    let mismatches = reconcile_balances(&global_state, &balances);
+
+                    if mismatches.is_empty() {
+                        println!(
+                            "all {} sampled balances match at block {}",
+                            addresses.len(),
+                            ctx.block
+                        );
+                    }
+                    for mismatch in &mismatches {
+                        println!(
+                            "{:?} recorded {} on-chain {} delta {}",
+                            mismatch.address,
+                            format_ether_rounded(mismatch.recorded, cli.decimals),
+                            format_ether_rounded(mismatch.on_chain, cli.decimals),
+                            mismatch.delta
+                        );
+                    }
+                }
+                Some(Command::Outstanding { distributors }) => {
+                    let distributors = Distributors::load(&distributors)?;
+                    let mut claims = vec![];
+                    for distributor in &distributors.distributors {
+                        claims
+                            .extend(fetch_claims(&source, distributor, ctx.block.as_u64()).await?);
+                    }
+
+                    let earned = global_state.get_user_rewards(report_block);
+                    let rows = outstanding(&earned, &claims, &distributors);
+                    for row in &rows {
+                        println!(
+                            "{:?} earned {} paid {} outstanding {}",
+                            row.address,
+                            format_ether_rounded(row.earned, cli.decimals),
+                            format_ether_rounded(row.paid, cli.decimals),
+                            format_signed_ether(row.outstanding, cli.decimals)
+                        );
+                    }
+
+                    let overpaid: Vec<_> = rows.iter().filter(|row| row.is_overpaid()).collect();
+                    if !overpaid.is_empty() {
+                        eprintln!(
+                            "warning: {} addresses were paid more than they have earned so far:",
+                            overpaid.len()
+                        );
+                        for row in overpaid {
+                            eprintln!(
+                                "  {:?} overpaid by {} ({:?})",
+                                row.address,
+                                format_ether_rounded(row.outstanding.unsigned_abs(), cli.decimals),
+                                row.paid_by_epoch
+                            );
+                        }
+                    }
+                }
+                Some(Command::Snapshot { output, key }) => {
+                    let mut snapshot =
+                        Snapshot::new(vault, ctx.chain_id, ctx.block, settings, &global_state);
+                    if let Some(path) = key {
+                        snapshot.sign(&load_signing_key(&path)?)?;
+                    }
+                    snapshot.lineage = Some(embedded_lineage(&output, run_id, &lineage_inputs)?);
+                    snapshot.save(&output)?;
+                    println!(
+                        "wrote snapshot at block {} to {} (hash {:?})",
+                        snapshot.block_number,
+                        output.display(),
+                        snapshot.hash
+                    );
+                }
+                Some(Command::Prove { output, events }) => {
+                    let config = ProofConfig {
+                        net_same_block: cli.net_same_block,
+                        vault,
+                        self_held_shares: cli.self_held_shares,
+                        checkpoint_interval: CHECKPOINT_INTERVAL,
+                        block_number: report_block,
+                        accrual_policy: cli.accrual_policy,
+                    };
+                    let mut proof = Proof::new(config, &all_events, &global_state);
+                    proof.lineage = Some(embedded_lineage(&output, run_id, &lineage_inputs)?);
+                    proof.save(&output)?;
+                    sign(&output)?;
+
+                    if let Some(path) = events {
+                        write_journal(BufWriter::new(File::create(&path)?), &all_events)?;
+                        sign(&path)?;
+                        record_lineage(&path, run_id, &lineage_inputs)?;
+                    }
+                    println!(
+                        "wrote proof to {} (events {:?}, amounts {:?})",
+                        output.display(),
+                        proof.events_hash,
+                        proof.amounts_hash
+                    );
+                }
+                _ => {
+                    let annotations = match &cli.annotations {
+                        Some(path) => Annotations::load(path)?,
+                        None => Annotations::default(),
+                    };
+                    let mut report = rewards_report(
+                        &global_state,
+                        report_block,
+                        &annotations,
+                        cli.filter_tag.as_deref(),
+                        (&sub_accounts, &checkpoints),
+                        cli.self_held_shares == SelfHeldShares::Separate,
+                    );
+                    if let Some(exact) = &exact {
+                        let integer = global_state.get_user_rewards(report_block);
+                        let exact = exact.user_rewards(report_block, &global_state);
+                        let sum = |rewards: &[(Address, U256)]| {
+                            rewards
+                                .iter()
+                                .fold(U256::zero(), |sum, (_, rewards)| sum + rewards)
+                        };
+                        eprintln!(
+                            "exact: the integer accounting rounded away {} wei over {} holders",
+                            sum(&exact) - sum(&integer),
+                            exact.len()
+                        );
+
+                        let exact: HashMap<_, _> = exact.into_iter().collect();
+                        for row in &mut report.rows {
+                            let address: Option<Address> = row.label.parse().ok();
+                            if let Some(rewards) = address.and_then(|a| exact.get(&a)) {
+                                report.total_rewards_given += *rewards - row.rewards;
+                                row.rewards = *rewards;
+                            }
+                        }
+                        report.rows.sort_by(|a, b| {
+                            b.rewards
+                                .cmp(&a.rewards)
+                                .then_with(|| a.label.cmp(&b.label))
+                        });
+                    }
+                    if let (Some(budget), Some(loyalty)) = (cli.loyalty_budget, &loyalty) {
+                        let bonuses = loyalty.bonuses(budget, report_block, &global_state);
+                        for row in &mut report.rows {
+                            let address: Option<Address> = row.label.parse().ok();
+                            row.bonus = address
+                                .and_then(|address| bonuses.get(&address).copied())
+                                .unwrap_or_default();
+                        }
+                        report.bonus_budget = Some(budget);
+                    }
+                    report.decimals = cli.decimals;
+                    report.rounding = cli.rounding;
+                    report.freshness = Some(freshness);
+                    if cli.quarantine.is_some() {
+                        report.quarantine = Some(QuarantineSummary::new(
+                            global_state.quarantined(),
+                            undecodable_logs,
+                        ));
+                    }
+                    if let Some(path) = &cli.hook {
+                        let policy = HookPolicy {
+                            allow_new_labels: cli.hook_allow_new_labels,
+                            max_increase_bps: cli.hook_max_increase_bps,
+                        };
+                        apply_hook(&mut report, path, policy)?;
+                    }
+                    let sanity = matches!(command, Some(Command::Sanity { .. }));
+                    let flags = if sanity || cli.strict {
+                        let previous =
+                            cli.previous.as_deref().map(RewardsFile::load).transpose()?;
+                        let bounds = SanityBounds {
+                            max_emission_pct: cli.max_emission_pct,
+                            max_change_pct: cli.max_change_pct,
+                        };
+                        sanity_check(
+                            &report,
+                            &pool_shares,
+                            &global_state,
+                            report_block,
+                            previous.as_ref(),
+                            bounds,
+                        )
+                    } else {
+                        vec![]
+                    };
+                    for flag in &flags {
+                        eprintln!("red flag: {}", flag);
+                    }
+
+                    if let Some(Command::Sanity { save }) = &command {
+                        if let Some(path) = save {
+                            let mut rewards = RewardsFile::new(&report, report_block);
+                            rewards.lineage =
+                                Some(embedded_lineage(path, run_id, &lineage_inputs)?);
+                            rewards.save(path)?;
+                            sign(path)?;
+                        }
+                        if !flags.is_empty() {
+                            return Err(Error::Flagged(format!(
+                                "{} red flags in {} rows",
+                                flags.len(),
+                                report.rows.len()
+                            ))
+                            .into());
+                        }
+                        println!("no red flags in {} rows", report.rows.len());
+                    } else {
+                        if !flags.is_empty() && !cli.acknowledge_flags {
+                            return Err(Error::Flagged(format!(
+                                "{} red flags, refusing to write payouts (--acknowledge-flags \
+                                 writes them anyway)",
+                                flags.len()
+                            ))
+                            .into());
+                        }
+                        if let Some(threshold_days) = cli.withhold_dormant {
+                            let dormant = find_dormant(
+                                &client,
+                                &ctx,
+                                &global_state,
+                                threshold_days,
+                                report_block,
+                            )
+                            .await?;
+                            withhold_dormant(&mut report, &dormant);
+                            eprintln!(
+                                "withheld the rewards of {} payees dormant for over {} days",
+                                dormant.len(),
+                                threshold_days
+                            );
+                        }
+                        if let (Some(min_payout), Some(rollover_out)) =
+                            (cli.min_payout, &cli.rollover_out)
+                        {
+                            let previous = match &cli.rollover_in {
+                                Some(path) => Rollover::load(path)?,
+                                None => Rollover::default(),
+                            };
+                            ensure!(
+                                previous.epoch < report_block.as_u64(),
+                                "the previous epoch ended at block {}, not before block {}",
+                                previous.epoch,
+                                report_block
+                            );
+                            let mut rollover = pay_epoch(
+                                &mut report,
+                                &previous,
+                                min_payout,
+                                report_block.as_u64(),
+                            );
+                            rollover.lineage =
+                                Some(embedded_lineage(rollover_out, run_id, &lineage_inputs)?);
+                            rollover.save(rollover_out)?;
+                            sign(rollover_out)?;
+                            eprintln!(
+                                "carried to the next epoch: {} addresses",
+                                rollover.carried.len()
+                            );
+                        }
+                        if let (Some(path), Some(chain)) = (&cli.address_map, cli.export_chain) {
+                            map_report(&mut report, &AddressMap::load(path)?, chain)?;
+                            for row in &report.withheld {
+                                eprintln!("withheld, no {} address: {}", chain, row.label);
+                            }
+                        }
+                        // JSON reports carry their lineage, unless audit mode appends to them
+                        let embeds_lineage = cli.format == ReportFormat::Json && !cli.audit_mode;
+                        if embeds_lineage {
+                            report.lineage =
+                                Some(embedded_lineage(&cli.output, run_id, &lineage_inputs)?);
+                        }
+                        let mut out = stdio::create(&cli.output).wrap_err_with(|| {
+                            format!("failed to write the report to {}", cli.output.display())
+                        })?;
+                        match cli.format {
+                            ReportFormat::Text => write_rewards(&mut out, &report, cli.audit_mode)?,
+                            ReportFormat::Html => {
+                                let run = RunMetadata {
+                                    vault,
+                                    chain_id: ctx.chain_id,
+                                    block_number: report_block,
+                                    settings: &settings,
+                                };
+                                write!(out, "{}", render_html(&report, &utilization, &run))?;
+                            }
+                            ReportFormat::Json => {
+                                writeln!(out, "{}", report.to_json(report_block))?
+                            }
+                        }
+                        if cli.audit_mode {
+                            writeln!(
+                                out,
+                                "total share-blocks: {}",
+                                global_state.total_share_blocks()
+                            )?;
+                        }
+                        out.flush()?;
+                        drop(out);
+                        sign(&cli.output)?;
+                        if !embeds_lineage {
+                            record_lineage(&cli.output, run_id, &lineage_inputs)?;
+                        }
+                        if let Some(path) = &cli.csv {
+                            let mut csv = vec![];
+                            write_rewards_csv(&global_state, report_block, &mut csv)?;
+                            stdio::replace(path, &csv).wrap_err_with(|| {
+                                format!("failed to write the rewards CSV to {}", path.display())
+                            })?;
+                            sign(path)?;
+                            record_lineage(path, run_id, &lineage_inputs)?;
+                        }
+                        if let Some(path) = &cli.merkle_out {
+                            let mut file =
+                                MerkleFile::build(&global_state.get_user_rewards(report_block))?;
+                            file.lineage = Some(embedded_lineage(path, run_id, &lineage_inputs)?);
+                            let json = serde_json::to_vec_pretty(&file)?;
+                            stdio::replace(path, &json).wrap_err_with(|| {
+                                format!("failed to write the merkle file to {}", path.display())
+                            })?;
+                            sign(path)?;
+                            eprintln!(
+                                "merkle root {:?} over {} claims",
+                                file.merkle_root,
+                                file.claims.len()
+                            );
+                        }
+                    }
+                }
+            }
+
+            if let Some(quality) = quality {
+                eprintln!("{}", quality);
+            }
+        }
+    }
+
+    print_performance(
+        &transport.stats(),
+        &transport.endpoint_stats(),
+        pipeline.as_ref(),
+    );
+
+    Ok(())
+}
+
+fn print_performance(
+    stats: &ProviderStats,
+    endpoints: &[ProviderStats],
+    pipeline: Option<&PipelineTimings>,
+) {
+    eprintln!(
+        "{}: {} requests, {} errors, ~{} bytes received, p50 {:?} p95 {:?} p99 {:?}",
+        stats.provider,
+        stats.requests,
+        stats.errors,
+        stats.bytes_received,
+        stats.p50,
+        stats.p95,
+        stats.p99
+    );
+
+    for sample in &stats.slowest {
+        let blocks: Vec<_> = sample.blocks.iter().map(|b| b.to_string()).collect();
+        eprintln!(
+            "  {:?} {} {}{}",
+            sample.latency,
+            sample.method,
+            blocks.join("..="),
+            if sample.failed { " (failed)" } else { "" }
+        );
+    }
+
+    if endpoints.len() > 1 {
+        eprintln!("endpoints:");
+        for endpoint in endpoints {
+            eprintln!(
+                "  {}: {} requests, {} errors",
+                endpoint.provider, endpoint.requests, endpoint.errors
+            );
+        }
+    }
+
+    let queried: Vec<_> = stats.queried_blocks.iter().map(|b| b.to_string()).collect();
+    eprintln!("queried blocks: {}", queried.join(", "));
+
+    if let Some(timings) = pipeline {
+        eprintln!("{}", timings);
+    }
+}
+
+/// The exit code for a failed run: the category of the library error behind it, or 1 for
+/// failures of the binary itself.
+pub fn exit_code(report: &eyre::Report) -> i32 {
+    report
+        .chain()
+        .find_map(|err| err.downcast_ref::<Error>())
+        .map_or(1, Error::exit_code)
+}
+
+/// Events of a complete cache file, or of a journal when the path ends in `.jsonl`, with the
+/// last block they cover: the cache's end, or the journal's last event, `from_block` without
+/// any. `-` reads either from standard input as `format` tells.
+fn load_events(path: &Path, format: InputFormat, from_block: u64) -> Result<(Vec<Event>, u64)> {
+    let journal_horizon = |events: &[Event]| {
+        events
+            .last()
+            .map_or(from_block, |evt| evt.block_number().as_u64())
+    };
+    let event_cache = if is_stdio(path) {
+        match parse_events(&stdio::read(path)?, format)? {
+            EventsInput::Cache(event_cache) => event_cache,
+            EventsInput::Journal(events) => {
+                let horizon = journal_horizon(&events);
+                return Ok((events, horizon));
+            }
+        }
+    } else if path.extension().is_some_and(|ext| ext == "jsonl") {
+        let events = read_journal(BufReader::new(File::open(path)?))?;
+        let horizon = journal_horizon(&events);
+        return Ok((events, horizon));
+    } else {
+        EventCache::load(path)?
+    };
+    ensure!(
+        event_cache.is_complete(),
+        "{} has unfetched chunks",
+        path.display()
+    );
+    Ok((event_cache.events, event_cache.to_block))
+}
+
+/// Payees dormant for more than `threshold_days` at `block_number`, fetching the timestamps of
+/// their last activity.
+async fn find_dormant(
+    client: &Client,
+    ctx: &EvaluationContext,
+    state: &GlobalState,
+    threshold_days: u64,
+    block_number: U64,
+) -> Result<Vec<DormancyRow>> {
+    let mut timestamps = TimestampCache::new();
+    timestamps.insert(ctx.block, ctx.timestamp);
+    let blocks = activity_blocks(state).into_iter().chain([block_number]);
+    timestamps.fetch(client, ctx, blocks).await?;
+    Ok(build_dormancy(
+        state,
+        &timestamps,
+        threshold_days,
+        block_number,
+    ))
+}
+
+/// Like `format_ether_rounded`, with a leading `-` for negative amounts.
+fn format_signed_ether(wei: I256, decimals: usize) -> String {
+    let sign = if wei.is_negative() { "-" } else { "" };
+    format!(
+        "{}{}",
+        sign,
+        format_ether_rounded(wei.unsigned_abs(), decimals)
+    )
+}
+
+/// Locks `path` for the rest of the scope, failing with `Error::Locked` when another process
+/// keeps holding it past the timeout. Standard input and output need no lock.
+fn lock(path: &Path, mode: LockMode, timeout: Duration) -> Result<Option<FileLock>> {
+    if is_stdio(path) {
+        return Ok(None);
+    }
+    let lock = FileLock::acquire(path, mode, timeout).map_err(Error::from)?;
+    if let Some(pid) = lock.stale_pid {
+        eprintln!(
+            "took over the lock on {} left by crashed pid {}",
+            path.display(),
+            pid
+        );
+    }
+    Ok(Some(lock))
+}
+
+/// The cache at `path` to sync up to `ctx.block`, or a new one to fill when there is none or it
+/// can't be resumed. `None` when it already goes past that block, leaving it for later runs.
+fn cache_to_sync(
+    path: &Path,
+    vault: Address,
+    ctx: &EvaluationContext,
+    from_block: u64,
+    chunk_size: u64,
+) -> Option<EventCache> {
+    let block = ctx.block.as_u64();
+    let fresh = EventCache::new(vault, ctx.chain_id, from_block, block, chunk_size);
+    if !path.exists() {
+        return Some(fresh);
+    }
+    match EventCache::load_resumable(path, vault, ctx.chain_id, from_block) {
+        Ok(event_cache) if event_cache.to_block <= block => Some(event_cache),
+        Ok(event_cache) => {
+            eprintln!(
+                "{} goes up to block {}, past block {}, fetching without it",
+                path.display(),
+                event_cache.to_block,
+                block
+            );
+            None
+        }
+        Err(err) => {
+            eprintln!("rebuilding {}: {}", path.display(), err);
+            Some(fresh)
+        }
+    }
+}
+
+/// Writes the address index next to a cache, unless it went to standard output.
+fn save_index(event_cache: &EventCache, path: &Path) -> Result<()> {
+    if !is_stdio(path) {
+        EventIndex::build(&event_cache.events).save(&index_path(path))?;
+    }
+    Ok(())
+}
+
+fn print_cache_summary(event_cache: &EventCache, path: &Path) {
+    let summary = format!(
+        "wrote {} events for blocks {}..={} to {} (hash {:?})",
+        event_cache.events.len(),
+        event_cache.from_block,
+        event_cache.to_block,
+        path.display(),
+        event_cache.hash()
+    );
+    // the cache itself is on standard output
+    if is_stdio(path) {
+        eprintln!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+    if event_cache.undecodable_logs > 0 {
+        eprintln!(
+            "warning: {} of {} logs could not be decoded, runs reading this cache need --best-effort",
+            event_cache.undecodable_logs, event_cache.logs
+        );
+    }
+    warn_unbacked(&event_cache.events, event_cache.vault);
+}
+
+/// Records that run `run_id` wrote the file at `path` from the files at `inputs`, in a sidecar.
+/// Standard input and output have no lineage.
+fn record_lineage(path: &Path, run_id: RunId, inputs: &[PathBuf]) -> Result<()> {
+    if is_stdio(path) {
+        return Ok(());
+    }
+    Lineage::record(path, run_id, read_inputs(path, inputs)?)?;
+    Ok(())
+}
+
+/// The lineage of the JSON document run `run_id` writes to `path` from the files at `inputs`,
+/// for its `lineage` field.
+fn embedded_lineage(path: &Path, run_id: RunId, inputs: &[PathBuf]) -> Result<EmbeddedLineage> {
+    Ok(EmbeddedLineage {
+        run_id,
+        inputs: read_inputs(path, inputs)?,
+    })
+}
+
+/// The files at `inputs` besides `path` and standard input, hashed.
+fn read_inputs(path: &Path, inputs: &[PathBuf]) -> Result<Vec<Input>> {
+    inputs
+        .iter()
+        .filter(|input| input.as_path() != path && !is_stdio(input))
+        .map(|input| Input::read(input))
+        .collect()
+}
+
+/// Every input path of the run, any of which may be `-`.
+fn stdin_paths(cli: &Cli) -> Vec<&Path> {
+    let mut paths: Vec<&Path> = [
+        &cli.cache,
+        &cli.annotations,
+        &cli.sub_accounts,
+        &cli.address_map,
+    ]
+    .into_iter()
+    .flatten()
+    .map(PathBuf::as_path)
+    .collect();
+    match &cli.command {
+        Some(Command::Bisect { left, right, .. }) => {
+            paths.push(left);
+            paths.extend(right.as_deref());
+        }
+        Some(Command::SimulateClaims { merkle_file, .. }) => paths.push(merkle_file),
+        Some(Command::VerifyProof { events, .. }) => paths.push(events),
+        Some(Command::Cache {
+            command: CacheCommand::Convert { input, .. },
+        }) => paths.push(input),
+        Some(Command::MergeCaches { inputs, .. }) => {
+            paths.extend(inputs.iter().map(PathBuf::as_path))
+        }
+        _ => {}
+    }
+    paths
+}
+
+fn warn_unbacked(events: &[Event], vault: Address) {
+    let unbacked = unbacked_events(events);
+    if let Some(first) = unbacked.first() {
+        eprintln!(
+            "warning: {} events move more shares than were minted before them (first at block {}), \
+             the logs may not come from the vault {:?}",
+            unbacked.len(),
+            first.block_number(),
+            vault
+        );
+    }
+}
+
+fn print_address_events(involving: Vec<&Event>, address: Address, decimals: usize) -> Result<()> {
+    // the address' own events are enough to know when it held shares, once transfers with
+    // others are seen from its side: their senders are unknown to this replay
+    let own_events = involving
+        .iter()
+        .map(|evt| match evt {
+            Event::Transfer(e) if e.from != address => Event::Deposit(Deposit {
+                address,
+                shares: e.shares,
+                block_number: e.block_number,
+                transaction_index: e.transaction_index,
+                log_index: e.log_index,
+            }),
+            Event::Transfer(e) if e.to != address => Event::Withdrawal(Withdraw {
+                address,
+                shares: e.shares,
+                block_number: e.block_number,
+                transaction_index: e.transaction_index,
+                log_index: e.log_index,
+            }),
+            evt => (*evt).clone(),
+        })
+        .collect();
+    let mut own_state = GlobalState::new();
+    own_state.process_events(own_events)?;
+    if let Some((first_block, last_block)) = own_state.user_active_span(address) {
+        if own_state.shares_of(address).is_zero() {
+            println!("held shares from block {} to {}", first_block, last_block);
+        } else {
+            println!("holding shares since block {}", first_block);
+        }
+    }
+
+    for evt in involving {
+        let shares = format_ether_rounded(evt.shares().0, decimals);
+
+        match evt {
+            Event::Deposit(e) => println!("{} deposit {}", e.block_number, shares),
+            Event::Withdrawal(e) => println!("{} withdraw {}", e.block_number, shares),
+            Event::Transfer(e) if e.from == address => {
+                println!("{} transfer {} to {:?}", e.block_number, shares, e.to)
+            }
+            Event::Transfer(e) => {
+                println!("{} transfer {} from {:?}", e.block_number, shares, e.from)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the replay's invariant warnings as it runs into them.
+struct WarningLog {
+    max_share_multiple: u64,
+}
+
+impl StateObserver for WarningLog {
+    fn on_invariant_warning(&mut self, warning: &InvariantWarning) {
+        match warning {
+            InvariantWarning::SuspiciousShares(evt) => eprintln!(
+                "warning: {} shares at block {} exceed {}x the staked total, possibly a decode error: {:?}",
+                evt.shares().0,
+                evt.block_number(),
+                self.max_share_multiple,
+                evt
+            ),
+            InvariantWarning::Quarantined(quarantined) => eprintln!(
+                "skipped: {} shares at block {} exceed what the sender holds: {:?}",
+                quarantined.event.shares().0,
+                quarantined.event.block_number(),
+                quarantined.event
+            ),
+        }
+    }
+}
+
+fn write_rewards(out: &mut dyn Write, report: &Report, audit_mode: bool) -> std::io::Result<()> {
+    write!(out, "{report}")?;
+    if audit_mode {
+        writeln!(out, "report hash: {:?}", report.hash())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Time and the chain head as the long-running components see them. Both are injected, so tests
+//! can move them by hand instead of sleeping or mining.
+
+use crate::error::{Error, Result};
+use crate::rpc::Client;
+use async_trait::async_trait;
+use ethers::{core::types::BlockNumber, providers::Middleware};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Tokio's clock, the default everywhere.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// The shared clock components hold when none is injected.
+pub(crate) fn tokio_clock() -> Arc<dyn Clock> {
+    Arc::new(TokioClock)
+}
+
+/// Which of the node's heads to follow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Head {
+    #[default]
+    Latest,
+    /// Unlikely to be reorged
+    Safe,
+    /// Can't be reorged without slashing
+    Finalized,
+}
+
+#[async_trait]
+pub trait HeadSource: Send + Sync {
+    async fn latest_block(&self) -> Result<u64>;
+
+    async fn safe_block(&self) -> Result<u64>;
+
+    async fn finalized_block(&self) -> Result<u64>;
+
+    async fn head(&self, head: Head) -> Result<u64> {
+        match head {
+            Head::Latest => self.latest_block().await,
+            Head::Safe => self.safe_block().await,
+            Head::Finalized => self.finalized_block().await,
+        }
+    }
+}
+
+async fn tagged_block(client: &Client, tag: BlockNumber, name: &str) -> Result<u64> {
+    client
+        .get_block(tag)
+        .await?
+        .and_then(|block| block.number)
+        .map(|number| number.as_u64())
+        .ok_or_else(|| Error::rpc(format!("the node has no {} block", name)))
+}
+
+#[async_trait]
+impl HeadSource for Client {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok(self.get_block_number().await?.as_u64())
+    }
+
+    async fn safe_block(&self) -> Result<u64> {
+        tagged_block(self, BlockNumber::Safe, "safe").await
+    }
+
+    async fn finalized_block(&self) -> Result<u64> {
+        tagged_block(self, BlockNumber::Finalized, "finalized").await
+    }
+}
+
+#[cfg(test)]
+pub mod manual {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::sync::watch;
+
+    /// A clock that only moves when advanced, waking the sleepers whose deadline it passes.
+    /// Clones share the time.
+    #[derive(Clone)]
+    pub struct ManualClock {
+        start: Instant,
+        elapsed: Arc<watch::Sender<Duration>>,
+    }
+
+    impl Default for ManualClock {
+        fn default() -> ManualClock {
+            ManualClock {
+                start: Instant::now(),
+                elapsed: Arc::new(watch::channel(Duration::ZERO).0),
+            }
+        }
+    }
+
+    impl ManualClock {
+        pub fn advance(&self, by: Duration) {
+            self.elapsed.send_modify(|elapsed| *elapsed += by);
+        }
+    }
+
+    #[async_trait]
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.borrow()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            let mut elapsed = self.elapsed.subscribe();
+            let deadline = *elapsed.borrow() + duration;
+            // the sender lives as long as `self`, so this only returns at the deadline
+            let _ = elapsed.wait_for(|elapsed| *elapsed >= deadline).await;
+        }
+    }
+
+    /// Heads that only move when set or advanced. Clones share them.
+    #[derive(Clone, Default)]
+    pub struct ManualHead {
+        latest: Arc<AtomicU64>,
+        safe: Arc<AtomicU64>,
+        finalized: Arc<AtomicU64>,
+    }
+
+    impl ManualHead {
+        pub fn set(&self, latest: u64, safe: u64, finalized: u64) {
+            self.latest.store(latest, Ordering::Relaxed);
+            self.safe.store(safe, Ordering::Relaxed);
+            self.finalized.store(finalized, Ordering::Relaxed);
+        }
+
+        /// Mines `blocks`, moving every head along.
+        pub fn advance(&self, blocks: u64) {
+            for head in [&self.latest, &self.safe, &self.finalized] {
+                head.fetch_add(blocks, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HeadSource for ManualHead {
+        async fn latest_block(&self) -> Result<u64> {
+            Ok(self.latest.load(Ordering::Relaxed))
+        }
+
+        async fn safe_block(&self) -> Result<u64> {
+            Ok(self.safe.load(Ordering::Relaxed))
+        }
+
+        async fn finalized_block(&self) -> Result<u64> {
+            Ok(self.finalized.load(Ordering::Relaxed))
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::state::{Deposit, Event, Withdraw};
+use crate::units::Shares;
+use ethers::core::types::{Address, U64};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The events of one address in one block, replayed as a single net delta.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoalescedDelta {
+    pub address: Address,
+    pub block_number: U64,
+    /// Events touching the address in the block, a transfer counting for both sides
+    pub events: usize,
+}
+
+/// Events rewritten by `coalesce`, with what each delta stands for.
+#[derive(Debug, Default)]
+pub(crate) struct Coalesced {
+    pub events: Vec<Event>,
+    pub deltas: Vec<CoalescedDelta>,
+    /// Original events left out in favor of the deltas
+    pub replaced: usize,
+}
+
+/// What the events of a block leave of a record's exit block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Unchanged,
+    /// Cleared by a deposit
+    Cleared,
+    /// Set by a withdrawal leaving no shares
+    Set,
+}
+
+/// The events of one address in the block so far.
+#[derive(Debug, Clone, Copy)]
+struct Tally {
+    address: Address,
+    /// Shares before the block, `None` without a record
+    before: Option<Shares>,
+    shares: Shares,
+    has_record: bool,
+    exit: Exit,
+    events: usize,
+}
+
+impl Tally {
+    /// Applies a move of `shares`, `false` when it sends shares the address doesn't hold.
+    fn apply(&mut self, incoming: bool, shares: Shares) -> bool {
+        self.events += 1;
+        if incoming {
+            self.shares += shares;
+            self.has_record = true;
+            self.exit = Exit::Cleared;
+            return true;
+        }
+        if !self.has_record || shares > self.shares {
+            return false;
+        }
+        self.shares -= shares;
+        if self.shares.is_zero() {
+            self.exit = Exit::Set;
+        }
+        true
+    }
+
+    /// The fewest deposits and withdrawals leaving the record as the block's events did.
+    fn deltas(&self, block_number: U64) -> Vec<Event> {
+        let deposit = |shares| {
+            Event::Deposit(Deposit {
+                address: self.address,
+                shares,
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let withdrawal = |shares| {
+            Event::Withdrawal(Withdraw {
+                address: self.address,
+                shares,
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let nothing = Shares::default();
+        let before = self.before.unwrap_or_default();
+
+        match self.exit {
+            Exit::Cleared if self.shares >= before => vec![deposit(self.shares - before)],
+            Exit::Cleared => vec![withdrawal(before - self.shares), deposit(nothing)],
+            Exit::Set if self.before.is_none() => vec![deposit(nothing), withdrawal(nothing)],
+            Exit::Set | Exit::Unchanged => vec![withdrawal(before - self.shares)],
+        }
+    }
+}
+
+/// Replaces the events of each block with one delta per address they touch, where that leaves
+/// fewer events. Under block-start accrual the emission of a block is distributed before any
+/// of its events and every event after an address's first finds its record up to date, so
+/// only the net change of each address is left to apply.
+///
+/// `record` gives the shares of addresses with a record before the first event. A block where
+/// an address sends shares it doesn't hold at that point is kept as it is, along with every
+/// block after it, so the replay fails or quarantines it like it would without coalescing.
+pub(crate) fn coalesce(evts: Vec<Event>, record: impl Fn(Address) -> Option<Shares>) -> Coalesced {
+    let mut coalesced = Coalesced::default();
+    // shares of every address seen so far, `None` while it has no record
+    let mut known: HashMap<Address, Option<Shares>> = HashMap::new();
+    let mut evts = evts.into_iter().peekable();
+
+    while let Some(first) = evts.next() {
+        let block_number = first.block_number();
+        let mut block = vec![first];
+        while let Some(evt) = evts.next_if(|evt| evt.block_number() == block_number) {
+            block.push(evt);
+        }
+
+        let mut tallies: Vec<Tally> = vec![];
+        let mut positions: HashMap<Address, usize> = HashMap::new();
+        let mut overdrawn = false;
+        for evt in &block {
+            let moves = match evt {
+                Event::Deposit(deposit) => vec![(deposit.address, true, deposit.shares)],
+                Event::Withdrawal(withdraw) => vec![(withdraw.address, false, withdraw.shares)],
+                Event::Transfer(transfer) => vec![
+                    (transfer.from, false, transfer.shares),
+                    (transfer.to, true, transfer.shares),
+                ],
+            };
+            for (address, incoming, shares) in moves {
+                let position = *positions.entry(address).or_insert_with(|| {
+                    let before = *known.entry(address).or_insert_with(|| record(address));
+                    tallies.push(Tally {
+                        address,
+                        before,
+                        shares: before.unwrap_or_default(),
+                        has_record: before.is_some(),
+                        exit: Exit::Unchanged,
+                        events: 0,
+                    });
+                    tallies.len() - 1
+                });
+                overdrawn |= !tallies[position].apply(incoming, shares);
+            }
+        }
+
+        if overdrawn {
+            coalesced.events.extend(block);
+            coalesced.events.extend(evts);
+            break;
+        }
+
+        for tally in &tallies {
+            known.insert(tally.address, Some(tally.shares));
+        }
+        let deltas: Vec<_> = tallies
+            .iter()
+            .flat_map(|tally| tally.deltas(block_number))
+            .collect();
+        if deltas.len() < block.len() {
+            coalesced.replaced += block.len();
+            coalesced
+                .deltas
+                .extend(tallies.iter().map(|tally| CoalescedDelta {
+                    address: tally.address,
+                    block_number,
+                    events: tally.events,
+                }));
+            coalesced.events.extend(deltas);
+        } else {
+            coalesced.events.extend(block);
+        }
+    }
+
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{GlobalState, Transfer, BLOCK_CONTRACT_DEPLOYED};
+    use ethers::core::types::U256;
+
+    const MARKET_MAKER: u64 = 0x3a3;
+
+    fn address(id: u64) -> Address {
+        Address::from_low_u64_be(id)
+    }
+
+    /// Blocks of deposits, withdrawals and transfers, most of them a market maker receiving
+    /// shares and passing them on in the same block. Every event is valid when replayed.
+    fn churn(blocks: u64) -> Vec<Event> {
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+        let mut balances: HashMap<Address, U256> = HashMap::new();
+        let mut evts = vec![];
+
+        for block in 1..=blocks {
+            let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + block * 3);
+            for _ in 0..next(12) {
+                let holder = address(1 + next(40));
+                let held = balances.get(&holder).copied().unwrap_or_default();
+                let shares = U256::from(1 + next(1_000)) * U256::exp10(15);
+                let evt = match next(4) {
+                    0 => Event::Deposit(Deposit {
+                        address: holder,
+                        shares: Shares(shares),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    1 if !held.is_zero() => Event::Withdrawal(Withdraw {
+                        address: holder,
+                        shares: Shares(held / 2 + 1),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    _ if !held.is_zero() => {
+                        // the market maker takes the shares and sends most of them on to another
+                        // holder, often one it hasn't seen
+                        let amount = if next(3) == 0 { held } else { held / 3 + 1 };
+                        let forwarded = if next(4) == 0 { amount / 2 } else { amount };
+                        let to = address(1 + next(60));
+                        let maker = address(MARKET_MAKER);
+                        for (from, to, shares) in [(holder, maker, amount), (maker, to, forwarded)]
+                        {
+                            evts.push(Event::Transfer(Transfer {
+                                from,
+                                to,
+                                shares: Shares(shares),
+                                block_number,
+                                transaction_index: 0,
+                                log_index: 0,
+                            }));
+                        }
+                        *balances.entry(holder).or_default() -= amount;
+                        *balances.entry(maker).or_default() += amount - forwarded;
+                        *balances.entry(to).or_default() += forwarded;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                match &evt {
+                    Event::Deposit(deposit) => {
+                        *balances.entry(holder).or_default() += deposit.shares.0
+                    }
+                    Event::Withdrawal(withdraw) => {
+                        *balances.entry(holder).or_default() -= withdraw.shares.0
+                    }
+                    Event::Transfer(_) => unreachable!(),
+                }
+                evts.push(evt);
+            }
+        }
+
+        evts
+    }
+
+    fn replay(evts: &[Event], coalescing: bool) -> GlobalState {
+        let mut state = GlobalState::new();
+        state.set_coalescing(coalescing);
+        state.process_events(evts.to_vec()).unwrap();
+        state
+    }
+
+    #[test]
+    fn coalesced_replays_match_raw_ones() {
+        for blocks in [1, 10, 400] {
+            let evts = churn(blocks);
+            let raw = replay(&evts, false);
+            // resuming from a snapshot coalesces against the records it restored
+            let (before, after) = evts.split_at(evts.len() / 2);
+            let mut resumed = GlobalState::resume(replay(before, true).snapshot());
+            resumed.set_coalescing(true);
+            resumed.process_events(after.to_vec()).unwrap();
+
+            for coalesced in [replay(&evts, true), resumed] {
+                assert_eq!(coalesced.state_hash(), raw.state_hash());
+                assert_eq!(coalesced.total_share_blocks(), raw.total_share_blocks());
+                let later = U64::from(BLOCK_CONTRACT_DEPLOYED + blocks * 3 + 50);
+                assert_eq!(
+                    coalesced.get_user_rewards(later),
+                    raw.get_user_rewards(later)
+                );
+                for holder in raw.users() {
+                    assert_eq!(
+                        coalesced.user_active_span(*holder),
+                        raw.user_active_span(*holder)
+                    );
+                    assert_eq!(
+                        coalesced.last_activity_block(*holder),
+                        raw.last_activity_block(*holder)
+                    );
+                }
+            }
+        }
+
+        let coalesced = replay(&churn(400), true);
+        assert!(coalesced.coalesced_events() > 0);
+        assert!(coalesced.record_updates() < replay(&churn(400), false).record_updates());
+        let maker = coalesced
+            .coalesced()
+            .iter()
+            .filter(|delta| delta.address == address(MARKET_MAKER))
+            .map(|delta| delta.events)
+            .sum::<usize>();
+        assert!(maker > 0 && maker % 2 == 0);
+    }
+
+    #[test]
+    fn exits_inside_a_block_are_kept() {
+        let (first, second) = (
+            U64::from(BLOCK_CONTRACT_DEPLOYED + 1),
+            U64::from(BLOCK_CONTRACT_DEPLOYED + 2),
+        );
+        let one = U256::exp10(18);
+        let deposit = |id, shares, block_number| {
+            Event::Deposit(Deposit {
+                address: address(id),
+                shares: Shares(shares),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let transfer = |from, to, shares| {
+            Event::Transfer(Transfer {
+                from: address(from),
+                to: address(to),
+                shares: Shares(shares),
+                block_number: second,
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let evts = vec![
+            deposit(1, one * 3, first),
+            // 1 empties and refills, 2 is new and passes everything on
+            transfer(1, 2, one * 3),
+            transfer(2, 3, one * 3),
+            deposit(1, one, second),
+            deposit(4, one, second),
+            deposit(4, one, second),
+            deposit(4, one, second),
+            deposit(4, one, second),
+            deposit(4, one, second + 1),
+        ];
+
+        let raw = replay(&evts, false);
+        let coalesced = replay(&evts, true);
+        assert_eq!(coalesced.coalesced_events(), 7);
+        assert_eq!(coalesced.state_hash(), raw.state_hash());
+        assert_eq!(
+            coalesced.user_active_span(address(2)),
+            Some((second, second))
+        );
+        for id in 1..=4 {
+            assert_eq!(
+                coalesced.user_active_span(address(id)),
+                raw.user_active_span(address(id))
+            );
+        }
+    }
+
+    #[test]
+    fn blocks_sending_unheld_shares_are_left_as_they_are() {
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 1);
+        let deposit = |id, block_number| {
+            Event::Deposit(Deposit {
+                address: address(id),
+                shares: Shares(U256::exp10(18)),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let send = Event::Transfer(Transfer {
+            from: address(1),
+            to: address(2),
+            shares: Shares(U256::exp10(18)),
+            block_number,
+            transaction_index: 0,
+            log_index: 0,
+        });
+        // the transfer comes before the deposit covering it
+        let evts = vec![
+            send,
+            deposit(1, block_number),
+            deposit(2, block_number),
+            deposit(2, block_number + 1),
+            deposit(2, block_number + 1),
+        ];
+
+        let coalesced = coalesce(evts.clone(), |_| None);
+        assert_eq!(coalesced.events, evts);
+        assert_eq!(coalesced.replaced, 0);
+        assert!(coalesced.deltas.is_empty());
+    }
+}
@@ -0,0 +1,460 @@
+use crate::error::{Error, Result};
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use crate::state::{Event, GlobalState};
+use crate::timestamps::TimestampCache;
+use chrono::{Datelike, Duration, NaiveDateTime};
+use ethers::core::types::{Address, U256, U64};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Bucket {
+    Weekly,
+    Monthly,
+}
+
+impl Bucket {
+    /// Weekly cohorts are labelled by the Monday starting the week, monthly ones by `YYYY-MM`.
+    pub fn label(&self, timestamp: u64) -> String {
+        let date = NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+            .expect("timestamp should be in range")
+            .date();
+
+        match self {
+            Bucket::Weekly => {
+                let monday = date - Duration::days(date.weekday().num_days_from_monday() as i64);
+                monday.format("%Y-%m-%d").to_string()
+            }
+            Bucket::Monthly => date.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CohortRow {
+    pub cohort: String,
+    pub holders: usize,
+    pub holders_remaining: usize,
+    pub retention_pct: f64,
+    #[serde(serialize_with = "serialize_u256")]
+    pub shares_remaining: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub peak_shares: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub rewards_earned: U256,
+}
+
+/// Groups every address by the period of its first share receipt and reports how much of
+/// each cohort is still staked at `block_number`.
+///
+/// `events` must be the same sorted stream that was fed to `state`; it is replayed to find
+/// the peak shares each cohort held at any point in time. Events the state skipped are left
+/// out, and holders may enter the stream with shares from before it, as in a windowed run.
+/// `timestamps` must hold the block of every holder's first share receipt.
+pub fn build_cohorts(
+    events: &[Event],
+    state: &GlobalState,
+    timestamps: &TimestampCache,
+    bucket: Bucket,
+    block_number: U64,
+) -> Result<Vec<CohortRow>> {
+    let cohort_of: HashMap<Address, String> = state
+        .users()
+        .map(|address| {
+            let first_block = state
+                .first_deposit_block(*address)
+                .expect("user should exist");
+            let timestamp = timestamps.get(first_block).ok_or_else(|| {
+                Error::config(format!(
+                    "the timestamp of block {} isn't cached",
+                    first_block
+                ))
+            })?;
+            Ok((*address, bucket.label(timestamp)))
+        })
+        .collect::<Result<_>>()?;
+
+    let skipped = skipped_indices(events, state.skipped_events());
+    let changes: Vec<(Address, U256, bool)> = events
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !skipped.contains(index))
+        .flat_map(|(_, evt)| match evt {
+            Event::Deposit(e) => vec![(e.address, e.shares.0, true)],
+            Event::Withdrawal(e) => vec![(e.address, e.shares.0, false)],
+            Event::Transfer(e) => vec![(e.from, e.shares.0, false), (e.to, e.shares.0, true)],
+        })
+        .filter(|(address, _, _)| cohort_of.contains_key(address))
+        .collect();
+
+    // unwinds the changes from the final balances to what each holder had before the stream
+    let mut opening: HashMap<Address, U256> = cohort_of
+        .keys()
+        .map(|address| (*address, state.shares_of(*address)))
+        .collect();
+    for (address, shares, incoming) in changes.iter().rev() {
+        let balance = opening.get_mut(address).expect("user should exist");
+        *balance = if *incoming {
+            balance.saturating_sub(*shares)
+        } else {
+            balance.saturating_add(*shares)
+        };
+    }
+
+    let mut current: HashMap<&str, U256> = HashMap::new();
+    for (address, balance) in &opening {
+        *current.entry(cohort_of[address].as_str()).or_default() += *balance;
+    }
+    let mut peaks = current.clone();
+
+    for (address, shares, incoming) in changes {
+        let cohort = cohort_of[&address].as_str();
+        let total = current.entry(cohort).or_default();
+
+        *total = if incoming {
+            total.saturating_add(shares)
+        } else {
+            total.saturating_sub(shares)
+        };
+
+        let peak = peaks.entry(cohort).or_default();
+        *peak = (*peak).max(*total);
+    }
+
+    let mut rows: BTreeMap<&str, CohortRow> = BTreeMap::new();
+
+    for (address, cohort) in cohort_of.iter() {
+        let row = rows.entry(cohort.as_str()).or_insert_with(|| CohortRow {
+            cohort: cohort.clone(),
+            holders: 0,
+            holders_remaining: 0,
+            retention_pct: 0.0,
+            shares_remaining: U256::from(0),
+            peak_shares: peaks.get(cohort.as_str()).copied().unwrap_or_default(),
+            rewards_earned: U256::from(0),
+        });
+
+        let shares = state.shares_of(*address);
+
+        row.holders += 1;
+        if !shares.is_zero() {
+            row.holders_remaining += 1;
+        }
+        row.shares_remaining += shares;
+        row.rewards_earned += state.preview_user_rewards(*address, block_number);
+    }
+
+    Ok(rows
+        .into_values()
+        .map(|mut row| {
+            row.retention_pct = row.holders_remaining as f64 * 100.0 / row.holders as f64;
+            row
+        })
+        .collect())
+}
+
+/// Indices in `events` of the events the state skipped. Those come in stream order, so each is
+/// the first equal event after the one before it. Cached events share positions, so only the
+/// events themselves tell them apart, and equal ones are interchangeable.
+fn skipped_indices(events: &[Event], skipped: &[Event]) -> HashSet<usize> {
+    let mut skipped = skipped.iter().peekable();
+    let mut indices = HashSet::new();
+    for (index, evt) in events.iter().enumerate() {
+        if skipped.peek() == Some(&evt) {
+            skipped.next();
+            indices.insert(index);
+        }
+    }
+    indices
+}
+
+pub fn render_cohorts(rows: &[CohortRow], format: OutputFormat, freshness: &Freshness) -> String {
+    let columns = [
+        "cohort",
+        "holders",
+        "holders_remaining",
+        "retention_pct",
+        "shares_remaining",
+        "peak_shares",
+        "rewards_earned",
+    ];
+
+    let cells = |row: &CohortRow| {
+        [
+            row.cohort.clone(),
+            row.holders.to_string(),
+            row.holders_remaining.to_string(),
+            format!("{:.2}", row.retention_pct),
+            row.shares_remaining.to_string(),
+            row.peak_shares.to_string(),
+            row.rewards_earned.to_string(),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(rows, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for row in rows {
+                out += &(cells(row).join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for row in rows {
+                out += &format!("| {} |\n", cells(row).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::SCHEMA_VERSION;
+    use crate::state::{Deposit, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+    const FRANK: &str = "0x000000000000000000000000000000000000F4a2";
+    const CAROL: &str = "0x00000000000000000000000000000000000CA201";
+    const DAVE: &str = "0x000000000000000000000000000000000000DA7e";
+    const ERIN: &str = "0x00000000000000000000000000000000000E2114";
+
+    // Monday, 2023-06-26 00:00:00 UTC
+    const WEEK_ONE: u64 = 1687737600;
+    const DAY: u64 = 86400;
+
+    fn block(offset: u64) -> U64 {
+        U64::from(BLOCK_CONTRACT_DEPLOYED + offset)
+    }
+
+    fn deposit(address: &str, shares: &str, offset: u64) -> Event {
+        Event::Deposit(Deposit {
+            address: address.parse().unwrap(),
+            shares: Shares(parse_ether(shares).unwrap()),
+            block_number: block(offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn withdraw(address: &str, shares: &str, offset: u64) -> Event {
+        Event::Withdrawal(Withdraw {
+            address: address.parse().unwrap(),
+            shares: Shares(parse_ether(shares).unwrap()),
+            block_number: block(offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn transfer(from: &str, to: &str, shares: &str, offset: u64) -> Event {
+        Event::Transfer(Transfer {
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+            shares: Shares(parse_ether(shares).unwrap()),
+            block_number: block(offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    #[test]
+    fn reports_retention_per_cohort() {
+        let events = vec![
+            deposit(BOB, "2", 0),
+            deposit(ALICE, "1", 10),
+            deposit(FRANK, "1", 20),
+            deposit(CAROL, "4", 100),
+            deposit(DAVE, "1", 110),
+            withdraw(ALICE, "1", 150),
+            transfer(CAROL, ERIN, "1", 200),
+            withdraw(CAROL, "3", 250),
+        ];
+
+        let mut timestamps = TimestampCache::new();
+        timestamps.insert(block(0), WEEK_ONE);
+        timestamps.insert(block(10), WEEK_ONE + 3600);
+        timestamps.insert(block(20), WEEK_ONE + DAY);
+        timestamps.insert(block(100), WEEK_ONE + 7 * DAY + 10);
+        timestamps.insert(block(110), WEEK_ONE + 8 * DAY);
+        timestamps.insert(block(200), WEEK_ONE + 14 * DAY + 5);
+
+        let mut global_state = GlobalState::new();
+        global_state.process_events(events.clone()).unwrap();
+
+        let head = block(300);
+        let rows =
+            build_cohorts(&events, &global_state, &timestamps, Bucket::Weekly, head).unwrap();
+
+        let cohorts: Vec<_> = rows.iter().map(|row| row.cohort.as_str()).collect();
+        assert_eq!(cohorts, vec!["2023-06-26", "2023-07-03", "2023-07-10"]);
+
+        let retention: Vec<_> = rows
+            .iter()
+            .map(|row| (row.holders, row.holders_remaining))
+            .collect();
+        assert_eq!(retention, vec![(3, 2), (2, 1), (1, 1)]);
+        assert_eq!(format!("{:.2}", rows[0].retention_pct), "66.67");
+        assert_eq!(rows[1].retention_pct, 50.0);
+        assert_eq!(rows[2].retention_pct, 100.0);
+
+        assert_eq!(rows[0].shares_remaining, parse_ether("3").unwrap());
+        assert_eq!(rows[0].peak_shares, parse_ether("4").unwrap());
+        assert_eq!(rows[1].shares_remaining, parse_ether("1").unwrap());
+        assert_eq!(rows[1].peak_shares, parse_ether("5").unwrap());
+        assert_eq!(rows[2].shares_remaining, parse_ether("1").unwrap());
+        assert_eq!(rows[2].peak_shares, parse_ether("1").unwrap());
+
+        let rewards: U256 = rows
+            .iter()
+            .fold(U256::from(0), |acc, row| acc + row.rewards_earned);
+        assert_eq!(rewards, global_state.get_all_rewards(head));
+    }
+
+    #[test]
+    fn leaves_out_events_the_state_skipped() {
+        let events = vec![
+            deposit(BOB, "1", 0),
+            withdraw(BOB, "2", 10),
+            withdraw(CAROL, "1", 20),
+            transfer(DAVE, BOB, "1", 30),
+        ];
+
+        let mut timestamps = TimestampCache::new();
+        timestamps.insert(block(0), WEEK_ONE);
+
+        let mut global_state = GlobalState::new();
+        global_state.set_best_effort(true);
+        global_state.process_events(events.clone()).unwrap();
+        assert_eq!(global_state.skipped_events().len(), 3);
+
+        let rows = build_cohorts(
+            &events,
+            &global_state,
+            &timestamps,
+            Bucket::Weekly,
+            block(50),
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].holders, rows[0].holders_remaining), (1, 1));
+        assert_eq!(rows[0].shares_remaining, parse_ether("1").unwrap());
+        assert_eq!(rows[0].peak_shares, parse_ether("1").unwrap());
+    }
+
+    #[test]
+    fn skips_only_the_skipped_one_of_events_sharing_a_position() {
+        // cached events have no log index, so the withdrawals at block 20 share a position
+        let events = vec![
+            deposit(BOB, "1", 10),
+            withdraw(BOB, "1", 20),
+            withdraw(CAROL, "1", 20),
+            deposit(BOB, "1", 30),
+        ];
+        assert_eq!(events[1].position(), events[2].position());
+
+        let mut timestamps = TimestampCache::new();
+        timestamps.insert(block(10), WEEK_ONE);
+
+        let mut global_state = GlobalState::new();
+        global_state.set_best_effort(true);
+        global_state.process_events(events.clone()).unwrap();
+        assert_eq!(global_state.skipped_events(), &events[2..3]);
+
+        let rows = build_cohorts(
+            &events,
+            &global_state,
+            &timestamps,
+            Bucket::Weekly,
+            block(50),
+        )
+        .unwrap();
+        assert_eq!(rows[0].shares_remaining, parse_ether("1").unwrap());
+        assert_eq!(rows[0].peak_shares, parse_ether("1").unwrap());
+
+        let err = build_cohorts(
+            &events,
+            &global_state,
+            &TimestampCache::new(),
+            Bucket::Weekly,
+            block(50),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::Config { .. }), "{}", err);
+    }
+
+    #[test]
+    fn starts_windowed_streams_from_the_holdings_before_them() {
+        let events = vec![
+            deposit(BOB, "2", 0),
+            deposit(ALICE, "1", 10),
+            withdraw(BOB, "1", 150),
+            withdraw(ALICE, "1", 160),
+        ];
+
+        let mut timestamps = TimestampCache::new();
+        timestamps.insert(block(0), WEEK_ONE);
+        timestamps.insert(block(10), WEEK_ONE + 3600);
+
+        let mut global_state = GlobalState::new();
+        global_state.process_events(events.clone()).unwrap();
+
+        // a window from block 100 opens with both holders staked, then shrinks
+        let window = &events[2..];
+        let rows = build_cohorts(
+            window,
+            &global_state,
+            &timestamps,
+            Bucket::Weekly,
+            block(200),
+        )
+        .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].holders, rows[0].holders_remaining), (2, 1));
+        assert_eq!(rows[0].shares_remaining, parse_ether("1").unwrap());
+        assert_eq!(rows[0].peak_shares, parse_ether("3").unwrap());
+    }
+
+    #[test]
+    fn exports_carry_the_schema_version() {
+        let rows = vec![CohortRow {
+            cohort: "2023-06".to_string(),
+            holders: 2,
+            holders_remaining: 1,
+            retention_pct: 50.0,
+            shares_remaining: parse_ether("1").unwrap(),
+            peak_shares: parse_ether("2").unwrap(),
+            rewards_earned: parse_ether("3").unwrap(),
+        }];
+        let freshness = Freshness::offline(17_600_000);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_cohorts(&rows, OutputFormat::Json, &freshness)).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["rows"][0]["shares_remaining"], "1000000000000000000");
+
+        let csv = render_cohorts(&rows, OutputFormat::Csv, &freshness);
+        let header = format!("# schema_version: {}", SCHEMA_VERSION);
+        assert_eq!(csv.lines().next(), Some(header.as_str()));
+        assert!(csv.lines().nth(2).unwrap().starts_with("cohort,"));
+    }
+
+    #[test]
+    fn buckets_by_calendar_month() {
+        assert_eq!(Bucket::Monthly.label(WEEK_ONE), "2023-06");
+        assert_eq!(Bucket::Monthly.label(WEEK_ONE + 7 * DAY), "2023-07");
+        assert_eq!(Bucket::Weekly.label(WEEK_ONE + 6 * DAY), "2023-06-26");
+    }
+}
@@ -0,0 +1,48 @@
+use crate::error::{ensure, Error, Result};
+use crate::rpc::Client;
+use ethers::{core::types::U64, providers::Middleware};
+
+/// The chain state a run is evaluated against, resolved once at startup. Every on-chain read
+/// is pinned to `block`, or to an explicit earlier block for historical sampling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EvaluationContext {
+    pub block: U64,
+    pub timestamp: u64,
+    pub chain_id: u64,
+}
+
+impl EvaluationContext {
+    /// Pins the run to `block`, or to the current head when none is given.
+    pub async fn resolve(client: &Client, block: Option<u64>) -> Result<EvaluationContext> {
+        let block = match block {
+            Some(block) => U64::from(block),
+            None => client.get_block_number().await?,
+        };
+        let timestamp = client
+            .get_block(block)
+            .await?
+            .ok_or_else(|| Error::rpc(format!("block {} not found", block)))?
+            .timestamp
+            .as_u64();
+        let chain_id = client.get_chainid().await?.as_u64();
+
+        Ok(EvaluationContext {
+            block,
+            timestamp,
+            chain_id,
+        })
+    }
+
+    /// Checks that a historical read at `block` doesn't look past the evaluation block.
+    pub fn historical(&self, block: U64) -> Result<U64> {
+        ensure!(
+            block <= self.block,
+            Error::config,
+            "block {} is past the evaluation block {}",
+            block,
+            self.block
+        );
+        Ok(block)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use crate::state::{Deposit, Event, GlobalState, StateError, Withdraw};
+use ethers::core::types::{Address, U256, U64};
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+#[derive(Debug, Serialize)]
+pub struct DilutionRow {
+    /// Place among the addresses by rewards, from 1
+    pub rank: usize,
+    pub address: Address,
+    #[serde(serialize_with = "serialize_u256")]
+    pub rewards: U256,
+    /// What every other address would have earned on top of its rewards without this one
+    #[serde(serialize_with = "serialize_u256")]
+    pub dilution: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub median_uplift: U256,
+}
+
+/// The events with `whale` taken out and everyone else's holdings left as they were: its
+/// deposits and withdrawals are dropped, shares it sent are minted to the receiver instead and
+/// shares it received are burned from the sender.
+fn without(events: &[Event], whale: Address) -> Vec<Event> {
+    events
+        .iter()
+        .filter_map(|evt| match evt {
+            Event::Deposit(e) if e.address == whale => None,
+            Event::Withdrawal(e) if e.address == whale => None,
+            Event::Transfer(e) if e.from == whale && e.to == whale => None,
+            Event::Transfer(e) if e.from == whale => Some(Event::Deposit(Deposit {
+                address: e.to,
+                shares: e.shares,
+                block_number: e.block_number,
+                transaction_index: e.transaction_index,
+                log_index: e.log_index,
+            })),
+            Event::Transfer(e) if e.to == whale => Some(Event::Withdrawal(Withdraw {
+                address: e.from,
+                shares: e.shares,
+                block_number: e.block_number,
+                transaction_index: e.transaction_index,
+                log_index: e.log_index,
+            })),
+            _ => Some(evt.clone()),
+        })
+        .collect()
+}
+
+/// The median, the lower-rounded mean of the middle two for an even count.
+fn median(mut amounts: Vec<U256>) -> U256 {
+    amounts.sort();
+    let mid = amounts.len() / 2;
+    match amounts.len() {
+        0 => U256::zero(),
+        len if len % 2 == 1 => amounts[mid],
+        _ => (amounts[mid - 1] + amounts[mid]) / 2,
+    }
+}
+
+/// Replays `events` once without each of the `top` addresses with the most rewards at
+/// `block_number`, and reports how much more everyone else would have earned.
+///
+/// `state` must have processed `events`, and `fresh` is an unprocessed state with the same
+/// settings that every counterfactual replay starts from. The replays share `events` and run
+/// in parallel. Fails if a replay without a whale can't apply its events.
+pub fn build_dilution(
+    events: &[Event],
+    state: &GlobalState,
+    fresh: &GlobalState,
+    top: usize,
+    block_number: U64,
+) -> Result<Vec<DilutionRow>, StateError> {
+    let mut ranked: Vec<(Address, U256)> = state
+        .users()
+        .map(|address| (*address, state.preview_user_rewards(*address, block_number)))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(top);
+
+    let next = AtomicUsize::new(0);
+    let workers = thread::available_parallelism().map_or(1, usize::from);
+
+    let mut rows: Vec<DilutionRow> = thread::scope(|scope| -> Result<_, StateError> {
+        let replays: Vec<_> = (0..workers.min(ranked.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut rows = vec![];
+                    // each worker takes the next whale left until none is
+                    loop {
+                        let rank = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&(whale, rewards)) = ranked.get(rank) else {
+                            return Ok(rows);
+                        };
+                        let mut counterfactual = fresh.clone();
+                        counterfactual.process_events(without(events, whale))?;
+
+                        // taking shares out never lowers anyone else's rewards
+                        let uplifts: Vec<U256> = state
+                            .users()
+                            .filter(|address| **address != whale)
+                            .map(|address| {
+                                counterfactual
+                                    .preview_user_rewards(*address, block_number)
+                                    .saturating_sub(
+                                        state.preview_user_rewards(*address, block_number),
+                                    )
+                            })
+                            .collect();
+
+                        rows.push(DilutionRow {
+                            rank: rank + 1,
+                            address: whale,
+                            rewards,
+                            dilution: uplifts
+                                .iter()
+                                .fold(U256::zero(), |sum, uplift| sum + uplift),
+                            median_uplift: median(uplifts),
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        let mut rows = vec![];
+        for replay in replays {
+            rows.extend(replay.join().unwrap()?);
+        }
+        Ok(rows)
+    })?;
+    rows.sort_by_key(|row| row.rank);
+    Ok(rows)
+}
+
+pub fn render_dilution(
+    rows: &[DilutionRow],
+    format: OutputFormat,
+    freshness: &Freshness,
+) -> String {
+    let columns = ["rank", "address", "rewards", "dilution", "median_uplift"];
+
+    let cells = |row: &DilutionRow| {
+        [
+            row.rank.to_string(),
+            format!("{:?}", row.address),
+            row.rewards.to_string(),
+            row.dilution.to_string(),
+            row.median_uplift.to_string(),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(rows, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for row in rows {
+                out += &(cells(row).join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for row in rows {
+                out += &format!("| {} |\n", cells(row).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Transfer, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    #[test]
+    fn replays_without_each_whale() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let carol = Address::from_low_u64_be(0xca401);
+        let block = |offset| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let ether = |amount| parse_ether(amount).unwrap();
+        let deposit = |address, shares, offset| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(ether(shares)),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let events = vec![
+            deposit(bob, "3", 0),
+            deposit(alice, "1", 0),
+            deposit(carol, "1", 100),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: carol,
+                shares: Shares(ether("1")),
+                block_number: block(150),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+
+        let mut state = GlobalState::new();
+        state.process_events(events.clone()).unwrap();
+        let rows = build_dilution(&events, &state, &GlobalState::new(), 2, block(210)).unwrap();
+
+        // with everyone: bob 75 + 30 + 24, alice 25 + 10 + 12, carol 10 + 24
+        // without bob, whose transfer becomes carol's deposit: alice 100 + 25 + 20, carol 25 + 40
+        // without alice: bob 100 + 37.5 + 30, carol 12.5 + 30, a wei short of each by rounding
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                (
+                    row.rank,
+                    row.address,
+                    row.rewards,
+                    row.dilution,
+                    row.median_uplift,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, bob, ether("129"), ether("129"), ether("64.5")),
+                (2, alice, ether("47"), ether("47") - 1, ether("23.5") - 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_the_whales_transfers_for_the_counterparty() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let transfer = |from, to| {
+            Event::Transfer(Transfer {
+                from,
+                to,
+                shares: Shares(U256::one()),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+
+        let events = without(&[transfer(bob, alice), transfer(alice, bob)], bob);
+        assert!(matches!(&events[0], Event::Deposit(e) if e.address == alice));
+        assert!(matches!(&events[1], Event::Withdrawal(e) if e.address == alice));
+        assert!(without(&[transfer(bob, bob)], bob).is_empty());
+        assert_eq!(median(vec![]), U256::zero());
+        assert_eq!(
+            median(vec![U256::from(3), U256::one(), U256::from(2)]),
+            2.into()
+        );
+    }
+}
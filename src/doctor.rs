@@ -0,0 +1,514 @@
+use crate::cache::EventCache;
+use crate::calls::call_uint;
+use crate::context::EvaluationContext;
+use crate::fetch::{LogSource, CHUNK_SIZE};
+use crate::flavor::VaultFlavor;
+use crate::index::{index_path, EventIndex};
+use crate::quarantine::{unknown_sender_burst, QuarantineReason};
+use crate::rpc::Client;
+use crate::state::{Event, GlobalState};
+use ethers::{
+    core::types::{Address, BlockId, Bytes, Filter, U256},
+    providers::Middleware,
+};
+use eyre::Result;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Outcome of one diagnostic, with what to do about it when it didn't pass.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remedy: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: String) -> Check {
+        Check {
+            name,
+            status: Status::Ok,
+            detail,
+            remedy: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: String, remedy: &str) -> Check {
+        Check {
+            name,
+            status: Status::Warn,
+            detail,
+            remedy: Some(remedy.to_string()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, remedy: &str) -> Check {
+        Check {
+            name,
+            status: Status::Fail,
+            detail,
+            remedy: Some(remedy.to_string()),
+        }
+    }
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+
+    /// ANSI color of the status on a terminal
+    fn color(self) -> &'static str {
+        match self {
+            Status::Ok => "\x1b[32m",
+            Status::Warn => "\x1b[33m",
+            Status::Fail => "\x1b[31m",
+        }
+    }
+}
+
+/// One line per check with the remedy under it, then a count per status. `color` highlights
+/// the statuses for a terminal.
+pub fn render(checks: &[Check], color: bool) -> String {
+    let paint = |status: Status, text: &str| {
+        if color {
+            format!("{}{}\x1b[0m", status.color(), text)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut out = String::new();
+    for check in checks {
+        let label = format!("[{:>4}]", check.status.label());
+        out += &format!(
+            "{} {}: {}\n",
+            paint(check.status, &label),
+            check.name,
+            check.detail
+        );
+        if let Some(remedy) = &check.remedy {
+            out += &format!("       -> {}\n", remedy);
+        }
+    }
+
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    out += &format!(
+        "{}, {}, {}\n",
+        paint(Status::Ok, &format!("{} ok", count(Status::Ok))),
+        paint(Status::Warn, &format!("{} warnings", count(Status::Warn))),
+        paint(Status::Fail, &format!("{} failed", count(Status::Fail)))
+    );
+    out
+}
+
+pub fn check_chain_id(expected: u64, actual: u64) -> Check {
+    if expected == actual {
+        Check::ok("chain", format!("provider is on chain {}", actual))
+    } else {
+        Check::fail(
+            "chain",
+            format!(
+                "provider is on chain {}, the vault lives on chain {}",
+                actual, expected
+            ),
+            "point the RPC URL at a node of the vault's chain",
+        )
+    }
+}
+
+pub fn check_vault_code(vault: Address, code: &Bytes) -> Check {
+    if code.is_empty() {
+        Check::fail(
+            "vault code",
+            format!("no contract code at {:?}", vault),
+            "check the vault address, or that the provider is synced past its deployment",
+        )
+    } else {
+        Check::ok("vault code", format!("{} bytes of code", code.len()))
+    }
+}
+
+/// Looks for vault logs in the chunk before `from_block`, which would mean accounting starts
+/// after the vault's first events.
+pub async fn check_first_log<S: LogSource>(
+    source: &S,
+    vault: Address,
+    from_block: u64,
+) -> Result<Check> {
+    let start = from_block.saturating_sub(CHUNK_SIZE);
+    let filter = Filter::new()
+        .address(vault)
+        .from_block(start)
+        .to_block(from_block.saturating_sub(1));
+    let logs = source.fetch_logs(&filter).await?;
+
+    let first = logs.iter().filter_map(|log| log.block_number).min();
+
+    Ok(match first {
+        Some(block) => Check::fail(
+            "start block",
+            format!(
+                "the vault emitted logs at block {}, before the configured start block {}",
+                block, from_block
+            ),
+            "move --from-block back to the vault's deployment block",
+        ),
+        None => Check::ok(
+            "start block",
+            format!("no vault logs in blocks {}..{}", start, from_block),
+        ),
+    })
+}
+
+pub fn check_decimals(decimals: Result<U256>) -> Check {
+    match decimals {
+        Ok(decimals) if decimals == U256::from(18) => {
+            Check::ok("decimals", "shares have 18 decimals".to_string())
+        }
+        Ok(decimals) => Check::fail(
+            "decimals",
+            format!("shares have {} decimals, amounts assume 18", decimals),
+            "rewards per share are scaled by 1e18, this vault is not supported as is",
+        ),
+        Err(err) => Check::warn(
+            "decimals",
+            format!("decimals() failed: {}", err),
+            "make sure the address is an ERC-4626 vault",
+        ),
+    }
+}
+
+pub fn check_cache(cache: &EventCache, vault: Address, chain_id: u64, head: u64) -> Check {
+    if cache.vault != vault || cache.chain_id != chain_id {
+        return Check::fail(
+            "cache",
+            format!(
+                "cache is for vault {:?} on chain {}, expected vault {:?} on chain {}",
+                cache.vault, cache.chain_id, vault, chain_id
+            ),
+            "fetch a new cache for this vault",
+        );
+    }
+    if cache.to_block > head {
+        return Check::fail(
+            "cache",
+            format!(
+                "cache ends at block {}, past the provider's head {}",
+                cache.to_block, head
+            ),
+            "the cache was fetched from another chain or a node ahead of this one",
+        );
+    }
+    if !cache.is_complete() {
+        return Check::warn(
+            "cache",
+            format!(
+                "blocks {}..={} are only partially fetched",
+                cache.from_block, cache.to_block
+            ),
+            "run fetch again with the same cache to resume",
+        );
+    }
+
+    Check::ok(
+        "cache",
+        format!(
+            "{} events for blocks {}..={}",
+            cache.events.len(),
+            cache.from_block,
+            cache.to_block
+        ),
+    )
+}
+
+pub fn check_index(index: Result<EventIndex>, cache: &EventCache) -> Check {
+    match index {
+        Ok(index) if index.is_current(&cache.events) => {
+            Check::ok("index", format!("{} addresses indexed", index.addresses()))
+        }
+        Ok(_) => Check::warn(
+            "index",
+            "index was built from other events than the cache holds".to_string(),
+            "run fetch again to rewrite it, until then it is rebuilt on every run",
+        ),
+        Err(err) => Check::warn(
+            "index",
+            format!("{:#}", err),
+            "run fetch again to write it, until then it is rebuilt on every run",
+        ),
+    }
+}
+
+/// Replays `events` skipping what overdraws, and looks at who the skipped senders are: a burst
+/// of unknown ones right after a block means logs from there on were decoded with the wrong
+/// layouts.
+pub fn check_layouts(flavor: &VaultFlavor, events: &[Event]) -> Check {
+    let counts = flavor
+        .layout_counts(events)
+        .iter()
+        .map(|(name, count)| format!("{} under {}", count, name))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut state = GlobalState::new();
+    state.set_best_effort(true);
+    if let Err(err) = state.process_events(events.to_vec()) {
+        return Check::fail(
+            "layouts",
+            format!("events decoded {}; {}", counts, err),
+            "check the flavor's layouts against the vault's events",
+        );
+    }
+    let quarantined = state.quarantined();
+    let unknown = quarantined
+        .iter()
+        .filter(|quarantined| quarantined.reason == QuarantineReason::UnknownSender)
+        .count();
+
+    match unknown_sender_burst(quarantined, &flavor.boundaries()) {
+        Some(burst) if burst.boundary.is_some() => Check::fail(
+            "layouts",
+            format!("events decoded {}; {}", counts, burst),
+            "check the segment's from_block against the upgrade transaction, and its layouts \
+             against the new contract's events",
+        ),
+        Some(burst) => Check::fail(
+            "layouts",
+            format!("events decoded {}; {}", counts, burst),
+            "if the vault was upgraded there, add a [[segments]] entry with its new layouts \
+             to the flavor",
+        ),
+        None if unknown > 0 => Check::warn(
+            "layouts",
+            format!(
+                "events decoded {}; {} events from unknown senders",
+                counts, unknown
+            ),
+            "run with --best-effort --quarantine to review them",
+        ),
+        None => Check::ok("layouts", format!("events decoded {}", counts)),
+    }
+}
+
+/// Runs every check against the provider and, when given, the cache.
+pub async fn diagnose(
+    client: &Client,
+    ctx: &EvaluationContext,
+    vault: Address,
+    flavor: &VaultFlavor,
+    chain_id: u64,
+    from_block: u64,
+    cache_path: Option<&Path>,
+) -> Result<Vec<Check>> {
+    let mut checks = vec![check_chain_id(chain_id, ctx.chain_id)];
+
+    let code = client
+        .get_code(vault, Some(BlockId::from(ctx.block)))
+        .await?;
+    checks.push(check_vault_code(vault, &code));
+    checks.push(check_first_log(client, vault, from_block).await?);
+    checks.push(check_decimals(
+        call_uint(client, vault, "decimals()", &[], ctx.block).await,
+    ));
+
+    if let Some(path) = cache_path {
+        match EventCache::load(path) {
+            Ok(cache) => {
+                checks.push(check_cache(&cache, vault, ctx.chain_id, ctx.block.as_u64()));
+                checks.push(check_index(EventIndex::load(&index_path(path)), &cache));
+                checks.push(check_layouts(flavor, &cache.events));
+            }
+            Err(err) => checks.push(Check::fail(
+                "cache",
+                format!("{:#}", err),
+                "fetch the cache again",
+            )),
+        }
+    }
+
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::chunk_grid;
+    use crate::fetch::mock::{deposit_log, MockSource};
+    use crate::state::{Deposit, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::U64, utils::parse_ether};
+    use eyre::eyre;
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    #[test]
+    fn flags_the_wrong_chain_and_missing_code() {
+        assert_eq!(check_chain_id(1, 1).status, Status::Ok);
+        assert_eq!(check_chain_id(1, 10).status, Status::Fail);
+
+        let vault = VAULT.parse().unwrap();
+        assert_eq!(check_vault_code(vault, &Bytes::new()).status, Status::Fail);
+        assert_eq!(
+            check_vault_code(vault, &Bytes::from(vec![0x60, 0x80])).status,
+            Status::Ok
+        );
+    }
+
+    #[tokio::test]
+    async fn flags_logs_before_the_start_block() {
+        let vault = VAULT.parse().unwrap();
+        let bob = Address::from_low_u64_be(0xb0b);
+        let one = parse_ether("1").unwrap();
+
+        let clean = MockSource {
+            logs: vec![deposit_log(bob, one, BLOCK_CONTRACT_DEPLOYED)],
+        };
+        let check = check_first_log(&clean, vault, BLOCK_CONTRACT_DEPLOYED)
+            .await
+            .unwrap();
+        assert_eq!(check.status, Status::Ok);
+
+        let early = MockSource {
+            logs: vec![deposit_log(bob, one, BLOCK_CONTRACT_DEPLOYED - 10)],
+        };
+        let check = check_first_log(&early, vault, BLOCK_CONTRACT_DEPLOYED)
+            .await
+            .unwrap();
+        assert_eq!(check.status, Status::Fail);
+        assert!(check
+            .detail
+            .contains(&(BLOCK_CONTRACT_DEPLOYED - 10).to_string()));
+    }
+
+    #[test]
+    fn grades_decimals() {
+        assert_eq!(check_decimals(Ok(U256::from(18))).status, Status::Ok);
+        assert_eq!(check_decimals(Ok(U256::from(6))).status, Status::Fail);
+        assert_eq!(check_decimals(Err(eyre!("reverted"))).status, Status::Warn);
+    }
+
+    #[test]
+    fn checks_cache_metadata_and_index() {
+        let vault = VAULT.parse().unwrap();
+        let from_block = BLOCK_CONTRACT_DEPLOYED;
+        let mut cache = EventCache::new(vault, 1, from_block, from_block + 99, 100);
+
+        assert_eq!(
+            check_cache(&cache, vault, 1, from_block + 500).status,
+            Status::Warn
+        );
+
+        cache.completed_chunks = chunk_grid(from_block, from_block + 99, 100)
+            .into_iter()
+            .map(|(start, _)| start)
+            .collect();
+        assert_eq!(
+            check_cache(&cache, vault, 1, from_block + 500).status,
+            Status::Ok
+        );
+        assert_eq!(
+            check_cache(&cache, vault, 5, from_block + 500).status,
+            Status::Fail
+        );
+        assert_eq!(
+            check_cache(&cache, vault, 1, from_block + 50).status,
+            Status::Fail
+        );
+
+        let other = EventCache::new(Address::zero(), 1, from_block, from_block + 99, 100);
+        assert_eq!(
+            check_cache(&other, vault, 1, from_block + 500).status,
+            Status::Fail
+        );
+
+        assert_eq!(
+            check_index(Ok(EventIndex::build(&cache.events)), &cache).status,
+            Status::Ok
+        );
+        assert_eq!(
+            check_index(Err(eyre!("failed to read index")), &cache).status,
+            Status::Warn
+        );
+    }
+
+    #[test]
+    fn flags_unknown_senders_after_an_unconfigured_upgrade() {
+        let one = parse_ether("1").unwrap();
+        let upgrade = BLOCK_CONTRACT_DEPLOYED + 1_000;
+        let deposit = |n: u64, block: u64| {
+            Event::Deposit(Deposit {
+                address: Address::from_low_u64_be(n),
+                shares: Shares(one),
+                block_number: U64::from(block),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let withdraw = |n: u64, block: u64| {
+            Event::Withdrawal(Withdraw {
+                address: Address::from_low_u64_be(n),
+                shares: Shares(one),
+                block_number: U64::from(block),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let flavor = VaultFlavor::oprtc_v1();
+
+        let clean = [deposit(1, BLOCK_CONTRACT_DEPLOYED), withdraw(1, upgrade)];
+        let check = check_layouts(&flavor, &clean);
+        assert_eq!(check.status, Status::Ok);
+        assert_eq!(check.detail, "events decoded 2 under oprtc-v1");
+
+        // deposits after the upgrade went undecoded, so their owners withdraw as strangers
+        let upgraded = [
+            deposit(1, BLOCK_CONTRACT_DEPLOYED),
+            withdraw(2, upgrade + 5),
+            withdraw(3, upgrade + 20),
+            withdraw(4, upgrade + 90),
+        ];
+        let check = check_layouts(&flavor, &upgraded);
+        assert_eq!(check.status, Status::Fail);
+        assert!(check
+            .detail
+            .contains(&format!("from block {}", upgrade + 5)));
+        assert!(check.remedy.unwrap().contains("[[segments]]"));
+
+        let check = check_layouts(&flavor, &upgraded[..2]);
+        assert_eq!(check.status, Status::Warn);
+    }
+
+    #[test]
+    fn renders_remedies_and_a_summary() {
+        let checks = [check_chain_id(1, 1), check_chain_id(1, 10)];
+
+        let plain = render(&checks, false);
+        let lines: Vec<_> = plain.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "[  ok] chain: provider is on chain 1");
+        assert!(lines[2].starts_with("       -> "));
+        assert_eq!(lines[3], "1 ok, 0 warnings, 1 failed");
+        assert!(!plain.contains('\x1b'));
+
+        assert!(render(&checks, true).contains("\x1b[31m[fail]\x1b[0m"));
+
+        let json = serde_json::to_value(&checks[1]).unwrap();
+        assert_eq!(json["status"], "fail");
+        assert!(serde_json::to_value(&checks[0]).unwrap()["remedy"].is_null());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use crate::report::{Report, ReportRow, WithholdReason};
+use crate::state::GlobalState;
+use crate::timestamps::TimestampCache;
+use ethers::core::types::{Address, U256, U64};
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Days without activity after which an address owed rewards counts as dormant.
+pub const DORMANCY_DAYS: u64 = 365;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DormancyRow {
+    pub address: Address,
+    /// Block of the address' last deposit, withdrawal or transfer
+    pub last_activity_block: U64,
+    /// Whole days from the last activity to the evaluation block
+    pub days_dormant: u64,
+    #[serde(serialize_with = "serialize_u256")]
+    pub rewards: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub shares: U256,
+}
+
+/// Blocks whose timestamps `build_dormancy` reads: the last activity of every user.
+pub fn activity_blocks(state: &GlobalState) -> Vec<U64> {
+    state
+        .users()
+        .filter_map(|address| state.last_activity_block(*address))
+        .collect()
+}
+
+/// Payees owed rewards at `block_number` whose last activity is more than `threshold_days`
+/// before it, longest dormant first. The days are measured between block timestamps, so a
+/// historical evaluation block gives the same answer whenever it runs.
+///
+/// `timestamps` must hold `block_number` and every block of `activity_blocks`.
+pub fn build_dormancy(
+    state: &GlobalState,
+    timestamps: &TimestampCache,
+    threshold_days: u64,
+    block_number: U64,
+) -> Vec<DormancyRow> {
+    let timestamp = |block: U64| {
+        timestamps
+            .get(block)
+            .unwrap_or_else(|| panic!("timestamp of block {} should be known", block))
+    };
+    let evaluated_at = timestamp(block_number);
+
+    let mut rows: Vec<DormancyRow> = state
+        .get_user_rewards(block_number)
+        .into_iter()
+        .filter(|(_, rewards)| !rewards.is_zero())
+        .filter_map(|(address, rewards)| {
+            let last_activity_block = state.last_activity_block(address)?;
+            let days_dormant =
+                evaluated_at.saturating_sub(timestamp(last_activity_block)) / SECONDS_PER_DAY;
+            (days_dormant > threshold_days).then(|| DormancyRow {
+                address,
+                last_activity_block,
+                days_dormant,
+                rewards,
+                shares: state.shares_of(address),
+            })
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        a.last_activity_block
+            .cmp(&b.last_activity_block)
+            .then(a.address.cmp(&b.address))
+    });
+    rows
+}
+
+/// Moves the rows of dormant addresses, sub-accounts included, to the report's withheld bucket.
+pub fn withhold_dormant(report: &mut Report, dormant: &[DormancyRow]) {
+    let dormant: HashSet<String> = dormant
+        .iter()
+        .map(|row| format!("{:?}", row.address))
+        .collect();
+
+    let (withheld, rows): (Vec<ReportRow>, Vec<ReportRow>) = std::mem::take(&mut report.rows)
+        .into_iter()
+        .partition(|row| {
+            let address = row
+                .label
+                .split_once('#')
+                .map_or(&*row.label, |(address, _)| address);
+            dormant.contains(address)
+        });
+    report.rows = rows;
+    report.withheld.extend(withheld.into_iter().map(|mut row| {
+        row.reason = Some(WithholdReason::Dormant);
+        row
+    }));
+}
+
+pub fn render_dormancy(
+    rows: &[DormancyRow],
+    format: OutputFormat,
+    freshness: &Freshness,
+) -> String {
+    let columns = [
+        "address",
+        "last_activity_block",
+        "days_dormant",
+        "rewards",
+        "shares",
+    ];
+
+    let cells = |row: &DormancyRow| {
+        [
+            format!("{:?}", row.address),
+            row.last_activity_block.to_string(),
+            row.days_dormant.to_string(),
+            row.rewards.to_string(),
+            row.shares.to_string(),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(rows, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for row in rows {
+                out += &(cells(row).join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for row in rows {
+                out += &format!("| {} |\n", cells(row).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::Annotations;
+    use crate::report::rewards_report;
+    use crate::state::{Deposit, Event, Transfer, BLOCK_CONTRACT_DEPLOYED};
+    use crate::subaccounts::SubAccounts;
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+    use std::collections::HashMap;
+
+    const DAY_BLOCKS: u64 = 7_200;
+
+    /// A block every 12 seconds from the deploy block.
+    fn timestamps(blocks: &[U64]) -> TimestampCache {
+        let mut timestamps = TimestampCache::new();
+        for block in blocks {
+            timestamps.insert(
+                *block,
+                1_686_000_000 + 12 * (*block - BLOCK_CONTRACT_DEPLOYED).as_u64(),
+            );
+        }
+        timestamps
+    }
+
+    #[test]
+    fn dormant_addresses_wake_up_on_any_activity() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let block = |days: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + days * DAY_BLOCKS);
+        let deposit = |address, days| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: block(days),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+
+        let mut state = GlobalState::new();
+        state
+            .process_events(vec![deposit(bob, 0), deposit(alice, 300)])
+            .unwrap();
+        let evaluated = block(400);
+        let dormancy = |state: &GlobalState| {
+            let mut blocks = activity_blocks(state);
+            blocks.push(evaluated);
+            build_dormancy(state, &timestamps(&blocks), DORMANCY_DAYS, evaluated)
+        };
+
+        // bob last deposited 400 days before, alice 100
+        let rows = dormancy(&state);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].address, bob);
+        assert_eq!(rows[0].last_activity_block, block(0));
+        assert_eq!(rows[0].days_dormant, 400);
+        assert_eq!(rows[0].shares, parse_ether("1").unwrap());
+        assert_eq!(rows[0].rewards, state.preview_user_rewards(bob, evaluated));
+
+        // receiving a transfer is activity too
+        state
+            .process_events(vec![Event::Transfer(Transfer {
+                from: alice,
+                to: bob,
+                shares: Shares(parse_ether("0.5").unwrap()),
+                block_number: block(390),
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap();
+        assert!(dormancy(&state).is_empty());
+        assert_eq!(state.last_activity_block(bob), Some(block(390)));
+    }
+
+    #[test]
+    fn withholds_dormant_rows_with_their_reason() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 100);
+        let mut state = GlobalState::new();
+        state
+            .process_events(
+                [bob, alice]
+                    .map(|address| {
+                        Event::Deposit(Deposit {
+                            address,
+                            shares: Shares(parse_ether("1").unwrap()),
+                            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                            transaction_index: 0,
+                            log_index: 0,
+                        })
+                    })
+                    .to_vec(),
+            )
+            .unwrap();
+        let mut report = rewards_report(
+            &state,
+            block_number,
+            &Annotations::default(),
+            None,
+            (&SubAccounts::default(), &HashMap::new()),
+            false,
+        );
+        let undormant = report.hash();
+
+        let dormant = DormancyRow {
+            address: bob,
+            last_activity_block: U64::from(BLOCK_CONTRACT_DEPLOYED),
+            days_dormant: 400,
+            rewards: parse_ether("50").unwrap(),
+            shares: parse_ether("1").unwrap(),
+        };
+        withhold_dormant(&mut report, &[dormant]);
+
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].label, format!("{:?}", alice));
+        assert_eq!(report.withheld.len(), 1);
+        assert_eq!(report.withheld[0].label, format!("{:?}", bob));
+        assert_eq!(report.withheld[0].reason, Some(WithholdReason::Dormant));
+        assert!(report.to_string().contains("withheld (1 dormant)"));
+        assert_ne!(report.hash(), undormant);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::lock::{LockError, EXIT_LOCKED};
+use crate::proof::ProofError;
+use crate::state::StateError;
+use ethers::providers::ProviderError;
+use std::io;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+type Cause = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything the library fails with, by what a caller would do about it. Like `eyre` contexts,
+/// the message says what failed and the source, if any, why.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Settings, settings files or caches that can't be used as given
+    #[error("{message}")]
+    Config {
+        message: String,
+        #[source]
+        source: Option<Cause>,
+    },
+    /// The provider failed or answered something unusable
+    #[error(transparent)]
+    Rpc(#[from] ProviderError),
+    /// A log, journal line or file that doesn't decode
+    #[error("{message}")]
+    Decode {
+        message: String,
+        #[source]
+        source: Option<Cause>,
+    },
+    /// Events the accounting can't apply, such as withdrawals of shares never received
+    #[error(transparent)]
+    Accounting(#[from] StateError),
+    /// A proof that doesn't match its events
+    #[error(transparent)]
+    Proof(#[from] ProofError),
+    /// Reading or writing a file or stream
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Data older than `--max-staleness` allows, or of unknown age
+    #[error("{0}")]
+    Stale(String),
+    /// Rewards the sanity checks flagged as implausible, without `--acknowledge-flags`
+    #[error("{0}")]
+    Flagged(String),
+    /// A file another process keeps locked past `--lock-timeout`
+    #[error("{0}")]
+    Locked(String),
+}
+
+/// Returns the error `$kind` builds from the formatted message unless `$cond` holds, like
+/// `eyre::ensure!`.
+macro_rules! ensure {
+    ($cond:expr, $kind:path, $($arg:tt)+) => {
+        if !$cond {
+            return Err($kind(format!($($arg)+)).into());
+        }
+    };
+}
+
+/// Returns the error `$kind` builds from the formatted message, like `eyre::bail!`.
+macro_rules! bail {
+    ($kind:path, $($arg:tt)+) => {
+        return Err($kind(format!($($arg)+)).into())
+    };
+}
+
+pub(crate) use {bail, ensure};
+
+impl Error {
+    pub fn config(message: impl Into<String>) -> Error {
+        Error::Config {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn decode(message: impl Into<String>) -> Error {
+        Error::Decode {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn rpc(message: impl Into<String>) -> Error {
+        Error::Rpc(ProviderError::CustomError(message.into()))
+    }
+
+    pub fn stale(message: impl Into<String>) -> Error {
+        Error::Stale(message.into())
+    }
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Error {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Attaches `cause` as the source of a config or decode error.
+    pub fn caused_by(mut self, cause: impl Into<Cause>) -> Error {
+        if let Error::Config { source, .. } | Error::Decode { source, .. } = &mut self {
+            *source = Some(cause.into());
+        }
+        self
+    }
+
+    /// Process exit code for a run failing with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config { .. } => 2,
+            Error::Rpc(_) => 3,
+            Error::Decode { .. } => 4,
+            Error::Accounting(_) => 5,
+            Error::Proof(_) => 6,
+            Error::Io { .. } => 7,
+            Error::Stale(_) => 8,
+            Error::Flagged(_) => 9,
+            Error::Locked(_) => EXIT_LOCKED,
+        }
+    }
+}
+
+impl From<LockError> for Error {
+    fn from(err: LockError) -> Error {
+        match err {
+            LockError::Busy { .. } => Error::Locked(err.to_string()),
+            LockError::Io(source) => Error::io("failed to lock", source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::Annotations;
+    use crate::flavor::VaultFlavor;
+    use crate::journal::read_journal;
+    use crate::state::{Event, GlobalState, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::core::types::{Address, Log, U256, U64};
+    use std::{error::Error as _, io::Cursor, path::Path};
+
+    #[test]
+    fn library_failures_match_their_category() {
+        let config = Annotations::parse("[treasury]\nnote = \"x\"\n").unwrap_err();
+        assert!(matches!(config, Error::Config { .. }));
+        assert_eq!(config.exit_code(), 2);
+
+        let flavor = VaultFlavor::oprtc_v1();
+        let truncated = Log {
+            topics: vec![flavor.events[0].topic0()],
+            ..Default::default()
+        };
+        let decode = flavor.decode(&truncated).unwrap_err();
+        assert!(matches!(decode, Error::Decode { .. }));
+        let journal = read_journal(Cursor::new("{}\n")).unwrap_err();
+        assert!(journal.source().unwrap().is::<serde_json::Error>());
+
+        let mut state = GlobalState::new();
+        let accounting = state
+            .try_process_events(vec![Event::Withdrawal(Withdraw {
+                address: Address::from_low_u64_be(0xb0b),
+                shares: Shares(U256::one()),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap_err();
+        assert!(matches!(accounting, Error::Accounting(_)));
+        assert_eq!(accounting.exit_code(), 5);
+
+        let io = Annotations::load(Path::new("/nonexistent/annotations.toml")).unwrap_err();
+        assert!(
+            matches!(io, Error::Io { ref source, .. } if source.kind() == io::ErrorKind::NotFound)
+        );
+
+        let rpc = Error::rpc("rate limited");
+        assert!(matches!(rpc, Error::Rpc(ProviderError::CustomError(_))));
+        assert_eq!(rpc.exit_code(), 3);
+    }
+}
@@ -0,0 +1,342 @@
+//! Exact rewards, the reference `--exact` checks the integer accounting against.
+//!
+//! A `Ledger` follows a replay as an observer and settles every holder against its own
+//! accumulator. With `RewardPerShare` it floors like the contract and agrees with
+//! `GlobalState` to the wei; with `BigRational` nothing is rounded until `to_wei`, which floors
+//! each holder's exact rewards once, at the very end. Rationals grow with every distribution,
+//! so exact replays are for audits of modest holder counts.
+
+use crate::observer::{StateObserver, UserDelta};
+use crate::state::{Event, GlobalState};
+use crate::units::{RewardPerShare, Rewards, Shares};
+use ethers::core::types::{Address, U256, U64};
+use num_bigint::{BigInt, Sign};
+use num_rational::BigRational;
+use std::{collections::HashMap, fmt};
+
+/// Holders above which `--exact` warns that the replay will be slow and memory hungry.
+pub const EXACT_HOLDER_WARNING: usize = 10_000;
+
+/// The arithmetic of a rewards accumulator: the rewards per share it grows by, and the
+/// rewards of shares held while it grew.
+pub trait Accumulator: Clone + fmt::Debug {
+    type Rewards: Clone + fmt::Debug;
+
+    fn zero() -> Self;
+
+    fn zero_rewards() -> Self::Rewards;
+
+    /// `emitted` wei spread over `shares`.
+    fn spread(emitted: U256, shares: U256) -> Self;
+
+    fn grow(&mut self, by: &Self);
+
+    /// Rewards of `shares` held while the accumulator grew from `since` to `self`.
+    fn settle(&self, since: &Self, shares: U256) -> Self::Rewards;
+
+    fn add_rewards(total: &mut Self::Rewards, rewards: Self::Rewards);
+
+    /// `rewards` in whole wei.
+    fn to_wei(rewards: &Self::Rewards) -> U256;
+}
+
+/// The contract's arithmetic: every spread floored at 1e18 per share.
+impl Accumulator for RewardPerShare {
+    type Rewards = Rewards;
+
+    fn zero() -> Self {
+        RewardPerShare::default()
+    }
+
+    fn zero_rewards() -> Rewards {
+        Rewards::default()
+    }
+
+    fn spread(emitted: U256, shares: U256) -> Self {
+        RewardPerShare::spread(emitted, Shares(shares))
+    }
+
+    fn grow(&mut self, by: &Self) {
+        *self += *by;
+    }
+
+    fn settle(&self, since: &Self, shares: U256) -> Rewards {
+        (*self - *since) * Shares(shares)
+    }
+
+    fn add_rewards(total: &mut Rewards, rewards: Rewards) {
+        *total += rewards;
+    }
+
+    fn to_wei(rewards: &Rewards) -> U256 {
+        rewards.to_wei().0
+    }
+}
+
+fn rational(amount: U256) -> BigRational {
+    let mut bytes = [0; 32];
+    amount.to_big_endian(&mut bytes);
+    BigRational::from_integer(BigInt::from_bytes_be(Sign::Plus, &bytes))
+}
+
+/// Exact fractions of a wei, floored only by `to_wei`.
+impl Accumulator for BigRational {
+    type Rewards = BigRational;
+
+    fn zero() -> Self {
+        rational(U256::zero())
+    }
+
+    fn zero_rewards() -> BigRational {
+        rational(U256::zero())
+    }
+
+    fn spread(emitted: U256, shares: U256) -> Self {
+        rational(emitted) / rational(shares)
+    }
+
+    fn grow(&mut self, by: &Self) {
+        *self += by;
+    }
+
+    fn settle(&self, since: &Self, shares: U256) -> BigRational {
+        (self - since) * rational(shares)
+    }
+
+    fn add_rewards(total: &mut BigRational, rewards: BigRational) {
+        *total += rewards;
+    }
+
+    fn to_wei(rewards: &BigRational) -> U256 {
+        let (_, bytes) = rewards.floor().to_integer().to_bytes_be();
+        U256::from_big_endian(&bytes)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Holding<A: Accumulator> {
+    shares: U256,
+    snapshot: A,
+    rewards: A::Rewards,
+}
+
+/// Rewards of every holder of a replay, settled with the arithmetic of `A`.
+///
+/// It sees the emissions the state distributes and the share changes it applies, so it
+/// follows the state's accrual policy, netting and quarantine. It must observe the replay from
+/// the first event.
+#[derive(Debug, Clone)]
+pub struct Ledger<A: Accumulator> {
+    per_share: A,
+    total_shares: U256,
+    holdings: HashMap<Address, Holding<A>>,
+}
+
+impl<A: Accumulator> Default for Ledger<A> {
+    fn default() -> Self {
+        Ledger {
+            per_share: A::zero(),
+            total_shares: U256::zero(),
+            holdings: HashMap::new(),
+        }
+    }
+}
+
+impl<A: Accumulator> Ledger<A> {
+    pub fn new() -> Ledger<A> {
+        Ledger::default()
+    }
+
+    /// Rewards of `address` at `block_number`, counting the emission `state` has yet to
+    /// distribute like `GlobalState::preview_user_rewards`.
+    pub fn rewards(&self, address: Address, block_number: U64, state: &GlobalState) -> U256 {
+        let Some(holding) = self.holdings.get(&address) else {
+            return U256::zero();
+        };
+
+        let mut per_share = self.per_share.clone();
+        if !self.total_shares.is_zero() {
+            let pending = state.pending_distribution(block_number).emitted;
+            per_share.grow(&A::spread(pending, self.total_shares));
+        }
+        let mut rewards = holding.rewards.clone();
+        A::add_rewards(
+            &mut rewards,
+            per_share.settle(&holding.snapshot, holding.shares),
+        );
+        A::to_wei(&rewards)
+    }
+
+    /// Rewards of every payee of `state` at `block_number`, like `GlobalState::get_user_rewards`
+    /// but in address order.
+    pub fn user_rewards(&self, block_number: U64, state: &GlobalState) -> Vec<(Address, U256)> {
+        let mut rewards: Vec<_> = self
+            .holdings
+            .keys()
+            .filter(|address| state.is_payee(**address))
+            .map(|address| (*address, self.rewards(*address, block_number, state)))
+            .filter(|(_, rewards)| !rewards.is_zero())
+            .collect();
+        rewards.sort();
+        rewards
+    }
+}
+
+impl<A: Accumulator> StateObserver for Ledger<A> {
+    fn on_event_applied(&mut self, _event: &Event, delta: &UserDelta) {
+        for change in &delta.changes {
+            let holding = self
+                .holdings
+                .entry(change.address)
+                .or_insert_with(|| Holding {
+                    shares: U256::zero(),
+                    snapshot: self.per_share.clone(),
+                    rewards: A::zero_rewards(),
+                });
+            A::add_rewards(
+                &mut holding.rewards,
+                self.per_share.settle(&holding.snapshot, holding.shares),
+            );
+            holding.snapshot = self.per_share.clone();
+
+            self.total_shares = self.total_shares - holding.shares + change.after.shares;
+            holding.shares = change.after.shares;
+        }
+    }
+
+    fn on_rewards_distributed(&mut self, _block_number: U64, amount: U256, _per_share_delta: U256) {
+        if !self.total_shares.is_zero() {
+            self.per_share.grow(&A::spread(amount, self.total_shares));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+
+    /// A random stream of deposits, withdrawals and transfers between five holders, several
+    /// events to a block at times, never moving more shares than the sender holds.
+    fn random_events(seed: u64, count: usize) -> Vec<Event> {
+        let mut state = seed;
+        let mut next = move |bound: u64| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % bound
+        };
+        let holders: Vec<_> = (1..=5).map(Address::from_low_u64_be).collect();
+        let mut held = vec![U256::zero(); holders.len()];
+        let mut block = BLOCK_CONTRACT_DEPLOYED;
+
+        let mut events = vec![];
+        while events.len() < count {
+            block += next(3) * next(50);
+            let block_number = U64::from(block);
+            let i = next(5) as usize;
+            // odd amounts up to 100 shares, so the spreads don't divide evenly
+            let shares = U256::from(next(100_000) + 1) * U256::exp10(15) + next(1_000);
+
+            match next(3) {
+                0 => {
+                    held[i] += shares;
+                    events.push(Event::Deposit(Deposit {
+                        address: holders[i],
+                        shares: Shares(shares),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }));
+                }
+                1 if !held[i].is_zero() => {
+                    let shares = shares.min(held[i]);
+                    held[i] -= shares;
+                    events.push(Event::Withdrawal(Withdraw {
+                        address: holders[i],
+                        shares: Shares(shares),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }));
+                }
+                2 if !held[i].is_zero() => {
+                    let to = (i + 1 + next(4) as usize) % holders.len();
+                    let shares = shares.min(held[i]);
+                    held[i] -= shares;
+                    held[to] += shares;
+                    events.push(Event::Transfer(Transfer {
+                        from: holders[i],
+                        to: holders[to],
+                        shares: Shares(shares),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }));
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    fn replay<A: Accumulator>(events: &[Event]) -> (GlobalState, Ledger<A>) {
+        let mut state = GlobalState::new();
+        let mut ledger = Ledger::new();
+        state
+            .process_events_with(events.to_vec(), &mut ledger)
+            .unwrap();
+        (state, ledger)
+    }
+
+    #[test]
+    fn integer_ledger_matches_the_state() {
+        for seed in 1..=10 {
+            let events = random_events(seed, 120);
+            let block = events.last().unwrap().block_number() + 100;
+            let (state, ledger) = replay::<RewardPerShare>(&events);
+
+            let mut expected = state.get_user_rewards(block);
+            expected.sort();
+            assert_eq!(
+                ledger.user_rewards(block, &state),
+                expected,
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn integer_rewards_stay_within_rounding_of_the_exact_ones() {
+        for seed in 1..=10 {
+            let events = random_events(seed, 120);
+            let block = events.last().unwrap().block_number() + 100;
+            let (state, exact) = replay::<BigRational>(&events);
+
+            // a floored spread loses under a wei per ether of shares held and distribution, a
+            // floored settlement under one wei per event
+            let deposited = events.iter().fold(U256::zero(), |sum, evt| match evt {
+                Event::Deposit(deposit) => sum + deposit.shares.0,
+                _ => sum,
+            });
+            let distributions = U256::from(events.len() + 1);
+            let tolerance = distributions * (deposited / U256::exp10(18) + 1) + distributions;
+            let mut exact_total = U256::zero();
+            for (address, integer) in state.get_user_rewards(block) {
+                let exact = exact.rewards(address, block, &state);
+                assert!(integer <= exact, "seed {}: {:?} rounded up", seed, address);
+                assert!(exact - integer <= tolerance, "seed {}: {:?}", seed, address);
+                exact_total += exact;
+            }
+
+            // nothing is lost to rounding before the final floor of each holder, unless the
+            // pool is empty at the end and the emission since is nobody's yet
+            if !state.total_shares_staked().is_zero() {
+                let emitted = state.total_emission(block);
+                assert!(exact_total <= emitted && emitted - exact_total < 5.into());
+            }
+        }
+    }
+}
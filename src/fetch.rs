@@ -0,0 +1,1082 @@
+use crate::error::{ensure, Error, Result};
+use crate::flavor::VaultFlavor;
+use crate::rpc::Client;
+use crate::state::Event;
+use async_trait::async_trait;
+use ethers::{
+    core::types::{Address, Filter, Log, U256},
+    providers::{FilterKind, Middleware},
+};
+use std::{
+    collections::BTreeMap,
+    future::{poll_fn, Future},
+    panic,
+    str::FromStr,
+    task::Poll,
+};
+use tokio::task::JoinSet;
+
+/// Blocks per log request unless given, well under the 10k block ranges public nodes cap
+/// `eth_getLogs` at.
+pub const CHUNK_SIZE: u64 = 5_000;
+
+/// Anything that can answer `eth_getLogs`, so fetching can run against a mock in tests.
+#[async_trait]
+pub trait LogSource: Send + Sync {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>>;
+}
+
+#[async_trait]
+impl LogSource for Client {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        Ok(self.get_logs(filter).await?)
+    }
+}
+
+/// How logs are requested from the provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FetchMode {
+    /// `eth_getLogs` for every event and range
+    Logs,
+    /// `eth_newFilter` then `eth_getFilterLogs`, for providers serving persistent filters better
+    Filter,
+}
+
+/// A node with persistent log filters.
+#[async_trait]
+pub trait FilterSource: Send + Sync {
+    async fn new_filter(&self, filter: &Filter) -> Result<U256>;
+    /// Every log matching the filter, or `None` once the node has expired it.
+    async fn filter_logs(&self, id: U256) -> Result<Option<Vec<Log>>>;
+    async fn uninstall_filter(&self, id: U256) -> Result<()>;
+}
+
+#[async_trait]
+impl FilterSource for Client {
+    async fn new_filter(&self, filter: &Filter) -> Result<U256> {
+        Ok(Middleware::new_filter(self, FilterKind::Logs(filter)).await?)
+    }
+
+    async fn filter_logs(&self, id: U256) -> Result<Option<Vec<Log>>> {
+        match self.request("eth_getFilterLogs", [id]).await {
+            Ok(logs) => Ok(Some(logs)),
+            Err(err) if err.to_string().to_lowercase().contains("filter not found") => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn uninstall_filter(&self, id: U256) -> Result<()> {
+        Middleware::uninstall_filter(self, id).await?;
+        Ok(())
+    }
+}
+
+/// Attempts at reading a filter the node keeps expiring before giving up.
+const FILTER_ATTEMPTS: usize = 3;
+
+/// Answers `fetch_logs` by installing a filter and reading it back, recreating the filter when
+/// the node expires it in between.
+#[derive(Clone)]
+pub struct PersistentFilters<S>(pub S);
+
+#[async_trait]
+impl<S: FilterSource> LogSource for PersistentFilters<S> {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        for _ in 0..FILTER_ATTEMPTS {
+            let id = self.0.new_filter(filter).await?;
+            let logs = self.0.filter_logs(id).await?;
+            if let Some(logs) = logs {
+                // expiry would clean it up as well, this only spares the node the wait
+                let _ = self.0.uninstall_filter(id).await;
+                return Ok(logs);
+            }
+        }
+
+        Err(Error::rpc(format!(
+            "filter expired {} times before it could be read",
+            FILTER_ATTEMPTS
+        )))
+    }
+}
+
+/// The client, fetching logs as `--fetch-mode` says.
+#[derive(Clone)]
+pub enum ClientSource {
+    Logs(Client),
+    Filter(PersistentFilters<Client>),
+}
+
+impl ClientSource {
+    pub fn new(client: Client, mode: FetchMode) -> ClientSource {
+        match mode {
+            FetchMode::Logs => ClientSource::Logs(client),
+            FetchMode::Filter => ClientSource::Filter(PersistentFilters(client)),
+        }
+    }
+}
+
+#[async_trait]
+impl LogSource for ClientSource {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        match self {
+            ClientSource::Logs(client) => client.fetch_logs(filter).await,
+            ClientSource::Filter(filters) => filters.fetch_logs(filter).await,
+        }
+    }
+}
+
+/// Events decoded from a block range, with a tally of the logs they came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct Decoded {
+    pub events: Vec<Event>,
+    /// Vault logs in range, including those the flavor skips on purpose
+    pub logs: usize,
+    /// Logs matching a layout that couldn't be decoded, which are left out of `events`
+    pub undecodable: usize,
+    /// Logs without a block number, pending when fetched, which are left out of `events`
+    pub unconfirmed: usize,
+    pub first_error: Option<String>,
+}
+
+impl Decoded {
+    /// Joins the chunks of consecutive ranges, in order.
+    pub fn concat(chunks: Vec<Decoded>) -> Decoded {
+        let mut joined = Decoded::default();
+        for chunk in chunks {
+            joined.events.extend(chunk.events);
+            joined.logs += chunk.logs;
+            joined.undecodable += chunk.undecodable;
+            joined.unconfirmed += chunk.unconfirmed;
+            joined.first_error = joined.first_error.or(chunk.first_error);
+        }
+        joined
+    }
+
+    /// The events, unless some log couldn't be decoded.
+    pub fn strict(self) -> Result<Vec<Event>> {
+        ensure!(
+            self.undecodable == 0,
+            Error::decode,
+            "{} of {} logs could not be decoded{} (--best-effort skips them)",
+            self.undecodable,
+            self.logs,
+            self.first_error
+                .map(|err| format!(", first: {}", err))
+                .unwrap_or_default()
+        );
+        Ok(self.events)
+    }
+}
+
+/// Fetches the vault's events in `[from_block, to_block]` and decodes them as described by
+/// `flavor`, in chain order. Logs a lagging provider returns past `to_block` or for another
+/// contract than `vault` are dropped, and logs that don't decode are counted and skipped.
+pub async fn fetch_events<S: LogSource>(
+    source: &S,
+    flavor: &VaultFlavor,
+    vault: Address,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Decoded> {
+    let logs = fetch_vault_logs(source, flavor, vault, from_block, to_block).await?;
+    Ok(decode_logs(flavor, &logs))
+}
+
+/// Provider messages refusing an `eth_getLogs` range as holding too many logs or blocks.
+pub(crate) const RANGE_LIMIT_ERRORS: &[&str] = &[
+    "query returned more than",
+    "more than 10000 results",
+    "too many results",
+    "block range",
+    "range too large",
+    "response size",
+    "limit exceeded",
+];
+
+fn is_range_limit(err: &Error) -> bool {
+    let message = err.to_string().to_lowercase();
+    matches!(err, Error::Rpc(_))
+        && RANGE_LIMIT_ERRORS
+            .iter()
+            .any(|limit| message.contains(limit))
+}
+
+/// The logs `filter` matches, its block range split in halves for as long as the provider
+/// refuses it as too large, down to single blocks. The halves are fetched one after the other
+/// and their logs joined in block order, so the result is what one request would have returned.
+pub(crate) async fn fetch_range<S: LogSource + ?Sized>(
+    source: &S,
+    filter: &Filter,
+) -> Result<Vec<Log>> {
+    let (Some(from_block), Some(to_block)) = (
+        filter.get_from_block().map(|b| b.as_u64()),
+        filter.get_to_block().map(|b| b.as_u64()),
+    ) else {
+        return source.fetch_logs(filter).await;
+    };
+
+    let mut logs = vec![];
+    let mut halvings = 0;
+    // the next range on top, so the halves are fetched in block order
+    let mut ranges = vec![(from_block, to_block)];
+    while let Some((start, end)) = ranges.pop() {
+        match source
+            .fetch_logs(&filter.clone().from_block(start).to_block(end))
+            .await
+        {
+            Ok(found) => logs.extend(found),
+            Err(err) if start < end && is_range_limit(&err) => {
+                let middle = start + (end - start) / 2;
+                ranges.push((middle + 1, end));
+                ranges.push((start, middle));
+                halvings += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    if halvings > 0 {
+        eprintln!(
+            "the provider refused blocks {} to {} as too large, fetched them in {} ranges",
+            from_block,
+            to_block,
+            halvings + 1
+        );
+    }
+    Ok(logs)
+}
+
+/// The vault's logs in `[from_block, to_block]` of every event in `flavor` and its segments,
+/// undecoded. The events are fetched concurrently and their logs joined one event after the
+/// other, as if fetched in turn.
+pub(crate) async fn fetch_vault_logs<S: LogSource>(
+    source: &S,
+    flavor: &VaultFlavor,
+    vault: Address,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<Log>> {
+    let filter = |signature: &str| {
+        Filter::new()
+            .address(vault)
+            .event(signature)
+            .from_block(from_block)
+            .to_block(to_block)
+    };
+
+    let mut vault_logs = vec![];
+    let mut dropped = 0;
+    let mut foreign = 0;
+
+    let filters: Vec<_> = flavor.signatures().into_iter().map(filter).collect();
+    let fetched = join_all(filters.iter().map(|filter| fetch_range(source, filter))).await;
+    for logs in fetched {
+        let logs = logs?;
+        let (logs, other_contracts): (Vec<_>, Vec<_>) =
+            logs.into_iter().partition(|log| log.address == vault);
+        foreign += other_contracts.len();
+        let (in_range, past_range): (Vec<_>, Vec<_>) = logs
+            .into_iter()
+            .partition(|log| log.block_number.is_none_or(|b| b.as_u64() <= to_block));
+        dropped += past_range.len();
+        vault_logs.extend(in_range);
+    }
+
+    if dropped > 0 {
+        eprintln!(
+            "dropped {} logs the provider returned past block {}",
+            dropped, to_block
+        );
+    }
+    if foreign > 0 {
+        eprintln!(
+            "dropped {} logs the provider returned for contracts other than {:?}",
+            foreign, vault
+        );
+    }
+
+    Ok(vault_logs)
+}
+
+/// Runs `futures` concurrently on the current task, returning their outputs in order.
+async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    poll_fn(|cx| {
+        let mut done = true;
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_none() {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(ready) => *output = Some(ready),
+                    Poll::Pending => done = false,
+                }
+            }
+        }
+        if done {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs.into_iter().map(Option::unwrap).collect()
+}
+
+/// Decodes a log of the vault into an event with the layouts `flavor` has in effect at its
+/// block. `None` for logs that are valid but not accounted for, such as those of other events
+/// or transfers to and from the zero address, and a decode error for logs too short for their
+/// layout or without a block number. Every log the crate replays is decoded here.
+pub fn decode_vault_log(log: &Log, flavor: &VaultFlavor) -> Result<Option<Event>> {
+    flavor.decode(log)
+}
+
+/// Decodes `logs` as described by `flavor`, in chain order, counting and skipping those that
+/// don't decode and those not yet in a block.
+pub(crate) fn decode_logs(flavor: &VaultFlavor, logs: &[Log]) -> Decoded {
+    let mut decoded = Decoded {
+        logs: logs.len(),
+        ..Decoded::default()
+    };
+
+    for log in logs {
+        if log.block_number.is_none() {
+            decoded.unconfirmed += 1;
+            continue;
+        }
+        match decode_vault_log(log, flavor) {
+            Ok(evt) => decoded.events.extend(evt),
+            Err(err) => {
+                decoded.undecodable += 1;
+                decoded.first_error.get_or_insert_with(|| {
+                    format!(
+                        "{:#} ({:?})",
+                        eyre::Report::new(err),
+                        log.transaction_hash.unwrap_or_default()
+                    )
+                });
+            }
+        }
+    }
+
+    decoded.events.sort_by_key(|evt| evt.position());
+    decoded
+}
+
+/// Fetches every chunk with up to `concurrency` requests in flight and returns what each chunk
+/// decoded to in the order the chunks were given, however the requests complete.
+pub async fn fetch_chunks<S: LogSource + Clone + 'static>(
+    source: &S,
+    flavor: &VaultFlavor,
+    vault: Address,
+    chunks: &[(u64, u64)],
+    concurrency: usize,
+) -> Result<Vec<Decoded>> {
+    let mut fetched = BTreeMap::new();
+    fetch_log_chunks(source, flavor, vault, chunks, concurrency, |i, logs| {
+        fetched.insert(i, decode_logs(flavor, &logs));
+        Ok(())
+    })
+    .await?;
+    Ok(fetched.into_values().collect())
+}
+
+/// Fetches the undecoded logs of every chunk with up to `concurrency` requests in flight,
+/// handing each to `fetched` with its position in `chunks` as soon as it arrives.
+pub(crate) async fn fetch_log_chunks<S: LogSource + Clone + 'static>(
+    source: &S,
+    flavor: &VaultFlavor,
+    vault: Address,
+    chunks: &[(u64, u64)],
+    concurrency: usize,
+    mut fetched: impl FnMut(usize, Vec<Log>) -> Result<()>,
+) -> Result<()> {
+    let mut tasks = JoinSet::new();
+    let mut pending = chunks.iter().copied().enumerate();
+
+    loop {
+        while tasks.len() < concurrency.max(1) {
+            let Some((i, (start, end))) = pending.next() else {
+                break;
+            };
+            let source = source.clone();
+            let flavor = flavor.clone();
+
+            tasks.spawn(async move {
+                let logs = fetch_vault_logs(&source, &flavor, vault, start, end).await?;
+                Ok::<_, Error>((i, logs))
+            });
+        }
+
+        match tasks.join_next().await {
+            Some(joined) => {
+                let (i, logs) =
+                    joined.unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))?;
+                fetched(i, logs)?;
+            }
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits `[from_block, to_block]` into chunks whose boundaries are aligned to multiples of
+/// `chunk_size`, so independent runs over the same range agree on the grid.
+pub fn chunk_grid(from_block: u64, to_block: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    let mut chunks = vec![];
+    let mut start = from_block;
+
+    while start <= to_block {
+        let end = ((start / chunk_size + 1) * chunk_size - 1).min(to_block);
+        chunks.push((start, end));
+        start = end + 1;
+    }
+
+    chunks
+}
+
+/// One contiguous slice of the chunk grid, written as `i/n` with `i` starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn select<'a>(&self, chunks: &'a [(u64, u64)]) -> &'a [(u64, u64)] {
+        let start = (self.index - 1) * chunks.len() / self.count;
+        let end = self.index * chunks.len() / self.count;
+        &chunks[start..end]
+    }
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected `i/n`, got `{}`", s))?;
+        let index: usize = index
+            .parse()
+            .map_err(|_| format!("invalid shard index `{}`", index))?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| format!("invalid shard count `{}`", count))?;
+
+        if index == 0 || index > count {
+            return Err(format!("shard index must be between 1 and {}", count));
+        }
+
+        Ok(Shard { index, count })
+    }
+}
+
+pub fn shard_range(
+    from_block: u64,
+    to_block: u64,
+    chunk_size: u64,
+    shard: Shard,
+) -> Result<(u64, u64)> {
+    let grid = chunk_grid(from_block, to_block, chunk_size);
+    let chunks = shard.select(&grid);
+
+    match (chunks.first(), chunks.last()) {
+        (Some(first), Some(last)) => Ok((first.0, last.1)),
+        _ => Err(Error::config(format!(
+            "shard {}/{} is empty, the range only has {} chunks",
+            shard.index,
+            shard.count,
+            grid.len()
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::flavor::{DEPOSIT_EVENT, TRANSFER_EVENT, WITHDRAW_EVENT};
+    use ethers::{
+        core::abi::{encode, Token},
+        core::types::{Bytes, ValueOrArray, H256, U256, U64},
+        utils::keccak256,
+    };
+
+    /// Contract every mock log is emitted by.
+    pub const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    /// Serves a fixed set of logs, honoring the topic0 and block range of a filter.
+    #[derive(Clone)]
+    pub struct MockSource {
+        pub logs: Vec<Log>,
+    }
+
+    #[async_trait]
+    impl LogSource for MockSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            let topic0 = match &filter.topics[0] {
+                Some(ValueOrArray::Value(Some(topic))) => Some(*topic),
+                _ => None,
+            };
+            let from_block = filter.get_from_block().unwrap_or_default();
+            let to_block = filter.get_to_block().unwrap_or(U64::MAX);
+
+            Ok(self
+                .logs
+                .iter()
+                .filter(|log| topic0.is_none() || Some(log.topics[0]) == topic0)
+                .filter(|log| {
+                    let block_number = log.block_number.unwrap();
+                    block_number >= from_block && block_number <= to_block
+                })
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn topic(address: Address) -> H256 {
+        H256::from(address)
+    }
+
+    fn signature(event: &str) -> H256 {
+        H256::from(keccak256(event))
+    }
+
+    pub fn deposit_log(owner: Address, shares: U256, block_number: u64) -> Log {
+        Log {
+            topics: vec![signature(DEPOSIT_EVENT), topic(owner), topic(owner)],
+            data: Bytes::from(encode(&[Token::Uint(shares), Token::Uint(shares)])),
+            block_number: Some(U64::from(block_number)),
+            address: VAULT.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    pub fn withdraw_log(owner: Address, shares: U256, block_number: u64) -> Log {
+        Log {
+            topics: vec![
+                signature(WITHDRAW_EVENT),
+                topic(owner),
+                topic(owner),
+                topic(owner),
+            ],
+            data: Bytes::from(encode(&[Token::Uint(shares), Token::Uint(shares)])),
+            block_number: Some(U64::from(block_number)),
+            address: VAULT.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    pub fn transfer_log(from: Address, to: Address, shares: U256, block_number: u64) -> Log {
+        Log {
+            topics: vec![signature(TRANSFER_EVENT), topic(from), topic(to)],
+            data: Bytes::from(encode(&[Token::Uint(shares)])),
+            block_number: Some(U64::from(block_number)),
+            address: VAULT.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    /// A log for an arbitrary event, with `indexed` as topics 1.. and `words` as data.
+    pub fn custom_log(event: &str, indexed: &[Address], words: &[U256], block_number: u64) -> Log {
+        let words: Vec<_> = words.iter().map(|word| Token::Uint(*word)).collect();
+
+        Log {
+            topics: std::iter::once(signature(event))
+                .chain(indexed.iter().map(|address| topic(*address)))
+                .collect(),
+            data: Bytes::from(encode(&words)),
+            block_number: Some(U64::from(block_number)),
+            address: VAULT.parse().unwrap(),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{deposit_log, transfer_log, withdraw_log, MockSource};
+    use super::*;
+    use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::core::types::{BlockNumber, U64};
+    use ethers::utils::parse_ether;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    const FROM: u64 = BLOCK_CONTRACT_DEPLOYED;
+    const TO: u64 = BLOCK_CONTRACT_DEPLOYED + 5_000;
+
+    /// Answers later ranges first, so chunks complete in reverse order.
+    #[derive(Clone)]
+    struct ReversingSource(MockSource);
+
+    #[async_trait]
+    impl LogSource for ReversingSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            let from_block = filter.get_from_block().unwrap().as_u64();
+            tokio::time::sleep(Duration::from_millis((TO - from_block) / 50)).await;
+            self.0.fetch_logs(filter).await
+        }
+    }
+
+    /// Installs filters over a `MockSource`, expiring each of the first `expire` filters before
+    /// it is read.
+    struct ExpiringFilters {
+        source: MockSource,
+        filters: Mutex<Vec<Filter>>,
+        expire: usize,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    #[async_trait]
+    impl FilterSource for ExpiringFilters {
+        async fn new_filter(&self, filter: &Filter) -> Result<U256> {
+            self.calls.lock().unwrap().push("new");
+            let mut filters = self.filters.lock().unwrap();
+            filters.push(filter.clone());
+            Ok(U256::from(filters.len() - 1))
+        }
+
+        async fn filter_logs(&self, id: U256) -> Result<Option<Vec<Log>>> {
+            self.calls.lock().unwrap().push("read");
+            if id.as_usize() < self.expire {
+                return Ok(None);
+            }
+            let filter = self.filters.lock().unwrap()[id.as_usize()].clone();
+            Ok(Some(self.source.fetch_logs(&filter).await?))
+        }
+
+        async fn uninstall_filter(&self, _id: U256) -> Result<()> {
+            self.calls.lock().unwrap().push("uninstall");
+            Ok(())
+        }
+    }
+
+    /// Ignores the end of the requested range, like a provider with indexing lag.
+    #[derive(Clone)]
+    struct LaggingSource(MockSource);
+
+    #[async_trait]
+    impl LogSource for LaggingSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            self.0
+                .fetch_logs(&filter.clone().to_block(BlockNumber::Latest))
+                .await
+        }
+    }
+
+    /// Records the block range of every request.
+    #[derive(Clone)]
+    struct RecordingSource {
+        source: MockSource,
+        ranges: Arc<Mutex<Vec<(u64, u64)>>>,
+    }
+
+    #[async_trait]
+    impl LogSource for RecordingSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            let range = (
+                filter.get_from_block().unwrap().as_u64(),
+                filter.get_to_block().unwrap().as_u64(),
+            );
+            self.ranges.lock().unwrap().push(range);
+            self.source.fetch_logs(filter).await
+        }
+    }
+
+    /// Refuses ranges of more than `max_blocks` blocks like a public node, counting requests.
+    struct RangeLimitedSource {
+        source: MockSource,
+        max_blocks: u64,
+        requests: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LogSource for RangeLimitedSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            *self.requests.lock().unwrap() += 1;
+            let blocks = filter.get_to_block().unwrap() - filter.get_from_block().unwrap() + 1;
+            if blocks.as_u64() > self.max_blocks {
+                return Err(Error::rpc("query returned more than 10000 results"));
+            }
+            self.source.fetch_logs(filter).await
+        }
+    }
+
+    /// Holds every request until `events` of them are in flight, so fetches made one after the
+    /// other never complete.
+    struct RendezvousSource {
+        source: MockSource,
+        barrier: tokio::sync::Barrier,
+    }
+
+    #[async_trait]
+    impl LogSource for RendezvousSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            self.barrier.wait().await;
+            self.source.fetch_logs(filter).await
+        }
+    }
+
+    #[test]
+    fn decodes_single_vault_logs() {
+        use crate::state::{Deposit, Transfer, Withdraw};
+
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let vault: Address = VAULT.parse().unwrap();
+        let flavor = VaultFlavor::oprtc_v1();
+        let one = parse_ether("1").unwrap();
+        let block_number = U64::from(FROM);
+        let decode = |log: Log| decode_vault_log(&log, &flavor);
+
+        assert_eq!(
+            decode(deposit_log(bob, one, FROM)).unwrap(),
+            Some(Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }))
+        );
+        assert_eq!(
+            decode(withdraw_log(bob, one, FROM)).unwrap(),
+            Some(Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: Shares(one),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }))
+        );
+        assert_eq!(
+            decode(transfer_log(bob, alice, one, FROM)).unwrap(),
+            Some(Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }))
+        );
+        // fee shares minted to the vault count as its deposit, other mints and burns not at all
+        assert_eq!(
+            decode(transfer_log(Address::zero(), vault, one, FROM)).unwrap(),
+            Some(Event::Deposit(Deposit {
+                address: vault,
+                shares: Shares(one),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }))
+        );
+        assert_eq!(
+            decode(transfer_log(Address::zero(), bob, one, FROM)).unwrap(),
+            None
+        );
+        assert_eq!(
+            decode(transfer_log(bob, Address::zero(), one, FROM)).unwrap(),
+            None
+        );
+        assert_eq!(decode(Log::default()).unwrap(), None);
+
+        let mut unnumbered = deposit_log(bob, one, FROM);
+        unnumbered.block_number = None;
+        let err = decode(unnumbered).unwrap_err();
+        assert!(matches!(err, Error::Decode { .. }), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn drops_logs_past_the_requested_range() {
+        let bob = BOB.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+
+        let source = LaggingSource(MockSource {
+            logs: vec![
+                deposit_log(bob, one, FROM),
+                withdraw_log(bob, one, TO),
+                deposit_log(bob, one, TO + 1),
+            ],
+        });
+
+        let events = fetch_events(
+            &source,
+            &VaultFlavor::oprtc_v1(),
+            VAULT.parse().unwrap(),
+            FROM,
+            TO,
+        )
+        .await
+        .unwrap()
+        .events;
+
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|evt| evt.block_number().as_u64() <= TO));
+    }
+
+    #[tokio::test]
+    async fn filter_mode_decodes_the_same_events() {
+        let bob = BOB.parse().unwrap();
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, one * 2, FROM),
+                transfer_log(bob, alice, one, FROM + 10),
+                withdraw_log(alice, one, FROM + 20),
+            ],
+        };
+        let flavor = VaultFlavor::oprtc_v1();
+        let vault = VAULT.parse().unwrap();
+
+        let filters = PersistentFilters(ExpiringFilters {
+            source: source.clone(),
+            filters: Mutex::new(vec![]),
+            expire: 1,
+            calls: Mutex::new(vec![]),
+        });
+        let via_filters = fetch_events(&filters, &flavor, vault, FROM, TO)
+            .await
+            .unwrap();
+        let via_logs = fetch_events(&source, &flavor, vault, FROM, TO)
+            .await
+            .unwrap();
+        assert_eq!(via_filters, via_logs);
+        assert_eq!(via_filters.events.len(), 3);
+
+        // the first filter expires and is recreated, every later one is read at once
+        let calls = filters.0.calls.lock().unwrap();
+        assert_eq!(calls[..5], ["new", "read", "new", "read", "uninstall"]);
+        assert_eq!(calls.len(), 3 * flavor.events.len() + 2);
+    }
+
+    #[tokio::test]
+    async fn reassembles_chunks_completing_out_of_order() {
+        let bob = BOB.parse().unwrap();
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, one * 4, FROM),
+                deposit_log(alice, one, FROM + 336),
+                transfer_log(bob, alice, one, FROM + 1_336),
+                deposit_log(alice, one * 2, FROM + 2_500),
+                withdraw_log(alice, one * 2, FROM + 2_500),
+                transfer_log(alice, bob, one, FROM + 3_900),
+                withdraw_log(bob, one, TO),
+            ],
+        };
+        let flavor = VaultFlavor::oprtc_v1();
+        let vault = VAULT.parse().unwrap();
+
+        let sequential = fetch_events(&source, &flavor, vault, FROM, TO)
+            .await
+            .unwrap()
+            .events;
+
+        let chunks = chunk_grid(FROM, TO, 1_000);
+        let concurrent = Decoded::concat(
+            fetch_chunks(&ReversingSource(source), &flavor, vault, &chunks, 4)
+                .await
+                .unwrap(),
+        )
+        .events;
+
+        assert_eq!(concurrent, sequential);
+
+        let mut sequential_state = GlobalState::new();
+        sequential_state.process_events(sequential).unwrap();
+        let mut concurrent_state = GlobalState::new();
+        concurrent_state.process_events(concurrent).unwrap();
+
+        assert_eq!(concurrent_state.state_hash(), sequential_state.state_hash());
+    }
+
+    #[test]
+    fn replays_same_block_events_in_log_order() {
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let flavor = VaultFlavor::oprtc_v1();
+        let one = parse_ether("1").unwrap();
+        let at = |mut log: Log, transaction_index: u64, log_index: u64| {
+            log.transaction_index = Some(transaction_index.into());
+            log.log_index = Some(log_index.into());
+            log
+        };
+
+        // in one block bob hands alice his shares and she withdraws them; the withdrawal's log
+        // stream is fetched first, so its log comes before the transfer's
+        let deposit = at(deposit_log(bob, one, FROM), 0, 0);
+        let transfer = at(transfer_log(bob, alice, one, FROM + 10), 3, 7);
+        let withdraw = at(withdraw_log(alice, one, FROM + 10), 5, 9);
+        let in_order = [deposit.clone(), transfer.clone(), withdraw.clone()];
+        let fetched = [deposit, withdraw, transfer];
+
+        let rewards = |logs: &[Log]| {
+            let mut state = GlobalState::new();
+            state.try_process_events(decode_logs(&flavor, logs).events)?;
+            Ok::<_, Error>(state.get_user_rewards(U64::from(FROM + 20)))
+        };
+        assert_eq!(rewards(&fetched).unwrap(), rewards(&in_order).unwrap());
+
+        // sorted by block alone, alice withdraws shares she doesn't hold yet
+        let mut by_block: Vec<Event> = fetched
+            .iter()
+            .filter_map(|log| decode_vault_log(log, &flavor).unwrap())
+            .collect();
+        by_block.sort_by_key(|evt| evt.block_number());
+        assert!(GlobalState::new().try_process_events(by_block).is_err());
+    }
+
+    #[tokio::test]
+    async fn halves_ranges_the_provider_refuses() {
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let one = parse_ether("1").unwrap();
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, one, FROM),
+                transfer_log(bob, alice, one, FROM + 999),
+                deposit_log(alice, one, FROM + 1_000),
+                withdraw_log(alice, one, TO),
+            ],
+        };
+        async fn fetch<S: LogSource>(source: &S) -> Result<Decoded> {
+            fetch_events(
+                source,
+                &VaultFlavor::oprtc_v1(),
+                VAULT.parse().unwrap(),
+                FROM,
+                TO,
+            )
+            .await
+        }
+
+        let unbounded = fetch(&source).await.unwrap();
+        let limited = RangeLimitedSource {
+            source: source.clone(),
+            max_blocks: 1_000,
+            requests: Mutex::new(0),
+        };
+        assert_eq!(fetch(&limited).await.unwrap(), unbounded);
+        // 5001 blocks go down to eight ranges of at most 626 blocks for each of three events,
+        // after seven refusals each
+        assert_eq!(*limited.requests.lock().unwrap(), 3 * (8 + 7));
+
+        // a single block refused is an error, there is nothing left to split
+        let refusing = RangeLimitedSource {
+            source,
+            max_blocks: 0,
+            requests: Mutex::new(0),
+        };
+        let err = fetch(&refusing).await.unwrap_err();
+        assert!(
+            err.to_string().contains("more than 10000 results"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn leaves_out_logs_not_yet_in_a_block() {
+        let bob = BOB.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let mut pending = deposit_log(bob, one, FROM + 1);
+        pending.block_number = None;
+
+        let decoded = decode_logs(
+            &VaultFlavor::oprtc_v1(),
+            &[deposit_log(bob, one, FROM), pending],
+        );
+        assert_eq!(decoded.events.len(), 1);
+        assert_eq!(decoded.unconfirmed, 1);
+        assert_eq!(decoded.undecodable, 0);
+        assert_eq!(decoded.logs, 2);
+        assert_eq!(decoded.strict().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetches_every_event_at_once() {
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let one = parse_ether("1").unwrap();
+        let source = MockSource {
+            logs: vec![
+                withdraw_log(bob, one, FROM + 20),
+                transfer_log(bob, alice, one, FROM + 10),
+                deposit_log(bob, one, FROM),
+                deposit_log(alice, one, FROM + 10),
+            ],
+        };
+        let flavor = VaultFlavor::oprtc_v1();
+        let vault = VAULT.parse().unwrap();
+
+        let mut sequential = vec![];
+        for signature in flavor.signatures() {
+            let filter = Filter::new()
+                .address(vault)
+                .event(signature)
+                .from_block(FROM)
+                .to_block(TO);
+            sequential.extend(source.fetch_logs(&filter).await.unwrap());
+        }
+
+        let rendezvous = RendezvousSource {
+            source,
+            barrier: tokio::sync::Barrier::new(flavor.signatures().len()),
+        };
+        let concurrent = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch_events(&rendezvous, &flavor, vault, FROM, TO),
+        )
+        .await
+        .expect("the events should be fetched concurrently")
+        .unwrap();
+        assert_eq!(concurrent, decode_logs(&flavor, &sequential));
+        assert_eq!(concurrent.events.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn requests_one_window_per_grid_chunk() {
+        let bob = BOB.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let to_block = FROM + 12_000;
+        let source = RecordingSource {
+            source: MockSource {
+                logs: vec![
+                    deposit_log(bob, one, FROM),
+                    withdraw_log(bob, one, to_block),
+                ],
+            },
+            ranges: Arc::default(),
+        };
+        let flavor = VaultFlavor::oprtc_v1();
+
+        let chunks = chunk_grid(FROM, to_block, CHUNK_SIZE);
+        let fetched = fetch_chunks(&source, &flavor, VAULT.parse().unwrap(), &chunks, 2)
+            .await
+            .unwrap();
+        assert_eq!(Decoded::concat(fetched).events.len(), 2);
+
+        // 17564663 is off the grid, so the first window ends at the next multiple of 5000
+        let windows = [
+            (FROM, 17_564_999),
+            (17_565_000, 17_569_999),
+            (17_570_000, 17_574_999),
+            (17_575_000, to_block),
+        ];
+        assert_eq!(chunks, windows);
+        let mut ranges = source.ranges.lock().unwrap().clone();
+        ranges.sort_unstable();
+        let every_event: Vec<_> = windows.iter().flat_map(|window| [*window; 3]).collect();
+        assert_eq!(ranges, every_event);
+    }
+}
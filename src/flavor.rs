@@ -0,0 +1,703 @@
+use crate::error::{bail, ensure, Error, Result};
+use crate::rebase::RebaseConfig;
+use crate::state::{Deposit, Emission, Event, Transfer, Withdraw};
+use crate::top_ups::TopUpConfig;
+use crate::units::Shares;
+use ethers::{
+    core::types::{Address, Log, H256, U256},
+    utils::{keccak256, parse_ether},
+};
+use serde::Deserialize;
+use std::{collections::HashSet, fs, path::Path, str::FromStr};
+
+pub const DEPOSIT_EVENT: &str = "Deposit(address,address,uint256,uint256)";
+pub const WITHDRAW_EVENT: &str = "Withdraw(address,address,address,uint256,uint256)";
+pub const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Deposit,
+    Withdraw,
+    Transfer,
+}
+
+/// Where an event keeps the fields the accounting needs.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventLayout {
+    pub signature: String,
+    pub kind: EventKind,
+    /// Topic holding the accounting address, the sender for transfers
+    pub address_topic: usize,
+    /// Topic holding the receiver, transfers only
+    #[serde(default)]
+    pub to_topic: Option<usize>,
+    /// Index of the 32-byte data word holding the shares
+    pub shares_word: usize,
+}
+
+impl EventLayout {
+    fn new(signature: &str, kind: EventKind, address_topic: usize, shares_word: usize) -> Self {
+        EventLayout {
+            signature: signature.to_string(),
+            kind,
+            address_topic,
+            to_topic: None,
+            shares_word,
+        }
+    }
+
+    pub fn topic0(&self) -> H256 {
+        H256::from(keccak256(&self.signature))
+    }
+
+    fn topic(&self, log: &Log, index: usize) -> Result<Address> {
+        log.topics
+            .get(index)
+            .map(|topic| Address::from(*topic))
+            .ok_or_else(|| Error::decode(format!("{} log has no topic {}", self.signature, index)))
+    }
+
+    /// Mints and burns are skipped, they are already covered by deposits and withdrawals. Mints
+    /// to the vault itself, such as fee shares, come without a deposit and count as one.
+    /// Logs too short for the layout are an error.
+    fn decode(&self, log: &Log) -> Result<Option<Event>> {
+        let address = self.topic(log, self.address_topic)?;
+        let word = self.shares_word * 32;
+        let shares = log
+            .data
+            .get(word..word + 32)
+            .map(U256::from)
+            .ok_or_else(|| {
+                Error::decode(format!(
+                    "{} log has no data word {}",
+                    self.signature, self.shares_word
+                ))
+            })?;
+        let block_number = log
+            .block_number
+            .ok_or_else(|| Error::decode(format!("{} log has no block number", self.signature)))?;
+        let transaction_index = log.transaction_index.unwrap_or_default().as_u64();
+        let log_index = log.log_index.unwrap_or_default().as_u64();
+
+        let event = match self.kind {
+            EventKind::Deposit => Event::Deposit(Deposit {
+                address,
+                shares: Shares(shares),
+                block_number,
+                transaction_index,
+                log_index,
+            }),
+            EventKind::Withdraw => Event::Withdrawal(Withdraw {
+                address,
+                shares: Shares(shares),
+                block_number,
+                transaction_index,
+                log_index,
+            }),
+            EventKind::Transfer => {
+                let to = self.topic(log, self.to_topic.expect("transfer has a receiver"))?;
+
+                if address.is_zero() && !to.is_zero() && to == log.address {
+                    return Ok(Some(Event::Deposit(Deposit {
+                        address: to,
+                        shares: Shares(shares),
+                        block_number,
+                        transaction_index,
+                        log_index,
+                    })));
+                }
+
+                if address.is_zero() || to.is_zero() {
+                    return Ok(None);
+                }
+
+                Event::Transfer(Transfer {
+                    from: address,
+                    to,
+                    shares: Shares(shares),
+                    block_number,
+                    transaction_index,
+                    log_index,
+                })
+            }
+        };
+
+        Ok(Some(event))
+    }
+}
+
+/// Event layouts in effect from `from_block` until the next segment, in place of the flavor's
+/// own `events`. Vaults whose upgrades changed their events list one per upgrade.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LayoutSegment {
+    pub from_block: u64,
+    /// Shown in diagnostics, `from block N` when left out
+    #[serde(default)]
+    pub name: Option<String>,
+    pub events: Vec<EventLayout>,
+}
+
+/// Declarative description of the events a vault emits and how each one maps onto an `Event`.
+/// Built-in flavors live in code, custom ones are read from TOML.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct VaultFlavor {
+    pub name: String,
+    pub events: Vec<EventLayout>,
+    /// Tokens emitted per second, for vaults that emit by time instead of one token per block
+    #[serde(default)]
+    pub emission_per_second: Option<String>,
+    /// Events emitting rebased amounts instead of shares, for rebasing share tokens
+    #[serde(default)]
+    pub rebase: Option<RebaseConfig>,
+    /// Layouts taking over from later blocks, in block order
+    #[serde(default)]
+    pub segments: Vec<LayoutSegment>,
+    /// Event announcing top-ups, for vaults emitting what the treasury pays in
+    #[serde(default)]
+    pub top_ups: Option<TopUpConfig>,
+}
+
+impl VaultFlavor {
+    pub fn erc4626() -> VaultFlavor {
+        VaultFlavor {
+            name: "erc4626".to_string(),
+            events: vec![
+                EventLayout::new(DEPOSIT_EVENT, EventKind::Deposit, 2, 1),
+                EventLayout::new(WITHDRAW_EVENT, EventKind::Withdraw, 3, 1),
+                EventLayout {
+                    to_topic: Some(2),
+                    ..EventLayout::new(TRANSFER_EVENT, EventKind::Transfer, 1, 0)
+                },
+            ],
+            emission_per_second: None,
+            rebase: None,
+            segments: vec![],
+            top_ups: None,
+        }
+    }
+
+    /// The OPRTC lending vault, which emits the standard ERC-4626 events.
+    pub fn oprtc_v1() -> VaultFlavor {
+        VaultFlavor {
+            name: "oprtc-v1".to_string(),
+            ..VaultFlavor::erc4626()
+        }
+    }
+
+    pub fn builtin(name: &str) -> Option<VaultFlavor> {
+        match name {
+            "erc4626" => Some(VaultFlavor::erc4626()),
+            "oprtc-v1" => Some(VaultFlavor::oprtc_v1()),
+            _ => None,
+        }
+    }
+
+    pub fn from_toml(contents: &str) -> Result<VaultFlavor> {
+        let flavor: VaultFlavor =
+            toml::from_str(contents).map_err(|err| Error::config(err.to_string()))?;
+        flavor.validate()?;
+        Ok(flavor)
+    }
+
+    pub fn load(path: &Path) -> Result<VaultFlavor> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| Error::io(format!("failed to read flavor {}", path.display()), err))?;
+
+        VaultFlavor::from_toml(&contents).map_err(|err| {
+            Error::config(format!("invalid flavor {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn emission(&self) -> Result<Emission> {
+        match &self.emission_per_second {
+            Some(_) if self.top_ups.is_some() => Err(Error::config(
+                "emission_per_second and top_ups are two emissions, give one",
+            )),
+            None if self.top_ups.is_some() => Ok(Emission::TopUps),
+            Some(rate) => {
+                let rate = parse_ether(rate).map_err(|_| {
+                    Error::config(format!(
+                        "emission_per_second `{}` is not a token amount",
+                        rate
+                    ))
+                })?;
+                Ok(Emission::PerSecond(rate))
+            }
+            None => Ok(Emission::PerBlock),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.events.is_empty(),
+            Error::config,
+            "flavor {} lists no events",
+            self.name
+        );
+        self.emission()?;
+        if let Some(rebase) = &self.rebase {
+            rebase.validate()?;
+        }
+        if let Some(top_ups) = &self.top_ups {
+            top_ups.validate()?;
+        }
+
+        validate_layouts(&self.events)?;
+        for (i, segment) in self.segments.iter().enumerate() {
+            ensure!(
+                i == 0 || self.segments[i - 1].from_block < segment.from_block,
+                Error::config,
+                "segment from block {} must start after the one before it",
+                segment.from_block
+            );
+            ensure!(
+                !segment.events.is_empty(),
+                Error::config,
+                "segment from block {} lists no events",
+                segment.from_block
+            );
+            validate_layouts(&segment.events).map_err(|err| {
+                Error::config(format!("segment from block {}", segment.from_block)).caused_by(err)
+            })?;
+        }
+
+        Ok(())
+    }
+    /// The segment in effect at `block`, `None` before the first one.
+    fn segment_at(&self, block: u64) -> Option<usize> {
+        self.segments
+            .iter()
+            .rposition(|segment| segment.from_block <= block)
+    }
+
+    /// The layouts logs of `block` are decoded with.
+    pub fn layouts_at(&self, block: u64) -> &[EventLayout] {
+        match self.segment_at(block) {
+            Some(i) => &self.segments[i].events,
+            None => &self.events,
+        }
+    }
+
+    /// Blocks from which a segment's layouts take over.
+    pub fn boundaries(&self) -> Vec<u64> {
+        self.segments
+            .iter()
+            .map(|segment| segment.from_block)
+            .collect()
+    }
+
+    /// Every event signature of the flavor and its segments, each once, which is what gets
+    /// fetched.
+    pub fn signatures(&self) -> Vec<&str> {
+        let mut signatures: Vec<&str> = vec![];
+        let layouts = self
+            .events
+            .iter()
+            .chain(self.segments.iter().flat_map(|segment| &segment.events));
+        for layout in layouts {
+            if !signatures.contains(&layout.signature.as_str()) {
+                signatures.push(&layout.signature);
+            }
+        }
+        signatures
+    }
+
+    /// How many of `events` were decoded under the flavor's own layouts and under each
+    /// segment's, by name.
+    pub fn layout_counts(&self, events: &[Event]) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = std::iter::once(self.name.clone())
+            .chain(self.segments.iter().map(|segment| {
+                segment
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("from block {}", segment.from_block))
+            }))
+            .map(|name| (name, 0))
+            .collect();
+        for evt in events {
+            let i = self
+                .segment_at(evt.block_number().as_u64())
+                .map_or(0, |i| i + 1);
+            counts[i].1 += 1;
+        }
+        counts
+    }
+
+    /// Decodes a log into an event with the layouts in effect at its block, `None` for logs
+    /// this flavor doesn't account for.
+    pub fn decode(&self, log: &Log) -> Result<Option<Event>> {
+        let Some(topic0) = log.topics.first() else {
+            return Ok(None);
+        };
+        // logs without a block number fail to decode whichever layout they meet
+        let layouts = self.layouts_at(log.block_number.unwrap_or_default().as_u64());
+
+        match layouts.iter().find(|layout| layout.topic0() == *topic0) {
+            Some(layout) => layout.decode(log),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Checks one set of layouts, the flavor's own or a segment's.
+fn validate_layouts(layouts: &[EventLayout]) -> Result<()> {
+    let mut signatures = HashSet::new();
+
+    for layout in layouts {
+        ensure!(
+            signatures.insert(&layout.signature),
+            Error::config,
+            "{} is listed twice",
+            layout.signature
+        );
+        ensure!(
+            (1..=3).contains(&layout.address_topic),
+            Error::config,
+            "{}: address_topic must be between 1 and 3",
+            layout.signature
+        );
+        match (layout.kind, layout.to_topic) {
+            (EventKind::Transfer, Some(to_topic)) => ensure!(
+                (1..=3).contains(&to_topic) && to_topic != layout.address_topic,
+                Error::config,
+                "{}: to_topic must be between 1 and 3 and differ from address_topic",
+                layout.signature
+            ),
+            (EventKind::Transfer, None) => {
+                bail!(
+                    Error::config,
+                    "{}: transfers need a to_topic",
+                    layout.signature
+                )
+            }
+            (_, Some(_)) => {
+                bail!(
+                    Error::config,
+                    "{}: only transfers have a to_topic",
+                    layout.signature
+                )
+            }
+            (_, None) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// A built-in flavor name, or a path to a TOML file describing a custom one.
+impl FromStr for VaultFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match VaultFlavor::builtin(s) {
+            Some(flavor) => Ok(flavor),
+            None if s.ends_with(".toml") => VaultFlavor::load(Path::new(s))
+                .map_err(|err| format!("{:#}", eyre::Report::new(err))),
+            None => Err(format!(
+                "unknown flavor `{}`, expected erc4626, oprtc-v1 or a .toml file",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::fetch_events;
+    use crate::fetch::mock::{custom_log, deposit_log, transfer_log, withdraw_log, MockSource};
+    use crate::state::BLOCK_CONTRACT_DEPLOYED;
+    use ethers::core::types::U64;
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    const SLASHING: &str = r#"
+        name = "slashing"
+        emission_per_second = "0.5"
+
+        [[events]]
+        signature = "Deposit(address,address,uint256,uint256)"
+        kind = "deposit"
+        address_topic = 2
+        shares_word = 1
+
+        [[events]]
+        signature = "Slashed(address,uint256,uint256,uint256)"
+        kind = "withdraw"
+        address_topic = 1
+        shares_word = 2
+    "#;
+
+    #[test]
+    fn builtin_flavors_match_legacy_decoding() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let block = U64::from(BLOCK_CONTRACT_DEPLOYED);
+
+        let logs = [
+            deposit_log(bob, one * 2, BLOCK_CONTRACT_DEPLOYED),
+            withdraw_log(bob, one, BLOCK_CONTRACT_DEPLOYED),
+            transfer_log(bob, alice, one, BLOCK_CONTRACT_DEPLOYED),
+            transfer_log(Address::zero(), alice, one, BLOCK_CONTRACT_DEPLOYED),
+        ];
+        let expected = vec![
+            Some(Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 2),
+                block_number: block,
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            Some(Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: Shares(one),
+                block_number: block,
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            Some(Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number: block,
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            None,
+        ];
+
+        for flavor in [VaultFlavor::erc4626(), VaultFlavor::oprtc_v1()] {
+            let decoded: Vec<_> = logs.iter().map(|log| flavor.decode(log).unwrap()).collect();
+            assert_eq!(decoded, expected, "{}", flavor.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_toml_flavor_decodes_fixture_logs() {
+        let flavor = VaultFlavor::from_toml(SLASHING).unwrap();
+        assert_eq!(flavor.events[1].kind, EventKind::Withdraw);
+        assert_eq!(
+            flavor.emission().unwrap(),
+            Emission::PerSecond(parse_ether("0.5").unwrap())
+        );
+        // a second emission on top
+        let err = VaultFlavor::from_toml(&format!("{}\n[top_ups]\n", SLASHING)).unwrap_err();
+        assert!(err.to_string().contains("two emissions"));
+
+        let bob: Address = BOB.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let from_block = BLOCK_CONTRACT_DEPLOYED;
+
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, one * 3, from_block),
+                // `Slashed(account, assets, penalty, shares)`
+                custom_log(
+                    "Slashed(address,uint256,uint256,uint256)",
+                    &[bob],
+                    &[one * 2, one / 2, one],
+                    from_block + 10,
+                ),
+                // not part of the flavor, so never fetched
+                transfer_log(bob, ALICE.parse().unwrap(), one, from_block + 20),
+            ],
+        };
+
+        let events = fetch_events(
+            &source,
+            &flavor,
+            VAULT.parse().unwrap(),
+            from_block,
+            from_block + 100,
+        )
+        .await
+        .unwrap()
+        .events;
+
+        assert_eq!(
+            events,
+            vec![
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(one * 3),
+                    block_number: U64::from(from_block),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Withdrawal(Withdraw {
+                    address: bob,
+                    shares: Shares(one),
+                    block_number: U64::from(from_block + 10),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_fee_mints_to_the_vault_as_deposits() {
+        let vault: Address = VAULT.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let flavor = VaultFlavor::oprtc_v1();
+
+        let mut fee_mint = transfer_log(Address::zero(), vault, one, BLOCK_CONTRACT_DEPLOYED);
+        fee_mint.address = vault;
+        let mut user_mint = transfer_log(
+            Address::zero(),
+            ALICE.parse().unwrap(),
+            one,
+            BLOCK_CONTRACT_DEPLOYED,
+        );
+        user_mint.address = vault;
+
+        assert_eq!(
+            flavor.decode(&fee_mint).unwrap(),
+            Some(Event::Deposit(Deposit {
+                address: vault,
+                shares: Shares(one),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            }))
+        );
+        assert_eq!(flavor.decode(&user_mint).unwrap(), None);
+    }
+
+    /// v2 moved the owner of deposits to the first topic and added a referrer.
+    const UPGRADED: &str = r#"
+        name = "upgraded"
+
+        [[events]]
+        signature = "Deposit(address,address,uint256,uint256)"
+        kind = "deposit"
+        address_topic = 2
+        shares_word = 1
+
+        [[events]]
+        signature = "Withdraw(address,address,address,uint256,uint256)"
+        kind = "withdraw"
+        address_topic = 3
+        shares_word = 1
+
+        [[segments]]
+        from_block = UPGRADE
+        name = "v2"
+
+        [[segments.events]]
+        signature = "Deposit(address,address,address,uint256,uint256)"
+        kind = "deposit"
+        address_topic = 1
+        shares_word = 1
+
+        [[segments.events]]
+        signature = "Withdraw(address,address,address,uint256,uint256)"
+        kind = "withdraw"
+        address_topic = 3
+        shares_word = 1
+    "#;
+
+    #[tokio::test]
+    async fn decodes_each_side_of_a_layout_switch() {
+        let upgrade = BLOCK_CONTRACT_DEPLOYED + 1_000;
+        let flavor =
+            VaultFlavor::from_toml(&UPGRADED.replace("UPGRADE", &upgrade.to_string())).unwrap();
+        assert_eq!(flavor.boundaries(), [upgrade]);
+        assert_eq!(flavor.signatures().len(), 3);
+
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let router = Address::from_low_u64_be(0x1234);
+        let referrer = Address::from_low_u64_be(0x4ef);
+        let one = parse_ether("1").unwrap();
+        let v1_deposit = "Deposit(address,address,uint256,uint256)";
+        let v2_deposit = "Deposit(address,address,address,uint256,uint256)";
+
+        let source = MockSource {
+            logs: vec![
+                custom_log(v1_deposit, &[router, bob], &[one, one], upgrade - 1),
+                // v2 logs only decode from the switch on, v1 ones only before it
+                custom_log(
+                    v2_deposit,
+                    &[bob, router, referrer],
+                    &[one, one],
+                    upgrade - 1,
+                ),
+                custom_log(
+                    v2_deposit,
+                    &[alice, router, referrer],
+                    &[one, one * 2],
+                    upgrade,
+                ),
+                custom_log(v1_deposit, &[router, alice], &[one, one], upgrade + 1),
+                withdraw_log(alice, one, upgrade + 2),
+                withdraw_log(bob, one, upgrade + 3),
+            ],
+        };
+        let events = fetch_events(
+            &source,
+            &flavor,
+            VAULT.parse().unwrap(),
+            BLOCK_CONTRACT_DEPLOYED,
+            upgrade + 100,
+        )
+        .await
+        .unwrap()
+        .events;
+
+        let deposits: Vec<_> = events
+            .iter()
+            .filter_map(|evt| match evt {
+                Event::Deposit(deposit) => Some((deposit.address, deposit.shares)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deposits, [(bob, Shares(one)), (alice, Shares(one * 2))]);
+        assert_eq!(
+            flavor.layout_counts(&events),
+            [("upgraded".to_string(), 1), ("v2".to_string(), 3)]
+        );
+
+        let mut state = crate::state::GlobalState::new();
+        state.try_process_events(events).unwrap();
+        assert_eq!(state.total_shares_staked(), one);
+
+        let earlier = r#"
+            [[segments]]
+            from_block = 10
+
+            [[segments.events]]
+            signature = "Deposit(address,address,uint256,uint256)"
+            kind = "deposit"
+            address_topic = 2
+            shares_word = 1
+        "#;
+        let unordered = UPGRADED.replace("UPGRADE", "20") + earlier;
+        let err = VaultFlavor::from_toml(&unordered).unwrap_err();
+        assert!(err.to_string().contains("must start after"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_transfers_without_a_receiver() {
+        let contents = r#"
+            name = "broken"
+
+            [[events]]
+            signature = "Transfer(address,address,uint256)"
+            kind = "transfer"
+            address_topic = 1
+            shares_word = 0
+        "#;
+
+        let err = VaultFlavor::from_toml(contents).unwrap_err();
+        assert!(err.to_string().contains("to_topic"));
+    }
+}
@@ -0,0 +1,264 @@
+//! How current the data behind an output is. Every output is stamped with it, so numbers from
+//! a cache that stopped syncing can't pass for fresh ones.
+
+use crate::context::EvaluationContext;
+use crate::error::{bail, ensure, Error, Result};
+use chrono::NaiveDateTime;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::{fmt, time::Duration};
+
+/// What outputs say in place of the age when no provider was read.
+pub const OFFLINE: &str = "freshness unknown (offline)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Freshness {
+    /// Last block the data covers
+    pub horizon_block: u64,
+    /// Timestamp of the horizon block, `None` offline
+    pub horizon_timestamp: Option<u64>,
+    /// Chain head when the run started, `None` offline
+    pub head_block: Option<u64>,
+    /// Seconds the horizon is behind the head, `None` offline
+    pub age_seconds: Option<u64>,
+}
+
+impl Freshness {
+    /// Data up to `horizon`, while the chain is at `head`.
+    pub fn online(horizon: &EvaluationContext, head: &EvaluationContext) -> Freshness {
+        Freshness {
+            horizon_block: horizon.block.as_u64(),
+            horizon_timestamp: Some(horizon.timestamp),
+            head_block: Some(head.block.as_u64()),
+            age_seconds: Some(head.timestamp.saturating_sub(horizon.timestamp)),
+        }
+    }
+
+    /// Data up to `horizon_block`, read without a provider.
+    pub fn offline(horizon_block: u64) -> Freshness {
+        Freshness {
+            horizon_block,
+            horizon_timestamp: None,
+            head_block: None,
+            age_seconds: None,
+        }
+    }
+
+    /// Fails with `Error::Stale` unless the horizon is known to be at most `max_staleness`
+    /// behind the head.
+    pub fn ensure_within(&self, max_staleness: Duration) -> Result<()> {
+        let Some(age) = self.age_seconds else {
+            bail!(
+                Error::stale,
+                "block {} has {}, --max-staleness can't be checked",
+                self.horizon_block,
+                OFFLINE
+            );
+        };
+        ensure!(
+            age <= max_staleness.as_secs(),
+            Error::stale,
+            "block {} is {} behind the head, more than the {} --max-staleness allows",
+            self.horizon_block,
+            format_age(age),
+            format_age(max_staleness.as_secs())
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for Freshness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "data horizon: block {}", self.horizon_block)?;
+        match (self.horizon_timestamp, self.head_block, self.age_seconds) {
+            (Some(timestamp), Some(head_block), Some(age)) => {
+                let date = NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+                    .map(|date| date.format(" (%Y-%m-%d %H:%M UTC)").to_string())
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "{}, {} behind head block {}",
+                    date,
+                    format_age(age),
+                    head_block
+                )
+            }
+            _ => write!(f, ", {}", OFFLINE),
+        }
+    }
+}
+
+/// The numbers, `null` offline, and the banner line spelling them out.
+impl Serialize for Freshness {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut freshness = serializer.serialize_struct("Freshness", 5)?;
+        freshness.serialize_field("horizon_block", &self.horizon_block)?;
+        freshness.serialize_field("horizon_timestamp", &self.horizon_timestamp)?;
+        freshness.serialize_field("head_block", &self.head_block)?;
+        freshness.serialize_field("age_seconds", &self.age_seconds)?;
+        freshness.serialize_field("banner", &self.to_string())?;
+        freshness.end()
+    }
+}
+
+/// Seconds as the two largest of days, hours, minutes and seconds, e.g. `3d 4h`.
+fn format_age(seconds: u64) -> String {
+    let parts = [
+        (seconds / 86_400, "d"),
+        (seconds / 3_600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let first = parts.iter().position(|(n, _)| *n > 0).unwrap_or(3);
+
+    parts[first..]
+        .iter()
+        .take(2)
+        .filter(|(n, _)| *n > 0 || first == 3)
+        .map(|(n, unit)| format!("{}{}", n, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses `--max-staleness` as a number of seconds, minutes, hours or days, e.g. `90m`.
+pub fn parse_max_staleness(value: &str) -> Result<Duration, String> {
+    let Some(unit) = value.chars().last() else {
+        return Err("expected a duration such as 1h".to_string());
+    };
+    let number = &value[..value.len() - unit.len_utf8()];
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3_600,
+        'd' => 86_400,
+        _ => return Err(format!("`{}` should end in s, m, h or d", value)),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("`{}` is not a whole number of {}", value, unit))?;
+
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::OutputFormat;
+    use crate::report::{Report, ReportRow};
+    use crate::rounding::Rounding;
+    use crate::utilization::{render_utilization, UtilizationBucket};
+    use ethers::core::types::{U256, U64};
+
+    fn context(block: u64, timestamp: u64) -> EvaluationContext {
+        EvaluationContext {
+            block: U64::from(block),
+            timestamp,
+            chain_id: 1,
+        }
+    }
+
+    #[test]
+    fn fails_past_the_max_staleness_and_offline() {
+        let one_hour = parse_max_staleness("1h").unwrap();
+        assert_eq!(one_hour, Duration::from_secs(3_600));
+        assert_eq!(
+            parse_max_staleness("90m").unwrap(),
+            Duration::from_secs(5_400)
+        );
+        assert!(parse_max_staleness("1w").is_err());
+        assert!(parse_max_staleness("h").is_err());
+
+        let horizon = context(17_600_000, 1_688_212_800);
+        let fresh = Freshness::online(&horizon, &context(17_600_300, 1_688_216_400));
+        assert!(fresh.ensure_within(one_hour).is_ok());
+
+        // a cache a week behind
+        let week_old = Freshness::online(&horizon, &context(17_650_000, 1_688_817_600));
+        let err = week_old.ensure_within(one_hour).unwrap_err();
+        assert_eq!(err.exit_code(), 8);
+        assert_eq!(
+            err.to_string(),
+            "block 17600000 is 7d behind the head, more than the 1h --max-staleness allows"
+        );
+
+        let err = Freshness::offline(17_600_000)
+            .ensure_within(one_hour)
+            .unwrap_err();
+        assert_eq!(err.exit_code(), 8);
+        assert!(err.to_string().contains(OFFLINE));
+    }
+
+    #[test]
+    fn stamps_every_output_format() {
+        let online = Freshness::online(
+            &context(17_600_000, 1_688_212_800),
+            &context(17_600_300, 1_688_216_520),
+        );
+        let banner =
+            "data horizon: block 17600000 (2023-07-01 12:00 UTC), 1h 2m behind head block 17600300";
+        assert_eq!(online.to_string(), banner);
+
+        let offline = Freshness::offline(17_600_000);
+        assert_eq!(
+            offline.to_string(),
+            "data horizon: block 17600000, freshness unknown (offline)"
+        );
+
+        let buckets = [UtilizationBucket {
+            from_block: 17_590_001,
+            to_block: 17_600_000,
+            emitted: U256::from(10),
+            attributed: U256::from(5),
+        }];
+        for freshness in [online, offline] {
+            let json: serde_json::Value = serde_json::from_str(&render_utilization(
+                &buckets,
+                OutputFormat::Json,
+                &freshness,
+            ))
+            .unwrap();
+            assert_eq!(json["freshness"]["horizon_block"], 17_600_000);
+            assert_eq!(json["freshness"]["banner"], freshness.to_string());
+
+            let csv = render_utilization(&buckets, OutputFormat::Csv, &freshness);
+            assert_eq!(
+                csv.lines().nth(1),
+                Some(format!("# {}", freshness).as_str())
+            );
+
+            let markdown = render_utilization(&buckets, OutputFormat::Markdown, &freshness);
+            assert_eq!(
+                markdown.lines().nth(1),
+                Some(format!("<!-- {} -->", freshness).as_str())
+            );
+        }
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_utilization(&buckets, OutputFormat::Json, &offline))
+                .unwrap();
+        assert_eq!(json["freshness"]["age_seconds"], serde_json::Value::Null);
+
+        let report = Report {
+            total_rewards_expected: U256::from(10),
+            total_rewards_given: U256::from(10),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 18,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            rows: vec![ReportRow {
+                label: "0x0000000000000000000000000000000000000b0b".to_string(),
+                rewards: U256::from(10),
+                bonus: U256::zero(),
+                note: None,
+                reason: None,
+            }],
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: Some(online),
+            bonus_budget: None,
+            lineage: None,
+        };
+        assert!(report.to_string().starts_with(&format!("{}\nrank", banner)));
+    }
+}
@@ -0,0 +1,56 @@
+use clap::{Arg, ArgAction, Command};
+use serde_json::{json, Value};
+
+/// The tree of `command` and its subcommands as JSON, with every flag's kind, values, default
+/// and environment variable. Built from the parser itself, so it can't drift from it.
+pub fn help_json(command: &Command) -> Value {
+    json!({
+        "name": command.get_name(),
+        "about": command.get_about().map(|about| about.to_string()),
+        "args": command
+            .get_arguments()
+            .filter(|arg| !arg.is_hide_set())
+            .map(arg_json)
+            .collect::<Vec<_>>(),
+        "subcommands": command
+            .get_subcommands()
+            .filter(|subcommand| !subcommand.is_hide_set())
+            .map(help_json)
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn arg_json(arg: &Arg) -> Value {
+    let kind = match arg.get_action() {
+        ArgAction::SetTrue | ArgAction::SetFalse => "flag",
+        ArgAction::Count => "count",
+        ArgAction::Append => "list",
+        _ => "value",
+    };
+
+    json!({
+        "id": arg.get_id().as_str(),
+        "long": arg.get_long(),
+        "short": arg.get_short().map(String::from),
+        "help": arg.get_help().map(|help| help.to_string()),
+        "kind": kind,
+        "value_names": arg
+            .get_value_names()
+            .map(|names| names.iter().map(|name| name.as_str()).collect::<Vec<_>>()),
+        "possible_values": arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect::<Vec<_>>(),
+        "default": arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        "env": arg.get_env().map(|env| env.to_string_lossy().into_owned()),
+        "required": arg.is_required_set(),
+        "global": arg.is_global_set(),
+        "positional": arg.is_positional(),
+    })
+}
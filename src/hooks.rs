@@ -0,0 +1,336 @@
+//! Post-processing hooks: WASM modules that adjust the rewards list before it is reported, for
+//! program-specific rules that don't belong in the accounting.
+//!
+//! A hook module exports:
+//!
+//! - `memory`, its linear memory
+//! - `alloc(len: i32) -> i32`, returning where `len` bytes of input may be written
+//! - `process(ptr: i32, len: i32) -> i64`, taking the input written at `ptr` and returning the
+//!   output as `out_ptr << 32 | out_len`
+//!
+//! The input is `{"schema_version": 1, "rows": [{"label": "0x..", "rewards": "<wei>"}]}`, the
+//! output a JSON object with the adjusted `rows` in the same shape, other fields ignored. Labels
+//! are addresses, or `0x..#name` for sub-accounts, and amounts decimal strings of wei. A hook
+//! runs with a fixed fuel budget, so one that loops forever fails instead of hanging the run.
+//! `examples/hooks/identity.wat` is the smallest module following this ABI.
+
+use crate::output::{serialize_u256, versioned_json};
+use crate::report::{Report, ReportRow};
+use ethers::core::types::U256;
+use eyre::{ensure, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+/// Instructions a hook may execute on one report.
+#[cfg(feature = "wasm-hooks")]
+pub const HOOK_FUEL: u64 = 1_000_000_000;
+
+/// What a hook's output must respect to replace the report rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HookPolicy {
+    /// Accept labels the input didn't list
+    pub allow_new_labels: bool,
+    /// How much the total may grow, in basis points of the input total
+    pub max_increase_bps: u32,
+}
+
+#[derive(Serialize)]
+struct InputRow<'a> {
+    label: &'a str,
+    #[serde(serialize_with = "serialize_u256")]
+    rewards: U256,
+}
+
+#[derive(Deserialize)]
+struct Output {
+    rows: Vec<OutputRow>,
+}
+
+#[derive(Deserialize)]
+struct OutputRow {
+    label: String,
+    rewards: String,
+}
+
+/// The canonical rewards list a hook receives.
+pub fn hook_input(rows: &[ReportRow]) -> Vec<u8> {
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|row| InputRow {
+            label: &row.label,
+            rewards: row.rewards,
+        })
+        .collect();
+    versioned_json(&rows).into_bytes()
+}
+
+/// Parses a hook's output and checks it against `policy`, returning the rows to report. Notes
+/// and bonuses of the input rows carry over by label.
+pub fn validate_output(
+    input: &[ReportRow],
+    output: &[u8],
+    policy: HookPolicy,
+) -> Result<Vec<ReportRow>> {
+    let output: Output = serde_json::from_slice(output).wrap_err("malformed hook output")?;
+    let inputs: HashMap<&str, &ReportRow> =
+        input.iter().map(|row| (row.label.as_str(), row)).collect();
+
+    let mut seen = HashSet::new();
+    let mut rows = vec![];
+    for row in output.rows {
+        let rewards = U256::from_dec_str(&row.rewards).map_err(|_| {
+            eyre!(
+                "hook returned `{}` as the rewards of {}",
+                row.rewards,
+                row.label
+            )
+        })?;
+        ensure!(
+            seen.insert(row.label.clone()),
+            "hook listed {} twice",
+            row.label
+        );
+        let (note, bonus) = match inputs.get(row.label.as_str()) {
+            Some(input) => (input.note.clone(), input.bonus),
+            None => {
+                ensure!(
+                    policy.allow_new_labels,
+                    "hook added {}, which the report didn't list (--hook-allow-new-labels accepts it)",
+                    row.label
+                );
+                (None, U256::zero())
+            }
+        };
+        rows.push(ReportRow {
+            label: row.label,
+            rewards,
+            bonus,
+            note,
+            reason: None,
+        });
+    }
+
+    let total_in = total(input);
+    let total_out = total(&rows);
+    let bound = total_in + total_in * policy.max_increase_bps / 10_000;
+    ensure!(
+        total_out <= bound,
+        "hook raised the total from {} to {} wei, past the {} bps allowed",
+        total_in,
+        total_out,
+        policy.max_increase_bps
+    );
+
+    Ok(rows)
+}
+
+fn total(rows: &[ReportRow]) -> U256 {
+    rows.iter()
+        .fold(U256::zero(), |total, row| total + row.rewards)
+}
+
+/// Runs the hook at `path` on the rows of `report` and replaces them with its validated output,
+/// largest rewards first, adjusting the total given by the difference.
+pub fn apply_hook(report: &mut Report, path: &Path, policy: HookPolicy) -> Result<()> {
+    let output = run_hook(path, &hook_input(&report.rows))?;
+    let mut rows = validate_output(&report.rows, &output, policy)?;
+    rows.sort_by_key(|row| std::cmp::Reverse(row.rewards));
+
+    report.total_rewards_given = report.total_rewards_given - total(&report.rows) + total(&rows);
+    report.rows = rows;
+    Ok(())
+}
+
+#[cfg(feature = "wasm-hooks")]
+fn run_hook(path: &Path, input: &[u8]) -> Result<Vec<u8>> {
+    WasmHook::load(path)?.run(input)
+}
+
+#[cfg(not(feature = "wasm-hooks"))]
+fn run_hook(path: &Path, _input: &[u8]) -> Result<Vec<u8>> {
+    eyre::bail!(
+        "cannot run hook {}, this build lacks the `wasm-hooks` feature",
+        path.display()
+    )
+}
+
+/// A loaded hook module, run through wasmtime with `HOOK_FUEL` per call.
+#[cfg(feature = "wasm-hooks")]
+pub struct WasmHook {
+    engine: wasmtime::Engine,
+    module: wasmtime::Module,
+}
+
+#[cfg(feature = "wasm-hooks")]
+impl WasmHook {
+    /// Compiles a module from WASM binary or text.
+    pub fn new(bytes: &[u8]) -> Result<WasmHook> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config).map_err(|err| eyre!("{:#}", err))?;
+        let module = wasmtime::Module::new(&engine, bytes)
+            .map_err(|err| eyre!("invalid hook module: {:#}", err))?;
+        Ok(WasmHook { engine, module })
+    }
+
+    pub fn load(path: &Path) -> Result<WasmHook> {
+        let bytes = std::fs::read(path)
+            .wrap_err_with(|| format!("failed to read hook {}", path.display()))?;
+        WasmHook::new(&bytes).wrap_err_with(|| format!("invalid hook {}", path.display()))
+    }
+
+    /// Runs the hook on `input` in a fresh instance, returning its raw output.
+    pub fn run(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let fail = |err: wasmtime::Error| match err.downcast_ref::<wasmtime::Trap>() {
+            Some(wasmtime::Trap::OutOfFuel) => eyre!("hook ran out of its {} fuel", HOOK_FUEL),
+            _ => eyre!("hook failed: {:#}", err),
+        };
+
+        let mut store = wasmtime::Store::new(&self.engine, ());
+        store.set_fuel(HOOK_FUEL).map_err(fail)?;
+        let instance = wasmtime::Instance::new(&mut store, &self.module, &[]).map_err(fail)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| eyre!("hook doesn't export its memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(fail)?;
+        let process = instance
+            .get_typed_func::<(i32, i32), i64>(&mut store, "process")
+            .map_err(fail)?;
+
+        let len = i32::try_from(input.len()).wrap_err("hook input too large")?;
+        let ptr = alloc.call(&mut store, len).map_err(fail)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|_| eyre!("hook allocated input outside its memory"))?;
+
+        let packed = process.call(&mut store, (ptr, len)).map_err(fail)? as u64;
+        let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+        memory
+            .read(&store, (packed >> 32) as usize, &mut output)
+            .map_err(|_| eyre!("hook returned output outside its memory"))?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::parse_ether;
+
+    fn rows() -> Vec<ReportRow> {
+        let row = |label: &str, rewards: &str| ReportRow {
+            label: label.to_string(),
+            rewards: parse_ether(rewards).unwrap(),
+            bonus: U256::zero(),
+            note: None,
+            reason: None,
+        };
+        vec![
+            row("0x0000000000000000000000000000000000000b0b", "60"),
+            row("0x00000000000000000000000000000000000a11ce", "40"),
+        ]
+    }
+
+    fn output(rows: &[(&str, &str)]) -> Vec<u8> {
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(label, rewards)| {
+                serde_json::json!({
+                    "label": label,
+                    "rewards": parse_ether(*rewards).unwrap().to_string(),
+                })
+            })
+            .collect();
+        serde_json::to_vec(&serde_json::json!({ "rows": rows })).unwrap()
+    }
+
+    #[test]
+    fn accepts_adjustments_within_the_policy() {
+        let input = rows();
+        let bob = input[0].label.as_str();
+        let alice = input[1].label.as_str();
+        let policy = HookPolicy::default();
+
+        let echoed = validate_output(&input, &hook_input(&input), policy).unwrap();
+        assert_eq!(echoed, input);
+
+        // excluding alice and rounding bob down never raises the total
+        let excluded = validate_output(&input, &output(&[(bob, "59")]), policy).unwrap();
+        assert_eq!(excluded.len(), 1);
+        assert_eq!(excluded[0].rewards, parse_ether("59").unwrap());
+
+        let allowed = HookPolicy {
+            allow_new_labels: true,
+            max_increase_bps: 100,
+        };
+        let carol = "0x00000000000000000000000000000000000ca201";
+        let moved = output(&[(bob, "60"), (alice, "39"), (carol, "2")]);
+        assert_eq!(validate_output(&input, &moved, allowed).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rejects_outputs_breaking_the_policy() {
+        let input = rows();
+        let bob = input[0].label.as_str();
+        let alice = input[1].label.as_str();
+        let carol = "0x00000000000000000000000000000000000ca201";
+        let policy = HookPolicy {
+            allow_new_labels: false,
+            max_increase_bps: 100,
+        };
+        let reject =
+            |output: &[u8]| format!("{:#}", validate_output(&input, output, policy).unwrap_err());
+
+        let inflated = output(&[(bob, "62"), (alice, "40")]);
+        assert!(reject(&inflated).contains("past the 100 bps allowed"));
+        assert!(validate_output(&input, &output(&[(bob, "61"), (alice, "40")]), policy).is_ok());
+
+        assert!(reject(&output(&[(bob, "60"), (carol, "40")])).contains("didn't list"));
+        assert!(reject(&output(&[(bob, "30"), (bob, "30")])).contains("twice"));
+
+        assert!(reject(b"not json").contains("malformed hook output"));
+        assert!(reject(br#"{"rows": [{"label": "x"}]}"#).contains("malformed hook output"));
+        let negative = br#"{"rows": [{"label": "x", "rewards": "-1"}]}"#;
+        assert!(reject(negative).contains("as the rewards of x"));
+    }
+
+    #[cfg(feature = "wasm-hooks")]
+    #[test]
+    fn runs_the_example_and_stops_failing_modules() {
+        let input = rows();
+        let identity = WasmHook::new(include_bytes!("../examples/hooks/identity.wat")).unwrap();
+        let output = identity.run(&hook_input(&input)).unwrap();
+        assert_eq!(
+            validate_output(&input, &output, HookPolicy::default()).unwrap(),
+            input
+        );
+
+        let module = |process: &str| {
+            WasmHook::new(
+                format!(
+                    r#"(module
+                        (memory (export "memory") 1)
+                        (func (export "alloc") (param i32) (result i32) i32.const 0)
+                        (func (export "process") (param i32 i32) (result i64) {process}))"#
+                )
+                .as_bytes(),
+            )
+            .unwrap()
+        };
+        let looping = module("(loop (br 0)) i64.const 0");
+        let err = looping.run(&hook_input(&input)).unwrap_err();
+        assert!(err.to_string().contains("ran out of"));
+
+        let trapping = module("unreachable");
+        let err = trapping.run(&hook_input(&input)).unwrap_err();
+        assert!(err.to_string().contains("hook failed"));
+
+        let out_of_bounds = module("i64.const 0x7fffffff00000010");
+        assert!(out_of_bounds.run(&hook_input(&input)).is_err());
+    }
+}
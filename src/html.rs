@@ -0,0 +1,483 @@
+//! The rewards report as a single self-contained HTML page, for readers without the CLI.
+//!
+//! Everything is inline: the styles, the script, the histogram and the utilization sparkline
+//! as SVG, and the table rows as a JSON blob the script renders a page at a time, so the file
+//! opens offline and makes no requests. Rows are compact arrays to keep a report of 50k
+//! holders within a few MB.
+
+use crate::output::{format_ether_rounded, SCHEMA_VERSION};
+use crate::report::Report;
+use crate::snapshot::ReplaySettings;
+use crate::utilization::UtilizationBucket;
+use ethers::{
+    core::types::{Address, H256, U256, U64},
+    utils::keccak256,
+};
+use serde_json::json;
+use std::fmt::Write;
+
+/// Table rows shown at a time.
+pub const PAGE_SIZE: usize = 500;
+/// Most points drawn in the sparkline, longer series keep the worst bucket of each stretch.
+const SPARKLINE_POINTS: usize = 240;
+
+/// What the report was computed from, listed under the run metadata.
+pub struct RunMetadata<'a> {
+    pub vault: Address,
+    pub chain_id: u64,
+    pub block_number: U64,
+    pub settings: &'a ReplaySettings,
+}
+
+impl RunMetadata<'_> {
+    /// keccak256 over the vault, the chain and the replay settings, which identifies runs
+    /// that must agree.
+    pub fn config_digest(&self) -> String {
+        let config = json!({
+            "vault": self.vault,
+            "chain_id": self.chain_id,
+            "settings": self.settings,
+        });
+        format!("{:?}", H256(keccak256(config.to_string())))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Holders bucketed by their share of the rewards given, one decade of percent per bucket.
+fn histogram(report: &Report) -> Vec<(&'static str, usize)> {
+    let mut buckets = vec![
+        ("< 0.001%", 0),
+        ("0.001–0.01%", 0),
+        ("0.01–0.1%", 0),
+        ("0.1–1%", 0),
+        ("1–10%", 0),
+        ("≥ 10%", 0),
+    ];
+    for row in &report.rows {
+        let pct = report.pct(row.rewards);
+        let bucket = match pct {
+            pct if pct < 0.001 => 0,
+            pct if pct < 0.01 => 1,
+            pct if pct < 0.1 => 2,
+            pct if pct < 1.0 => 3,
+            pct if pct < 10.0 => 4,
+            _ => 5,
+        };
+        buckets[bucket].1 += 1;
+    }
+    buckets
+}
+
+fn histogram_svg(buckets: &[(&str, usize)]) -> String {
+    let (width, height, bar) = (480, 160, 80);
+    let most = buckets
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut svg = format!(
+        r#"<svg viewBox="0 0 {} {}" width="{}" height="{}" role="img" aria-label="holders by share of the rewards">"#,
+        width,
+        height + 40,
+        width,
+        height + 40
+    );
+    for (i, (label, count)) in buckets.iter().enumerate() {
+        let h = count * height / most;
+        let x = i * bar;
+        write!(
+            svg,
+            r#"<rect x="{}" y="{}" width="{}" height="{}"><title>{}: {}</title></rect><text x="{}" y="{}" class="count">{}</text><text x="{}" y="{}">{}</text>"#,
+            x + 8,
+            height - h + 16,
+            bar - 16,
+            h,
+            label,
+            count,
+            x + bar / 2,
+            height - h + 12,
+            count,
+            x + bar / 2,
+            height + 32,
+            label
+        )
+        .unwrap();
+    }
+    svg + "</svg>"
+}
+
+/// The utilization of every bucket in percent, as a line between 0 and 100.
+fn sparkline_svg(buckets: &[UtilizationBucket]) -> String {
+    let (width, height) = (480.0, 60.0);
+    let stretch = buckets.len().div_ceil(SPARKLINE_POINTS).max(1);
+    let points: Vec<f64> = buckets
+        .chunks(stretch)
+        .map(|stretch| {
+            stretch
+                .iter()
+                .map(UtilizationBucket::ratio)
+                .fold(f64::INFINITY, f64::min)
+        })
+        .collect();
+
+    let step = width / (points.len().max(2) - 1) as f64;
+    let line: Vec<String> = points
+        .iter()
+        .enumerate()
+        .map(|(i, ratio)| {
+            format!(
+                "{:.1},{:.1}",
+                i as f64 * step,
+                height - ratio.clamp(0.0, 1.0) * height
+            )
+        })
+        .collect();
+    format!(
+        r#"<svg viewBox="0 0 {w} {h}" width="{w}" height="{h}" role="img" aria-label="emission utilization"><polyline points="{}"/></svg>"#,
+        line.join(" "),
+        w = width,
+        h = height
+    )
+}
+
+/// The rows as JSON for the script, safe to inline in a `<script>` element.
+fn table_data(report: &Report) -> String {
+    let mut columns = vec!["address", "rewards", "pct"];
+    if report.bonus_budget.is_some() {
+        columns.push("bonus");
+    }
+    columns.push("note");
+
+    // rounded like the text report, so the listed amounts add up to their total
+    let unit = U256::exp10(18 - report.decimals.min(18));
+    let rounded = |amounts: Vec<U256>| -> Vec<String> {
+        report
+            .rounding
+            .round_to_unit(&amounts, unit)
+            .into_iter()
+            .map(|amount| format_ether_rounded(amount, report.decimals))
+            .collect()
+    };
+    let rewards = rounded(report.rows.iter().map(|row| row.rewards).collect());
+    let bonuses = rounded(report.rows.iter().map(|row| row.bonus).collect());
+
+    let rows: Vec<_> = report
+        .rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut cells = vec![
+                json!(row.label),
+                json!(rewards[i]),
+                json!((report.pct(row.rewards) * 10_000.0).round() / 10_000.0),
+            ];
+            if report.bonus_budget.is_some() {
+                cells.push(json!(bonuses[i]));
+            }
+            cells.push(json!(row.note));
+            cells
+        })
+        .collect();
+
+    let data = json!({
+        "schema_version": SCHEMA_VERSION,
+        "page_size": PAGE_SIZE,
+        "columns": columns,
+        "rows": rows,
+    });
+    // a label can't close the script element early
+    data.to_string().replace('<', "\\u003c")
+}
+
+/// Renders `report` with its utilization series as a standalone HTML page.
+pub fn render_html(
+    report: &Report,
+    utilization: &[UtilizationBucket],
+    run: &RunMetadata,
+) -> String {
+    let mut summary = vec![
+        ("rewards given", report.ether(report.total_rewards_given)),
+        ("expected", report.ether(report.total_rewards_expected)),
+        ("holders", report.rows.len().to_string()),
+    ];
+    if !report.protocol_rewards.is_zero() {
+        summary.push((
+            "held by the protocol",
+            report.ether(report.protocol_rewards),
+        ));
+    }
+    if let Some(budget) = report.bonus_budget {
+        summary.push(("bonus budget", report.ether(budget)));
+    }
+    if !report.withheld.is_empty() {
+        summary.push(("withheld", report.withheld.len().to_string()));
+    }
+    let summary: String = summary
+        .iter()
+        .map(|(name, value)| format!("<div><dt>{}</dt><dd>{}</dd></div>", name, value))
+        .collect();
+
+    let mut warnings = String::new();
+    if let Some(quarantine) = report
+        .quarantine
+        .filter(|quarantine| !quarantine.is_empty())
+    {
+        write!(
+            warnings,
+            r#"<p class="warning">APPROXIMATE: {} events and {} undecodable logs were quarantined</p>"#,
+            quarantine.events, quarantine.undecodable_logs
+        )
+        .unwrap();
+    }
+
+    let mut metadata = vec![
+        ("vault", format!("{:?}", run.vault)),
+        ("chain id", run.chain_id.to_string()),
+        ("block", run.block_number.to_string()),
+        ("config digest", run.config_digest()),
+        ("report hash", format!("{:?}", report.hash())),
+        (
+            "rounding",
+            format!("{:?} to {} decimals", report.rounding, report.decimals),
+        ),
+        (
+            "settings",
+            serde_json::to_string(run.settings).expect("settings should serialize"),
+        ),
+    ];
+    if let Some(freshness) = &report.freshness {
+        metadata.push(("freshness", freshness.to_string()));
+    }
+    if let Some(chain) = report.export_chain {
+        metadata.push(("exported for", chain.to_string()));
+    }
+    let metadata: String = metadata
+        .iter()
+        .map(|(name, value)| format!("<tr><th>{}</th><td>{}</td></tr>", name, escape(value)))
+        .collect();
+
+    let utilization_section = if utilization.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "<section><h2>Emission utilization</h2>{}<p>{} buckets of {} blocks</p></section>",
+            sparkline_svg(utilization),
+            utilization.len(),
+            utilization[0].to_block - utilization[0].from_block + 1
+        )
+    };
+
+    TEMPLATE
+        .replace("{{block}}", &run.block_number.to_string())
+        .replace("{{summary}}", &summary)
+        .replace("{{warnings}}", &warnings)
+        .replace("{{histogram}}", &histogram_svg(&histogram(report)))
+        .replace("{{utilization}}", &utilization_section)
+        .replace("{{metadata}}", &metadata)
+        .replace("{{data}}", &table_data(report))
+}
+
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Rewards at block {{block}}</title>
+<style>
+body { font: 14px/1.4 system-ui, sans-serif; margin: 2em auto; max-width: 1100px; color: #222; }
+dl { display: flex; flex-wrap: wrap; gap: 1.5em; }
+dt { color: #666; font-size: 12px; }
+dd { margin: 0; font-size: 20px; font-variant-numeric: tabular-nums; }
+.warning { background: #fde2e2; padding: .5em 1em; }
+svg rect { fill: #4a74c9; }
+svg text { font-size: 11px; text-anchor: middle; }
+svg polyline { fill: none; stroke: #4a74c9; stroke-width: 1.5; }
+table { border-collapse: collapse; width: 100%; }
+th, td { padding: 2px 8px; text-align: right; border-bottom: 1px solid #eee; }
+th:nth-child(2), td:nth-child(2), td:last-child { text-align: left; }
+thead th { cursor: pointer; user-select: none; }
+td:nth-child(2) { font-family: monospace; }
+details table th { text-align: left; }
+</style>
+</head>
+<body>
+<h1>Rewards at block {{block}}</h1>
+{{warnings}}
+<dl>{{summary}}</dl>
+<section><h2>Holders by share of the rewards</h2>{{histogram}}</section>
+{{utilization}}
+<section>
+<h2>Leaderboard</h2>
+<input id="search" type="search" placeholder="Search addresses and notes">
+<span id="page"></span>
+<button id="prev">&lsaquo;</button><button id="next">&rsaquo;</button>
+<table><thead id="head"></thead><tbody id="rows"></tbody></table>
+</section>
+<details><summary>Run metadata</summary><table>{{metadata}}</table></details>
+<script id="data" type="application/json">{{data}}</script>
+<script>
+(function () {
+  var data = JSON.parse(document.getElementById("data").textContent);
+  var rows = data.rows.map(function (row, i) { return [i + 1].concat(row); });
+  var columns = ["rank"].concat(data.columns);
+  var numeric = function (c) { return c !== "address" && c !== "note"; };
+  var shown = rows, sort = 0, ascending = true, page = 0;
+
+  function render() {
+    var pages = Math.max(1, Math.ceil(shown.length / data.page_size));
+    page = Math.min(page, pages - 1);
+    var body = document.getElementById("rows");
+    body.textContent = "";
+    shown.slice(page * data.page_size, (page + 1) * data.page_size).forEach(function (row) {
+      var tr = body.insertRow();
+      row.forEach(function (cell) { tr.insertCell().textContent = cell === null ? "" : cell; });
+    });
+    document.getElementById("page").textContent =
+      "page " + (page + 1) + " of " + pages + ", " + shown.length + " rows";
+  }
+
+  var head = document.getElementById("head").insertRow();
+  columns.forEach(function (column, c) {
+    var th = document.createElement("th");
+    th.textContent = column;
+    th.onclick = function () {
+      ascending = sort === c ? !ascending : !numeric(column) || c === 0;
+      sort = c;
+      shown = shown.slice().sort(function (a, b) {
+        var x = a[c], y = b[c];
+        if (numeric(column)) { x = parseFloat(x); y = parseFloat(y); }
+        else { x = String(x || ""); y = String(y || ""); }
+        return (x < y ? -1 : x > y ? 1 : 0) * (ascending ? 1 : -1);
+      });
+      render();
+    };
+    head.appendChild(th);
+  });
+
+  document.getElementById("search").oninput = function (e) {
+    var needle = e.target.value.toLowerCase();
+    shown = rows.filter(function (row) {
+      return row.some(function (cell) { return String(cell).toLowerCase().indexOf(needle) >= 0; });
+    });
+    page = 0;
+    render();
+  };
+  document.getElementById("prev").onclick = function () { page = Math.max(0, page - 1); render(); };
+  document.getElementById("next").onclick = function () { page += 1; render(); };
+  render();
+})();
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::Annotations;
+    use crate::report::{rewards_report, ReportRow};
+    use crate::scenario::Scenario;
+    use crate::state::{AccrualPolicy, Emission, GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use crate::subaccounts::{Checkpoints, SubAccounts};
+    use crate::utilization::utilization_series;
+    use std::path::Path;
+
+    fn settings() -> ReplaySettings {
+        ReplaySettings {
+            net_same_block: false,
+            emission: Emission::PerBlock,
+            accrual_policy: AccrualPolicy::BlockStart,
+        }
+    }
+
+    #[test]
+    fn renders_the_canonical_scenario() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/distributes_rewards.toml");
+        let mut state = GlobalState::new();
+        state.set_distribution_tracking(true);
+        state
+            .process_events(Scenario::load(&path).unwrap().events())
+            .unwrap();
+        let block = U64::from(BLOCK_CONTRACT_DEPLOYED + 200);
+        let mut report = rewards_report(
+            &state,
+            block,
+            &Annotations::default(),
+            None,
+            (&SubAccounts::default(), &Checkpoints::new()),
+            false,
+        );
+        report.decimals = 2;
+        report.rows[0].note = Some("</script><b>".to_string());
+        let settings = settings();
+        let run = RunMetadata {
+            vault: Address::from_low_u64_be(0x7a),
+            chain_id: 1,
+            block_number: block,
+            settings: &settings,
+        };
+        let html = render_html(&report, &utilization_series(&state, block, 50), &run);
+
+        // bob 100 + 50, alice 50
+        assert!(html.contains(r#"<dd>200.00</dd>"#));
+        assert!(html.contains(&format!(
+            r#"["{}","150.00",75.0,"\u003c/script>\u003cb>"]"#,
+            report.rows[0].label
+        )));
+        assert!(html.contains(r#""columns":["address","rewards","pct","note"]"#));
+        assert!(html.contains("<title>1–10%: 0</title>"));
+        assert!(html.contains("<title>≥ 10%: 2</title>"));
+        assert!(html.contains("<polyline points="));
+        assert!(html.contains(&run.config_digest()));
+        assert!(html.contains(&format!("{:?}", report.hash())));
+        assert!(!html.contains("http"));
+        assert_eq!(html.matches("</script>").count(), 2);
+    }
+
+    #[test]
+    fn stays_small_for_many_holders() {
+        let state = GlobalState::new();
+        let block = U64::from(BLOCK_CONTRACT_DEPLOYED);
+        let mut report = rewards_report(
+            &state,
+            block,
+            &Annotations::default(),
+            None,
+            (&SubAccounts::default(), &Checkpoints::new()),
+            false,
+        );
+        report.rows = (0..50_000)
+            .map(|i| ReportRow {
+                label: format!("{:?}", Address::from_low_u64_be(i)),
+                rewards: U256::exp10(18) * (i + 1),
+                bonus: U256::zero(),
+                note: None,
+                reason: None,
+            })
+            .collect();
+        report.total_rewards_given = report
+            .rows
+            .iter()
+            .map(|row| row.rewards)
+            .fold(U256::zero(), |sum, rewards| sum + rewards);
+        let settings = settings();
+        let run = RunMetadata {
+            vault: Address::zero(),
+            chain_id: 1,
+            block_number: block,
+            settings: &settings,
+        };
+
+        let html = render_html(&report, &[], &run);
+        assert!(html.len() < 5_000_000, "{} bytes", html.len());
+        assert!(!html.contains("Emission utilization"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::state::{events_hash, Event};
+use crate::stdio;
+use ethers::core::types::{Address, H256};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+/// Positions of the events touching each address in an event stream, transfers under both
+/// sides. Tied to the stream it was built from by `events_hash`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventIndex {
+    pub events_hash: H256,
+    by_address: HashMap<Address, Vec<u32>>,
+}
+
+impl EventIndex {
+    pub fn build(events: &[Event]) -> EventIndex {
+        let mut by_address: HashMap<Address, Vec<u32>> = HashMap::new();
+
+        for (i, evt) in events.iter().enumerate() {
+            let i = u32::try_from(i).expect("event stream should fit a u32 index");
+            match evt {
+                Event::Deposit(e) => by_address.entry(e.address).or_default().push(i),
+                Event::Withdrawal(e) => by_address.entry(e.address).or_default().push(i),
+                Event::Transfer(e) => {
+                    by_address.entry(e.from).or_default().push(i);
+                    if e.to != e.from {
+                        by_address.entry(e.to).or_default().push(i);
+                    }
+                }
+            }
+        }
+
+        EventIndex {
+            events_hash: events_hash(events),
+            by_address,
+        }
+    }
+
+    /// Whether the index was built from exactly `events`.
+    pub fn is_current(&self, events: &[Event]) -> bool {
+        self.events_hash == events_hash(events)
+    }
+
+    /// Every event touching `address`, in stream order. `events` must be the stream the index
+    /// was built from.
+    pub fn events_for<'a>(
+        &'a self,
+        events: &'a [Event],
+        address: Address,
+    ) -> impl Iterator<Item = &'a Event> + 'a {
+        self.by_address
+            .get(&address)
+            .into_iter()
+            .flatten()
+            .map(|i| &events[*i as usize])
+    }
+
+    pub fn addresses(&self) -> usize {
+        self.by_address.len()
+    }
+
+    /// Rough heap size of the index, ignoring the map's spare capacity.
+    pub fn memory_bytes(&self) -> usize {
+        self.by_address
+            .values()
+            .map(|positions| {
+                size_of::<(Address, Vec<u32>)>() + positions.capacity() * size_of::<u32>()
+            })
+            .sum()
+    }
+
+    pub fn load(path: &Path) -> Result<EventIndex> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read index {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse index {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        stdio::replace(path, &serde_json::to_vec(self)?)
+            .wrap_err_with(|| format!("failed to write index {}", path.display()))
+    }
+
+    /// The index saved next to the cache at `cache_path`, rebuilt in memory when it is missing
+    /// or was built from other events.
+    pub fn for_cache(cache_path: &Path, events: &[Event]) -> EventIndex {
+        match EventIndex::load(&index_path(cache_path)) {
+            Ok(index) if index.is_current(events) => index,
+            _ => EventIndex::build(events),
+        }
+    }
+}
+
+/// `<cache>.index`, next to the cache it indexes.
+pub fn index_path(cache_path: &Path) -> PathBuf {
+    let mut path = cache_path.as_os_str().to_owned();
+    path.push(".index");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{events_involving, Deposit, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::U64, utils::parse_ether};
+
+    fn events() -> Vec<Event> {
+        let one = parse_ether("1").unwrap();
+
+        (0..300u64)
+            .map(|i| {
+                let address = Address::from_low_u64_be(i % 7 + 1);
+                let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + i);
+                match i % 3 {
+                    0 => Event::Deposit(Deposit {
+                        address,
+                        shares: Shares(one),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    1 => Event::Transfer(Transfer {
+                        from: address,
+                        to: Address::from_low_u64_be(i % 5 + 1),
+                        shares: Shares(one),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    _ => Event::Withdrawal(Withdraw {
+                        address,
+                        shares: Shares(one),
+                        block_number,
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_a_linear_scan() {
+        let events = events();
+        let index = EventIndex::build(&events);
+
+        assert_eq!(index.addresses(), 7);
+        for i in 0..=8 {
+            let address = Address::from_low_u64_be(i);
+            let indexed: Vec<_> = index.events_for(&events, address).collect();
+            assert_eq!(indexed, events_involving(&events, address), "{:?}", address);
+        }
+        assert!(index.memory_bytes() > 0);
+    }
+
+    #[test]
+    fn goes_stale_when_the_cache_changes() {
+        let dir = std::env::temp_dir().join(format!("oprtc-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("cache.json");
+
+        let mut events = events();
+        EventIndex::build(&events)
+            .save(&index_path(&cache_path))
+            .unwrap();
+        assert!(EventIndex::load(&index_path(&cache_path))
+            .unwrap()
+            .is_current(&events));
+
+        events.truncate(100);
+        let index = EventIndex::for_cache(&cache_path, &events);
+        assert!(index.is_current(&events));
+        assert_eq!(
+            index
+                .events_for(&events, Address::from_low_u64_be(1))
+                .count(),
+            events_involving(&events, Address::from_low_u64_be(1)).len()
+        );
+    }
+}
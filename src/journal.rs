@@ -0,0 +1,141 @@
+use crate::error::{Error, Result};
+use crate::state::Event;
+use std::io::{self, BufRead, Lines, Write};
+use std::iter::Enumerate;
+
+/// Reads an events journal, one JSON-encoded `Event` per line. Blank lines are skipped.
+pub fn read_journal<R: BufRead>(reader: R) -> Result<Vec<Event>> {
+    journal_events(reader)
+        .map(|evt| evt.map(|(_, evt)| evt))
+        .collect()
+}
+
+/// The events of a journal read one line at a time, each with its line number, so a journal
+/// larger than memory can be replayed.
+pub fn journal_events<R: BufRead>(reader: R) -> JournalEvents<R> {
+    JournalEvents {
+        lines: reader.lines().enumerate(),
+    }
+}
+
+pub struct JournalEvents<R> {
+    lines: Enumerate<Lines<R>>,
+}
+
+impl<R: BufRead> Iterator for JournalEvents<R> {
+    type Item = Result<(usize, Event)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, line) in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    return Some(Err(Error::io(
+                        format!("failed to read journal line {}", i + 1),
+                        err,
+                    )))
+                }
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            return Some(
+                serde_json::from_str(&line)
+                    .map(|evt| (i + 1, evt))
+                    .map_err(|err| {
+                        Error::decode(format!("malformed event on journal line {}", i + 1))
+                            .caused_by(err)
+                    }),
+            );
+        }
+
+        None
+    }
+}
+
+/// Writes `events` in the format read by `read_journal`.
+pub fn write_journal<W: Write>(mut writer: W, events: &[Event]) -> Result<()> {
+    for evt in events {
+        serde_json::to_writer(&mut writer, evt)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(writer))
+            .map_err(|err| Error::io("failed to write journal", err))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, GlobalState, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::U64, utils::parse_ether};
+    use std::io::Cursor;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    fn journal(events: &[Event]) -> String {
+        let mut out = vec![];
+        write_journal(&mut out, events).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn computes_rewards_from_a_piped_journal() {
+        let events = vec![
+            Event::Deposit(Deposit {
+                address: BOB.parse().unwrap(),
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Deposit(Deposit {
+                address: ALICE.parse().unwrap(),
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 100),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: BOB.parse().unwrap(),
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 200),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+
+        let piped = read_journal(Cursor::new(journal(&events) + "\n")).unwrap();
+        assert_eq!(piped, events);
+
+        let mut global_state = GlobalState::new();
+        global_state.process_events(piped).unwrap();
+
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 300);
+        let bob_rewards = global_state.preview_user_rewards(BOB.parse().unwrap(), block_number);
+        let alice_rewards = global_state.preview_user_rewards(ALICE.parse().unwrap(), block_number);
+
+        assert_eq!(bob_rewards, parse_ether("150").unwrap());
+        assert_eq!(alice_rewards, parse_ether("150").unwrap());
+    }
+
+    #[test]
+    fn reports_the_line_of_a_malformed_event() {
+        let evt = Event::Deposit(Deposit {
+            address: BOB.parse().unwrap(),
+            shares: Shares(parse_ether("1").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+            transaction_index: 0,
+            log_index: 0,
+        });
+        let contents = journal(&[evt]) + "\n{\"Deposit\": {}}\n";
+
+        let err = read_journal(Cursor::new(contents)).unwrap_err();
+        assert_eq!(err.to_string(), "malformed event on journal line 3");
+    }
+}
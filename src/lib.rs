@@ -0,0 +1,189 @@
+//! Per-user rewards for the OPRTC lending vault, reconstructed from its share events.
+//!
+//! The stable API is what [`prelude`] re-exports. Everything else is internal to the crate and
+//! the `oprtc_calculator` binary, and may change in any release.
+//!
+//! Replaying events:
+//!
+//! ```
+//! use ethers::{core::types::{Address, U64}, utils::parse_ether};
+//! use oprtc_calculator::prelude::*;
+//!
+//! let bob = Address::from_low_u64_be(0xb0b);
+//! let mut state = GlobalState::new();
+//! state.process_events(vec![Event::Deposit(Deposit {
+//!     address: bob,
+//!     shares: Shares(parse_ether("1").unwrap()),
+//!     block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+//!     transaction_index: 0,
+//!     log_index: 0,
+//! })])
+//! .unwrap();
+//!
+//! let block = U64::from(BLOCK_CONTRACT_DEPLOYED + 100);
+//! assert_eq!(state.get_user_rewards(block), vec![(bob, parse_ether("100").unwrap())]);
+//! ```
+//!
+//! Fetching events and reporting rewards:
+//!
+//! ```no_run
+//! use ethers::providers::{Http, Provider};
+//! use oprtc_calculator::prelude::*;
+//! use std::str::FromStr;
+//!
+//! # async fn report() -> eyre::Result<()> {
+//! let url = "https://rpc.flashbots.net";
+//! let client: Client = Provider::new(Instrumented::new(url, Http::from_str(url)?));
+//! let vault = "0xaF53431488E871D103baA0280b6360998F0F9926".parse()?;
+//!
+//! let ctx = EvaluationContext::resolve(&client, None).await?;
+//! let chunks = chunk_grid(BLOCK_CONTRACT_DEPLOYED, ctx.block.as_u64(), CHUNK_SIZE);
+//! let fetched = fetch_chunks(&client, &VaultFlavor::oprtc_v1(), vault, &chunks, 4).await?;
+//!
+//! let mut state = GlobalState::new();
+//! state.process_events(Decoded::concat(fetched).strict()?)?;
+//! let report = rewards_report(
+//!     &state,
+//!     ctx.block,
+//!     &Annotations::default(),
+//!     None,
+//!     (&SubAccounts::default(), &Checkpoints::new()),
+//!     false,
+//! );
+//! print!("{report}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Querying a cached snapshot of the event history at one of its blocks:
+//!
+//! ```no_run
+//! use ethers::core::types::{Address, U64};
+//! use oprtc_calculator::prelude::*;
+//! use std::path::Path;
+//!
+//! # fn query() -> eyre::Result<()> {
+//! let cache = EventCache::load(Path::new("events.json"))?;
+//! let mut state = GlobalState::new();
+//! state.process_events(cache.events)?;
+//!
+//! let bob: Address = "0x0000000000000000000000000000000000000B0b".parse()?;
+//! let block = U64::from(cache.to_block);
+//! println!(
+//!     "{} shares, {} rewards, active {:?}",
+//!     state.shares_of(bob),
+//!     state.preview_user_rewards(bob, block),
+//!     state.user_active_span(bob)
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+mod accumulators;
+mod address_map;
+mod annotations;
+mod binary_cache;
+mod bisect;
+mod cache;
+mod calls;
+mod chunks;
+mod claims;
+#[doc(hidden)]
+pub mod cli;
+mod clock;
+mod coalesce;
+mod cohorts;
+mod context;
+mod dilution;
+mod doctor;
+mod dormancy;
+mod error;
+mod exact;
+mod fetch;
+mod flavor;
+mod freshness;
+mod help;
+mod hooks;
+mod html;
+mod index;
+mod journal;
+mod lineage;
+mod lock;
+mod lots;
+mod loyalty;
+mod merge;
+mod observer;
+mod oracle;
+mod output;
+mod payout;
+mod pipeline;
+mod proof;
+mod quality;
+mod quarantine;
+mod rebase;
+mod reconcile;
+mod report;
+mod rounding;
+mod rpc;
+mod sanity;
+mod scenario;
+mod signing;
+mod simulate;
+mod snapshot;
+mod state;
+mod stdio;
+mod subaccounts;
+mod timestamps;
+mod top_ups;
+mod units;
+mod utilization;
+mod verify;
+mod watch;
+
+pub use error::{Error, Result};
+
+/// The public API, covered by semver.
+pub mod prelude {
+    pub use crate::accumulators::{series_blocks, Accumulator, CHECKPOINT_BLOCKS};
+    pub use crate::address_map::{map_report, AddressMap, ExportChain};
+    pub use crate::annotations::{Annotation, Annotations};
+    pub use crate::cache::{CacheFormat, EventCache};
+    pub use crate::chunks::{ChunkMeta, ChunkStore, CHUNK_SCHEMA_VERSION};
+    pub use crate::clock::{Clock, Head, HeadSource, TokioClock};
+    pub use crate::coalesce::CoalescedDelta;
+    pub use crate::context::EvaluationContext;
+    pub use crate::error::Error;
+    pub use crate::fetch::{
+        chunk_grid, decode_vault_log, fetch_chunks, fetch_events, Decoded, LogSource, CHUNK_SIZE,
+    };
+    pub use crate::flavor::{LayoutSegment, VaultFlavor};
+    pub use crate::freshness::Freshness;
+    pub use crate::journal::{journal_events, read_journal, write_journal, JournalEvents};
+    pub use crate::loyalty::{LoyaltyBonus, MIN_AGE_BLOCKS};
+    pub use crate::observer::{InvariantWarning, RecordChange, StateObserver, UserDelta, UserView};
+    pub use crate::oracle::{
+        OracleConfig, OracleMetrics, OracleSource, RewardView, RewardsOracle, StalePolicy,
+    };
+    pub use crate::pipeline::{fetch_pipelined, PipelineTimings};
+    pub use crate::proof::{Amount, Proof, ProofConfig, ProofError, CHECKPOINT_INTERVAL};
+    pub use crate::quarantine::{
+        unknown_sender_burst, QuarantineReason, QuarantineSummary, QuarantinedEvent,
+        UnknownSenderBurst,
+    };
+    pub use crate::rebase::{IndexSeries, Normalized, RebaseConfig};
+    pub use crate::report::{rewards_report, write_rewards_csv, Report, ReportRow, WithholdReason};
+    pub use crate::rounding::Rounding;
+    pub use crate::rpc::{is_retryable, Client, Instrumented, RetryPolicy};
+    pub use crate::sanity::{
+        sanity_check, MaxRewards, PoolShares, RedFlag, RewardsFile, SanityBounds, Violation,
+    };
+    pub use crate::state::{
+        AccrualPolicy, Deposit, Distribution, Emission, Event, GlobalState, IngestSummary,
+        InvariantCheckpoint, SelfHeldShares, StateError, Transfer, Withdraw,
+        BLOCK_CONTRACT_DEPLOYED,
+    };
+    pub use crate::subaccounts::{Checkpoints, SubAccounts};
+    pub use crate::timestamps::TimestampCache;
+    pub use crate::top_ups::{TopUp, TopUpConfig, TopUpReconciliation, TopUps, REWARD_ADDED_EVENT};
+    pub use crate::units::{RewardPerShare, RewardWei, Rewards, Shares};
+}
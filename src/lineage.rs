@@ -0,0 +1,481 @@
+//! Lineage of the files a run writes: the run that wrote an artifact and the hash and run of
+//! every file it was built from, so a report can be traced back through the snapshot and cache
+//! it came from. JSON documents carry it in a top-level `lineage` field their hashes leave out,
+//! caches, journals, CSV and text get a `.lineage.json` sidecar instead. A cache's hash covers
+//! the whole document, so even a JSON one keeps the sidecar.
+
+use crate::signing::canonical_bytes;
+use crate::stdio;
+use ethers::{core::types::H256, utils::keccak256};
+use eyre::{ensure, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt, fs,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Identifier of one run: a UUID v7, so ids sort by the millisecond the run started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct RunId([u8; 16]);
+
+impl RunId {
+    pub fn generate() -> RunId {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let state = RandomState::new();
+        RunId::from_parts(millis, [state.hash_one(0u8), state.hash_one(1u8)])
+    }
+
+    /// Lays out the 48 bits of `millis`, then `random` around the version and variant bits.
+    fn from_parts(millis: u64, random: [u64; 2]) -> RunId {
+        let mut bytes = [0; 16];
+        bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+        bytes[6..8].copy_from_slice(&random[0].to_be_bytes()[..2]);
+        bytes[8..].copy_from_slice(&random[1].to_be_bytes());
+        bytes[6] = 0x70 | (bytes[6] & 0x0f);
+        bytes[8] = 0x80 | (bytes[8] & 0x3f);
+        RunId(bytes)
+    }
+}
+
+impl fmt::Display for RunId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RunId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RunId, String> {
+        let digits = s.replace('-', "");
+        let mut bytes = [0; 16];
+        if digits.len() != 32 || !digits.is_ascii() {
+            return Err(format!("{} is not a UUID", s));
+        }
+        for (byte, pair) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).expect("ASCII digits should be UTF-8");
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| format!("{} is not a UUID", s))?;
+        }
+        Ok(RunId(bytes))
+    }
+}
+
+impl From<RunId> for String {
+    fn from(id: RunId) -> String {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for RunId {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<RunId, String> {
+        s.parse()
+    }
+}
+
+/// A file an artifact was built from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    /// As the run was given it
+    pub path: PathBuf,
+    pub hash: H256,
+    /// Run that wrote the file, left out for files without lineage such as annotations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<RunId>,
+}
+
+impl Input {
+    /// Hashes the file at `path`, taking its run from its lineage when that still matches it.
+    pub fn read(path: &Path) -> Result<Input> {
+        let hash = hash_file(path)?;
+        let run_id = Lineage::load(path)?
+            .filter(|lineage| lineage.hash == hash)
+            .map(|lineage| lineage.run_id);
+        Ok(Input {
+            path: path.to_path_buf(),
+            hash,
+            run_id,
+        })
+    }
+}
+
+/// The run that wrote an artifact and the files it was built from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lineage {
+    pub run_id: RunId,
+    /// keccak256 of the artifact's canonical bytes, as signatures cover them
+    pub hash: H256,
+    pub inputs: Vec<Input>,
+}
+
+/// Lineage as a JSON artifact carries it in its `lineage` field. It can't hash the file holding
+/// it, so `Lineage::load` hashes the file instead.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddedLineage {
+    pub run_id: RunId,
+    pub inputs: Vec<Input>,
+}
+
+/// Any JSON document, read for its `lineage` field alone.
+#[derive(Deserialize)]
+struct Carrier {
+    lineage: Option<EmbeddedLineage>,
+}
+
+impl Lineage {
+    /// Records that run `run_id` wrote the file at `path` from `inputs`, returning where.
+    pub fn record(path: &Path, run_id: RunId, inputs: Vec<Input>) -> Result<PathBuf> {
+        let lineage = Lineage {
+            run_id,
+            hash: hash_file(path)?,
+            inputs,
+        };
+        let lineage_path = lineage_path(path);
+        let json = serde_json::to_string_pretty(&lineage).expect("lineage should serialize");
+        stdio::replace(&lineage_path, (json + "\n").as_bytes())
+            .wrap_err_with(|| format!("failed to write lineage {}", lineage_path.display()))?;
+        Ok(lineage_path)
+    }
+
+    /// The lineage of the file at `path`, from its `lineage` field or else from its sidecar, if
+    /// one was recorded.
+    pub fn load(path: &Path) -> Result<Option<Lineage>> {
+        if let Some(lineage) = Lineage::embedded(path)? {
+            return Ok(Some(lineage));
+        }
+        let lineage_path = lineage_path(path);
+        if !lineage_path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(&lineage_path)
+            .wrap_err_with(|| format!("failed to read lineage {}", lineage_path.display()))?;
+        serde_json::from_str(&json)
+            .map(Some)
+            .wrap_err_with(|| format!("{} is not a lineage file", lineage_path.display()))
+    }
+
+    /// The lineage in the `lineage` field of the JSON document at `path`. Anything else, binary
+    /// caches included, has none.
+    fn embedded(path: &Path) -> Result<Option<Lineage>> {
+        let contents =
+            fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        if contents.trim_ascii_start().first() != Some(&b'{') {
+            return Ok(None);
+        }
+        let embedded = serde_json::from_slice::<Carrier>(&contents)
+            .ok()
+            .and_then(|carrier| carrier.lineage);
+        Ok(embedded.map(|EmbeddedLineage { run_id, inputs }| Lineage {
+            run_id,
+            hash: H256(keccak256(canonical_bytes(&contents))),
+            inputs,
+        }))
+    }
+}
+
+/// Where the lineage of `path` is written: `report.json` in `report.json.lineage.json`.
+pub fn lineage_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".lineage.json");
+    PathBuf::from(name)
+}
+
+/// keccak256 of the canonical contents of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<H256> {
+    let contents = fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    Ok(H256(keccak256(canonical_bytes(&contents))))
+}
+
+/// Where an input of an artifact was found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    /// A file with the input's hash and lineage, whose own inputs follow it
+    Artifact { path: PathBuf, run_id: RunId },
+    /// A file with the input's hash but no lineage
+    Source { path: PathBuf },
+    /// A file already among the artifact's descendants, which is not followed again
+    Cycle { path: PathBuf },
+    /// No file with the input's hash
+    Missing,
+}
+
+/// One input in the ancestry of an artifact, `depth` 0 for the artifact's own inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ancestor {
+    pub depth: usize,
+    pub input: Input,
+    pub origin: Origin,
+}
+
+/// A file of the directory searched, with its lineage if it matches the file.
+struct Entry {
+    path: PathBuf,
+    lineage: Option<Lineage>,
+}
+
+/// The ancestry of the artifact at `path`, depth first. Inputs are found by hash among the
+/// files of `dir`, so copies moved there or renamed are followed too.
+pub fn ancestry(path: &Path, dir: &Path) -> Result<(Lineage, Vec<Ancestor>)> {
+    let lineage = Lineage::load(path)?.ok_or_else(|| eyre!("{} has no lineage", path.display()))?;
+    ensure!(
+        lineage.hash == hash_file(path)?,
+        "{} changed since its lineage was recorded",
+        path.display()
+    );
+
+    let index = index(dir)?;
+    let mut ancestors = vec![];
+    walk(&index, &lineage, &mut vec![lineage.hash], &mut ancestors);
+    Ok((lineage, ancestors))
+}
+
+/// The files of `dir` by hash, skipping lineage and signature files. Names are visited in
+/// order, so the first of identical files is the one found.
+fn index(dir: &Path) -> Result<HashMap<H256, Entry>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        let name = path.to_string_lossy();
+        if path.is_file() && !name.ends_with(".lineage.json") && !name.ends_with(".sig") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut index = HashMap::new();
+    for path in paths {
+        let hash = hash_file(&path)?;
+        if index.contains_key(&hash) {
+            continue;
+        }
+        let lineage = Lineage::load(&path)?.filter(|lineage| lineage.hash == hash);
+        index.insert(hash, Entry { path, lineage });
+    }
+    Ok(index)
+}
+
+fn walk(
+    index: &HashMap<H256, Entry>,
+    lineage: &Lineage,
+    descendants: &mut Vec<H256>,
+    ancestors: &mut Vec<Ancestor>,
+) {
+    let depth = descendants.len() - 1;
+    for input in &lineage.inputs {
+        let (origin, parent) = match index.get(&input.hash) {
+            None => (Origin::Missing, None),
+            Some(entry) if descendants.contains(&input.hash) => (
+                Origin::Cycle {
+                    path: entry.path.clone(),
+                },
+                None,
+            ),
+            Some(Entry {
+                path,
+                lineage: None,
+            }) => (Origin::Source { path: path.clone() }, None),
+            Some(Entry {
+                path,
+                lineage: Some(parent),
+            }) => (
+                Origin::Artifact {
+                    path: path.clone(),
+                    run_id: parent.run_id,
+                },
+                Some(parent),
+            ),
+        };
+        ancestors.push(Ancestor {
+            depth,
+            input: input.clone(),
+            origin,
+        });
+
+        if let Some(parent) = parent {
+            descendants.push(input.hash);
+            walk(index, parent, descendants, ancestors);
+            descendants.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(n: u8) -> RunId {
+        RunId::from_parts(1_700_000_000_000 + u64::from(n), [0; 2])
+    }
+
+    /// Writes `contents` to `name` in `dir` with the lineage of run `n` built from `inputs`.
+    fn artifact(dir: &Path, name: &str, contents: &str, n: u8, inputs: &[&str]) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        let inputs = inputs
+            .iter()
+            .map(|input| Input::read(&dir.join(input)).unwrap())
+            .collect();
+        Lineage::record(&path, run(n), inputs).unwrap();
+        path
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("oprtc-lineage-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn run_ids_are_v7_and_round_trip() {
+        let id = RunId::generate();
+        let text = id.to_string();
+        assert_eq!(text.len(), 36);
+        assert_eq!(&text[14..15], "7");
+        assert!(matches!(&text[19..20], "8" | "9" | "a" | "b"));
+        assert_eq!(text.parse::<RunId>().unwrap(), id);
+        // earlier runs sort first whatever their random bits
+        assert!(RunId::from_parts(1, [u64::MAX; 2]) < RunId::from_parts(2, [0; 2]));
+        assert!("not-a-uuid".parse::<RunId>().is_err());
+    }
+
+    #[test]
+    fn follows_inputs_through_renamed_copies() {
+        let dir = temp_dir("tree");
+        artifact(&dir, "cache.json", r#"{"events": []}"#, 1, &[]);
+        fs::write(dir.join("annotations.toml"), "[notes]\n").unwrap();
+        artifact(&dir, "snapshot.json", r#"{"block": 1}"#, 2, &["cache.json"]);
+        let report = artifact(
+            &dir,
+            "report.json",
+            r#"{"users": []}"#,
+            3,
+            &["snapshot.json", "annotations.toml"],
+        );
+        // the snapshot is found by hash after a rename
+        fs::rename(dir.join("snapshot.json"), dir.join("renamed.json")).unwrap();
+        fs::rename(
+            dir.join("snapshot.json.lineage.json"),
+            dir.join("renamed.json.lineage.json"),
+        )
+        .unwrap();
+
+        let (lineage, ancestors) = ancestry(&report, &dir).unwrap();
+        assert_eq!(lineage.run_id, run(3));
+        let found: Vec<_> = ancestors
+            .iter()
+            .map(|ancestor| (ancestor.depth, ancestor.origin.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    0,
+                    Origin::Artifact {
+                        path: dir.join("renamed.json"),
+                        run_id: run(2)
+                    }
+                ),
+                (
+                    1,
+                    Origin::Artifact {
+                        path: dir.join("cache.json"),
+                        run_id: run(1)
+                    }
+                ),
+                (
+                    0,
+                    Origin::Source {
+                        path: dir.join("annotations.toml")
+                    }
+                ),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_the_lineage_json_documents_carry() {
+        let dir = temp_dir("embedded");
+        artifact(&dir, "cache.bin", "\0\x01", 1, &[]);
+        let embedded = EmbeddedLineage {
+            run_id: run(2),
+            inputs: vec![Input::read(&dir.join("cache.bin")).unwrap()],
+        };
+        let proof = dir.join("proof.json");
+        let json = serde_json::json!({ "amounts": [], "lineage": embedded });
+        fs::write(&proof, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+        // without a sidecar, and whatever a stale one left over says
+        assert!(!lineage_path(&proof).exists());
+        fs::write(lineage_path(&proof), "{}").unwrap();
+
+        assert_eq!(Input::read(&proof).unwrap().run_id, Some(run(2)));
+        let (lineage, ancestors) = ancestry(&proof, &dir).unwrap();
+        assert_eq!(lineage.hash, hash_file(&proof).unwrap());
+        assert_eq!(
+            ancestors[0].origin,
+            Origin::Artifact {
+                path: dir.join("cache.bin"),
+                run_id: run(1)
+            }
+        );
+        // documents without the field and binary files have no embedded lineage
+        assert_eq!(Lineage::embedded(&dir.join("cache.bin")).unwrap(), None);
+        fs::write(dir.join("plain.json"), r#"{"users": []}"#).unwrap();
+        assert_eq!(Lineage::load(&dir.join("plain.json")).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_cycles_and_missing_inputs() {
+        let dir = temp_dir("cycle");
+        let a = artifact(&dir, "a.json", "[1]", 1, &[]);
+        artifact(&dir, "gone.json", "[0]", 0, &[]);
+        let b = artifact(&dir, "b.json", "[2]", 2, &["a.json", "gone.json"]);
+        fs::remove_file(dir.join("gone.json")).unwrap();
+        // a lineage edited to name its own descendant
+        Lineage::record(&a, run(1), vec![Input::read(&b).unwrap()]).unwrap();
+
+        let (_, ancestors) = ancestry(&b, &dir).unwrap();
+        let found: Vec<_> = ancestors
+            .iter()
+            .map(|ancestor| (ancestor.depth, ancestor.origin.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    0,
+                    Origin::Artifact {
+                        path: a.clone(),
+                        run_id: run(1)
+                    }
+                ),
+                (1, Origin::Cycle { path: b.clone() }),
+                (0, Origin::Missing),
+            ]
+        );
+        assert_eq!(ancestors[2].input.path, dir.join("gone.json"));
+
+        // an artifact changed after its lineage was recorded is refused
+        fs::write(&b, "[3]").unwrap();
+        assert!(ancestry(&b, &dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+use fs2::FileExt;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Exit code used when another process holds a lock past `--lock-timeout` (`EX_TEMPFAIL`).
+pub const EXIT_LOCKED: i32 = 75;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Readers, any number of them may hold the lock at once
+    Shared,
+    /// A single writer, which records its PID in the lockfile
+    Exclusive,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    Busy { path: PathBuf, pid: Option<u32> },
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Busy {
+                path,
+                pid: Some(pid),
+            } => write!(f, "{} is locked by pid {}", path.display(), pid),
+            LockError::Busy { path, pid: None } => {
+                write!(f, "{} is locked by another reader", path.display())
+            }
+            LockError::Io(err) => write!(f, "failed to lock: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(err: io::Error) -> Self {
+        LockError::Io(err)
+    }
+}
+
+/// Advisory lock on `<target>.lock`, released when dropped. The OS drops the lock of a crashed
+/// process, so a PID left behind in the lockfile only ever names a stale writer.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    /// PID of a dead writer whose lockfile was taken over
+    pub stale_pid: Option<u32>,
+}
+
+impl FileLock {
+    pub fn acquire(
+        target: &Path,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> Result<FileLock, LockError> {
+        let path = lock_path(target);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let started = Instant::now();
+
+        loop {
+            let locked = match mode {
+                LockMode::Shared => FileExt::try_lock_shared(&file),
+                LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+            };
+
+            match locked {
+                Ok(()) => break,
+                Err(err) if err.kind() == fs2::lock_contended_error().kind() => {
+                    if started.elapsed() >= timeout {
+                        let pid = read_pid(&mut file).filter(|pid| is_alive(*pid));
+                        return Err(LockError::Busy { path, pid });
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let mut stale_pid = None;
+
+        if mode == LockMode::Exclusive {
+            stale_pid = read_pid(&mut file).filter(|pid| !is_alive(*pid));
+            file.set_len(0)?;
+            file.rewind()?;
+            write!(file, "{}", std::process::id())?;
+            file.sync_all()?;
+        }
+
+        Ok(FileLock { file, stale_pid })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+fn lock_path(target: &Path) -> PathBuf {
+    let mut path = target.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // signal 0 only checks that the process exists and may be signalled
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    fn target(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("oprtc-lock-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("cache.json")
+    }
+
+    #[test]
+    fn writers_are_serialized() {
+        let target = target("serialize");
+        let log = Arc::new(Mutex::new(vec![]));
+
+        let first = FileLock::acquire(&target, LockMode::Exclusive, Duration::ZERO).unwrap();
+
+        let busy = FileLock::acquire(&target, LockMode::Shared, Duration::ZERO).unwrap_err();
+        match &busy {
+            LockError::Busy { pid, .. } => assert_eq!(*pid, Some(std::process::id())),
+            err => panic!("unexpected error {}", err),
+        }
+        assert_eq!(Error::from(busy).exit_code(), EXIT_LOCKED);
+
+        let second = {
+            let target = target.clone();
+            let log = log.clone();
+            thread::spawn(move || {
+                let _lock = FileLock::acquire(&target, LockMode::Exclusive, Duration::from_secs(5))
+                    .unwrap();
+                log.lock().unwrap().push("second");
+            })
+        };
+
+        thread::sleep(Duration::from_millis(300));
+        log.lock().unwrap().push("first");
+        drop(first);
+
+        second.join().unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn readers_share_the_lock() {
+        let target = target("shared");
+
+        let _first = FileLock::acquire(&target, LockMode::Shared, Duration::ZERO).unwrap();
+        let _second = FileLock::acquire(&target, LockMode::Shared, Duration::ZERO).unwrap();
+
+        assert!(FileLock::acquire(&target, LockMode::Exclusive, Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn recovers_from_a_crashed_writer() {
+        let target = target("stale");
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        fs::write(lock_path(&target), dead_pid.to_string()).unwrap();
+
+        let lock = FileLock::acquire(&target, LockMode::Exclusive, Duration::ZERO).unwrap();
+
+        assert_eq!(lock.stale_pid, Some(dead_pid));
+        assert_eq!(
+            fs::read_to_string(lock_path(&target)).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Shares received at one block, tracked oldest first. The loyalty bonus ages them, and a
+//! minimum staking duration holds them out of the rewards until they mature.
+
+use ethers::core::types::{Address, U256, U64};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+
+/// Shares received at one block, which age together.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Lot {
+    pub since: U64,
+    pub shares: U256,
+}
+
+/// Takes `shares` from the oldest of `lots` first, returning the parts taken, oldest first.
+pub(crate) fn take_oldest(lots: &mut VecDeque<Lot>, mut shares: U256) -> Vec<Lot> {
+    let mut taken = vec![];
+
+    while !shares.is_zero() {
+        let Some(lot) = lots.front_mut() else {
+            break;
+        };
+        let part = shares.min(lot.shares);
+        taken.push(Lot {
+            since: lot.since,
+            shares: part,
+        });
+        lot.shares -= part;
+        shares -= part;
+        if lot.shares.is_zero() {
+            lots.pop_front();
+        }
+    }
+
+    taken
+}
+
+/// The lots still short of a minimum staking duration, and the blocks they mature at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Maturity {
+    min_blocks: u64,
+    lots: BTreeMap<Address, VecDeque<Lot>>,
+    /// Block every lot matures at, soonest first. Entries of lots that left early lapse
+    schedule: BinaryHeap<Reverse<(U64, Address)>>,
+}
+
+impl PartialEq for Maturity {
+    fn eq(&self, other: &Maturity) -> bool {
+        self.min_blocks == other.min_blocks
+            && self.lots == other.lots
+            && self.schedule.clone().into_sorted_vec() == other.schedule.clone().into_sorted_vec()
+    }
+}
+
+impl Maturity {
+    pub fn new(min_blocks: u64) -> Maturity {
+        Maturity {
+            min_blocks,
+            lots: BTreeMap::new(),
+            schedule: BinaryHeap::new(),
+        }
+    }
+
+    pub fn min_blocks(&self) -> u64 {
+        self.min_blocks
+    }
+
+    /// Opens a lot of the `shares` `address` received at `block_number`.
+    pub fn receive(&mut self, address: Address, shares: U256, block_number: U64) {
+        if shares.is_zero() {
+            return;
+        }
+        self.schedule
+            .push(Reverse((block_number + self.min_blocks, address)));
+        self.lots.entry(address).or_default().push_back(Lot {
+            since: block_number,
+            shares,
+        });
+    }
+
+    /// Drops `shares` of `address` that left before maturing, from its oldest lots first.
+    pub fn forfeit(&mut self, address: Address, shares: U256) {
+        if let Some(lots) = self.lots.get_mut(&address) {
+            take_oldest(lots, shares);
+            if lots.is_empty() {
+                self.lots.remove(&address);
+            }
+        }
+    }
+
+    /// The first block up to `block_number` a lot matures at.
+    pub fn next_due(&self, block_number: U64) -> Option<U64> {
+        self.schedule
+            .peek()
+            .map(|Reverse((due, _))| *due)
+            .filter(|due| *due <= block_number)
+    }
+
+    /// The lots maturing up to `block_number`, as the shares of each address grouped by the
+    /// block they mature at.
+    pub fn due_by(&self, block_number: U64) -> BTreeMap<U64, Vec<(Address, U256)>> {
+        let addresses: BTreeSet<_> = self
+            .schedule
+            .iter()
+            .filter(|Reverse((due, _))| *due <= block_number)
+            .map(|Reverse((_, address))| *address)
+            .collect();
+
+        let mut due_by: BTreeMap<U64, Vec<(Address, U256)>> = BTreeMap::new();
+        for address in addresses {
+            for lot in self.lots.get(&address).into_iter().flatten() {
+                let due = lot.since + self.min_blocks;
+                if due > block_number {
+                    break;
+                }
+                due_by.entry(due).or_default().push((address, lot.shares));
+            }
+        }
+        due_by
+    }
+
+    /// Closes the lots maturing up to `block_number`, returning the shares of each address
+    /// that matured.
+    pub fn mature(&mut self, block_number: U64) -> BTreeMap<Address, U256> {
+        let mut matured: BTreeMap<Address, U256> = BTreeMap::new();
+
+        while let Some(Reverse((due, address))) = self.schedule.peek().copied() {
+            if due > block_number {
+                break;
+            }
+            self.schedule.pop();
+            let Some(lots) = self.lots.get_mut(&address) else {
+                continue;
+            };
+            while let Some(lot) = lots.front() {
+                if lot.since + self.min_blocks > block_number {
+                    break;
+                }
+                *matured.entry(address).or_default() += lot.shares;
+                lots.pop_front();
+            }
+            if lots.is_empty() {
+                self.lots.remove(&address);
+            }
+        }
+
+        matured
+    }
+}
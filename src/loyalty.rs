@@ -0,0 +1,238 @@
+//! The loyalty bonus, a second budget paid on top of the rewards to shares held for long.
+//!
+//! Every receipt of shares opens a lot that ages from its block, and shares leaving a position
+//! close its oldest lots first. A lot earns bonus share-blocks once it is `min_age` blocks old,
+//! so a partial withdrawal leaves what remains of older lots their age, and only shares bought
+//! back after a full exit start over. The budget is split pro rata over the bonus share-blocks
+//! of every payee.
+
+use crate::lots::{take_oldest, Lot};
+use crate::observer::{StateObserver, UserDelta};
+use crate::state::{Event, GlobalState};
+use ethers::{
+    core::types::{Address, U256, U64},
+    utils::parse_ether,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+/// 90 days of 12 second blocks.
+pub const MIN_AGE_BLOCKS: u64 = 648_000;
+
+/// A position held without a full exit since `since`, as its lots oldest first.
+#[derive(Debug, Clone, Default)]
+struct Position {
+    since: U64,
+    lots: VecDeque<Lot>,
+}
+
+/// Tracks the share lots of every position as an observer of the replay, so it sees exactly the
+/// events the accounting applied.
+#[derive(Debug, Clone, Default)]
+pub struct LoyaltyBonus {
+    min_age: u64,
+    positions: HashMap<Address, Position>,
+    /// Bonus share-blocks of shares that have left
+    closed: HashMap<Address, U256>,
+}
+
+impl LoyaltyBonus {
+    pub fn new(min_age: u64) -> LoyaltyBonus {
+        LoyaltyBonus {
+            min_age,
+            ..LoyaltyBonus::default()
+        }
+    }
+
+    /// Share-blocks of `shares` held over `(since, until]` past the age threshold.
+    fn bonus_share_blocks(&self, since: U64, shares: U256, until: U64) -> U256 {
+        shares * until.as_u64().saturating_sub(since.as_u64() + self.min_age)
+    }
+
+    fn receive(&mut self, address: Address, shares: U256, block_number: U64) {
+        let position = self.positions.entry(address).or_insert_with(|| Position {
+            since: block_number,
+            lots: VecDeque::new(),
+        });
+        position.lots.push_back(Lot {
+            since: block_number,
+            shares,
+        });
+    }
+
+    /// Closes the oldest lots of `address` first, ending its position when nothing is left.
+    fn release(&mut self, address: Address, shares: U256, block_number: U64) {
+        let Some(mut position) = self.positions.remove(&address) else {
+            return;
+        };
+        let closed = take_oldest(&mut position.lots, shares)
+            .into_iter()
+            .fold(U256::zero(), |sum, lot| {
+                sum + self.bonus_share_blocks(lot.since, lot.shares, block_number)
+            });
+
+        *self.closed.entry(address).or_default() += closed;
+        if !position.lots.is_empty() {
+            self.positions.insert(address, position);
+        }
+    }
+
+    /// Block since which `address` has held shares without a full exit.
+    pub fn holding_since(&self, address: Address) -> Option<U64> {
+        self.positions.get(&address).map(|position| position.since)
+    }
+
+    /// Share-blocks of `address` up to `block_number` that earn the bonus.
+    pub fn eligible_share_blocks(&self, address: Address, block_number: U64) -> U256 {
+        let open = self
+            .positions
+            .get(&address)
+            .map_or(U256::zero(), |position| {
+                position.lots.iter().fold(U256::zero(), |sum, lot| {
+                    sum + self.bonus_share_blocks(lot.since, lot.shares, block_number)
+                })
+            });
+        self.closed.get(&address).copied().unwrap_or_default() + open
+    }
+
+    /// `budget` split over the eligible share-blocks of the payees of `state` up to
+    /// `block_number`, rounded down. Addresses without any are left out.
+    pub fn bonuses(
+        &self,
+        budget: U256,
+        block_number: U64,
+        state: &GlobalState,
+    ) -> BTreeMap<Address, U256> {
+        let addresses: BTreeSet<_> = self.positions.keys().chain(self.closed.keys()).collect();
+        let eligible: Vec<_> = addresses
+            .into_iter()
+            .filter(|address| state.is_payee(**address))
+            .map(|address| (*address, self.eligible_share_blocks(*address, block_number)))
+            .filter(|(_, share_blocks)| !share_blocks.is_zero())
+            .collect();
+        let total = eligible
+            .iter()
+            .fold(U256::zero(), |sum, (_, share_blocks)| sum + share_blocks);
+
+        eligible
+            .into_iter()
+            .map(|(address, share_blocks)| (address, budget * share_blocks / total))
+            .collect()
+    }
+}
+
+impl StateObserver for LoyaltyBonus {
+    fn on_event_applied(&mut self, event: &Event, delta: &UserDelta) {
+        for change in &delta.changes {
+            let before = change.before.unwrap_or_default().shares;
+            let after = change.after.shares;
+            if after > before {
+                self.receive(change.address, after - before, event.block_number());
+            } else if after < before {
+                self.release(change.address, before - after, event.block_number());
+            }
+        }
+    }
+}
+
+/// Parses a `--loyalty-budget` amount in ether.
+pub fn parse_budget(value: &str) -> Result<U256, String> {
+    parse_ether(value).map_err(|err| format!("`{}` is not an ether amount: {}", value, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+
+    const MIN_AGE: u64 = 100;
+
+    fn block(offset: u64) -> U64 {
+        U64::from(BLOCK_CONTRACT_DEPLOYED + offset)
+    }
+
+    fn deposit(address: Address, shares: u64, offset: u64) -> Event {
+        Event::Deposit(Deposit {
+            address,
+            shares: Shares(U256::from(shares)),
+            block_number: block(offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn withdraw(address: Address, shares: u64, offset: u64) -> Event {
+        Event::Withdrawal(Withdraw {
+            address,
+            shares: Shares(U256::from(shares)),
+            block_number: block(offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn replay(events: Vec<Event>) -> (GlobalState, LoyaltyBonus) {
+        let mut state = GlobalState::new();
+        let mut loyalty = LoyaltyBonus::new(MIN_AGE);
+        state.process_events_with(events, &mut loyalty).unwrap();
+        (state, loyalty)
+    }
+
+    #[test]
+    fn partial_withdrawals_spend_the_oldest_lots_first() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let (_, loyalty) = replay(vec![
+            deposit(bob, 3, 0),
+            deposit(bob, 2, 50),
+            // takes 1 of the first lot, which keeps its age for the other 2
+            withdraw(bob, 1, 120),
+            // takes the rest of the first lot and 1 of the second
+            withdraw(bob, 3, 160),
+        ]);
+
+        assert_eq!(loyalty.holding_since(bob), Some(block(0)));
+        // first lot: 1 share over (100, 120] and 2 over (100, 160]; second: 1 over (150, 160]
+        // and the last one still open over (150, 200]
+        let closed = 20 + 2 * 60 + 10;
+        assert_eq!(
+            loyalty.eligible_share_blocks(bob, block(200)),
+            U256::from(closed + 50)
+        );
+    }
+
+    #[test]
+    fn a_full_exit_starts_the_age_over() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let (state, loyalty) = replay(vec![
+            deposit(bob, 1, 0),
+            deposit(alice, 1, 0),
+            withdraw(bob, 1, 150),
+            deposit(bob, 1, 160),
+            // received shares open a lot of their own
+            Event::Transfer(Transfer {
+                from: alice,
+                to: bob,
+                shares: Shares(U256::one()),
+                block_number: block(170),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ]);
+
+        assert_eq!(loyalty.holding_since(bob), Some(block(160)));
+        assert_eq!(loyalty.holding_since(alice), None);
+        // bob: (100, 150] before the exit, nothing since; alice: (100, 170]
+        assert_eq!(loyalty.eligible_share_blocks(bob, block(200)), 50.into());
+        assert_eq!(loyalty.eligible_share_blocks(alice, block(200)), 70.into());
+        // and both of bob's lots past their age by then, (260, 300] and (270, 300]
+        assert_eq!(
+            loyalty.eligible_share_blocks(bob, block(300)),
+            (50 + 40 + 30).into()
+        );
+
+        let bonuses = loyalty.bonuses(U256::from(1_200), block(200), &state);
+        assert_eq!(bonuses[&bob], 500.into());
+        assert_eq!(bonuses[&alice], 700.into());
+    }
+}
@@ -0,0 +1,362 @@
+use crate::error::{bail, ensure, Error, Result};
+use crate::fetch::LogSource;
+use async_trait::async_trait;
+use ethers::core::types::{Filter, Log, H256, U256};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashSet},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// What to do when two sources disagree about the same log.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Refuse to merge, naming every conflict
+    #[default]
+    Fail,
+    /// Keep the named source's version, failing on conflicts it isn't part of
+    PreferSource(String),
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fail" => Ok(ConflictPolicy::Fail),
+            Some(("prefer-source", name)) if !name.is_empty() => {
+                Ok(ConflictPolicy::PreferSource(name.to_string()))
+            }
+            _ => Err(format!(
+                "unknown conflict policy `{}`, expected fail or prefer-source:<name>",
+                s
+            )),
+        }
+    }
+}
+
+/// Two sources returning different contents for the same log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub transaction_hash: H256,
+    pub log_index: U256,
+    /// The source whose log was seen first, then the one disagreeing with it
+    pub sources: (String, String),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "log {} of {:?} differs between {} and {}",
+            self.log_index, self.transaction_hash, self.sources.0, self.sources.1
+        )
+    }
+}
+
+/// Where the logs of a merge came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Provenance {
+    /// Distinct logs each source listed
+    pub listed: BTreeMap<String, usize>,
+    /// Merged logs taken from each source, the first to list a log unless a conflict was
+    /// resolved in favor of another
+    pub kept: BTreeMap<String, usize>,
+    /// Logs dropped because a source had already listed the same contents
+    pub duplicates: usize,
+    /// Every conflict found, resolved or not
+    pub conflicts: Vec<Conflict>,
+}
+
+impl Provenance {
+    pub fn total(&self) -> usize {
+        self.kept.values().sum()
+    }
+
+    /// Merged logs `source` didn't list, such as those of a range it is missing.
+    pub fn missing_from(&self, source: &str) -> usize {
+        self.total() - self.listed.get(source).copied().unwrap_or_default()
+    }
+
+    fn absorb(&mut self, other: Provenance) {
+        for (source, count) in other.listed {
+            *self.listed.entry(source).or_default() += count;
+        }
+        for (source, count) in other.kept {
+            *self.kept.entry(source).or_default() += count;
+        }
+        self.duplicates += other.duplicates;
+        self.conflicts.extend(other.conflicts);
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kept: Vec<_> = self
+            .kept
+            .iter()
+            .map(|(source, count)| format!("{} from {}", count, source))
+            .collect();
+        write!(
+            f,
+            "merged {} logs: {}, {} duplicates, {} conflicts",
+            self.total(),
+            kept.join(", "),
+            self.duplicates,
+            self.conflicts.len()
+        )?;
+        for source in self.listed.keys() {
+            let missing = self.missing_from(source);
+            if missing > 0 {
+                write!(f, "; {} is missing {}", source, missing)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The fields a log decodes from, which two sources must agree on.
+fn same_contents(a: &Log, b: &Log) -> bool {
+    (a.address, &a.topics, &a.data, a.block_number)
+        == (b.address, &b.topics, &b.data, b.block_number)
+}
+
+/// Merges log streams named by their source into one, deduplicated on transaction hash and log
+/// index and ordered by block and log index. Sources earlier in `streams` win ties between
+/// identical logs.
+pub fn merge_logs(
+    streams: Vec<(String, Vec<Log>)>,
+    policy: &ConflictPolicy,
+) -> Result<(Vec<Log>, Provenance)> {
+    let mut merged: BTreeMap<(H256, U256), (String, Log)> = BTreeMap::new();
+    let mut provenance = Provenance::default();
+    let mut unresolved = vec![];
+
+    for (source, logs) in streams {
+        let mut listed = HashSet::new();
+        for log in logs {
+            let (Some(transaction_hash), Some(log_index)) = (log.transaction_hash, log.log_index)
+            else {
+                bail!(
+                    Error::decode,
+                    "{} returned a log without a transaction hash or log index, which can't be merged",
+                    source
+                );
+            };
+            let key = (transaction_hash, log_index);
+            listed.insert(key);
+
+            let (kept_source, kept_log) = match merged.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert((source.clone(), log));
+                    *provenance.kept.entry(source.clone()).or_default() += 1;
+                    continue;
+                }
+                Entry::Occupied(entry) => entry.into_mut(),
+            };
+            if same_contents(kept_log, &log) {
+                provenance.duplicates += 1;
+                continue;
+            }
+
+            let conflict = Conflict {
+                transaction_hash,
+                log_index,
+                sources: (kept_source.clone(), source.clone()),
+            };
+            match policy {
+                ConflictPolicy::PreferSource(preferred) if *preferred == source => {
+                    *provenance.kept.get_mut(kept_source.as_str()).unwrap() -= 1;
+                    *provenance.kept.entry(source.clone()).or_default() += 1;
+                    *kept_source = source.clone();
+                    *kept_log = log;
+                }
+                ConflictPolicy::PreferSource(preferred) if *preferred == *kept_source => {}
+                _ => unresolved.push(conflict.to_string()),
+            }
+            provenance.conflicts.push(conflict);
+        }
+        provenance.listed.insert(source, listed.len());
+    }
+
+    ensure!(
+        unresolved.is_empty(),
+        Error::decode,
+        "sources disagree on {} logs: {}",
+        unresolved.len(),
+        unresolved.join("; ")
+    );
+
+    let mut logs: Vec<Log> = merged.into_values().map(|(_, log)| log).collect();
+    logs.sort_by_key(|log| (log.block_number, log.log_index));
+    Ok((logs, provenance))
+}
+
+/// Fetches every filter from each named source and merges the answers, keeping a running
+/// provenance of all fetches. Clones share it.
+#[derive(Clone)]
+pub struct MergedSource<S> {
+    sources: Vec<(String, S)>,
+    policy: ConflictPolicy,
+    provenance: Arc<Mutex<Provenance>>,
+}
+
+impl<S> MergedSource<S> {
+    pub fn new(sources: Vec<(String, S)>, policy: ConflictPolicy) -> Result<MergedSource<S>> {
+        let mut names = HashSet::new();
+        for (name, _) in &sources {
+            ensure!(
+                names.insert(name.as_str()),
+                Error::config,
+                "source {} is named twice",
+                name
+            );
+        }
+        if let ConflictPolicy::PreferSource(preferred) = &policy {
+            ensure!(
+                names.contains(preferred.as_str()),
+                Error::config,
+                "the preferred source {} is not one of {}",
+                preferred,
+                sources
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        Ok(MergedSource {
+            sources,
+            policy,
+            provenance: Arc::new(Mutex::new(Provenance::default())),
+        })
+    }
+
+    /// Whether more than one source is merged, which is when the provenance is worth reporting.
+    pub fn is_merging(&self) -> bool {
+        self.sources.len() > 1
+    }
+
+    pub fn provenance(&self) -> Provenance {
+        self.provenance.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl<S: LogSource> LogSource for MergedSource<S> {
+    async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+        if let [(_, source)] = self.sources.as_slice() {
+            return source.fetch_logs(filter).await;
+        }
+
+        let mut streams = vec![];
+        for (name, source) in &self.sources {
+            let logs = source.fetch_logs(filter).await.map_err(|err| match err {
+                Error::Rpc(err) => Error::rpc(format!("source {} failed: {}", name, err)),
+                err => err,
+            })?;
+            streams.push((name.clone(), logs));
+        }
+
+        let (logs, provenance) = merge_logs(streams, &self.policy)?;
+        self.provenance.lock().unwrap().absorb(provenance);
+        Ok(logs)
+    }
+}
+
+/// Parses a `name=url` source.
+pub fn parse_named_url(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+            Ok((name.to_string(), url.to_string()))
+        }
+        _ => Err(format!("expected `name=url`, got `{}`", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::mock::deposit_log;
+    use crate::state::BLOCK_CONTRACT_DEPLOYED;
+    use ethers::core::types::{Address, U64};
+
+    /// A deposit of `shares` at `offset` blocks past the deployment, keyed like a real log.
+    fn keyed_log(offset: u64, shares: u64) -> Log {
+        let mut log = deposit_log(
+            Address::from_low_u64_be(0xb0b),
+            U256::from(shares),
+            BLOCK_CONTRACT_DEPLOYED + offset,
+        );
+        log.transaction_hash = Some(H256::from_low_u64_be(offset));
+        log.log_index = Some(U256::zero());
+        log
+    }
+
+    fn stream(name: &str, offsets: std::ops::Range<u64>) -> (String, Vec<Log>) {
+        (
+            name.to_string(),
+            offsets.map(|offset| keyed_log(offset, 1)).collect(),
+        )
+    }
+
+    #[test]
+    fn merges_overlapping_and_partial_sources_in_canonical_order() {
+        // rpc covers blocks 50..150 in reverse, cache only 0..100
+        let mut rpc = stream("rpc", 50..150);
+        rpc.1.reverse();
+        let cache = stream("cache", 0..100);
+
+        let (logs, provenance) = merge_logs(vec![cache, rpc], &ConflictPolicy::Fail).unwrap();
+
+        assert_eq!(logs.len(), 150);
+        assert!(logs
+            .windows(2)
+            .all(|pair| pair[0].block_number < pair[1].block_number));
+        assert_eq!(
+            logs[0].block_number,
+            Some(U64::from(BLOCK_CONTRACT_DEPLOYED))
+        );
+        assert_eq!(provenance.kept["cache"], 100);
+        assert_eq!(provenance.kept["rpc"], 50);
+        assert_eq!(provenance.duplicates, 50);
+        assert_eq!(provenance.missing_from("cache"), 50);
+        assert_eq!(provenance.missing_from("rpc"), 50);
+        assert_eq!(
+            provenance.to_string(),
+            "merged 150 logs: 100 from cache, 50 from rpc, 50 duplicates, 0 conflicts; \
+             cache is missing 50; rpc is missing 50"
+        );
+    }
+
+    #[test]
+    fn names_both_sources_of_a_conflict_unless_one_is_preferred() {
+        let cache = stream("cache", 0..10);
+        let mut rpc = stream("rpc", 0..10);
+        rpc.1[7] = keyed_log(7, 2);
+
+        let err = merge_logs(vec![cache.clone(), rpc.clone()], &ConflictPolicy::Fail)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("disagree on 1 logs"));
+        assert!(err.contains(&format!(
+            "log 0 of {:?} differs between cache and rpc",
+            H256::from_low_u64_be(7)
+        )));
+
+        let policy: ConflictPolicy = "prefer-source:rpc".parse().unwrap();
+        let (logs, provenance) = merge_logs(vec![cache.clone(), rpc.clone()], &policy).unwrap();
+        assert_eq!(logs[7], rpc.1[7]);
+        assert_eq!(provenance.conflicts.len(), 1);
+        assert_eq!((provenance.kept["cache"], provenance.kept["rpc"]), (9, 1));
+
+        let policy = ConflictPolicy::PreferSource("cache".to_string());
+        let (logs, _) = merge_logs(vec![cache.clone(), rpc], &policy).unwrap();
+        assert_eq!(logs[7], cache.1[7]);
+
+        assert!("prefer-source:".parse::<ConflictPolicy>().is_err());
+        assert!(MergedSource::<()>::new(vec![("rpc".to_string(), ())], policy.clone()).is_err());
+    }
+}
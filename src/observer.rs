@@ -0,0 +1,234 @@
+use crate::quarantine::QuarantinedEvent;
+use crate::state::Event;
+use ethers::core::types::{Address, U256, U64};
+
+/// A user's record as observers see it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserView {
+    pub shares: U256,
+    /// Rewards settled into the record, in wei, leaving out what accrued since the snapshot
+    pub rewards_accumulated: U256,
+    /// Accumulator value the record last settled at, scaled by 1e18
+    pub rewards_per_share_snapshot: U256,
+}
+
+/// A record an event touched, before and after it was applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordChange {
+    pub address: Address,
+    /// `None` when the event created the record
+    pub before: Option<UserView>,
+    pub after: UserView,
+}
+
+/// The records an event touched: the depositor's or withdrawer's, or a transfer's sender then
+/// recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserDelta {
+    pub changes: Vec<RecordChange>,
+}
+
+/// Something the accounting let through or left out that an operator should look at.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvariantWarning {
+    /// Moved more than the `set_max_share_multiple` limit of the staked total, and was applied
+    SuspiciousShares(Event),
+    /// Moved more shares than the sender held, and was skipped in best-effort mode
+    Quarantined(QuarantinedEvent),
+}
+
+/// Callbacks from `GlobalState::process_events_with`, in processing order. Every method does
+/// nothing unless overridden, and `()` observes nothing.
+pub trait StateObserver {
+    /// An event changed the records in `delta`. Same-block pairs netted out aren't applied, so
+    /// aren't reported.
+    fn on_event_applied(&mut self, _event: &Event, _delta: &UserDelta) {}
+
+    /// The emission up to `block_number`, `amount` wei, was folded into the accumulator, which
+    /// grew by `per_share_delta`, scaled by 1e18.
+    fn on_rewards_distributed(
+        &mut self,
+        _block_number: U64,
+        _amount: U256,
+        _per_share_delta: U256,
+    ) {
+    }
+
+    fn on_invariant_warning(&mut self, _warning: &InvariantWarning) {}
+}
+
+impl StateObserver for () {}
+
+/// An observer that may be left out, observing nothing when `None`.
+impl<T: StateObserver> StateObserver for Option<T> {
+    fn on_event_applied(&mut self, event: &Event, delta: &UserDelta) {
+        if let Some(observer) = self {
+            observer.on_event_applied(event, delta);
+        }
+    }
+
+    fn on_rewards_distributed(&mut self, block_number: U64, amount: U256, per_share_delta: U256) {
+        if let Some(observer) = self {
+            observer.on_rewards_distributed(block_number, amount, per_share_delta);
+        }
+    }
+
+    fn on_invariant_warning(&mut self, warning: &InvariantWarning) {
+        if let Some(observer) = self {
+            observer.on_invariant_warning(warning);
+        }
+    }
+}
+
+/// Both observers, each told about every step in turn.
+impl<A: StateObserver, B: StateObserver> StateObserver for (A, B) {
+    fn on_event_applied(&mut self, event: &Event, delta: &UserDelta) {
+        self.0.on_event_applied(event, delta);
+        self.1.on_event_applied(event, delta);
+    }
+
+    fn on_rewards_distributed(&mut self, block_number: U64, amount: U256, per_share_delta: U256) {
+        self.0
+            .on_rewards_distributed(block_number, amount, per_share_delta);
+        self.1
+            .on_rewards_distributed(block_number, amount, per_share_delta);
+    }
+
+    fn on_invariant_warning(&mut self, warning: &InvariantWarning) {
+        self.0.on_invariant_warning(warning);
+        self.1.on_invariant_warning(warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, GlobalState, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    /// Writes every callback down as a line.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+    }
+
+    impl StateObserver for Recorder {
+        fn on_event_applied(&mut self, event: &Event, delta: &UserDelta) {
+            let changes: Vec<_> = delta
+                .changes
+                .iter()
+                .map(|change| {
+                    let shares = |view: &UserView| view.shares / parse_ether("1").unwrap();
+                    format!(
+                        "{:?} {:?}->{}",
+                        change.address.to_low_u64_be(),
+                        change.before.as_ref().map(shares),
+                        shares(&change.after)
+                    )
+                })
+                .collect();
+            self.calls.push(format!(
+                "applied {} at {}: {}",
+                match event {
+                    Event::Deposit(_) => "deposit",
+                    Event::Withdrawal(_) => "withdrawal",
+                    Event::Transfer(_) => "transfer",
+                },
+                event.block_number().as_u64() - BLOCK_CONTRACT_DEPLOYED,
+                changes.join(", ")
+            ));
+        }
+
+        fn on_rewards_distributed(
+            &mut self,
+            block_number: U64,
+            amount: U256,
+            per_share_delta: U256,
+        ) {
+            self.calls.push(format!(
+                "distributed {} up to {}, {} per share",
+                amount / parse_ether("1").unwrap(),
+                block_number.as_u64() - BLOCK_CONTRACT_DEPLOYED,
+                per_share_delta / parse_ether("1").unwrap()
+            ));
+        }
+
+        fn on_invariant_warning(&mut self, warning: &InvariantWarning) {
+            self.calls.push(match warning {
+                InvariantWarning::SuspiciousShares(event) => {
+                    format!("suspicious at {}", event.block_number())
+                }
+                InvariantWarning::Quarantined(quarantined) => format!(
+                    "quarantined at {}, sender held {}",
+                    quarantined.event.block_number().as_u64() - BLOCK_CONTRACT_DEPLOYED,
+                    quarantined.held / parse_ether("1").unwrap()
+                ),
+            });
+        }
+    }
+
+    #[test]
+    fn reports_each_step_of_a_replay_in_order() {
+        let bob = Address::from_low_u64_be(0xb);
+        let alice = Address::from_low_u64_be(0xa);
+        let one = parse_ether("1").unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+
+        let events = vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 2),
+                block_number: block(0),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number: block(10),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: alice,
+                shares: Shares(one * 5),
+                block_number: block(20),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: Shares(one),
+                block_number: block(30),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+
+        let mut state = GlobalState::new();
+        state.set_best_effort(true);
+        let mut recorder = Recorder::default();
+        state
+            .process_events_with(events.clone(), &mut recorder)
+            .unwrap();
+
+        assert_eq!(
+            recorder.calls,
+            [
+                "applied deposit at 0: 11 None->2",
+                "distributed 10 up to 10, 5 per share",
+                "applied transfer at 10: 11 Some(2)->1, 10 None->1",
+                "quarantined at 20, sender held 1",
+                "distributed 20 up to 30, 10 per share",
+                "applied withdrawal at 30: 11 Some(1)->0",
+            ]
+        );
+
+        let mut unobserved = GlobalState::new();
+        unobserved.set_best_effort(true);
+        unobserved.process_events(events).unwrap();
+        assert_eq!(state.state_hash(), unobserved.state_hash());
+    }
+}
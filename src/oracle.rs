@@ -0,0 +1,549 @@
+//! A read-through rewards oracle for services that embed the calculator: the vault history is
+//! backfilled on the first call and followed incrementally after that, each refresh served
+//! until it is older than the TTL.
+//!
+//! Concurrent callers that find the cache cold or expired share a single refresh. Once it is
+//! older than the TTL, [`StalePolicy`] decides whether a caller waits for the refresh or gets
+//! the stale value while the refresh runs in the background. A failed refresh never replaces
+//! the cached state: waiting callers get its error, and under `ServeStale` the stale value is
+//! served, the failure only counted in [`OracleMetrics::refresh_errors`]. Events are applied
+//! up to the head [`OracleConfig::follow`] names as the node reports it, so a reorg past that
+//! head is not undone.
+
+use crate::clock::{tokio_clock, Clock, Head, HeadSource};
+use crate::error::Result;
+use crate::fetch::{chunk_grid, fetch_chunks, Decoded, LogSource, CHUNK_SIZE};
+use crate::flavor::VaultFlavor;
+use crate::rpc::Client;
+use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+use ethers::core::types::{Address, U256, U64};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+use tokio::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A chain the oracle follows: the vault's logs, and the head to fetch them up to.
+pub trait OracleSource: LogSource + HeadSource + Clone + 'static {}
+
+impl<S: LogSource + HeadSource + Clone + 'static> OracleSource for S {}
+
+/// What a caller gets once the cached rewards are older than the TTL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// Wait for the refresh, failing when it does
+    #[default]
+    Wait,
+    /// Get the stale value at once while the refresh runs in the background
+    ServeStale,
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct OracleConfig {
+    pub vault: Address,
+    pub flavor: VaultFlavor,
+    /// How long a refresh is served before the next call triggers another
+    pub ttl: Duration,
+    pub policy: StalePolicy,
+    /// Requests in flight while fetching
+    pub concurrency: usize,
+    /// Head refreshes fetch up to
+    pub follow: Head,
+    /// Block the vault was deployed at, where the first refresh starts fetching
+    pub deployed_block: u64,
+}
+
+impl OracleConfig {
+    /// Default settings for an oracle of `vault`: refreshed at most once a block, waiting for
+    /// refreshes.
+    pub fn new(vault: Address) -> OracleConfig {
+        OracleConfig {
+            vault,
+            flavor: VaultFlavor::oprtc_v1(),
+            ttl: Duration::from_secs(12),
+            policy: StalePolicy::Wait,
+            concurrency: 4,
+            follow: Head::Latest,
+            deployed_block: BLOCK_CONTRACT_DEPLOYED,
+        }
+    }
+}
+
+/// The rewards of one address as of the oracle's last refresh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewardView {
+    pub address: Address,
+    pub shares: U256,
+    pub rewards: U256,
+    /// Block the rewards are accounted up to
+    pub block: U64,
+    /// Time since that block was fetched
+    pub age: Duration,
+    /// Older than the TTL, served while a refresh runs in the background
+    pub stale: bool,
+}
+
+/// Counts since the oracle was created, one of `hits`, `misses` or `stale` per call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OracleMetrics {
+    /// Served from a refresh younger than the TTL
+    pub hits: u64,
+    /// Waited for a refresh, the first backfill included
+    pub misses: u64,
+    /// Served past the TTL under `StalePolicy::ServeStale`
+    pub stale: u64,
+    pub refreshes: u64,
+    pub refresh_errors: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_errors: AtomicU64,
+}
+
+fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Accounting state as of one refresh, replaced whole by the next.
+struct Snapshot {
+    state: GlobalState,
+    block: u64,
+    fetched_at: Instant,
+}
+
+impl Snapshot {
+    fn is_fresh(&self, now: Instant, ttl: Duration) -> bool {
+        now - self.fetched_at < ttl
+    }
+
+    fn view(&self, address: Address, now: Instant, ttl: Duration) -> RewardView {
+        let block = U64::from(self.block);
+        let age = now - self.fetched_at;
+        RewardView {
+            address,
+            shares: self.state.shares_of(address),
+            rewards: self.state.preview_user_rewards(address, block),
+            block,
+            age,
+            stale: age >= ttl,
+        }
+    }
+}
+
+struct Inner<S> {
+    source: S,
+    config: OracleConfig,
+    clock: Arc<dyn Clock>,
+    snapshot: RwLock<Option<Arc<Snapshot>>>,
+    /// Held by whichever call is refreshing, so concurrent calls share one refresh
+    refreshing: Arc<Mutex<()>>,
+    counters: Counters,
+}
+
+impl<S: OracleSource> Inner<S> {
+    fn current(&self) -> Option<Arc<Snapshot>> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Fetches the events past the current snapshot up to the head and publishes the result.
+    /// Callers hold `refreshing`.
+    async fn refresh(&self) -> Result<Arc<Snapshot>> {
+        let refreshed = self.fetch().await;
+        match &refreshed {
+            Ok(snapshot) => {
+                bump(&self.counters.refreshes);
+                *self.snapshot.write().unwrap() = Some(snapshot.clone());
+            }
+            Err(_) => bump(&self.counters.refresh_errors),
+        }
+        refreshed
+    }
+
+    async fn fetch(&self) -> Result<Arc<Snapshot>> {
+        let head = self.source.head(self.config.follow).await?;
+        let current = self.current();
+        let (mut state, synced_block) = match &current {
+            Some(snapshot) => (snapshot.state.clone(), snapshot.block),
+            None => (
+                GlobalState::deployed_at(self.config.deployed_block),
+                self.config.deployed_block - 1,
+            ),
+        };
+
+        // a node behind the last refresh has nothing new
+        if head > synced_block {
+            let chunks = chunk_grid(synced_block + 1, head, CHUNK_SIZE);
+            let config = &self.config;
+            let fetched = fetch_chunks(
+                &self.source,
+                &config.flavor,
+                config.vault,
+                &chunks,
+                config.concurrency,
+            )
+            .await?;
+            state.try_process_events(Decoded::concat(fetched).strict()?)?;
+        }
+
+        Ok(Arc::new(Snapshot {
+            state,
+            block: head.max(synced_block),
+            fetched_at: self.clock.now(),
+        }))
+    }
+}
+
+/// Rewards of a vault, served from a cache refreshed from `S` at most once per TTL. Clones share
+/// the cache.
+#[derive(Clone)]
+pub struct RewardsOracle<S = Client> {
+    inner: Arc<Inner<S>>,
+}
+
+impl<S: OracleSource> RewardsOracle<S> {
+    /// Nothing is fetched until the first call.
+    pub fn new(source: S, config: OracleConfig) -> RewardsOracle<S> {
+        RewardsOracle::with_clock(source, config, tokio_clock())
+    }
+
+    /// An oracle timing its TTL by `clock`.
+    pub fn with_clock(source: S, config: OracleConfig, clock: Arc<dyn Clock>) -> RewardsOracle<S> {
+        RewardsOracle {
+            inner: Arc::new(Inner {
+                source,
+                config,
+                clock,
+                snapshot: RwLock::new(None),
+                refreshing: Arc::new(Mutex::new(())),
+                counters: Counters::default(),
+            }),
+        }
+    }
+
+    pub async fn rewards_of(&self, address: Address) -> Result<RewardView> {
+        let snapshot = self.snapshot().await?;
+        Ok(snapshot.view(address, self.inner.clock.now(), self.inner.config.ttl))
+    }
+
+    pub fn metrics(&self) -> OracleMetrics {
+        let counters = &self.inner.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        OracleMetrics {
+            hits: load(&counters.hits),
+            misses: load(&counters.misses),
+            stale: load(&counters.stale),
+            refreshes: load(&counters.refreshes),
+            refresh_errors: load(&counters.refresh_errors),
+        }
+    }
+
+    async fn snapshot(&self) -> Result<Arc<Snapshot>> {
+        let inner = &self.inner;
+        let ttl = inner.config.ttl;
+
+        match inner.current() {
+            Some(snapshot) if snapshot.is_fresh(inner.clock.now(), ttl) => {
+                bump(&inner.counters.hits);
+                return Ok(snapshot);
+            }
+            Some(snapshot) if inner.config.policy == StalePolicy::ServeStale => {
+                bump(&inner.counters.stale);
+                // a held lock means a refresh is already on its way
+                if let Ok(guard) = inner.refreshing.clone().try_lock_owned() {
+                    let inner = inner.clone();
+                    tokio::spawn(async move {
+                        let _guard = guard;
+                        // a failure is counted, and the next stale call retries
+                        let _ = inner.refresh().await;
+                    });
+                }
+                return Ok(snapshot);
+            }
+            _ => {}
+        }
+
+        bump(&inner.counters.misses);
+        let _guard = inner.refreshing.lock().await;
+        // the call that held the lock before may have refreshed already
+        match inner.current() {
+            Some(snapshot) if snapshot.is_fresh(inner.clock.now(), ttl) => Ok(snapshot),
+            _ => inner.refresh().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::manual::{ManualClock, ManualHead};
+    use crate::error::Error;
+    use crate::fetch::mock::{deposit_log, MockSource, VAULT};
+    use async_trait::async_trait;
+    use ethers::{core::types::Filter, utils::parse_ether};
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Mutex as StdMutex,
+    };
+    use tokio::task::JoinSet;
+
+    const TTL: Duration = Duration::from_secs(12);
+
+    /// A chain whose head and logs tests move along, answering each call after a second and
+    /// failing every call while it is down.
+    #[derive(Clone, Default)]
+    struct MockChain {
+        logs: Arc<StdMutex<Vec<ethers::core::types::Log>>>,
+        head: Arc<AtomicU64>,
+        down: Arc<AtomicBool>,
+        head_calls: Arc<AtomicUsize>,
+    }
+
+    impl MockChain {
+        fn deposit(&self, owner: Address, ether: &str, block_number: u64) {
+            let shares = parse_ether(ether).unwrap();
+            self.logs
+                .lock()
+                .unwrap()
+                .push(deposit_log(owner, shares, block_number));
+            self.head.fetch_max(block_number, Ordering::Relaxed);
+        }
+
+        fn mine(&self, blocks: u64) {
+            self.head.fetch_add(blocks, Ordering::Relaxed);
+        }
+
+        async fn respond(&self) -> Result<()> {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if self.down.load(Ordering::Relaxed) {
+                return Err(Error::rpc("provider is down"));
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl LogSource for MockChain {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<ethers::core::types::Log>> {
+            self.respond().await?;
+            let logs = self.logs.lock().unwrap().clone();
+            MockSource { logs }.fetch_logs(filter).await
+        }
+    }
+
+    #[async_trait]
+    impl HeadSource for MockChain {
+        async fn latest_block(&self) -> Result<u64> {
+            self.head_calls.fetch_add(1, Ordering::Relaxed);
+            self.respond().await?;
+            Ok(self.head.load(Ordering::Relaxed))
+        }
+
+        async fn safe_block(&self) -> Result<u64> {
+            self.latest_block().await
+        }
+
+        async fn finalized_block(&self) -> Result<u64> {
+            self.latest_block().await
+        }
+    }
+
+    fn oracle(chain: &MockChain, policy: StalePolicy) -> RewardsOracle<MockChain> {
+        let mut config = OracleConfig::new(VAULT.parse().unwrap());
+        config.ttl = TTL;
+        config.policy = policy;
+        RewardsOracle::new(chain.clone(), config)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_the_cache_until_the_ttl_then_follows_the_chain() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let chain = MockChain::default();
+        chain.deposit(bob, "1", BLOCK_CONTRACT_DEPLOYED);
+        chain.mine(100);
+        let oracle = oracle(&chain, StalePolicy::Wait);
+        assert_eq!(chain.head_calls.load(Ordering::Relaxed), 0);
+
+        let backfilled = oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(backfilled.rewards, parse_ether("100").unwrap());
+        assert!(!backfilled.stale);
+
+        chain.deposit(bob, "1", BLOCK_CONTRACT_DEPLOYED + 200);
+        tokio::time::advance(TTL / 2).await;
+        let cached = oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(cached.block, backfilled.block);
+        assert_eq!(cached.age, TTL / 2);
+
+        tokio::time::advance(TTL).await;
+        let refreshed = oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(refreshed.block, U64::from(BLOCK_CONTRACT_DEPLOYED + 200));
+        assert_eq!(refreshed.shares, parse_ether("2").unwrap());
+        assert_eq!(refreshed.rewards, parse_ether("200").unwrap());
+
+        assert_eq!(chain.head_calls.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            oracle.metrics(),
+            OracleMetrics {
+                hits: 1,
+                misses: 2,
+                stale: 0,
+                refreshes: 2,
+                refresh_errors: 0,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_one_refresh() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let chain = MockChain::default();
+        chain.deposit(bob, "1", BLOCK_CONTRACT_DEPLOYED);
+        chain.mine(100);
+        let oracle = oracle(&chain, StalePolicy::Wait);
+
+        let mut callers = JoinSet::new();
+        for _ in 0..10 {
+            let oracle = oracle.clone();
+            callers.spawn(async move { oracle.rewards_of(bob).await.unwrap() });
+        }
+        while let Some(view) = callers.join_next().await {
+            assert_eq!(view.unwrap().rewards, parse_ether("100").unwrap());
+        }
+
+        assert_eq!(chain.head_calls.load(Ordering::Relaxed), 1);
+        let metrics = oracle.metrics();
+        assert_eq!((metrics.misses, metrics.refreshes), (10, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_stale_values_while_revalidating_and_while_the_provider_is_down() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let chain = MockChain::default();
+        chain.deposit(bob, "1", BLOCK_CONTRACT_DEPLOYED);
+        chain.mine(100);
+        let stale_oracle = oracle(&chain, StalePolicy::ServeStale);
+        let waiting_oracle = oracle(&chain, StalePolicy::Wait);
+        let warm = stale_oracle.rewards_of(bob).await.unwrap();
+        waiting_oracle.rewards_of(bob).await.unwrap();
+
+        chain.mine(50);
+        tokio::time::advance(TTL).await;
+        let stale = stale_oracle.rewards_of(bob).await.unwrap();
+        assert!(stale.stale);
+        assert_eq!(stale.rewards, warm.rewards);
+        // served at once, with the refresh still running
+        assert_eq!(stale_oracle.metrics().refreshes, 1);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let revalidated = stale_oracle.rewards_of(bob).await.unwrap();
+        assert!(!revalidated.stale);
+        assert_eq!(revalidated.rewards, parse_ether("150").unwrap());
+
+        chain.down.store(true, Ordering::Relaxed);
+        tokio::time::advance(TTL).await;
+        let served = stale_oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(served.rewards, revalidated.rewards);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(stale_oracle.rewards_of(bob).await.unwrap().stale);
+
+        let metrics = stale_oracle.metrics();
+        assert_eq!((metrics.stale, metrics.refresh_errors), (3, 1));
+
+        let err = waiting_oracle.rewards_of(bob).await.unwrap_err();
+        assert!(matches!(err, Error::Rpc(_)));
+        assert_eq!(waiting_oracle.metrics().refresh_errors, 1);
+    }
+
+    /// Fixed logs behind a manually moved head, recording the block range of every fetch.
+    #[derive(Clone)]
+    struct RecordingChain {
+        logs: MockSource,
+        head: ManualHead,
+        ranges: Arc<StdMutex<Vec<(U64, U64)>>>,
+    }
+
+    #[async_trait]
+    impl LogSource for RecordingChain {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<ethers::core::types::Log>> {
+            let range = (
+                filter.get_from_block().unwrap(),
+                filter.get_to_block().unwrap(),
+            );
+            self.ranges.lock().unwrap().push(range);
+            self.logs.fetch_logs(filter).await
+        }
+    }
+
+    #[async_trait]
+    impl HeadSource for RecordingChain {
+        async fn latest_block(&self) -> Result<u64> {
+            self.head.latest_block().await
+        }
+
+        async fn safe_block(&self) -> Result<u64> {
+            self.head.safe_block().await
+        }
+
+        async fn finalized_block(&self) -> Result<u64> {
+            self.head.finalized_block().await
+        }
+    }
+
+    #[tokio::test]
+    async fn fetches_exactly_the_blocks_the_head_advanced_by() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let start = BLOCK_CONTRACT_DEPLOYED + 100;
+        let chain = RecordingChain {
+            logs: MockSource {
+                logs: vec![deposit_log(
+                    bob,
+                    parse_ether("1").unwrap(),
+                    BLOCK_CONTRACT_DEPLOYED,
+                )],
+            },
+            head: ManualHead::default(),
+            ranges: Arc::default(),
+        };
+        chain.head.set(start, start, start);
+        let clock = ManualClock::default();
+        let mut config = OracleConfig::new(VAULT.parse().unwrap());
+        config.ttl = TTL;
+        let oracle =
+            RewardsOracle::with_clock(chain.clone(), config.clone(), Arc::new(clock.clone()));
+        oracle.rewards_of(bob).await.unwrap();
+        chain.ranges.lock().unwrap().clear();
+
+        chain.head.advance(3);
+        clock.advance(TTL - Duration::from_secs(1));
+        let cached = oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(cached.block, U64::from(start));
+        assert!(chain.ranges.lock().unwrap().is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        let refreshed = oracle.rewards_of(bob).await.unwrap();
+        assert_eq!(refreshed.block, U64::from(start + 3));
+        assert_eq!(refreshed.age, Duration::ZERO);
+        assert_eq!(refreshed.rewards, parse_ether("103").unwrap());
+        let ranges = chain.ranges.lock().unwrap().clone();
+        assert!(!ranges.is_empty());
+        assert!(ranges
+            .iter()
+            .all(|range| *range == (U64::from(start + 1), U64::from(start + 3))));
+
+        chain.head.set(start + 10, start + 6, start + 5);
+        config.follow = Head::Finalized;
+        let finalized = RewardsOracle::with_clock(chain, config, Arc::new(clock));
+        assert_eq!(
+            finalized.rewards_of(bob).await.unwrap().block,
+            U64::from(start + 5)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::freshness::Freshness;
+use ethers::core::types::U256;
+use serde::{Serialize, Serializer};
+
+/// Version of the export shapes, bumped whenever columns are added, removed or renamed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    Markdown,
+}
+
+/// Format of the rewards report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// The aligned table
+    Text,
+    /// A self-contained page with a searchable table and charts
+    Html,
+    /// Totals and every payee's rewards in wei and ether
+    Json,
+}
+
+/// Formats wei as ether with `decimals` fractional digits, rounding half up. Exports keep wei.
+pub fn format_ether_rounded(wei: U256, decimals: usize) -> String {
+    let decimals = decimals.min(18);
+    let scale = U256::exp10(18 - decimals);
+    let rounded = (wei + scale / 2) / scale;
+
+    if decimals == 0 {
+        return rounded.to_string();
+    }
+
+    let unit = U256::exp10(decimals);
+    format!(
+        "{}.{:0>width$}",
+        rounded / unit,
+        (rounded % unit).to_string(),
+        width = decimals
+    )
+}
+
+/// Serializes a `U256` as a decimal string so JSON consumers don't lose precision.
+pub fn serialize_u256<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+#[derive(Serialize)]
+struct Export<'a, T> {
+    schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    freshness: Option<&'a Freshness>,
+    rows: &'a [T],
+}
+
+/// Renders rows as a JSON object tagged with the current `SCHEMA_VERSION`.
+pub fn versioned_json<T: Serialize>(rows: &[T]) -> String {
+    let export = Export {
+        schema_version: SCHEMA_VERSION,
+        freshness: None,
+        rows,
+    };
+
+    serde_json::to_string_pretty(&export).expect("rows should serialize")
+}
+
+/// Like `versioned_json`, for rows drawn from chain data up to the horizon of `freshness`.
+pub fn stamped_json<T: Serialize>(rows: &[T], freshness: &Freshness) -> String {
+    let export = Export {
+        schema_version: SCHEMA_VERSION,
+        freshness: Some(freshness),
+        rows,
+    };
+
+    serde_json::to_string_pretty(&export).expect("rows should serialize")
+}
+
+/// The comment lines opening a CSV export: the schema version, then the data horizon.
+pub fn csv_preamble(freshness: &Freshness) -> String {
+    format!("# schema_version: {}\n# {}\n", SCHEMA_VERSION, freshness)
+}
+
+/// The comment lines opening a Markdown export, as `csv_preamble`.
+pub fn markdown_preamble(freshness: &Freshness) -> String {
+    format!(
+        "<!-- schema_version: {} -->\n<!-- {} -->\n",
+        SCHEMA_VERSION, freshness
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::utils::parse_ether;
+
+    #[test]
+    fn rounds_ether_half_up_to_the_configured_decimals() {
+        let value = parse_ether("1.23456789").unwrap();
+
+        assert_eq!(format_ether_rounded(value, 4), "1.2346");
+        assert_eq!(format_ether_rounded(value, 2), "1.23");
+        assert_eq!(format_ether_rounded(value, 0), "1");
+        assert_eq!(
+            format_ether_rounded(parse_ether("0.005").unwrap(), 2),
+            "0.01"
+        );
+        assert_eq!(
+            format_ether_rounded(parse_ether("2.004").unwrap(), 2),
+            "2.00"
+        );
+        assert_eq!(format_ether_rounded(value, 18), "1.234567890000000000");
+    }
+}
@@ -0,0 +1,242 @@
+use crate::lineage::EmbeddedLineage;
+use crate::report::{Report, ReportRow};
+use ethers::{core::types::U256, utils::parse_ether};
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// State carried from one payout epoch to the next, keyed by report label.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rollover {
+    /// Block the epoch was reported at
+    pub epoch: u64,
+    /// Cumulative rewards through `epoch`, so the next epoch only adds what was earned since
+    pub earned: BTreeMap<String, U256>,
+    /// Payouts withheld below the minimum, kept until they cross it
+    pub carried: BTreeMap<String, U256>,
+    /// Run that wrote the rollover and the files it was built from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+impl Rollover {
+    pub fn load(path: &Path) -> Result<Rollover> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read rollover {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse rollover {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .wrap_err_with(|| format!("failed to write rollover {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payouts {
+    /// Largest first
+    pub paid: Vec<(String, U256)>,
+    pub carried: BTreeMap<String, U256>,
+}
+
+/// Pays every label whose earnings plus carried amount reach `min_payout` and carries the rest.
+/// Carried amounts of labels that earned nothing this epoch are kept as they are.
+pub fn apply_min_payout(
+    earned: &[(String, U256)],
+    carried_in: &BTreeMap<String, U256>,
+    min_payout: U256,
+) -> Payouts {
+    let mut due = carried_in.clone();
+    for (label, amount) in earned {
+        *due.entry(label.clone()).or_default() += *amount;
+    }
+
+    let mut paid = vec![];
+    let mut carried = BTreeMap::new();
+    for (label, amount) in due {
+        if amount.is_zero() {
+            continue;
+        }
+        if amount >= min_payout {
+            paid.push((label, amount));
+        } else {
+            carried.insert(label, amount);
+        }
+    }
+    paid.sort_by(|(a_label, a), (b_label, b)| b.cmp(a).then_with(|| a_label.cmp(b_label)));
+
+    Payouts { paid, carried }
+}
+
+fn total<'a>(amounts: impl Iterator<Item = &'a U256>) -> U256 {
+    amounts.fold(U256::zero(), |sum, amount| sum + *amount)
+}
+
+/// Turns a cumulative rewards report into the payouts of the epoch ending at `epoch`: the
+/// rewards earned since `previous`, plus its carried amounts, less what stays below
+/// `min_payout`. Returns the rollover for the next epoch.
+pub fn pay_epoch(
+    report: &mut Report,
+    previous: &Rollover,
+    min_payout: U256,
+    epoch: u64,
+) -> Rollover {
+    let cumulative: BTreeMap<_, _> = report
+        .rows
+        .iter()
+        .map(|row| (row.label.clone(), row.rewards))
+        .collect();
+    let earned: Vec<_> = cumulative
+        .iter()
+        .map(|(label, rewards)| {
+            let before = previous.earned.get(label).copied().unwrap_or_default();
+            (label.clone(), rewards.saturating_sub(before))
+        })
+        .collect();
+
+    let payouts = apply_min_payout(&earned, &previous.carried, min_payout);
+
+    let notes: BTreeMap<_, _> = report
+        .rows
+        .iter()
+        .map(|row| (row.label.clone(), row.note.clone()))
+        .collect();
+    report.rows = payouts
+        .paid
+        .iter()
+        .map(|(label, rewards)| ReportRow {
+            label: label.clone(),
+            rewards: *rewards,
+            bonus: U256::zero(),
+            note: notes.get(label).cloned().flatten(),
+            reason: None,
+        })
+        .collect();
+
+    report.total_rewards_expected =
+        total(earned.iter().map(|(_, amount)| amount)) + total(previous.carried.values());
+    report.total_rewards_given = total(payouts.paid.iter().map(|(_, amount)| amount));
+
+    Rollover {
+        epoch,
+        earned: cumulative,
+        carried: payouts.carried,
+        lineage: None,
+    }
+}
+
+/// Parses a `--min-payout` amount in ether.
+pub fn parse_min_payout(value: &str) -> Result<U256, String> {
+    parse_ether(value).map_err(|err| format!("`{}` is not an ether amount: {}", value, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rounding::Rounding;
+
+    fn ether(value: &str) -> U256 {
+        parse_ether(value).unwrap()
+    }
+
+    fn report(rows: &[(&str, &str)]) -> Report {
+        Report {
+            total_rewards_expected: U256::zero(),
+            total_rewards_given: U256::zero(),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 18,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+            rows: rows
+                .iter()
+                .map(|(label, rewards)| ReportRow {
+                    label: label.to_string(),
+                    rewards: ether(rewards),
+                    bonus: U256::zero(),
+                    note: None,
+                    reason: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn carries_dust_until_it_crosses_the_threshold() {
+        let min = ether("1");
+        let mut carried = BTreeMap::new();
+        let mut paid_total = U256::zero();
+        let mut earned_total = U256::zero();
+
+        for (epoch, amount) in ["0.3", "0.3", "0.5"].iter().enumerate() {
+            let earned = vec![
+                ("bob".to_string(), ether(amount)),
+                ("alice".to_string(), ether("1")),
+            ];
+            earned_total += ether(amount) + ether("1");
+
+            let payouts = apply_min_payout(&earned, &carried, min);
+            let paid_bob = payouts.paid.iter().find(|(label, _)| label == "bob");
+            match epoch {
+                0 | 1 => assert_eq!(paid_bob, None),
+                _ => assert_eq!(paid_bob, Some(&("bob".to_string(), ether("1.1")))),
+            }
+
+            paid_total += total(payouts.paid.iter().map(|(_, amount)| amount));
+            carried = payouts.carried;
+        }
+
+        assert!(carried.is_empty());
+        assert_eq!(paid_total, earned_total);
+    }
+
+    #[test]
+    fn keeps_carried_amounts_of_addresses_that_stop_earning() {
+        let min = ether("0.5");
+        let mut carried = BTreeMap::from([("carol".to_string(), ether("0.2"))]);
+
+        for _ in 0..3 {
+            let payouts = apply_min_payout(&[("bob".to_string(), ether("1"))], &carried, min);
+            assert_eq!(payouts.paid, vec![("bob".to_string(), ether("1"))]);
+            carried = payouts.carried;
+        }
+
+        assert_eq!(
+            carried,
+            BTreeMap::from([("carol".to_string(), ether("0.2"))])
+        );
+    }
+
+    #[test]
+    fn pays_only_what_was_earned_since_the_last_epoch() {
+        let min = ether("0.5");
+
+        let mut first = report(&[("bob", "0.3"), ("alice", "2")]);
+        let rollover = pay_epoch(&mut first, &Rollover::default(), min, 100);
+        assert_eq!(first.rows.len(), 1);
+        assert_eq!(first.total_rewards_given, ether("2"));
+        assert_eq!(rollover.carried["bob"], ether("0.3"));
+
+        // cumulative rewards keep growing, alice earned 1 more and bob 0.3 more
+        let restored: Rollover =
+            serde_json::from_str(&serde_json::to_string(&rollover).unwrap()).unwrap();
+        let mut second = report(&[("bob", "0.6"), ("alice", "3")]);
+        let rollover = pay_epoch(&mut second, &restored, min, 200);
+
+        assert_eq!(rollover.epoch, 200);
+        assert!(rollover.carried.is_empty());
+        assert_eq!(second.rows[0].label, "alice");
+        assert_eq!(second.rows[0].rewards, ether("1"));
+        assert_eq!(second.rows[1].rewards, ether("0.6"));
+        assert_eq!(second.total_rewards_expected, ether("1.6"));
+        assert_eq!(second.total_rewards_given, ether("1.6"));
+    }
+}
@@ -0,0 +1,327 @@
+//! Fetching, decoding and processing of chunks as overlapping stages: while the processor
+//! replays one chunk, later ones are being decoded and fetched.
+//!
+//! A fetch stage keeps up to `depth` chunks in flight, a pool of decoders turns their raw logs
+//! into events, and the processor is handed the decoded chunks one at a time in grid order,
+//! however they complete. A chunk holds its slot from the moment it is requested until it has
+//! been processed, so at most `depth` chunks are held in memory at any stage.
+
+use crate::error::{Error, Result};
+use crate::fetch::{decode_logs, fetch_vault_logs, Decoded, LogSource};
+use crate::flavor::VaultFlavor;
+use ethers::core::types::{Address, Log};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, panic,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::Instant,
+};
+
+/// Time each stage spent working, summed over the chunks, and the wall time of the whole run.
+/// Busy times adding up to more than the wall time are the overlap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineTimings {
+    pub fetch: Duration,
+    pub decode: Duration,
+    pub process: Duration,
+    pub wall: Duration,
+    pub chunks: usize,
+}
+
+impl fmt::Display for PipelineTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pipeline: {} chunks, busy fetching {:?}, decoding {:?}, processing {:?}, over {:?}",
+            self.chunks, self.fetch, self.decode, self.process, self.wall
+        )
+    }
+}
+
+/// A chunk on its way through the stages, numbered by its place in the grid.
+struct InFlight<T> {
+    sequence: usize,
+    data: T,
+    fetch: Duration,
+    decode: Duration,
+    _slot: OwnedSemaphorePermit,
+}
+
+/// Fetches and decodes every chunk with up to `depth` of them between being requested and
+/// processed, and hands them to `process` in the order the chunks were given. Stops at the
+/// first error of any stage.
+pub async fn fetch_pipelined<S, F>(
+    source: &S,
+    flavor: &VaultFlavor,
+    vault: Address,
+    chunks: &[(u64, u64)],
+    depth: usize,
+    mut process: F,
+) -> Result<PipelineTimings>
+where
+    S: LogSource + Clone + 'static,
+    F: FnMut(Decoded) -> Result<()>,
+{
+    let started = Instant::now();
+    let depth = depth.max(1);
+    let slots = Arc::new(Semaphore::new(depth));
+    let (raw_tx, raw_rx) = mpsc::channel::<InFlight<Vec<Log>>>(depth);
+    let (decoded_tx, mut decoded_rx) = mpsc::channel::<InFlight<Decoded>>(depth);
+    // dropped on return, which aborts whatever stage is still running
+    let mut stages = JoinSet::new();
+
+    let pending: VecDeque<_> = chunks.iter().copied().enumerate().collect();
+    stages.spawn(fetch_stage(
+        source.clone(),
+        flavor.clone(),
+        vault,
+        pending,
+        slots,
+        raw_tx,
+    ));
+
+    let raw_rx = Arc::new(Mutex::new(raw_rx));
+    let decoders = thread::available_parallelism().map_or(1, usize::from);
+    for _ in 0..decoders.min(depth) {
+        let raw_rx = raw_rx.clone();
+        let decoded_tx = decoded_tx.clone();
+        let flavor = flavor.clone();
+
+        stages.spawn(async move {
+            loop {
+                // the lock is released before decoding, so decoders take turns only at receiving
+                let Some(chunk) = raw_rx.lock().await.recv().await else {
+                    return Ok(());
+                };
+                let decoding = Instant::now();
+                let decoded = InFlight {
+                    sequence: chunk.sequence,
+                    data: decode_logs(&flavor, &chunk.data),
+                    fetch: chunk.fetch,
+                    decode: decoding.elapsed(),
+                    _slot: chunk._slot,
+                };
+                if decoded_tx.send(decoded).await.is_err() {
+                    return Ok(());
+                }
+            }
+        });
+    }
+    drop(decoded_tx);
+
+    let mut timings = PipelineTimings::default();
+    let mut reorder = BTreeMap::new();
+    while let Some(chunk) = decoded_rx.recv().await {
+        reorder.insert(chunk.sequence, chunk);
+        while let Some(chunk) = reorder.remove(&timings.chunks) {
+            let processing = Instant::now();
+            process(chunk.data)?;
+            timings.process += processing.elapsed();
+            timings.fetch += chunk.fetch;
+            timings.decode += chunk.decode;
+            timings.chunks += 1;
+        }
+    }
+
+    // the channels close early only when a stage failed
+    while let Some(joined) = stages.join_next().await {
+        joined.unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))?;
+    }
+    debug_assert_eq!(timings.chunks, chunks.len());
+
+    timings.wall = started.elapsed();
+    Ok(timings)
+}
+
+/// Requests the chunks in order, each once a slot is free, and passes on their logs as they
+/// arrive.
+async fn fetch_stage<S: LogSource + Clone + 'static>(
+    source: S,
+    flavor: VaultFlavor,
+    vault: Address,
+    mut pending: VecDeque<(usize, (u64, u64))>,
+    slots: Arc<Semaphore>,
+    raw_tx: mpsc::Sender<InFlight<Vec<Log>>>,
+) -> Result<()> {
+    let mut requests = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            Some(joined) = requests.join_next() => {
+                let chunk: InFlight<Vec<Log>> =
+                    joined.unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))?;
+                if raw_tx.send(chunk).await.is_err() {
+                    return Ok(());
+                }
+            }
+            slot = slots.clone().acquire_owned(), if !pending.is_empty() => {
+                let slot = slot.expect("the semaphore is never closed");
+                let (sequence, (start, end)) = pending.pop_front().unwrap();
+                let source = source.clone();
+                let flavor = flavor.clone();
+
+                requests.spawn(async move {
+                    let fetching = Instant::now();
+                    let logs = fetch_vault_logs(&source, &flavor, vault, start, end).await?;
+                    Ok::<_, Error>(InFlight {
+                        sequence,
+                        data: logs,
+                        fetch: fetching.elapsed(),
+                        decode: Duration::ZERO,
+                        _slot: slot,
+                    })
+                });
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{
+        chunk_grid, fetch_events,
+        mock::{deposit_log, transfer_log, withdraw_log, MockSource, VAULT},
+    };
+    use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use async_trait::async_trait;
+    use ethers::{core::types::Filter, utils::parse_ether};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const FROM: u64 = BLOCK_CONTRACT_DEPLOYED;
+    const TO: u64 = BLOCK_CONTRACT_DEPLOYED + 9_999;
+
+    /// Sleeps before answering, longer for earlier ranges so chunks complete in reverse, and
+    /// counts the requests in flight.
+    #[derive(Clone)]
+    struct SlowSource {
+        source: MockSource,
+        latency: Duration,
+        in_flight: Arc<AtomicUsize>,
+        most_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl SlowSource {
+        fn new(source: MockSource, latency: Duration) -> SlowSource {
+            SlowSource {
+                source,
+                latency,
+                in_flight: Arc::new(AtomicUsize::new(0)),
+                most_in_flight: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LogSource for SlowSource {
+        async fn fetch_logs(&self, filter: &Filter) -> Result<Vec<Log>> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            let from_block = filter.get_from_block().unwrap().as_u64();
+            let behind = (TO - from_block) as u32 / 1_000;
+            tokio::time::sleep(self.latency + self.latency * behind / 10).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.source.fetch_logs(filter).await
+        }
+    }
+
+    fn history() -> MockSource {
+        let bob = "0x0000000000000000000000000000000000000B0b"
+            .parse()
+            .unwrap();
+        let alice = "0x00000000000000000000000000000000000A11cE"
+            .parse()
+            .unwrap();
+        let one = parse_ether("1").unwrap();
+
+        MockSource {
+            logs: vec![
+                deposit_log(bob, one * 4, FROM),
+                deposit_log(alice, one, FROM + 336),
+                transfer_log(bob, alice, one, FROM + 1_336),
+                deposit_log(alice, one * 2, FROM + 2_500),
+                withdraw_log(alice, one * 2, FROM + 2_500),
+                transfer_log(alice, bob, one, FROM + 6_900),
+                withdraw_log(bob, one, TO),
+            ],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn processes_chunks_in_order_within_the_depth() {
+        let flavor = VaultFlavor::oprtc_v1();
+        let vault = VAULT.parse().unwrap();
+        let source = SlowSource::new(history(), Duration::from_millis(100));
+        let chunks = chunk_grid(FROM, TO, 1_000);
+
+        let mut pipelined = GlobalState::new();
+        let mut processed = vec![];
+        let timings = fetch_pipelined(&source, &flavor, vault, &chunks, 3, |decoded| {
+            processed.push(decoded.events.first().map(|evt| evt.block_number()));
+            pipelined.try_process_events(decoded.strict()?)
+        })
+        .await
+        .unwrap();
+
+        let whole = fetch_events(&history(), &flavor, vault, FROM, TO)
+            .await
+            .unwrap();
+        let mut sequential = GlobalState::new();
+        sequential.process_events(whole.events).unwrap();
+
+        assert_eq!(pipelined.state_hash(), sequential.state_hash());
+        assert_eq!(timings.chunks, chunks.len());
+        let blocks: Vec<_> = processed.into_iter().flatten().collect();
+        assert!(blocks.windows(2).all(|pair| pair[0] <= pair[1]));
+        // every chunk asks once per event of the flavor
+        let most = source.most_in_flight.load(Ordering::SeqCst);
+        assert!(
+            most <= 3 * flavor.events.len(),
+            "{} requests in flight",
+            most
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overlaps_fetching_with_the_chunks_before() {
+        let flavor = VaultFlavor::oprtc_v1();
+        let vault = VAULT.parse().unwrap();
+        let latency = Duration::from_millis(100);
+        let source = SlowSource::new(history(), latency);
+        let chunks = chunk_grid(FROM, TO, 1_000);
+
+        let serial = Instant::now();
+        for (start, end) in &chunks {
+            fetch_events(&source, &flavor, vault, *start, *end)
+                .await
+                .unwrap();
+        }
+        let serial = serial.elapsed();
+
+        let timings = fetch_pipelined(&source, &flavor, vault, &chunks, 4, |_| Ok(()))
+            .await
+            .unwrap();
+
+        assert!(timings.fetch >= serial);
+        assert!(
+            timings.wall * 3 < serial,
+            "{:?} vs {:?}",
+            timings.wall,
+            serial
+        );
+
+        let err = fetch_pipelined(&source, &flavor, vault, &chunks, 4, |_| {
+            Err(Error::config("stop"))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+    }
+}
@@ -0,0 +1,518 @@
+use crate::error::{ensure, Error, Result};
+use crate::journal::journal_events;
+use crate::lineage::EmbeddedLineage;
+use crate::state::{
+    events_hash, AccrualPolicy, Event, EventsHasher, GlobalState, InvariantCheckpoint,
+    SelfHeldShares, StateError,
+};
+use ethers::{
+    core::{
+        abi::{encode, Token},
+        types::{Address, H256, U256, U64},
+    },
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io::BufRead, path::Path};
+
+/// Events between two invariant checkpoints of a proof.
+pub const CHECKPOINT_INTERVAL: usize = 10_000;
+
+/// Settings that change the amounts a replay produces.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ProofConfig {
+    pub net_same_block: bool,
+    pub vault: Address,
+    pub self_held_shares: SelfHeldShares,
+    pub checkpoint_interval: usize,
+    /// Block the amounts are evaluated at
+    pub block_number: U64,
+    #[serde(
+        default = "AccrualPolicy::legacy",
+        skip_serializing_if = "AccrualPolicy::is_legacy"
+    )]
+    pub accrual_policy: AccrualPolicy,
+}
+
+impl ProofConfig {
+    /// Default settings for a proof of `vault` evaluated at `block_number`.
+    pub fn new(vault: Address, block_number: U64) -> ProofConfig {
+        ProofConfig {
+            net_same_block: false,
+            vault,
+            self_held_shares: SelfHeldShares::Exclude,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            block_number,
+            accrual_policy: AccrualPolicy::default(),
+        }
+    }
+
+    /// The policy is hashed only when it isn't the legacy one, so older proofs keep theirs.
+    pub fn digest(&self) -> H256 {
+        let mut tokens = vec![
+            Token::Bool(self.net_same_block),
+            Token::Address(self.vault),
+            Token::Uint(U256::from(self.self_held_shares as u8)),
+            Token::Uint(U256::from(self.checkpoint_interval)),
+            Token::Uint(U256::from(self.block_number.as_u64())),
+        ];
+        if !self.accrual_policy.is_legacy() {
+            tokens.push(Token::Uint(U256::from(self.accrual_policy as u8)));
+        }
+        H256::from(keccak256(encode(&tokens)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Amount {
+    pub address: Address,
+    pub amount: U256,
+}
+
+/// Everything needed to check a distribution offline against its event stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Proof {
+    pub events_hash: H256,
+    pub config: ProofConfig,
+    pub config_digest: H256,
+    pub checkpoints: Vec<InvariantCheckpoint>,
+    /// Sorted by address
+    pub amounts: Vec<Amount>,
+    pub amounts_hash: H256,
+    /// Run that wrote the proof and the files it was built from, left out of every hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ProofError {
+    ConfigDigest,
+    Checkpoint {
+        expected: Box<InvariantCheckpoint>,
+        actual: Option<Box<InvariantCheckpoint>>,
+    },
+    Amount {
+        address: Address,
+        expected: U256,
+        actual: U256,
+    },
+    AmountsHash,
+    EventsHash,
+    /// The events can't be replayed, so they aren't the ones proven
+    Replay(StateError),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::ConfigDigest => write!(f, "config digest doesn't match the config"),
+            ProofError::Checkpoint { expected, actual } => write!(
+                f,
+                "checkpoint after event {} (block {}) diverges: expected {:?}, replay gave {:?}",
+                expected.events_processed, expected.block_number, expected, actual
+            ),
+            ProofError::Amount {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "amount of {:?} is {}, replay gave {}",
+                address, expected, actual
+            ),
+            ProofError::AmountsHash => write!(f, "amounts hash doesn't match the amounts"),
+            ProofError::EventsHash => write!(f, "events hash doesn't match the event stream"),
+            ProofError::Replay(err) => write!(f, "event stream doesn't replay: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProofError {}
+
+fn amounts_hash(amounts: &[Amount]) -> H256 {
+    let tokens: Vec<_> = amounts
+        .iter()
+        .map(|a| Token::Tuple(vec![Token::Address(a.address), Token::Uint(a.amount)]))
+        .collect();
+
+    H256::from(keccak256(encode(&tokens)))
+}
+
+fn amounts_at(state: &GlobalState, block_number: U64) -> Vec<Amount> {
+    let mut amounts: Vec<_> = state
+        .get_user_rewards(block_number)
+        .into_iter()
+        .map(|(address, amount)| Amount { address, amount })
+        .collect();
+    amounts.sort_by_key(|a| a.address);
+    amounts
+}
+
+fn replay_state(config: &ProofConfig) -> GlobalState {
+    let mut state = GlobalState::new();
+    state.set_same_block_netting(config.net_same_block);
+    state.set_accrual_policy(config.accrual_policy);
+    state.set_self_held_shares(config.vault, config.self_held_shares);
+    state.set_checkpoint_interval(Some(config.checkpoint_interval));
+    state
+}
+
+fn replay(config: &ProofConfig, events: &[Event]) -> Result<GlobalState, StateError> {
+    let mut state = replay_state(config);
+    state.process_events(events.to_vec())?;
+    Ok(state)
+}
+
+/// A journal replayed in full, holding only the events of one block at a time.
+struct JournalReplay {
+    state: GlobalState,
+    events_hash: H256,
+    events: usize,
+}
+
+/// Replays a journal in canonical order, calling `after_block` with the state after every
+/// block. Events sharing a block are applied together, so same-block netting sees them all.
+/// Fails on the first event out of order rather than sorting them.
+fn replay_journal<R: BufRead>(
+    config: &ProofConfig,
+    reader: R,
+    mut after_block: impl FnMut(&GlobalState) -> Result<()>,
+) -> Result<JournalReplay> {
+    let mut state = replay_state(config);
+    let mut hasher = EventsHasher::new();
+    let mut block: Vec<Event> = vec![];
+    let mut last = None;
+    let mut events = 0;
+
+    for evt in journal_events(reader) {
+        let (line, evt) = evt?;
+        if let Some((last_line, last_position)) = last {
+            ensure!(
+                evt.position() >= last_position,
+                Error::decode,
+                "event on journal line {} at {:?} comes before the one on line {} at {:?}, \
+                 --sort-first sorts the journal in memory",
+                line,
+                evt.position(),
+                last_line,
+                last_position
+            );
+        }
+        last = Some((line, evt.position()));
+
+        if block
+            .first()
+            .is_some_and(|first| first.block_number() != evt.block_number())
+        {
+            state.try_process_events(std::mem::take(&mut block))?;
+            after_block(&state)?;
+        }
+        hasher.update(&evt);
+        block.push(evt);
+        events += 1;
+    }
+    if !block.is_empty() {
+        state.try_process_events(block)?;
+        after_block(&state)?;
+    }
+
+    Ok(JournalReplay {
+        state,
+        events_hash: hasher.finish(),
+        events,
+    })
+}
+
+impl Proof {
+    /// Builds the proof from a state that processed `events` with the settings in `config`,
+    /// including its checkpoint interval.
+    pub fn new(config: ProofConfig, events: &[Event], state: &GlobalState) -> Proof {
+        Proof::with_events_hash(config, events_hash(events), state)
+    }
+
+    /// Builds the proof of the events of a journal in canonical order, replaying them one block
+    /// at a time.
+    pub fn from_journal<R: BufRead>(config: ProofConfig, reader: R) -> Result<Proof> {
+        let replay = replay_journal(&config, reader, |_| Ok(()))?;
+        Ok(Proof::with_events_hash(
+            config,
+            replay.events_hash,
+            &replay.state,
+        ))
+    }
+
+    fn with_events_hash(config: ProofConfig, events_hash: H256, state: &GlobalState) -> Proof {
+        let amounts = amounts_at(state, config.block_number);
+
+        Proof {
+            events_hash,
+            config_digest: config.digest(),
+            checkpoints: state.checkpoints().to_vec(),
+            amounts_hash: amounts_hash(&amounts),
+            amounts,
+            config,
+            lineage: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Proof> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| Error::io(format!("failed to read proof {}", path.display()), err))?;
+
+        serde_json::from_str(&contents).map_err(|err| {
+            Error::decode(format!("failed to parse proof {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(self).expect("proof should serialize");
+        fs::write(path, contents)
+            .map_err(|err| Error::io(format!("failed to write proof {}", path.display()), err))
+    }
+
+    /// Replays `events` and checks every claim of the proof, reporting the first that fails.
+    /// Checkpoints are checked before the events hash, so a tampered stream is pinned down to
+    /// the first checkpoint it affects.
+    pub fn verify(&self, events: &[Event]) -> Result<(), ProofError> {
+        if self.config.digest() != self.config_digest {
+            return Err(ProofError::ConfigDigest);
+        }
+
+        let state = replay(&self.config, events).map_err(ProofError::Replay)?;
+        self.check_checkpoints(state.checkpoints(), 0)?;
+        self.check_replay(&state, events_hash(events))
+    }
+
+    /// Like `verify`, reading the events from a journal in canonical order and replaying them
+    /// one block at a time, so memory stays flat however long the journal. Checkpoints are
+    /// checked as the replay reaches them. Returns the number of events.
+    pub fn verify_journal<R: BufRead>(&self, reader: R) -> Result<usize> {
+        if self.config.digest() != self.config_digest {
+            return Err(ProofError::ConfigDigest.into());
+        }
+
+        let mut checked = 0;
+        let replay = replay_journal(&self.config, reader, |state| {
+            self.check_checkpoints(state.checkpoints(), checked)?;
+            checked = state.checkpoints().len();
+            Ok(())
+        })?;
+        self.check_replay(&replay.state, replay.events_hash)?;
+
+        Ok(replay.events)
+    }
+
+    /// Checks the checkpoints of a replay from the `from`-th on.
+    fn check_checkpoints(
+        &self,
+        actual: &[InvariantCheckpoint],
+        from: usize,
+    ) -> Result<(), ProofError> {
+        for (expected, actual) in self.checkpoints.iter().zip(actual).skip(from) {
+            if actual != expected {
+                return Err(ProofError::Checkpoint {
+                    expected: Box::new(expected.clone()),
+                    actual: Some(Box::new(actual.clone())),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the claims of the proof against a finished replay, from its last checkpoints on.
+    fn check_replay(&self, state: &GlobalState, events_hash: H256) -> Result<(), ProofError> {
+        if let Some(expected) = self.checkpoints.get(state.checkpoints().len()) {
+            return Err(ProofError::Checkpoint {
+                expected: Box::new(expected.clone()),
+                actual: None,
+            });
+        }
+
+        let amounts = amounts_at(state, self.config.block_number);
+
+        for expected in &self.amounts {
+            let actual = amounts
+                .iter()
+                .find(|a| a.address == expected.address)
+                .map(|a| a.amount)
+                .unwrap_or_default();
+            if actual != expected.amount {
+                return Err(ProofError::Amount {
+                    address: expected.address,
+                    expected: expected.amount,
+                    actual,
+                });
+            }
+        }
+
+        if amounts.len() != self.amounts.len() || amounts_hash(&self.amounts) != self.amounts_hash {
+            return Err(ProofError::AmountsHash);
+        }
+
+        if events_hash != self.events_hash {
+            return Err(ProofError::EventsHash);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::write_journal;
+    use crate::state::{Deposit, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    fn events() -> Vec<Event> {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+
+        (0..10)
+            .flat_map(|i| {
+                [
+                    Event::Deposit(Deposit {
+                        address: bob,
+                        shares: Shares(parse_ether("2").unwrap()),
+                        block_number: block(i * 30),
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    Event::Transfer(Transfer {
+                        from: bob,
+                        to: alice,
+                        shares: Shares(parse_ether("1").unwrap()),
+                        block_number: block(i * 30 + 10),
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    Event::Withdrawal(Withdraw {
+                        address: alice,
+                        shares: Shares(parse_ether("0.5").unwrap()),
+                        block_number: block(i * 30 + 20),
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                ]
+            })
+            .collect()
+    }
+
+    fn proof() -> Proof {
+        let config = ProofConfig {
+            net_same_block: false,
+            vault: "0xaF53431488E871D103baA0280b6360998F0F9926"
+                .parse()
+                .unwrap(),
+            self_held_shares: SelfHeldShares::Exclude,
+            checkpoint_interval: 4,
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 400),
+            accrual_policy: AccrualPolicy::BlockStart,
+        };
+        let state = replay(&config, &events()).unwrap();
+        Proof::new(config, &events(), &state)
+    }
+
+    #[test]
+    fn verifies_its_own_proof() {
+        let proof = proof();
+
+        assert_eq!(proof.checkpoints.len(), 7);
+        assert_eq!(proof.amounts.len(), 2);
+        assert_eq!(proof.verify(&events()), Ok(()));
+
+        let restored: Proof =
+            serde_json::from_str(&serde_json::to_string(&proof).unwrap()).unwrap();
+        assert_eq!(restored.verify(&events()), Ok(()));
+    }
+
+    #[test]
+    fn pins_a_tampered_event_to_the_first_affected_checkpoint() {
+        let proof = proof();
+        let mut events = events();
+        // the 13th event, so checkpoints after events 4, 8 and 12 still hold
+        if let Event::Deposit(deposit) = &mut events[12] {
+            deposit.shares = Shares(parse_ether("3").unwrap());
+        }
+
+        match proof.verify(&events) {
+            Err(ProofError::Checkpoint { expected, .. }) => {
+                assert_eq!(expected.events_processed, 16)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_amount() {
+        let mut proof = proof();
+        let alice: Address = ALICE.parse().unwrap();
+        let entry = proof
+            .amounts
+            .iter_mut()
+            .find(|a| a.address == alice)
+            .unwrap();
+        entry.amount += U256::one();
+
+        match proof.verify(&events()) {
+            Err(ProofError::Amount { address, .. }) => assert_eq!(address, alice),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    fn journal(events: &[Event]) -> Vec<u8> {
+        let mut out = vec![];
+        write_journal(&mut out, events).unwrap();
+        out
+    }
+
+    #[test]
+    fn streams_a_journal_to_the_same_proof_and_verdicts() {
+        let proof = proof();
+        let streamed = Proof::from_journal(proof.config.clone(), journal(&events()).as_slice());
+        assert_eq!(streamed.unwrap(), proof);
+        assert_eq!(
+            proof.verify_journal(journal(&events()).as_slice()).unwrap(),
+            30
+        );
+
+        let mut events = events();
+        if let Event::Deposit(deposit) = &mut events[12] {
+            deposit.shares = Shares(parse_ether("3").unwrap());
+        }
+        match proof.verify_journal(journal(&events).as_slice()) {
+            Err(Error::Proof(ProofError::Checkpoint { expected, .. })) => {
+                assert_eq!(expected.events_processed, 16)
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // a truncated journal misses the checkpoints after its end
+        match proof.verify_journal(journal(&events[..10]).as_slice()) {
+            Err(Error::Proof(ProofError::Checkpoint { expected, actual })) => {
+                assert_eq!((expected.events_processed, actual), (12, None))
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn stops_at_the_first_event_out_of_order() {
+        let mut events = events();
+        events.swap(4, 5);
+
+        let err = proof()
+            .verify_journal(journal(&events).as_slice())
+            .unwrap_err();
+        assert!(matches!(err, Error::Decode { .. }));
+        assert!(err.to_string().starts_with("event on journal line 6 "));
+        assert!(err.to_string().contains("--sort-first"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::state::Event;
+use ethers::core::types::{I256, U256};
+use std::fmt;
+
+/// How much of the input made it into the accounting, reported by `--best-effort` runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataQuality {
+    /// Vault logs behind the events, or the events themselves when read from a journal
+    pub logs: usize,
+    pub undecodable_logs: usize,
+    pub skipped_events: usize,
+    /// Shares recorded minus the vault's `totalSupply`, `None` when it couldn't be read
+    pub supply_delta: Option<I256>,
+}
+
+impl DataQuality {
+    /// Share of the logs that were decoded and applied, in percent.
+    pub fn score(&self) -> f64 {
+        if self.logs == 0 {
+            return 100.0;
+        }
+
+        let clean = self
+            .logs
+            .saturating_sub(self.undecodable_logs + self.skipped_events);
+        clean as f64 * 100.0 / self.logs as f64
+    }
+}
+
+impl fmt::Display for DataQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "data quality: {:.2}% of {} logs processed cleanly ({} undecodable, {} events skipped)",
+            self.score(),
+            self.logs,
+            self.undecodable_logs,
+            self.skipped_events
+        )?;
+        match self.supply_delta {
+            Some(delta) => write!(f, ", recorded shares off totalSupply by {}", delta),
+            None => write!(f, ", totalSupply unavailable"),
+        }
+    }
+}
+
+/// Withdrawals and transfers moving more shares than the deposits before them left in supply.
+/// The share token can't emit those, so finding any usually means the logs are from another
+/// contract, such as a token sent to the vault or a wrong vault address.
+pub fn unbacked_events(events: &[Event]) -> Vec<&Event> {
+    let mut supply = U256::zero();
+    let mut unbacked = vec![];
+
+    for evt in events {
+        match evt {
+            Event::Deposit(deposit) => supply += deposit.shares.0,
+            Event::Withdrawal(_) | Event::Transfer(_) if evt.shares().0 > supply => {
+                unbacked.push(evt)
+            }
+            Event::Withdrawal(withdraw) => supply -= withdraw.shares.0,
+            Event::Transfer(_) => {}
+        }
+    }
+
+    unbacked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::fetch_events;
+    use crate::fetch::mock::transfer_log;
+    use crate::fetch::mock::{deposit_log, withdraw_log, MockSource};
+    use crate::flavor::VaultFlavor;
+    use crate::state::{Deposit, Transfer};
+    use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{
+        core::types::{Address, U64},
+        utils::parse_ether,
+    };
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    #[tokio::test]
+    async fn scores_the_share_of_logs_processed_cleanly() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let stranger = Address::from_low_u64_be(0x5);
+        let one = parse_ether("1").unwrap();
+        let from_block = BLOCK_CONTRACT_DEPLOYED;
+
+        let mut logs = vec![];
+        for i in 0..6 {
+            logs.push(deposit_log(bob, one, from_block + i));
+        }
+        // two truncated deposits and a withdrawal by someone who never deposited
+        for i in 0..2 {
+            let mut truncated = deposit_log(bob, one, from_block + 10 + i);
+            truncated.data = truncated.data[..20].to_vec().into();
+            logs.push(truncated);
+        }
+        logs.push(withdraw_log(stranger, one, from_block + 20));
+        logs.push(withdraw_log(bob, one, from_block + 30));
+
+        let decoded = fetch_events(
+            &MockSource { logs },
+            &VaultFlavor::oprtc_v1(),
+            VAULT.parse().unwrap(),
+            from_block,
+            from_block + 100,
+        )
+        .await
+        .unwrap();
+        assert_eq!((decoded.logs, decoded.undecodable), (10, 2));
+        assert!(decoded.clone().strict().is_err());
+
+        let mut global_state = GlobalState::new();
+        global_state.set_best_effort(true);
+        global_state.process_events(decoded.events).unwrap();
+        assert_eq!(global_state.skipped_events().len(), 1);
+        assert_eq!(global_state.shares_of(bob), one * 5);
+
+        let quality = DataQuality {
+            logs: decoded.logs,
+            undecodable_logs: decoded.undecodable,
+            skipped_events: global_state.skipped_events().len(),
+            supply_delta: Some(I256::zero()),
+        };
+        assert_eq!(quality.score(), 70.0);
+        assert!(quality
+            .to_string()
+            .starts_with("data quality: 70.00% of 10 logs processed cleanly"));
+    }
+
+    #[test]
+    fn flags_transfers_moving_more_than_was_minted() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED);
+
+        let mut events = vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 2),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+        assert!(unbacked_events(&events).is_empty());
+
+        // the reward token sent to the vault, read as if it were shares
+        let reward_token = VaultFlavor::oprtc_v1()
+            .decode(&transfer_log(
+                alice,
+                bob,
+                one * 1_000,
+                BLOCK_CONTRACT_DEPLOYED,
+            ))
+            .unwrap()
+            .unwrap();
+        events.push(reward_token.clone());
+        assert_eq!(unbacked_events(&events), vec![&reward_token]);
+    }
+}
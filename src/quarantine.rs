@@ -0,0 +1,482 @@
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use crate::state::Event;
+use ethers::core::types::{Address, U256};
+use serde::Serialize;
+use std::fmt;
+
+/// Why the accounting left an event or log out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuarantineReason {
+    /// Moved shares from an address never seen holding any
+    UnknownSender,
+    /// Moved more shares than the sender held
+    InsufficientShares,
+    /// Matched a vault event but couldn't be decoded
+    Undecodable,
+}
+
+impl fmt::Display for QuarantineReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuarantineReason::UnknownSender => "unknown_sender",
+            QuarantineReason::InsufficientShares => "insufficient_shares",
+            QuarantineReason::Undecodable => "undecodable",
+        })
+    }
+}
+
+/// An event best-effort mode skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedEvent {
+    pub event: Event,
+    pub reason: QuarantineReason,
+    /// Shares the sender held when the event was skipped
+    pub held: U256,
+}
+
+/// A line of the quarantine report, kept for manual review.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuarantineRow {
+    /// `None` on the line tallying undecodable logs
+    pub block_number: Option<u64>,
+    pub kind: &'static str,
+    pub sender: Option<Address>,
+    pub recipient: Option<Address>,
+    #[serde(serialize_with = "serialize_u256")]
+    pub shares: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub held: U256,
+    pub reason: QuarantineReason,
+    /// Shares moved by this line and every one before it
+    #[serde(serialize_with = "serialize_u256")]
+    pub cumulative_shares: U256,
+    pub detail: String,
+}
+
+/// One line per skipped event in processing order, then one tallying the undecodable logs if
+/// there were any.
+pub fn quarantine_rows(
+    quarantined: &[QuarantinedEvent],
+    undecodable_logs: usize,
+    first_error: Option<&str>,
+) -> Vec<QuarantineRow> {
+    let mut cumulative_shares = U256::zero();
+    let mut rows: Vec<QuarantineRow> = quarantined
+        .iter()
+        .map(|quarantined| {
+            let (kind, sender, recipient) = match &quarantined.event {
+                Event::Deposit(deposit) => ("deposit", deposit.address, None),
+                Event::Withdrawal(withdraw) => ("withdrawal", withdraw.address, None),
+                Event::Transfer(transfer) => ("transfer", transfer.from, Some(transfer.to)),
+            };
+            let shares = quarantined.event.shares().0;
+            cumulative_shares += shares;
+            QuarantineRow {
+                block_number: Some(quarantined.event.block_number().as_u64()),
+                kind,
+                sender: Some(sender),
+                recipient,
+                shares,
+                held: quarantined.held,
+                reason: quarantined.reason,
+                cumulative_shares,
+                detail: format!("{:?}", quarantined.event),
+            }
+        })
+        .collect();
+
+    if undecodable_logs > 0 {
+        rows.push(QuarantineRow {
+            block_number: None,
+            kind: "logs",
+            sender: None,
+            recipient: None,
+            shares: U256::zero(),
+            held: U256::zero(),
+            reason: QuarantineReason::Undecodable,
+            cumulative_shares,
+            detail: match first_error {
+                Some(err) => format!("{} logs, first: {}", undecodable_logs, err),
+                None => format!("{} logs", undecodable_logs),
+            },
+        });
+    }
+
+    rows
+}
+
+/// What an approximate report leaves out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuarantineSummary {
+    pub events: usize,
+    /// Shares the quarantined events moved
+    pub shares: U256,
+    pub undecodable_logs: usize,
+}
+
+impl QuarantineSummary {
+    pub fn new(quarantined: &[QuarantinedEvent], undecodable_logs: usize) -> QuarantineSummary {
+        QuarantineSummary {
+            events: quarantined.len(),
+            shares: quarantined.iter().fold(U256::zero(), |sum, quarantined| {
+                sum + quarantined.event.shares().0
+            }),
+            undecodable_logs,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events == 0 && self.undecodable_logs == 0
+    }
+}
+
+/// Blocks after a layout switch in which withdrawals and transfers of unknown senders count
+/// towards a burst, about a day.
+pub const BURST_WINDOW: u64 = 7_200;
+
+/// Fewest unknown senders in the window that make a burst.
+const BURST_MIN: usize = 3;
+
+/// Unknown senders bunched up after a block, the mark of logs decoded with the wrong layout
+/// from a vault upgrade on: deposits are credited to another topic's address, and their
+/// owners show up as strangers when they withdraw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownSenderBurst {
+    pub from_block: u64,
+    /// Unknown senders in `[from_block, from_block + BURST_WINDOW)`
+    pub events: usize,
+    /// Unknown senders of the whole replay
+    pub total: usize,
+    /// The configured layout switch the burst follows, `None` when it follows none
+    pub boundary: Option<u64>,
+}
+
+impl fmt::Display for UnknownSenderBurst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} events from unknown senders fall within {} blocks ",
+            self.events, self.total, BURST_WINDOW
+        )?;
+        match self.boundary {
+            Some(boundary) => write!(
+                f,
+                "after the layout switch at block {}, its block or its layouts are likely wrong",
+                boundary
+            ),
+            None => write!(
+                f,
+                "from block {}, as after a vault upgrade that changed its event layouts",
+                self.from_block
+            ),
+        }
+    }
+}
+
+/// Looks for most of the unknown senders falling right after one of the layout switches at
+/// `boundaries`, or else right after any single block.
+pub fn unknown_sender_burst(
+    quarantined: &[QuarantinedEvent],
+    boundaries: &[u64],
+) -> Option<UnknownSenderBurst> {
+    let mut blocks: Vec<u64> = quarantined
+        .iter()
+        .filter(|quarantined| quarantined.reason == QuarantineReason::UnknownSender)
+        .map(|quarantined| quarantined.event.block_number().as_u64())
+        .collect();
+    blocks.sort_unstable();
+
+    let burst = |from_block: u64, boundary| UnknownSenderBurst {
+        from_block,
+        events: blocks.partition_point(|block| *block < from_block + BURST_WINDOW)
+            - blocks.partition_point(|block| *block < from_block),
+        total: blocks.len(),
+        boundary,
+    };
+
+    // the switches first, so a tie goes to them
+    boundaries
+        .iter()
+        .map(|boundary| burst(*boundary, Some(*boundary)))
+        .chain(blocks.iter().map(|block| burst(*block, None)))
+        .filter(|burst| burst.events >= BURST_MIN && burst.events * 2 >= burst.total)
+        .fold(None, |best: Option<UnknownSenderBurst>, burst| match best {
+            Some(best) if best.events >= burst.events => Some(best),
+            _ => Some(burst),
+        })
+}
+
+pub fn render_quarantine(
+    rows: &[QuarantineRow],
+    format: OutputFormat,
+    freshness: &Freshness,
+) -> String {
+    let columns = [
+        "block_number",
+        "kind",
+        "sender",
+        "recipient",
+        "shares",
+        "held",
+        "reason",
+        "cumulative_shares",
+        "detail",
+    ];
+
+    let address = |address: Option<Address>| {
+        address
+            .map(|address| format!("{:?}", address))
+            .unwrap_or_default()
+    };
+    let cells = |row: &QuarantineRow| {
+        [
+            row.block_number
+                .map(|block| block.to_string())
+                .unwrap_or_default(),
+            row.kind.to_string(),
+            address(row.sender),
+            address(row.recipient),
+            row.shares.to_string(),
+            row.held.to_string(),
+            row.reason.to_string(),
+            row.cumulative_shares.to_string(),
+            row.detail.clone(),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(rows, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for row in rows {
+                let mut cells = cells(row);
+                // the debug output of an event has commas and quotes of its own
+                cells[8] = format!("\"{}\"", cells[8].replace('"', "\"\""));
+                out += &(cells.join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for row in rows {
+                out += &format!("| {} |\n", cells(row).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, GlobalState, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::U64, utils::parse_ether};
+
+    #[test]
+    fn leaves_the_state_of_a_clean_replay_without_the_quarantined_events() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let stranger = Address::from_low_u64_be(0x5);
+        let one = parse_ether("1").unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let events = vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 2),
+                block_number: block(0),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: stranger,
+                shares: Shares(one),
+                block_number: block(10),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one * 3),
+                block_number: block(20),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number: block(30),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+
+        let mut quarantining = GlobalState::new();
+        quarantining.set_best_effort(true);
+        quarantining.process_events(events.clone()).unwrap();
+
+        let quarantined = quarantining.quarantined();
+        let reasons: Vec<_> = quarantined
+            .iter()
+            .map(|quarantined| (quarantined.reason, quarantined.held))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (QuarantineReason::UnknownSender, U256::zero()),
+                (QuarantineReason::InsufficientShares, one * 2),
+            ]
+        );
+
+        let mut clean = GlobalState::new();
+        clean
+            .process_events(
+                events
+                    .into_iter()
+                    .filter(|evt| !quarantined.iter().any(|q| q.event == *evt))
+                    .collect(),
+            )
+            .unwrap();
+        assert_eq!(quarantining.state_hash(), clean.state_hash());
+        let at = block(100);
+        assert_eq!(
+            quarantining.get_user_rewards(at),
+            clean.get_user_rewards(at)
+        );
+
+        let summary = QuarantineSummary::new(quarantined, 0);
+        assert_eq!((summary.events, summary.shares), (2, one * 4));
+    }
+
+    #[test]
+    fn quarantines_empty_moves_of_unknown_senders() {
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let stranger = Address::from_low_u64_be(0xdead);
+        let block = U64::from(BLOCK_CONTRACT_DEPLOYED);
+        let events = vec![
+            Event::Deposit(Deposit {
+                address: alice,
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: block,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: stranger,
+                to: alice,
+                shares: Shares(U256::zero()),
+                block_number: block + 10,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ];
+
+        let mut quarantining = GlobalState::new();
+        quarantining.set_best_effort(true);
+        quarantining.process_events(events).unwrap();
+
+        let quarantined = quarantining.quarantined();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].reason, QuarantineReason::UnknownSender);
+        assert_eq!(quarantining.shares_of(stranger), U256::zero());
+    }
+
+    #[test]
+    fn exports_cumulative_shares_and_undecodable_logs() {
+        let one = parse_ether("1").unwrap();
+        let withdraw = |shares| QuarantinedEvent {
+            event: Event::Withdrawal(Withdraw {
+                address: Address::from_low_u64_be(0x5),
+                shares: Shares(shares),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            reason: QuarantineReason::UnknownSender,
+            held: U256::zero(),
+        };
+
+        let rows = quarantine_rows(
+            &[withdraw(one), withdraw(one * 2)],
+            3,
+            Some("truncated data"),
+        );
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1].cumulative_shares, one * 3);
+        assert_eq!(rows[2].reason, QuarantineReason::Undecodable);
+        assert_eq!(rows[2].detail, "3 logs, first: truncated data");
+
+        let freshness = Freshness::offline(BLOCK_CONTRACT_DEPLOYED);
+        let csv = render_quarantine(&rows, OutputFormat::Csv, &freshness);
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[4].starts_with(&format!(
+            "{},withdrawal,{:?},,{},0,unknown_sender,{},\"Withdrawal(",
+            BLOCK_CONTRACT_DEPLOYED,
+            Address::from_low_u64_be(0x5),
+            one * 2,
+            one * 3
+        )));
+        assert!(lines[5].starts_with(",logs,,,0,0,undecodable,"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render_quarantine(&rows, OutputFormat::Json, &freshness))
+                .unwrap();
+        assert_eq!(json["rows"][0]["reason"], "unknown_sender");
+        assert_eq!(json["rows"][2]["block_number"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn recognizes_unknown_senders_bunched_after_a_layout_switch() {
+        let one = parse_ether("1").unwrap();
+        let upgrade = BLOCK_CONTRACT_DEPLOYED + 100_000;
+        let stranger = |n: u64, block: u64| QuarantinedEvent {
+            event: Event::Withdrawal(Withdraw {
+                address: Address::from_low_u64_be(n),
+                shares: Shares(one),
+                block_number: U64::from(block),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            reason: QuarantineReason::UnknownSender,
+            held: U256::zero(),
+        };
+
+        let bunched = [
+            stranger(1, BLOCK_CONTRACT_DEPLOYED + 10),
+            stranger(2, upgrade + 10),
+            stranger(3, upgrade + 500),
+            stranger(4, upgrade + 2_000),
+        ];
+        let burst = unknown_sender_burst(&bunched, &[upgrade]).unwrap();
+        assert_eq!(
+            burst,
+            UnknownSenderBurst {
+                from_block: upgrade,
+                events: 3,
+                total: 4,
+                boundary: Some(upgrade),
+            }
+        );
+        assert!(burst
+            .to_string()
+            .contains("after the layout switch at block"));
+
+        // without the switch configured, the burst starts at its first unknown sender
+        let burst = unknown_sender_burst(&bunched, &[]).unwrap();
+        assert_eq!((burst.from_block, burst.boundary), (upgrade + 10, None));
+
+        let scattered: Vec<_> = (1..=4)
+            .map(|n| stranger(n, upgrade + n * BURST_WINDOW))
+            .collect();
+        assert_eq!(unknown_sender_burst(&scattered, &[upgrade]), None);
+    }
+}
@@ -0,0 +1,392 @@
+//! Rebasing share tokens, whose balances scale by a global index. Transfers move raw shares,
+//! while some events emit rebased amounts, `shares * index / 1e18`. A flavor's `[rebase]`
+//! section names those events and where the index comes from, and their amounts are converted
+//! back to shares before the accounting sees them.
+//!
+//! ```toml
+//! [rebase]
+//! rebased = ["deposit", "withdraw"]
+//! token = "0x0000000000000000000000000000000000000B0b"
+//! ```
+
+use crate::error::{ensure, Error, Result};
+use crate::fetch::{chunk_grid, LogSource, CHUNK_SIZE};
+use crate::flavor::EventKind;
+use crate::state::{Deposit, Event, Withdraw};
+use crate::units::Shares;
+use ethers::{
+    core::types::{Address, Filter, U256},
+    utils::parse_ether,
+};
+use serde::Deserialize;
+use std::{fs, path::Path, path::PathBuf};
+
+pub const REBASED_EVENT: &str = "Rebased(uint256)";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RebaseConfig {
+    /// Kinds of events whose amounts are rebased
+    pub rebased: Vec<EventKind>,
+    /// Token emitting `Rebased(uint256 newIndex)`
+    #[serde(default)]
+    pub token: Option<Address>,
+    /// CSV file of `block,index` rows to read the index from instead of `token`
+    #[serde(default)]
+    pub index_csv: Option<PathBuf>,
+    /// Index before the first rebase, 1 unless given
+    #[serde(default)]
+    pub initial_index: Option<String>,
+}
+
+impl RebaseConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.rebased.is_empty(),
+            Error::config,
+            "rebase lists no rebased events"
+        );
+        ensure!(
+            self.token.is_some() != self.index_csv.is_some(),
+            Error::config,
+            "rebase needs exactly one of token and index_csv"
+        );
+        self.initial_index()?;
+        Ok(())
+    }
+
+    /// The index before the first rebase, scaled by 1e18.
+    pub fn initial_index(&self) -> Result<U256> {
+        let Some(index) = &self.initial_index else {
+            return Ok(parse_ether("1").unwrap());
+        };
+        parse_ether(index)
+            .ok()
+            .filter(|index| !index.is_zero())
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "initial_index `{}` is not a positive number",
+                    index
+                ))
+            })
+    }
+
+    /// Reads the index series from `index_csv`, or fetches every rebase of `token` from
+    /// `from_block`, the vault's deployment, up to `to_block`.
+    pub async fn index_series<S: LogSource>(
+        &self,
+        source: &S,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<IndexSeries> {
+        let initial = self.initial_index()?;
+        match (&self.index_csv, self.token) {
+            (Some(path), _) => IndexSeries::load_csv(initial, path),
+            (None, Some(token)) => {
+                IndexSeries::fetch(source, token, initial, from_block, to_block).await
+            }
+            (None, None) => Err(Error::config("rebase has no index source")),
+        }
+    }
+
+    /// Converts the amounts of rebased events into shares. Mints to the vault count as
+    /// transfers, which is what they were decoded from.
+    pub fn normalize(
+        &self,
+        events: Vec<Event>,
+        series: &IndexSeries,
+        vault: Address,
+    ) -> Normalized {
+        let mut dust = U256::zero();
+        let mut convert = |kind: EventKind, amount: U256, block: u64| {
+            if !self.rebased.contains(&kind) {
+                return amount;
+            }
+            let (shares, dropped) = series.to_shares(amount, block);
+            dust += dropped;
+            shares
+        };
+
+        let events = events
+            .into_iter()
+            .map(|evt| {
+                let block = evt.block_number().as_u64();
+                match evt {
+                    Event::Deposit(deposit) => {
+                        let kind = if deposit.address == vault {
+                            EventKind::Transfer
+                        } else {
+                            EventKind::Deposit
+                        };
+                        Event::Deposit(Deposit {
+                            shares: Shares(convert(kind, deposit.shares.0, block)),
+                            ..deposit
+                        })
+                    }
+                    Event::Withdrawal(withdraw) => Event::Withdrawal(Withdraw {
+                        shares: Shares(convert(EventKind::Withdraw, withdraw.shares.0, block)),
+                        ..withdraw
+                    }),
+                    Event::Transfer(mut transfer) => {
+                        transfer.shares =
+                            Shares(convert(EventKind::Transfer, transfer.shares.0, block));
+                        Event::Transfer(transfer)
+                    }
+                }
+            })
+            .collect();
+
+        Normalized { events, dust }
+    }
+}
+
+/// Events with every amount in shares.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Normalized {
+    pub events: Vec<Event>,
+    /// Rebased wei the conversions rounded off, summed over the events
+    pub dust: U256,
+}
+
+/// The rebase index over time, scaled by 1e18.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSeries {
+    initial: U256,
+    /// Index set at each block, by block
+    points: Vec<(u64, U256)>,
+}
+
+impl IndexSeries {
+    /// Takes the index set at each block, in any order. Of several set at the same block, the
+    /// last one given holds.
+    pub fn new(initial: U256, mut points: Vec<(u64, U256)>) -> Result<IndexSeries> {
+        ensure!(
+            points.iter().all(|(_, index)| !index.is_zero()),
+            Error::config,
+            "rebase index can't be zero"
+        );
+        points.sort_by_key(|(block, _)| *block);
+        points.reverse();
+        points.dedup_by_key(|(block, _)| *block);
+        points.reverse();
+
+        Ok(IndexSeries { initial, points })
+    }
+
+    pub fn parse_csv(initial: U256, contents: &str) -> Result<IndexSeries> {
+        let mut lines = contents.lines().enumerate();
+        ensure!(
+            lines
+                .next()
+                .is_some_and(|(_, header)| header.trim() == "block,index"),
+            Error::config,
+            "expected a `block,index` header"
+        );
+
+        let mut points = vec![];
+        for (i, line) in lines.filter(|(_, line)| !line.trim().is_empty()) {
+            let point = line.split_once(',').and_then(|(block, index)| {
+                let block = block.trim().parse().ok()?;
+                let index = U256::from_dec_str(index.trim()).ok()?;
+                Some((block, index))
+            });
+            points.push(
+                point.ok_or_else(|| {
+                    Error::config(format!("line {}: expected `block,index`", i + 1))
+                })?,
+            );
+        }
+
+        IndexSeries::new(initial, points)
+    }
+
+    pub fn load_csv(initial: U256, path: &Path) -> Result<IndexSeries> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            Error::io(
+                format!("failed to read rebase index {}", path.display()),
+                err,
+            )
+        })?;
+
+        IndexSeries::parse_csv(initial, &contents).map_err(|err| {
+            Error::config(format!("invalid rebase index {}", path.display())).caused_by(err)
+        })
+    }
+
+    /// Every `Rebased` log of `token` from the vault's deployment at `from_block` up to
+    /// `to_block`.
+    pub async fn fetch<S: LogSource>(
+        source: &S,
+        token: Address,
+        initial: U256,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<IndexSeries> {
+        let mut logs = vec![];
+        for (start, end) in chunk_grid(from_block, to_block, CHUNK_SIZE) {
+            let filter = Filter::new()
+                .address(token)
+                .event(REBASED_EVENT)
+                .from_block(start)
+                .to_block(end);
+            logs.extend(source.fetch_logs(&filter).await?);
+        }
+        logs.retain(|log| log.address == token);
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+        let points =
+            logs.iter()
+                .map(|log| {
+                    let block = log.block_number.ok_or_else(|| {
+                        Error::decode(format!("{} log has no block number", REBASED_EVENT))
+                    })?;
+                    let index = log.data.get(..32).map(U256::from).ok_or_else(|| {
+                        Error::decode(format!("{} log has no index", REBASED_EVENT))
+                    })?;
+                    Ok((block.as_u64(), index))
+                })
+                .collect::<Result<_>>()?;
+
+        IndexSeries::new(initial, points)
+    }
+
+    /// The index in force at `block`, including a rebase at that very block.
+    pub fn index_at(&self, block: u64) -> U256 {
+        match self.points.partition_point(|(set_at, _)| *set_at <= block) {
+            0 => self.initial,
+            i => self.points[i - 1].1,
+        }
+    }
+
+    /// Converts a rebased amount into shares, flooring, with the rebased wei that were rounded
+    /// off.
+    pub fn to_shares(&self, amount: U256, block: u64) -> (U256, U256) {
+        let index = self.index_at(block);
+        let shares = amount * parse_ether("1").unwrap() / index;
+        (shares, amount - self.to_rebased(shares, block))
+    }
+
+    /// Converts shares into a rebased amount, flooring like the token's `balanceOf`.
+    pub fn to_rebased(&self, shares: U256, block: u64) -> U256 {
+        shares * self.index_at(block) / parse_ether("1").unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::fetch_events;
+    use crate::fetch::mock::{custom_log, deposit_log, transfer_log, withdraw_log, MockSource};
+    use crate::flavor::VaultFlavor;
+    use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    fn ether(value: &str) -> U256 {
+        parse_ether(value).unwrap()
+    }
+
+    #[test]
+    fn looks_up_the_index_in_force_at_a_block() {
+        let series = IndexSeries::parse_csv(
+            ether("1"),
+            "block,index\n200,3000000000000000000\n100,2000000000000000000\n100,1500000000000000000\n",
+        )
+        .unwrap();
+
+        let at = |block| series.index_at(block);
+        assert_eq!(at(99), ether("1"));
+        assert_eq!(at(100), ether("1.5"));
+        assert_eq!(at(199), ether("1.5"));
+        assert_eq!(at(200), ether("3"));
+        assert_eq!(at(u64::MAX), ether("3"));
+
+        assert!(IndexSeries::parse_csv(ether("1"), "block,index\n100,0\n").is_err());
+        assert!(IndexSeries::parse_csv(ether("1"), "block,index\n100\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn reconciles_balances_across_a_rebase_in_both_units() {
+        let vault: Address = VAULT.parse().unwrap();
+        let token = Address::from_low_u64_be(0x7);
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let block = |offset: u64| BLOCK_CONTRACT_DEPLOYED + offset;
+
+        let mut rebase = custom_log(REBASED_EVENT, &[], &[ether("1.5")], block(10));
+        rebase.address = token;
+        let source = MockSource {
+            logs: vec![
+                // 100 rebased at an index of 1 are 100 shares
+                deposit_log(bob, ether("100"), block(0)),
+                rebase,
+                transfer_log(bob, alice, ether("30"), block(20)),
+                // 15 rebased at 1.5 are 10 shares
+                withdraw_log(alice, ether("15"), block(30)),
+                // 10 rebased at 1.5 are 6.66.. shares, a wei short of 10 when rebased back
+                deposit_log(bob, ether("10"), block(40)),
+            ],
+        };
+
+        let flavor = VaultFlavor::from_toml(&format!(
+            "name = \"rebasing\"\n\
+             [[events]]\nsignature = \"Deposit(address,address,uint256,uint256)\"\n\
+             kind = \"deposit\"\naddress_topic = 2\nshares_word = 1\n\
+             [[events]]\nsignature = \"Withdraw(address,address,address,uint256,uint256)\"\n\
+             kind = \"withdraw\"\naddress_topic = 3\nshares_word = 1\n\
+             [[events]]\nsignature = \"Transfer(address,address,uint256)\"\n\
+             kind = \"transfer\"\naddress_topic = 1\nto_topic = 2\nshares_word = 0\n\
+             [rebase]\nrebased = [\"deposit\", \"withdraw\"]\ntoken = \"{:?}\"\n",
+            token
+        ))
+        .unwrap();
+        let config = flavor.rebase.as_ref().unwrap();
+
+        let events = fetch_events(&source, &flavor, vault, block(0), block(50))
+            .await
+            .unwrap()
+            .strict()
+            .unwrap();
+        let series = config
+            .index_series(&source, block(0), block(50))
+            .await
+            .unwrap();
+        let normalized = config.normalize(events, &series, vault);
+        assert_eq!(normalized.dust, U256::one());
+
+        let mut state = GlobalState::new();
+        state.process_events(normalized.events).unwrap();
+
+        // sharesOf and balanceOf of the rebasing token at the head
+        let third = U256::from_dec_str("6666666666666666666").unwrap();
+        let on_chain = [
+            (bob, ether("70") + third, ether("115") - 1),
+            (alice, ether("20"), ether("30")),
+        ];
+        for (address, shares, balance) in on_chain {
+            assert_eq!(state.shares_of(address), shares);
+            assert_eq!(
+                series.to_rebased(state.shares_of(address), block(50)),
+                balance
+            );
+        }
+    }
+
+    #[test]
+    fn needs_exactly_one_index_source() {
+        let config = |source: &str| {
+            toml::from_str::<RebaseConfig>(&format!("rebased = [\"deposit\"]\n{}", source))
+                .unwrap()
+                .validate()
+        };
+
+        assert!(config("token = \"0x0000000000000000000000000000000000000007\"").is_ok());
+        assert!(config("index_csv = \"index.csv\"").is_ok());
+        assert!(config("").is_err());
+        assert!(config(
+            "token = \"0x0000000000000000000000000000000000000007\"\nindex_csv = \"index.csv\""
+        )
+        .is_err());
+        assert!(config("index_csv = \"index.csv\"\ninitial_index = \"0\"").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::calls::call_uint;
+use crate::context::EvaluationContext;
+use crate::rpc::Client;
+use crate::state::GlobalState;
+use ethers::core::{
+    abi::Token,
+    types::{Address, I256, U256},
+};
+use eyre::Result;
+
+const BALANCE_OF: &str = "balanceOf(address)";
+
+#[derive(Debug, PartialEq)]
+pub struct BalanceMismatch {
+    pub address: Address,
+    pub recorded: U256,
+    pub on_chain: U256,
+    /// `recorded - on_chain`: positive when we track extra shares, negative when events are missing
+    pub delta: I256,
+}
+
+/// The `count` addresses holding the most shares, largest first.
+pub fn largest_holders(state: &GlobalState, count: usize) -> Vec<Address> {
+    let mut holders: Vec<_> = state
+        .users()
+        .map(|address| (*address, state.shares_of(*address)))
+        .filter(|(_, shares)| !shares.is_zero())
+        .collect();
+
+    holders.sort_by_key(|&(address, shares)| (std::cmp::Reverse(shares), address));
+    holders.truncate(count);
+
+    holders.into_iter().map(|(address, _)| address).collect()
+}
+
+pub async fn fetch_balances(
+    client: &Client,
+    ctx: &EvaluationContext,
+    vault: Address,
+    addresses: &[Address],
+) -> Result<Vec<(Address, U256)>> {
+    let mut balances = vec![];
+
+    for address in addresses {
+        let args = [Token::Address(*address)];
+        let balance = call_uint(client, vault, BALANCE_OF, &args, ctx.block).await?;
+        balances.push((*address, balance));
+    }
+
+    Ok(balances)
+}
+
+/// Compares on-chain `balanceOf` values against the shares reconstructed from events.
+pub fn reconcile_balances(
+    state: &GlobalState,
+    on_chain: &[(Address, U256)],
+) -> Vec<BalanceMismatch> {
+    on_chain
+        .iter()
+        .filter_map(|&(address, on_chain)| {
+            let recorded = state.shares_of(address);
+
+            if recorded == on_chain {
+                return None;
+            }
+
+            Some(BalanceMismatch {
+                address,
+                recorded,
+                on_chain,
+                delta: I256::from_raw(recorded) - I256::from_raw(on_chain),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Event, Transfer, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::core::types::U64;
+    use ethers::utils::parse_ether;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    #[test]
+    fn flags_addresses_disagreeing_with_balance_of() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+
+        let mut global_state = GlobalState::new();
+        global_state
+            .process_events(vec![
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(parse_ether("3").unwrap()),
+                    block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Transfer(Transfer {
+                    from: bob,
+                    to: alice,
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 10),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            ])
+            .unwrap();
+
+        assert_eq!(largest_holders(&global_state, 1), vec![bob]);
+
+        // alice received another share through an event we never saw
+        let on_chain = vec![
+            (bob, parse_ether("2").unwrap()),
+            (alice, parse_ether("2").unwrap()),
+        ];
+
+        let mismatches = reconcile_balances(&global_state, &on_chain);
+
+        assert_eq!(
+            mismatches,
+            vec![BalanceMismatch {
+                address: alice,
+                recorded: parse_ether("1").unwrap(),
+                on_chain: parse_ether("2").unwrap(),
+                delta: I256::from_raw(parse_ether("1").unwrap()) * -1,
+            }]
+        );
+    }
+}
@@ -0,0 +1,711 @@
+use crate::address_map::ExportChain;
+use crate::annotations::Annotations;
+use crate::error::{Error, Result};
+use crate::freshness::Freshness;
+use crate::lineage::EmbeddedLineage;
+use crate::output::{format_ether_rounded, serialize_u256};
+use crate::quarantine::QuarantineSummary;
+use crate::rounding::Rounding;
+use crate::state::GlobalState;
+use crate::subaccounts::{Checkpoints, SubAccounts};
+use crate::utilization::ratio;
+use ethers::{
+    core::{
+        abi::{encode, Token},
+        types::{H256, U256, U64},
+    },
+    utils::{format_ether, keccak256, to_checksum},
+};
+use serde::Serialize;
+use std::{fmt, io::Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportRow {
+    /// Address, or `0x..#name` for a sub-account
+    pub label: String,
+    pub rewards: U256,
+    /// Loyalty bonus, paid from its own budget on top of the rewards
+    pub bonus: U256,
+    pub note: Option<String>,
+    /// Why the row is held back, for rows in `Report::withheld`
+    pub reason: Option<WithholdReason>,
+}
+
+/// Why a row is held back from the payout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WithholdReason {
+    /// No address on the export chain
+    Unmapped,
+    /// No deposit, withdrawal or transfer for longer than the dormancy threshold
+    Dormant,
+}
+
+impl WithholdReason {
+    /// Code the reason is listed and hashed under.
+    pub fn code(self) -> &'static str {
+        match self {
+            WithholdReason::Unmapped => "unmapped",
+            WithholdReason::Dormant => "dormant",
+        }
+    }
+}
+
+impl ReportRow {
+    /// Reason of a withheld row, unmapped unless it says otherwise.
+    fn withheld_for(&self) -> WithholdReason {
+        self.reason.unwrap_or(WithholdReason::Unmapped)
+    }
+}
+
+/// Rewards report, rendered as an aligned table with a totals footer.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Report {
+    pub total_rewards_expected: U256,
+    pub total_rewards_given: U256,
+    /// Rewards of the vault's own shares, which are not paid out
+    pub protocol_rewards: U256,
+    /// List the protocol rewards on a line of their own
+    pub protocol_row: bool,
+    /// Fractional digits of the ether amounts shown
+    pub decimals: usize,
+    /// How the amounts shown are cut down to `decimals`
+    pub rounding: Rounding,
+    /// Share of the emission credited to staked shares, in percent
+    pub emission_utilization: Option<f64>,
+    /// Largest rewards first
+    pub rows: Vec<ReportRow>,
+    /// Rows held back from the payout, each with its reason
+    pub withheld: Vec<ReportRow>,
+    /// Chain the row addresses were mapped to, if any
+    pub export_chain: Option<ExportChain>,
+    /// What the accounting left out, making the report approximate
+    pub quarantine: Option<QuarantineSummary>,
+    /// How current the chain data behind the report is, shown above it
+    pub freshness: Option<Freshness>,
+    /// Loyalty bonus budget the row bonuses are split from, which adds a bonus column
+    pub bonus_budget: Option<U256>,
+    /// Run that wrote the report and the files it was built from, given in the JSON format
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+impl Report {
+    /// Hash over the amounts of the report, leaving out notes and the floating point
+    /// percentages, so the same inputs always attest to the same hash.
+    pub fn hash(&self) -> H256 {
+        let mut tokens = vec![
+            Token::Uint(self.total_rewards_expected),
+            Token::Uint(self.total_rewards_given),
+            Token::Uint(self.protocol_rewards),
+        ];
+        tokens.extend(self.rows.iter().map(|row| {
+            Token::Tuple(vec![
+                Token::String(row.label.clone()),
+                Token::Uint(row.rewards),
+            ])
+        }));
+        // reports without bonuses or withheld rows keep the hash they always had
+        if let Some(budget) = self.bonus_budget {
+            tokens.push(Token::String("bonus".to_string()));
+            tokens.push(Token::Uint(budget));
+            tokens.extend(self.rows.iter().map(|row| Token::Uint(row.bonus)));
+        }
+        if !self.withheld.is_empty() {
+            tokens.push(Token::String("withheld".to_string()));
+            tokens.extend(self.withheld.iter().map(|row| {
+                Token::Tuple(vec![
+                    Token::String(row.label.clone()),
+                    Token::Uint(row.rewards),
+                ])
+            }));
+        }
+        // only unmapped rows were withheld before there were other reasons
+        if self
+            .withheld
+            .iter()
+            .any(|row| row.withheld_for() != WithholdReason::Unmapped)
+        {
+            tokens.push(Token::String("reasons".to_string()));
+            tokens.extend(
+                self.withheld
+                    .iter()
+                    .map(|row| Token::String(row.withheld_for().code().to_string())),
+            );
+        }
+
+        H256::from(keccak256(encode(&tokens)))
+    }
+
+    /// `wei` as ether with `decimals` fractional digits, rounded by the policy.
+    pub(crate) fn ether(&self, wei: U256) -> String {
+        let unit = U256::exp10(18 - self.decimals.min(18));
+        format_ether_rounded(self.rounding.div(wei, unit) * unit, self.decimals)
+    }
+
+    /// Share of the rewards given, in percent.
+    pub fn pct(&self, rewards: U256) -> f64 {
+        pct(rewards, self.total_rewards_given)
+    }
+}
+
+/// `rewards` as a share of `given`, in percent.
+fn pct(rewards: U256, given: U256) -> f64 {
+    let given: f64 = format_ether(given).parse().unwrap();
+    let rewards: f64 = format_ether(rewards).parse().unwrap();
+    if given == 0.0 {
+        0.0
+    } else {
+        rewards * 100.0 / given
+    }
+}
+
+/// Writes the rewards of every payee at `block` as CSV, largest first, with their checksummed
+/// address, the shares they hold and their share of the rewards given.
+pub fn write_rewards_csv<W: Write>(state: &GlobalState, block: U64, mut out: W) -> Result<()> {
+    let given = state.get_all_rewards(block);
+    let mut csv = "address,shares_staked,rewards_wei,rewards_ether,pct\n".to_string();
+    for (address, rewards) in state.get_user_rewards(block) {
+        csv += &format!(
+            "{},{},{},{},{:.4}\n",
+            to_checksum(&address, None),
+            state.shares_of(address),
+            rewards,
+            format_ether(rewards),
+            pct(rewards, given)
+        );
+    }
+
+    out.write_all(csv.as_bytes())
+        .and_then(|()| out.flush())
+        .map_err(|err| Error::io("failed to write the rewards CSV", err))
+}
+
+/// Shape of the rewards report in `--format json`. Amounts in wei are decimal strings so they
+/// keep their precision.
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    #[serde(serialize_with = "serialize_u256")]
+    total_rewards_expected: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    total_rewards_given: U256,
+    current_block: u64,
+    users: Vec<JsonRewards<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lineage: Option<&'a EmbeddedLineage>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonRewards<'a> {
+    address: &'a str,
+    #[serde(serialize_with = "serialize_u256")]
+    rewards_wei: U256,
+    rewards_ether: String,
+    pct: f64,
+}
+
+impl Report {
+    /// The report as JSON, listing the rows paid out at `current_block`.
+    pub fn to_json(&self, current_block: U64) -> String {
+        let report = JsonReport {
+            total_rewards_expected: self.total_rewards_expected,
+            total_rewards_given: self.total_rewards_given,
+            current_block: current_block.as_u64(),
+            users: self
+                .rows
+                .iter()
+                .map(|row| JsonRewards {
+                    address: &row.label,
+                    rewards_wei: row.rewards,
+                    rewards_ether: format_ether(row.rewards),
+                    pct: self.pct(row.rewards),
+                })
+                .collect(),
+            lineage: self.lineage.as_ref(),
+        };
+
+        serde_json::to_string_pretty(&report).expect("report should serialize")
+    }
+}
+
+/// Rewards of every payee at `block_number`, split into sub-accounts where configured and
+/// restricted to `filter_tag` when given.
+pub fn rewards_report(
+    global_state: &GlobalState,
+    block_number: U64,
+    annotations: &Annotations,
+    filter_tag: Option<&str>,
+    (sub_accounts, checkpoints): (&SubAccounts, &Checkpoints),
+    protocol_row: bool,
+) -> Report {
+    let total_rewards_expected = global_state.total_emission(block_number);
+    let distribution = global_state.total_distribution(block_number);
+
+    let mut rows = vec![];
+    for (addr, rewards) in global_state.get_user_rewards(block_number) {
+        if filter_tag.is_some_and(|tag| !annotations.has_tag(addr, tag)) {
+            continue;
+        }
+
+        let labeled = if sub_accounts.is_split(addr) {
+            sub_accounts.split(addr, checkpoints, global_state, block_number)
+        } else {
+            vec![(format!("{:?}", addr), rewards)]
+        };
+
+        let note = annotations.get(addr).and_then(|a| a.note.clone());
+        for (label, rewards) in labeled {
+            rows.push(ReportRow {
+                label,
+                rewards,
+                bonus: U256::zero(),
+                note: note.clone(),
+                reason: None,
+            });
+        }
+    }
+
+    Report {
+        total_rewards_expected,
+        total_rewards_given: global_state.get_all_rewards(block_number),
+        protocol_rewards: global_state.protocol_rewards(block_number),
+        protocol_row,
+        decimals: 18,
+        rounding: Rounding::Floor,
+        emission_utilization: Some(ratio(distribution.attributed, distribution.emitted) * 100.0),
+        rows,
+        withheld: vec![],
+        export_chain: None,
+        quarantine: None,
+        freshness: None,
+        bonus_budget: None,
+        lineage: None,
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut header = vec!["rank", "address", "rewards", "pct"];
+        if self.bonus_budget.is_some() {
+            header.push("bonus");
+        }
+        // the listed amounts add up to their total, rounded the same way
+        let unit = U256::exp10(18 - self.decimals.min(18));
+        let rounded = |amounts: Vec<U256>| self.rounding.round_to_unit(&amounts, unit);
+        let rewards = rounded(self.rows.iter().map(|row| row.rewards).collect());
+        let bonuses = rounded(self.rows.iter().map(|row| row.bonus).collect());
+        let mut cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .zip(rewards.into_iter().zip(bonuses))
+            .enumerate()
+            .map(|(i, (row, (rewards, bonus)))| {
+                let mut cells = vec![
+                    (i + 1).to_string(),
+                    row.label.clone(),
+                    format_ether_rounded(rewards, self.decimals),
+                    format!("{:.4}", self.pct(row.rewards)),
+                ];
+                if self.bonus_budget.is_some() {
+                    cells.push(format_ether_rounded(bonus, self.decimals));
+                }
+                cells
+            })
+            .collect();
+        let mut unlisted = |label: String, amount: U256| {
+            let mut row = vec!["-".to_string(), label, self.ether(amount), "-".to_string()];
+            row.resize(header.len(), "-".to_string());
+            cells.push(row);
+        };
+        if self.protocol_row {
+            unlisted("protocol".to_string(), self.protocol_rewards);
+        }
+        let mut reasons: Vec<_> = self.withheld.iter().map(ReportRow::withheld_for).collect();
+        reasons.sort();
+        reasons.dedup();
+        for reason in reasons {
+            let (count, withheld) = self
+                .withheld
+                .iter()
+                .filter(|row| row.withheld_for() == reason)
+                .fold((0, U256::zero()), |(count, sum), row| {
+                    (count + 1, sum + row.rewards)
+                });
+            unlisted(format!("withheld ({} {})", count, reason.code()), withheld);
+        }
+
+        let mut widths: Vec<_> = header.iter().map(|cell| cell.len()).collect();
+        for row in &cells {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        // the address column is left aligned, numbers right aligned
+        let write_row = |f: &mut fmt::Formatter<'_>, row: &[&str]| -> fmt::Result {
+            for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                match i {
+                    0 => write!(f, "{:>width$}", cell)?,
+                    1 => write!(f, "  {:<width$}", cell)?,
+                    _ => write!(f, "  {:>width$}", cell)?,
+                }
+            }
+            Ok(())
+        };
+
+        if let Some(freshness) = &self.freshness {
+            writeln!(f, "{}", freshness)?;
+        }
+        if let Some(quarantine) = self.quarantine.filter(|quarantine| !quarantine.is_empty()) {
+            writeln!(
+                f,
+                "APPROXIMATE: {} events moving {} shares and {} undecodable logs were quarantined",
+                quarantine.events,
+                format_ether_rounded(quarantine.shares, self.decimals),
+                quarantine.undecodable_logs
+            )?;
+        }
+        write_row(f, &header)?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{}",
+            "-".repeat(widths.iter().sum::<usize>() + 2 * (widths.len() - 1))
+        )?;
+
+        for (i, cells) in cells.iter().enumerate() {
+            let cells: Vec<_> = cells.iter().map(String::as_str).collect();
+            write_row(f, &cells)?;
+            match self.rows.get(i) {
+                Some(ReportRow {
+                    note: Some(note), ..
+                }) => write!(f, "  {}", note)?,
+                Some(_) => {}
+                None => write!(f, "  not paid out")?,
+            }
+            writeln!(f)?;
+        }
+
+        let total_pct: f64 = self.rows.iter().map(|row| self.pct(row.rewards)).sum();
+        write!(
+            f,
+            "total: {} of {} expected",
+            self.ether(self.total_rewards_given),
+            self.ether(self.total_rewards_expected)
+        )?;
+        if !self.protocol_rewards.is_zero() {
+            write!(
+                f,
+                ", {} held by the protocol",
+                self.ether(self.protocol_rewards)
+            )?;
+        }
+        write!(f, " ({:.4}% listed)", total_pct)?;
+        if let Some(budget) = self.bonus_budget {
+            let bonuses = self
+                .rows
+                .iter()
+                .fold(U256::zero(), |sum, row| sum + row.bonus);
+            write!(
+                f,
+                ", {} of the {} bonus budget",
+                self.ether(bonuses),
+                self.ether(budget)
+            )?;
+        }
+        if let Some(utilization) = self.emission_utilization {
+            write!(f, ", {:.4}% of the emission attributed", utilization)?;
+        }
+        if let Some(chain) = self.export_chain {
+            write!(f, ", exported for {}", chain)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Event, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::core::types::Address;
+    use ethers::utils::parse_ether;
+
+    #[test]
+    fn renders_an_aligned_table_with_totals() {
+        let report = Report {
+            total_rewards_expected: parse_ether("100").unwrap(),
+            total_rewards_given: parse_ether("100").unwrap(),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 18,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+            rows: vec![
+                ReportRow {
+                    label: "0x0000000000000000000000000000000000000b0b".to_string(),
+                    rewards: parse_ether("75").unwrap(),
+                    bonus: U256::zero(),
+                    note: Some("old treasury".to_string()),
+                    reason: None,
+                },
+                ReportRow {
+                    label: "0x0000000000000000000000000000000000000b0b#a".to_string(),
+                    rewards: parse_ether("25").unwrap(),
+                    bonus: U256::zero(),
+                    note: None,
+                    reason: None,
+                },
+            ],
+        };
+
+        let rendered = format!("{report}");
+        let lines: Vec<_> = rendered.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("rank  address"));
+        assert!(lines[2].ends_with("old treasury"));
+
+        // every column of the table ends at the same offset on every line
+        let pct_end = lines[0].len();
+        assert_eq!(lines[2].find("  old treasury"), Some(pct_end));
+        assert_eq!(lines[3].len(), pct_end);
+        assert_eq!(lines[2].find("75.0"), lines[3].find("25.0"));
+
+        assert!(lines[4].starts_with("total: 100.0"));
+        assert!(lines[4].ends_with("expected (100.0000% listed)"));
+
+        let report = Report {
+            decimals: 2,
+            ..report
+        };
+        let rendered = report.to_string();
+        assert!(rendered.contains("  75.00  "));
+        assert!(rendered.contains("total: 100.00 of 100.00 expected"));
+
+        let report = Report {
+            quarantine: Some(QuarantineSummary {
+                events: 2,
+                shares: parse_ether("3").unwrap(),
+                undecodable_logs: 1,
+            }),
+            ..report
+        };
+        assert!(report.to_string().starts_with(
+            "APPROXIMATE: 2 events moving 3.00 shares and 1 undecodable logs were quarantined\nrank"
+        ));
+
+        let mut report = Report {
+            quarantine: None,
+            bonus_budget: Some(parse_ether("10").unwrap()),
+            ..report
+        };
+        report.rows[0].bonus = parse_ether("7.5").unwrap();
+        let rendered = report.to_string();
+        let lines: Vec<_> = rendered.lines().collect();
+        assert!(lines[0].ends_with("pct  bonus"));
+        assert!(lines[3].ends_with("25.0000   0.00"));
+        assert!(lines[4].ends_with(", 7.50 of the 10.00 bonus budget"));
+        let hash = report.hash();
+        report.rows[0].bonus = parse_ether("7").unwrap();
+        assert_ne!(report.hash(), hash);
+    }
+
+    #[test]
+    fn serializes_wei_as_decimal_strings() {
+        let report = Report {
+            total_rewards_expected: parse_ether("100").unwrap(),
+            total_rewards_given: parse_ether("80").unwrap(),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 2,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+            rows: vec![ReportRow {
+                label: "0x0000000000000000000000000000000000000b0b".to_string(),
+                rewards: parse_ether("80").unwrap() + 1,
+                bonus: U256::zero(),
+                note: None,
+                reason: None,
+            }],
+        };
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json(U64::from(42))).unwrap();
+        assert_eq!(json["total_rewards_expected"], "100000000000000000000");
+        assert_eq!(json["total_rewards_given"], "80000000000000000000");
+        assert_eq!(json["current_block"], 42);
+        let user = &json["users"][0];
+        assert_eq!(
+            user["address"],
+            "0x0000000000000000000000000000000000000b0b"
+        );
+        assert_eq!(user["rewards_wei"], "80000000000000000001");
+        assert_eq!(user["rewards_ether"], "80.000000000000000001");
+        assert_eq!(user["pct"], 100.0);
+    }
+
+    #[test]
+    fn exports_every_payee_as_csv() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        let deposit = |address, offset| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(one),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + offset),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let mut state = GlobalState::new();
+        state
+            .process_events(vec![deposit(bob, 0), deposit(alice, 100)])
+            .unwrap();
+
+        let mut out = vec![];
+        write_rewards_csv(&state, U64::from(BLOCK_CONTRACT_DEPLOYED + 200), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "address,shares_staked,rewards_wei,rewards_ether,pct\n\
+             0x0000000000000000000000000000000000000B0b,1000000000000000000,\
+             150000000000000000000,150.000000000000000000,75.0000\n\
+             0x00000000000000000000000000000000000A11cE,1000000000000000000,\
+             50000000000000000000,50.000000000000000000,25.0000\n"
+        );
+    }
+
+    #[test]
+    fn lists_amounts_adding_up_to_their_rounded_total() {
+        let row = |rewards: &str| ReportRow {
+            label: format!("0x{:0>40}", rewards.replace('.', "")),
+            rewards: parse_ether(rewards).unwrap(),
+            bonus: U256::zero(),
+            note: None,
+            reason: None,
+        };
+        let mut report = Report {
+            total_rewards_expected: parse_ether("1.018").unwrap(),
+            total_rewards_given: parse_ether("1.018").unwrap(),
+            protocol_rewards: U256::zero(),
+            protocol_row: false,
+            decimals: 2,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+            rows: vec![row("1.006"), row("0.006"), row("0.006")],
+        };
+
+        let listed = |report: &Report| -> Vec<String> {
+            let rendered = report.to_string();
+            let mut amounts: Vec<_> = rendered
+                .lines()
+                .skip(2)
+                .map(|line| line.split_whitespace().nth(2).unwrap().to_string())
+                .collect();
+            amounts.pop();
+            amounts
+        };
+
+        assert_eq!(listed(&report), ["1.01", "0.00", "0.00"]);
+        assert!(report.to_string().contains("total: 1.01 of 1.01 expected"));
+        report.rounding = Rounding::HalfEven;
+        assert_eq!(listed(&report), ["1.01", "0.01", "0.00"]);
+        assert!(report.to_string().contains("total: 1.02 of 1.02 expected"));
+        report.rounding = Rounding::Ceil;
+        assert_eq!(listed(&report), ["1.01", "0.01", "0.00"]);
+    }
+
+    #[test]
+    fn accounts_for_protocol_rewards() {
+        let mut report = Report {
+            total_rewards_expected: parse_ether("100").unwrap(),
+            total_rewards_given: parse_ether("60").unwrap(),
+            protocol_rewards: parse_ether("40").unwrap(),
+            protocol_row: false,
+            decimals: 18,
+            rounding: Rounding::Floor,
+            emission_utilization: None,
+            withheld: vec![],
+            export_chain: None,
+            quarantine: None,
+            freshness: None,
+            bonus_budget: None,
+            lineage: None,
+            rows: vec![ReportRow {
+                label: "0x0000000000000000000000000000000000000b0b".to_string(),
+                rewards: parse_ether("60").unwrap(),
+                bonus: U256::zero(),
+                note: None,
+                reason: None,
+            }],
+        };
+
+        let excluded = report.to_string();
+        assert_eq!(excluded.lines().count(), 4);
+        assert!(excluded.contains("held by the protocol"));
+
+        report.protocol_row = true;
+        let separate = report.to_string();
+        let lines: Vec<_> = separate.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[3].contains("protocol"));
+        assert!(lines[3].ends_with("not paid out"));
+    }
+
+    #[test]
+    fn audit_runs_hash_identically() {
+        // equal stakes tie on rewards, so only the tie-break fixes their order
+        let events: Vec<_> = (1..=20u64)
+            .map(|i| {
+                Event::Deposit(Deposit {
+                    address: Address::from_low_u64_be(i),
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                    transaction_index: 0,
+                    log_index: 0,
+                })
+            })
+            .collect();
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 1_000);
+
+        let run = || {
+            let mut global_state = GlobalState::new();
+            global_state.process_events(events.clone()).unwrap();
+            rewards_report(
+                &global_state,
+                block_number,
+                &Annotations::default(),
+                None,
+                (&SubAccounts::default(), &Checkpoints::new()),
+                false,
+            )
+        };
+
+        let (first, second) = (run(), run());
+        assert_eq!(first.rows.len(), 20);
+        assert_eq!(first.hash(), second.hash());
+        assert_eq!(first.to_string(), second.to_string());
+        assert!(first.rows[0].label < first.rows[1].label);
+        assert_eq!(first.emission_utilization, Some(100.0));
+        assert!(first
+            .to_string()
+            .ends_with("listed), 100.0000% of the emission attributed\n"));
+    }
+}
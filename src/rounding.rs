@@ -0,0 +1,146 @@
+//! Rounding of amounts on their way out of the accounting: splitting an address' rewards
+//! between its sub-accounts, and cutting the rewards report down to `--decimals`.
+//!
+//! The accounting itself keeps flooring like the contract, whatever the policy: the
+//! accumulator increase of every distribution and the rewards settled from it.
+
+use ethers::core::types::U256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rounding {
+    /// Down, like integer division
+    #[default]
+    Floor,
+    /// To the nearest, ties to even
+    HalfEven,
+    /// Up
+    Ceil,
+}
+
+impl Rounding {
+    pub fn div(self, numerator: U256, denominator: U256) -> U256 {
+        let (quotient, remainder) = numerator.div_mod(denominator);
+        let up = match self {
+            Rounding::Floor => false,
+            Rounding::HalfEven => {
+                let twice = remainder * 2;
+                twice > denominator || (twice == denominator && quotient.bit(0))
+            }
+            Rounding::Ceil => !remainder.is_zero(),
+        };
+
+        if up {
+            quotient + 1
+        } else {
+            quotient
+        }
+    }
+
+    /// Rounds every amount to a multiple of `unit`. Their total is rounded by the policy, and
+    /// the amounts with the largest remainders are rounded up until they add up to it.
+    pub fn round_to_unit(self, amounts: &[U256], unit: U256) -> Vec<U256> {
+        let total = amounts
+            .iter()
+            .fold(U256::zero(), |sum, amount| sum + amount);
+
+        largest_remainder(amounts, unit, self.div(total, unit))
+            .into_iter()
+            .map(|units| units * unit)
+            .collect()
+    }
+}
+
+/// Splits `total` wei in proportion to `weights`, adding up to it exactly. Every policy splits
+/// a whole amount the same way, so this takes none.
+pub fn split(total: U256, weights: &[U256]) -> Vec<U256> {
+    let sum = weights
+        .iter()
+        .fold(U256::zero(), |sum, weight| sum + weight);
+    let numerators: Vec<_> = weights.iter().map(|weight| total * weight).collect();
+
+    largest_remainder(&numerators, sum, total)
+}
+
+/// Every `numerator / denominator` floored, then raised by one in order of the largest
+/// remainder, the earlier entry first among equals, until they add up to `target`.
+fn largest_remainder(numerators: &[U256], denominator: U256, target: U256) -> Vec<U256> {
+    let (mut quotients, remainders): (Vec<U256>, Vec<U256>) = numerators
+        .iter()
+        .map(|numerator| numerator.div_mod(denominator))
+        .unzip();
+    let floored = quotients.iter().fold(U256::zero(), |sum, q| sum + q);
+
+    let mut order: Vec<usize> = (0..quotients.len()).collect();
+    order.sort_by(|a, b| remainders[*b].cmp(&remainders[*a]));
+    for i in order.into_iter().take((target - floored).as_usize()) {
+        quotients[i] += U256::one();
+    }
+
+    quotients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wei(amounts: &[u64]) -> Vec<U256> {
+        amounts.iter().copied().map(U256::from).collect()
+    }
+
+    #[test]
+    fn rounds_quotients_by_the_policy() {
+        let cases = [(25, 10), (15, 10), (26, 10), (24, 10), (20, 10)];
+        let rounded = |rounding: Rounding| -> Vec<u64> {
+            cases
+                .iter()
+                .map(|(n, d)| rounding.div(U256::from(*n), U256::from(*d)).as_u64())
+                .collect()
+        };
+
+        assert_eq!(rounded(Rounding::Floor), [2, 1, 2, 2, 2]);
+        assert_eq!(rounded(Rounding::HalfEven), [2, 2, 3, 2, 2]);
+        assert_eq!(rounded(Rounding::Ceil), [3, 2, 3, 3, 2]);
+    }
+
+    #[test]
+    fn conserves_the_rounded_total_of_a_split_under_every_policy() {
+        // 0.5 + 0.5 + 0.7 + 0.3 + 1.5 units: each rounded on its own they'd add up to 1, 3 or
+        // 6 units, not the 3, 4 or 4 their total rounds to
+        let amounts = wei(&[50, 50, 70, 30, 150]);
+        let unit = U256::from(100);
+
+        let floor = Rounding::Floor.round_to_unit(&amounts, unit);
+        let half_even = Rounding::HalfEven.round_to_unit(&amounts, unit);
+        let ceil = Rounding::Ceil.round_to_unit(&amounts, unit);
+
+        assert_eq!(floor, wei(&[100, 0, 100, 0, 100]));
+        assert_eq!(half_even, wei(&[100, 100, 100, 0, 100]));
+        assert_eq!(ceil, wei(&[100, 100, 100, 0, 100]));
+
+        let sum = |amounts: &[U256]| amounts.iter().fold(U256::zero(), |sum, a| sum + a);
+        for (rounding, rounded) in [
+            (Rounding::Floor, &floor),
+            (Rounding::HalfEven, &half_even),
+            (Rounding::Ceil, &ceil),
+        ] {
+            assert_eq!(sum(rounded), rounding.div(sum(&amounts), unit) * unit);
+            for (amount, rounded) in amounts.iter().zip(rounded) {
+                assert!(*rounded + unit > *amount && *rounded < *amount + unit);
+            }
+        }
+
+        // at 3.2 units half even sides with floor instead
+        let amounts = wei(&[50, 50, 70, 30, 120]);
+        assert_eq!(
+            Rounding::HalfEven.round_to_unit(&amounts, unit),
+            Rounding::Floor.round_to_unit(&amounts, unit)
+        );
+    }
+
+    #[test]
+    fn splits_to_the_wei() {
+        assert_eq!(split(U256::from(100), &wei(&[1, 1, 1])), wei(&[34, 33, 33]));
+        assert_eq!(split(U256::from(10), &wei(&[33, 33, 34])), wei(&[3, 3, 4]));
+        assert_eq!(split(U256::from(7), &wei(&[60, 40])), wei(&[4, 3]));
+    }
+}
@@ -0,0 +1,651 @@
+use crate::fetch::RANGE_LIMIT_ERRORS;
+use async_trait::async_trait;
+use ethers::providers::{Http, JsonRpcClient, Provider, ProviderError, RpcError};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::RandomState, BTreeSet},
+    fmt::Debug,
+    hash::BuildHasher,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub type Client = Provider<Instrumented<Http>>;
+
+/// Provider messages of failures a request may get past when sent again: timeouts, dropped
+/// connections, rate limits and server errors.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "timed out",
+    "timeout",
+    "connection reset",
+    "connection refused",
+    "connection closed",
+    "broken pipe",
+    "error sending request",
+    "too many requests",
+    "rate limit",
+    "rate exceeded",
+    "bad gateway",
+    "service unavailable",
+    "gateway timeout",
+    "internal server error",
+    "temporarily unavailable",
+];
+
+/// Whether a request failing with `err` is worth sending again. Ranges refused as too large
+/// aren't, fetching splits them instead, and neither is anything else the node rejects.
+pub fn is_retryable(err: &ProviderError) -> bool {
+    let message = err.to_string().to_lowercase();
+    if RANGE_LIMIT_ERRORS
+        .iter()
+        .any(|limit| message.contains(limit))
+    {
+        return false;
+    }
+
+    err.as_error_response()
+        .is_some_and(|response| response.code == 429)
+        || TRANSIENT_ERRORS
+            .iter()
+            .any(|transient| message.contains(transient))
+}
+
+/// How requests failing with a retryable error are sent again: up to `max_attempts` in all,
+/// waiting twice as long after every failure, from `base_delay` up to `max_delay`, each wait
+/// jittered so that clients failing together don't retry together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Every request sent once.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Wait after the `failures`-th failure in a row: half the backoff, plus up to the other
+    /// half at random.
+    fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        let random = RandomState::new().hash_one(failures) % 1_000;
+        backoff / 2 + backoff.mul_f64(random as f64 / 2_000.0)
+    }
+}
+
+/// How long an endpoint that gave up on a request is passed over for the others.
+pub const FAILOVER_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RequestSample {
+    /// Name of the endpoint the request was sent to
+    pub endpoint: String,
+    pub method: String,
+    /// Blocks the request was pinned to, both ends of the range for `eth_getLogs`
+    pub blocks: Vec<u64>,
+    pub latency: Duration,
+    pub failed: bool,
+    /// Length of the JSON result, a rough estimate of the bytes received
+    pub bytes: usize,
+}
+
+/// Wraps a transport and records the latency, size and outcome of every request sent through
+/// it, every attempt of a retried one included. Clones share the same samples.
+///
+/// Requests go to the first healthy endpoint, the one given to `new` and then the fallbacks
+/// in order. An endpoint still failing once its retries are used up is passed over for
+/// `FAILOVER_COOLDOWN`, and the request is sent to the next one. A request pinned to a block
+/// is never served by an endpoint behind that block, so every endpoint gives the same result.
+#[derive(Debug, Clone)]
+pub struct Instrumented<T> {
+    name: String,
+    endpoints: Vec<Arc<Endpoint<T>>>,
+    samples: Arc<Mutex<Vec<RequestSample>>>,
+    retry: RetryPolicy,
+}
+
+/// One transport of an `Instrumented` one, and how it has been doing.
+#[derive(Debug)]
+struct Endpoint<T> {
+    name: String,
+    inner: T,
+    health: Mutex<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// When the endpoint last gave up on a request, cleared by one succeeding
+    down_since: Option<tokio::time::Instant>,
+    /// Highest block the endpoint is known to have
+    head: u64,
+}
+
+impl<T> Endpoint<T> {
+    fn new(name: &str, inner: T) -> Endpoint<T> {
+        Endpoint {
+            name: name.to_string(),
+            inner,
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Raises the head known of the endpoint to the `eth_blockNumber` result `head`.
+    fn saw_head(&self, head: &Value) {
+        let mut health = self.health.lock().unwrap();
+        health.head = health.head.max(hex_block(head).unwrap_or_default());
+    }
+
+    fn is_down(&self) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .down_since
+            .is_some_and(|since| since.elapsed() < FAILOVER_COOLDOWN)
+    }
+}
+
+impl<T> Instrumented<T> {
+    /// Sends every request once, see `with_retry`.
+    pub fn new(name: &str, inner: T) -> Instrumented<T> {
+        Instrumented {
+            name: name.to_string(),
+            endpoints: vec![Arc::new(Endpoint::new(name, inner))],
+            samples: Arc::new(Mutex::new(vec![])),
+            retry: RetryPolicy::none(),
+        }
+    }
+
+    /// Sends requests failing with a retryable error again as `policy` says, warning about
+    /// every retry.
+    pub fn with_retry(self, policy: RetryPolicy) -> Instrumented<T> {
+        Instrumented {
+            retry: policy,
+            ..self
+        }
+    }
+
+    /// Fails over to `inner` when the endpoints before it keep failing.
+    pub fn with_fallback(mut self, name: &str, inner: T) -> Instrumented<T> {
+        self.endpoints.push(Arc::new(Endpoint::new(name, inner)));
+        self
+    }
+
+    pub fn stats(&self) -> ProviderStats {
+        ProviderStats::new(&self.name, &self.samples.lock().unwrap())
+    }
+
+    /// The stats of every endpoint, in the order they are tried.
+    pub fn endpoint_stats(&self) -> Vec<ProviderStats> {
+        let samples = self.samples.lock().unwrap();
+        self.endpoints
+            .iter()
+            .map(|endpoint| {
+                let served: Vec<_> = samples
+                    .iter()
+                    .filter(|sample| sample.endpoint == endpoint.name)
+                    .cloned()
+                    .collect();
+                ProviderStats::new(&endpoint.name, &served)
+            })
+            .collect()
+    }
+
+    /// The healthy endpoints, then those passed over, each in the order given.
+    fn route(&self) -> Vec<&Endpoint<T>> {
+        let (up, down): (Vec<_>, Vec<_>) = self
+            .endpoints
+            .iter()
+            .map(Arc::as_ref)
+            .partition(|endpoint| !endpoint.is_down());
+        up.into_iter().chain(down).collect()
+    }
+}
+
+impl<T: JsonRpcClient> Instrumented<T> {
+    /// Sends a request to `endpoint`, again as the retry policy says while it fails with a
+    /// retryable error.
+    async fn send<P>(
+        &self,
+        endpoint: &Endpoint<T>,
+        method: &str,
+        params: &P,
+        blocks: &[u64],
+    ) -> Result<Value, ProviderError>
+    where
+        P: Debug + Serialize + Send + Sync,
+    {
+        let mut failures = 0;
+        loop {
+            let start = Instant::now();
+            let result: Result<Value, ProviderError> = endpoint
+                .inner
+                .request(method, params)
+                .await
+                .map_err(Into::into);
+            let latency = start.elapsed();
+
+            self.samples.lock().unwrap().push(RequestSample {
+                endpoint: endpoint.name.clone(),
+                method: method.to_string(),
+                blocks: blocks.to_vec(),
+                latency,
+                failed: result.is_err(),
+                bytes: result.as_ref().map_or(0, |value| value.to_string().len()),
+            });
+
+            match result {
+                Err(err) if failures + 1 < self.retry.max_attempts && is_retryable(&err) => {
+                    failures += 1;
+                    let delay = self.retry.delay(failures as u32);
+                    eprintln!(
+                        "warning: {} to {} failed, retrying in {:?} ({} of {} attempts): {}",
+                        method, endpoint.name, delay, failures, self.retry.max_attempts, err
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Whether `endpoint` has `block`, asking it for its head when the last one it gave is
+    /// behind.
+    async fn has_block(&self, endpoint: &Endpoint<T>, block: u64) -> bool {
+        if endpoint.health.lock().unwrap().head >= block {
+            return true;
+        }
+        if let Ok(head) = self.send(endpoint, "eth_blockNumber", &(), &[]).await {
+            endpoint.saw_head(&head);
+        }
+        endpoint.health.lock().unwrap().head >= block
+    }
+}
+
+#[async_trait]
+impl<T: JsonRpcClient> JsonRpcClient for Instrumented<T> {
+    type Error = ProviderError;
+
+    async fn request<P, R>(&self, method: &str, params: P) -> Result<R, ProviderError>
+    where
+        P: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params_json = serde_json::to_value(&params).unwrap_or_default();
+        let blocks = block_tags(method, &params_json);
+        debug_assert!(
+            blocks.iter().all(Option::is_some),
+            "{} issued without a pinned block: {}",
+            method,
+            params_json
+        );
+        let blocks: Vec<u64> = blocks.into_iter().flatten().collect();
+        // a single endpoint serves whatever it has, as it always did
+        let pinned_to = blocks.iter().max().filter(|_| self.endpoints.len() > 1);
+
+        let route = self.route();
+        let mut last_err = None;
+        for (i, endpoint) in route.iter().enumerate() {
+            if let Some(&block) = pinned_to {
+                if !self.has_block(endpoint, block).await {
+                    eprintln!(
+                        "warning: {} is behind block {}, skipping it for {}",
+                        endpoint.name, block, method
+                    );
+                    continue;
+                }
+            }
+
+            match self.send(endpoint, method, &params, &blocks).await {
+                Ok(value) => {
+                    endpoint.health.lock().unwrap().down_since = None;
+                    if method == "eth_blockNumber" {
+                        endpoint.saw_head(&value);
+                    }
+                    return serde_json::from_value(value).map_err(ProviderError::SerdeJson);
+                }
+                Err(err) if is_retryable(&err) => {
+                    endpoint.health.lock().unwrap().down_since = Some(tokio::time::Instant::now());
+                    if let Some(next) = route.get(i + 1) {
+                        eprintln!(
+                            "warning: {} keeps failing, failing over to {}: {}",
+                            endpoint.name, next.name, err
+                        );
+                    }
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            ProviderError::CustomError(format!(
+                "no endpoint has reached block {} yet",
+                pinned_to.copied().unwrap_or_default()
+            ))
+        }))
+    }
+}
+
+/// Blocks a request reads chain state at, both ends of the range for `eth_getLogs`. A `None`
+/// stands for a moving tag such as `latest`, or for a missing one.
+fn block_tags(method: &str, params: &Value) -> Vec<Option<u64>> {
+    let block = hex_block;
+    match method {
+        "eth_getLogs" => vec![block(&params[0]["fromBlock"]), block(&params[0]["toBlock"])],
+        "eth_getBlockByNumber" => vec![block(&params[0])],
+        "eth_call" | "eth_estimateGas" | "eth_getBalance" | "eth_getCode" => {
+            vec![block(&params[1])]
+        }
+        _ => vec![],
+    }
+}
+
+fn hex_block(tag: &Value) -> Option<u64> {
+    let tag = tag.as_str()?.strip_prefix("0x")?;
+    u64::from_str_radix(tag, 16).ok()
+}
+
+#[derive(Debug)]
+pub struct ProviderStats {
+    pub provider: String,
+    pub requests: usize,
+    pub errors: usize,
+    pub bytes_received: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// The five slowest requests, slowest first
+    pub slowest: Vec<RequestSample>,
+    /// Every distinct block a request was pinned to
+    pub queried_blocks: BTreeSet<u64>,
+}
+
+impl ProviderStats {
+    fn new(provider: &str, samples: &[RequestSample]) -> ProviderStats {
+        let mut latencies: Vec<_> = samples.iter().map(|sample| sample.latency).collect();
+        latencies.sort_unstable();
+
+        // nearest rank
+        let percentile = |pct: usize| {
+            let rank = (pct * latencies.len()).div_ceil(100);
+            latencies
+                .get(rank.saturating_sub(1))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        let mut slowest = samples.to_vec();
+        slowest.sort_by_key(|sample| std::cmp::Reverse(sample.latency));
+        slowest.truncate(5);
+
+        ProviderStats {
+            provider: provider.to_string(),
+            requests: samples.len(),
+            errors: samples.iter().filter(|sample| sample.failed).count(),
+            bytes_received: samples.iter().map(|sample| sample.bytes).sum(),
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            slowest,
+            queried_blocks: samples
+                .iter()
+                .flat_map(|sample| sample.blocks.iter().copied())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::VecDeque;
+
+    /// Answers requests from a script of delays and results, in order.
+    #[derive(Debug)]
+    struct MockTransport {
+        script: Mutex<VecDeque<(u64, Result<Value, String>)>>,
+    }
+
+    #[async_trait]
+    impl JsonRpcClient for MockTransport {
+        type Error = ProviderError;
+
+        async fn request<P, R>(&self, _method: &str, _params: P) -> Result<R, ProviderError>
+        where
+            P: Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            let (delay_ms, result) = self.script.lock().unwrap().pop_front().unwrap();
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+
+            let value = result.map_err(ProviderError::CustomError)?;
+            serde_json::from_value(value).map_err(ProviderError::SerdeJson)
+        }
+    }
+
+    fn get_logs_params(from_block: u64) -> Value {
+        json!([{
+            "fromBlock": format!("{:#x}", from_block),
+            "toBlock": format!("{:#x}", from_block + 9_999),
+        }])
+    }
+
+    #[tokio::test]
+    async fn records_latency_and_errors_per_request() {
+        let delays = [15, 5, 40, 10, 30, 20, 35, 25];
+        let mut script: Vec<_> = delays.iter().map(|delay| (*delay, Ok(json!([])))).collect();
+        script.push((1, Err("rate limited".to_string())));
+        let transport = mock(script);
+
+        for (i, _) in delays.iter().enumerate() {
+            let logs: Vec<Value> = transport
+                .request("eth_getLogs", get_logs_params(i as u64 * 10_000))
+                .await
+                .unwrap();
+            assert!(logs.is_empty());
+        }
+        let failed: Result<Value, _> = transport
+            .request("eth_getBlockByNumber", json!(["0x10c0b97", false]))
+            .await;
+        assert!(failed.is_err());
+
+        let stats = transport.stats();
+        assert_eq!(stats.provider, "mock");
+        assert_eq!(stats.requests, 9);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(stats.bytes_received, 8 * "[]".len());
+
+        assert!(stats.p50 >= Duration::from_millis(15));
+        assert!(stats.p50 <= stats.p95 && stats.p95 <= stats.p99);
+        assert!(stats.p99 >= Duration::from_millis(40));
+
+        let slowest: Vec<_> = stats
+            .slowest
+            .iter()
+            .map(|sample| sample.blocks[0])
+            .collect();
+        assert_eq!(slowest, vec![20_000, 60_000, 40_000, 70_000, 50_000]);
+        assert_eq!(stats.queried_blocks.len(), 17);
+        assert!(stats.queried_blocks.contains(&79_999));
+    }
+
+    fn mock(script: Vec<(u64, Result<Value, String>)>) -> Instrumented<MockTransport> {
+        Instrumented::new("mock", endpoint(script))
+    }
+
+    fn endpoint(script: Vec<(u64, Result<Value, String>)>) -> MockTransport {
+        MockTransport {
+            script: Mutex::new(script.into()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_errors_with_backoff() {
+        let transport = mock(vec![
+            (0, Err("429 Too Many Requests".to_string())),
+            (0, Err("connection reset by peer".to_string())),
+            (0, Ok(json!("0x1"))),
+        ])
+        .with_retry(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        });
+
+        let start = tokio::time::Instant::now();
+        let chain_id: Value = transport.request("eth_chainId", ()).await.unwrap();
+        assert_eq!(chain_id, json!("0x1"));
+
+        // half of 100ms and 200ms at least, all of it at most
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(150) && waited <= Duration::from_millis(300));
+        let stats = transport.stats();
+        assert_eq!((stats.requests, stats.errors), (3, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_permanent_errors_and_after_the_last_attempt() {
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        for (message, attempts) in [
+            ("invalid params", 1),
+            ("query returned more than 10000 results", 1),
+            ("operation timed out", 3),
+        ] {
+            let transport = mock(vec![(0, Err(message.to_string())); 3]).with_retry(retry);
+            let failed: Result<Value, _> = transport.request("eth_chainId", ()).await;
+            assert!(failed.unwrap_err().to_string().contains(message));
+            assert_eq!(transport.stats().requests, attempts, "{}", message);
+        }
+
+        let transport = mock(vec![(0, Err("bad gateway".to_string())); 3]);
+        let _: Result<Value, _> = transport.request("eth_chainId", ()).await;
+        assert_eq!(transport.stats().requests, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_over_while_an_endpoint_is_down() {
+        let bad_gateway = || (0, Err("bad gateway".to_string()));
+        let transport = mock(vec![bad_gateway(), bad_gateway(), (0, Ok(json!("0x3")))])
+            .with_fallback(
+                "backup",
+                endpoint(vec![(0, Ok(json!("0x1"))), (0, Ok(json!("0x2")))]),
+            )
+            .with_retry(RetryPolicy {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(1),
+            });
+
+        // the backup serves once the retries on mock are used up, then until the cooldown
+        for expected in ["0x1", "0x2"] {
+            let chain_id: Value = transport.request("eth_chainId", ()).await.unwrap();
+            assert_eq!(chain_id, json!(expected));
+        }
+        tokio::time::advance(FAILOVER_COOLDOWN).await;
+        let chain_id: Value = transport.request("eth_chainId", ()).await.unwrap();
+        assert_eq!(chain_id, json!("0x3"));
+
+        let served: Vec<_> = transport
+            .endpoint_stats()
+            .iter()
+            .map(|stats| (stats.provider.clone(), stats.requests, stats.errors))
+            .collect();
+        assert_eq!(
+            served,
+            vec![("mock".to_string(), 3, 2), ("backup".to_string(), 2, 0)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_endpoints_behind_the_pinned_block() {
+        let transport = mock(vec![
+            (0, Ok(json!("0x2710"))),
+            (0, Err("service unavailable".to_string())),
+            (0, Err("query returned more than 10000 results".to_string())),
+        ])
+        .with_fallback("behind", endpoint(vec![(0, Ok(json!("0x63")))]))
+        .with_fallback(
+            "ahead",
+            endpoint(vec![(0, Ok(json!("0x2710"))), (0, Ok(json!([])))]),
+        );
+
+        let logs: Vec<Value> = transport
+            .request("eth_getLogs", get_logs_params(0))
+            .await
+            .unwrap();
+        assert!(logs.is_empty());
+
+        // the node answered, so a range it refuses is the caller's to split
+        tokio::time::advance(FAILOVER_COOLDOWN).await;
+        let refused: Result<Vec<Value>, _> =
+            transport.request("eth_getLogs", get_logs_params(0)).await;
+        assert!(refused.unwrap_err().to_string().contains("10000 results"));
+
+        let served: Vec<_> = transport
+            .endpoint_stats()
+            .iter()
+            .map(|stats| stats.requests)
+            .collect();
+        assert_eq!(served, vec![3, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn records_pinned_blocks() {
+        let transport = mock(vec![(0, Ok(json!("0x"))), (0, Ok(json!("0x1")))]);
+
+        let _: Value = transport
+            .request("eth_call", json!([{ "to": "0x0" }, "0x10c0b97"]))
+            .await
+            .unwrap();
+        let _: Value = transport.request("eth_chainId", ()).await.unwrap();
+
+        let stats = transport.stats();
+        assert_eq!(
+            stats.queried_blocks.into_iter().collect::<Vec<_>>(),
+            vec![17_566_615]
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "eth_call issued without a pinned block")]
+    async fn rejects_calls_at_latest() {
+        let transport = mock(vec![(0, Ok(json!("0x")))]);
+
+        let _: Result<Value, _> = transport
+            .request("eth_call", json!([{ "to": "0x0" }, "latest"]))
+            .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "eth_getLogs issued without a pinned block")]
+    async fn rejects_open_ended_log_ranges() {
+        let transport = mock(vec![(0, Ok(json!([])))]);
+
+        let _: Result<Value, _> = transport
+            .request("eth_getLogs", json!([{ "fromBlock": "0x10c0b97" }]))
+            .await;
+    }
+}
@@ -0,0 +1,650 @@
+//! Plausibility bounds on the rewards report, the last gate before payouts. No address may
+//! earn more than its shares could have, more than a set share of the emission, or much more
+//! or less than the previous report gave it.
+
+use crate::error::{Error, Result};
+use crate::lineage::EmbeddedLineage;
+use crate::observer::{StateObserver, UserDelta};
+use crate::report::Report;
+use crate::state::{Event, GlobalState, BLOCK_CONTRACT_DEPLOYED};
+use crate::utilization::ratio;
+use ethers::{
+    core::types::{Address, U256, U64},
+    utils::format_ether,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::Path,
+};
+
+/// The largest share of the pool every holder reached, and the blocks whose emission it could
+/// have been credited, recorded while the replay applies events.
+#[derive(Debug, Clone)]
+pub struct PoolShares {
+    total: U256,
+    applied: usize,
+    /// The pool total after every applied event, cut down to the minimums of its suffixes:
+    /// increasing in both the event index and the total
+    minimums: Vec<(usize, U256)>,
+    /// Block the pool last became empty at
+    emptied_at: U64,
+    /// Block the pool last filled up again at, and the block it had been empty since. The
+    /// emission in between is credited to whoever filled it.
+    refilled: Option<(U64, U64)>,
+    holders: HashMap<Address, Holding>,
+}
+
+#[derive(Debug, Clone)]
+struct Holding {
+    shares: U256,
+    /// Index of the event that last changed the shares
+    since: usize,
+    /// First block whose emission the holder could have been credited
+    earning_from: U64,
+    /// Block the holder last left the pool at, `None` while holding
+    exited_at: Option<U64>,
+    /// Largest share of the pool before the current holding, as shares over the pool total
+    largest_share: (U256, U256),
+}
+
+/// Most an address could have earned: the emission since it could first be credited, up to
+/// when it left the pool, times the largest share of the pool it held.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MaxRewards {
+    pub emitted: U256,
+    /// In percent
+    pub pool_share: f64,
+    pub rewards: U256,
+}
+
+impl PoolShares {
+    pub fn new() -> PoolShares {
+        PoolShares::deployed_at(BLOCK_CONTRACT_DEPLOYED)
+    }
+
+    /// Pool shares of a vault deployed at `block_number`, whose first depositor earns from there.
+    pub fn deployed_at(block_number: u64) -> PoolShares {
+        PoolShares {
+            total: U256::zero(),
+            applied: 0,
+            minimums: vec![],
+            emptied_at: U64::from(block_number),
+            refilled: None,
+            holders: HashMap::new(),
+        }
+    }
+
+    /// Smallest pool total after the events from index `since` on.
+    fn smallest_total_since(&self, since: usize) -> U256 {
+        let i = self.minimums.partition_point(|(index, _)| *index < since);
+        self.minimums[i].1
+    }
+
+    /// Most `address` could have earned up to `block_number`, nothing if it never held shares.
+    pub fn max_rewards(
+        &self,
+        address: Address,
+        state: &GlobalState,
+        block_number: U64,
+    ) -> MaxRewards {
+        let Some(holding) = self.holders.get(&address) else {
+            return MaxRewards::default();
+        };
+        let (shares, total) = if holding.shares.is_zero() {
+            holding.largest_share
+        } else {
+            larger(
+                holding.largest_share,
+                (holding.shares, self.smallest_total_since(holding.since)),
+            )
+        };
+
+        let until = holding.exited_at.unwrap_or(block_number).min(block_number);
+        let emitted = if holding.earning_from < until {
+            state.total_emission(until) - state.total_emission(holding.earning_from)
+        } else {
+            U256::zero()
+        };
+
+        MaxRewards {
+            emitted,
+            pool_share: ratio(shares, total) * 100.0,
+            rewards: emitted * shares / total,
+        }
+    }
+}
+
+impl Default for PoolShares {
+    fn default() -> PoolShares {
+        PoolShares::new()
+    }
+}
+
+/// The larger of two fractions.
+fn larger(a: (U256, U256), b: (U256, U256)) -> (U256, U256) {
+    if b.0 * a.1 > a.0 * b.1 {
+        b
+    } else {
+        a
+    }
+}
+
+impl StateObserver for PoolShares {
+    fn on_event_applied(&mut self, event: &Event, delta: &UserDelta) {
+        let block_number = event.block_number();
+        if self.total.is_zero() {
+            self.refilled = Some((block_number, self.emptied_at));
+        }
+
+        for change in &delta.changes {
+            let before = change.before.map_or(U256::zero(), |view| view.shares);
+            let after = change.after.shares;
+            // the share held since the last change peaked where the pool was smallest
+            let held = self
+                .holders
+                .get(&change.address)
+                .filter(|holding| !holding.shares.is_zero())
+                .map(|holding| (holding.shares, self.smallest_total_since(holding.since)));
+            let earning_from = match self.refilled {
+                Some((refilled_at, empty_since)) if refilled_at == block_number => empty_since,
+                _ => block_number,
+            };
+
+            let holding = self.holders.entry(change.address).or_insert(Holding {
+                shares: U256::zero(),
+                since: 0,
+                earning_from,
+                exited_at: None,
+                largest_share: (U256::zero(), U256::one()),
+            });
+            if let Some(held) = held {
+                holding.largest_share = larger(holding.largest_share, held);
+            }
+            holding.shares = after;
+            holding.since = self.applied;
+            holding.exited_at = after.is_zero().then_some(block_number);
+            self.total = self.total - before + after;
+        }
+
+        if self.total.is_zero() {
+            self.emptied_at = block_number;
+        }
+        while self
+            .minimums
+            .last()
+            .is_some_and(|(_, total)| *total >= self.total)
+        {
+            self.minimums.pop();
+        }
+        self.minimums.push((self.applied, self.total));
+        self.applied += 1;
+    }
+}
+
+/// Limits of the sanity checks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SanityBounds {
+    /// Largest share of the total emission one address may earn, in percent
+    pub max_emission_pct: f64,
+    /// Largest change of an address' rewards against the previous report, in percent
+    pub max_change_pct: f64,
+}
+
+/// The bound a report row broke, with the numbers it was checked against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// More than the emission while it held shares, times its largest share of the pool
+    AboveTheoreticalMax(MaxRewards),
+    /// More than `max_pct` percent of the total emission
+    EmissionShare {
+        total_emission: U256,
+        pct: f64,
+        max_pct: f64,
+    },
+    /// Changed by more than `max_pct` percent against the previous report
+    Changed {
+        previous: U256,
+        pct: f64,
+        max_pct: f64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedFlag {
+    pub label: String,
+    pub rewards: U256,
+    pub violation: Violation,
+}
+
+impl fmt::Display for RedFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} rewards", self.label, format_ether(self.rewards))?;
+        match &self.violation {
+            Violation::AboveTheoreticalMax(max) => write!(
+                f,
+                " exceed the theoretical maximum of {}, {:.4}% of the pool at most over {} \
+                 emitted while it held shares",
+                format_ether(max.rewards),
+                max.pool_share,
+                format_ether(max.emitted)
+            ),
+            Violation::EmissionShare {
+                total_emission,
+                pct,
+                max_pct,
+            } => write!(
+                f,
+                " are {:.4}% of the {} emitted, more than {}%",
+                pct,
+                format_ether(*total_emission),
+                max_pct
+            ),
+            Violation::Changed {
+                previous,
+                pct,
+                max_pct,
+            } => write!(
+                f,
+                " changed by {:+.2}% from {} in the previous report, more than {}%",
+                pct,
+                format_ether(*previous),
+                max_pct
+            ),
+        }
+    }
+}
+
+/// Checks every row of `report`, made at `block_number`, against the bounds, and against the
+/// rows of `previous` when given. Labels of sub-accounts are bounded by their address, and
+/// labels that aren't addresses by nothing.
+pub fn sanity_check(
+    report: &Report,
+    pool_shares: &PoolShares,
+    state: &GlobalState,
+    block_number: U64,
+    previous: Option<&RewardsFile>,
+    bounds: SanityBounds,
+) -> Vec<RedFlag> {
+    let total_emission = state.total_emission(block_number);
+    let mut flags = vec![];
+    let mut flag = |label: &str, rewards: U256, violation| {
+        flags.push(RedFlag {
+            label: label.to_string(),
+            rewards,
+            violation,
+        })
+    };
+
+    for row in &report.rows {
+        let address = row.label.split('#').next().unwrap_or_default().parse();
+        let max = match address {
+            Ok(address) => pool_shares.max_rewards(address, state, block_number),
+            Err(_) => MaxRewards::default(),
+        };
+        if row.rewards > max.rewards {
+            flag(&row.label, row.rewards, Violation::AboveTheoreticalMax(max));
+        }
+
+        let pct = ratio(row.rewards, total_emission) * 100.0;
+        if !total_emission.is_zero() && pct > bounds.max_emission_pct {
+            flag(
+                &row.label,
+                row.rewards,
+                Violation::EmissionShare {
+                    total_emission,
+                    pct,
+                    max_pct: bounds.max_emission_pct,
+                },
+            );
+        }
+    }
+
+    let Some(previous) = previous else {
+        return flags;
+    };
+    let current: BTreeMap<_, _> = report
+        .rows
+        .iter()
+        .map(|row| (row.label.as_str(), row.rewards))
+        .collect();
+    // addresses new since the previous report aren't a change
+    for (label, before) in &previous.rewards {
+        let now = current.get(label.as_str()).copied().unwrap_or_default();
+        if before.is_zero() {
+            continue;
+        }
+        let pct = if now >= *before {
+            ratio(now - before, *before) * 100.0
+        } else {
+            -ratio(*before - now, *before) * 100.0
+        };
+        if pct.abs() > bounds.max_change_pct {
+            flag(
+                label,
+                now,
+                Violation::Changed {
+                    previous: *before,
+                    pct,
+                    max_pct: bounds.max_change_pct,
+                },
+            );
+        }
+    }
+
+    flags
+}
+
+/// Rewards per label of a report, as the JSON `examples/json_report.rs` prints and
+/// `sanity --save` writes: `{"block": .., "rows": [{"address": .., "rewards": "<wei>"}]}`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RewardsFile {
+    pub block: u64,
+    pub rewards: BTreeMap<String, U256>,
+    /// Run that wrote the file and the files it was built from
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RewardsJson {
+    block: u64,
+    rows: Vec<RowJson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lineage: Option<EmbeddedLineage>,
+}
+
+/// Amounts as decimal wei strings, which JSON numbers can't hold.
+#[derive(Serialize, Deserialize)]
+struct RowJson {
+    address: String,
+    rewards: String,
+}
+
+impl RewardsFile {
+    pub fn new(report: &Report, block_number: U64) -> RewardsFile {
+        RewardsFile {
+            block: block_number.as_u64(),
+            rewards: report
+                .rows
+                .iter()
+                .map(|row| (row.label.clone(), row.rewards))
+                .collect(),
+            lineage: None,
+        }
+    }
+
+    pub fn parse(json: &str) -> Result<RewardsFile> {
+        let parsed: RewardsJson = serde_json::from_str(json)
+            .map_err(|err| Error::decode("invalid rewards JSON").caused_by(err))?;
+
+        let mut rewards = BTreeMap::new();
+        for row in parsed.rows {
+            let amount = U256::from_dec_str(&row.rewards).map_err(|err| {
+                Error::decode(format!("rewards of {} are not wei", row.address)).caused_by(err)
+            })?;
+            rewards.insert(row.address, amount);
+        }
+
+        Ok(RewardsFile {
+            block: parsed.block,
+            rewards,
+            lineage: parsed.lineage,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<RewardsFile> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| Error::io(format!("failed to read rewards {}", path.display()), err))?;
+
+        RewardsFile::parse(&contents).map_err(|err| {
+            Error::decode(format!("invalid rewards {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = RewardsJson {
+            block: self.block,
+            rows: self
+                .rewards
+                .iter()
+                .map(|(label, rewards)| RowJson {
+                    address: label.clone(),
+                    rewards: rewards.to_string(),
+                })
+                .collect(),
+            lineage: self.lineage.clone(),
+        };
+        let contents = serde_json::to_vec_pretty(&json).expect("rewards serialize to JSON");
+
+        fs::write(path, contents)
+            .map_err(|err| Error::io(format!("failed to write rewards {}", path.display()), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::Annotations;
+    use crate::report::{rewards_report, ReportRow};
+    use crate::state::{Deposit, Withdraw};
+    use crate::subaccounts::{Checkpoints, SubAccounts};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    const BOUNDS: SanityBounds = SanityBounds {
+        max_emission_pct: 100.0,
+        max_change_pct: 1_000.0,
+    };
+
+    fn ether(value: u64) -> U256 {
+        parse_ether(value).unwrap()
+    }
+
+    fn block(offset: u64) -> U64 {
+        U64::from(BLOCK_CONTRACT_DEPLOYED + offset)
+    }
+
+    fn label(address: Address) -> String {
+        format!("{:?}", address)
+    }
+
+    /// Bob stakes a share alone, Carol joins with 3, both leave the pool empty, and Dave
+    /// refills it, credited the emission of the empty blocks.
+    fn replayed() -> (GlobalState, PoolShares, Report, [Address; 3]) {
+        let [bob, carol, dave] = [0xb0b, 0xca201, 0xda4e].map(Address::from_low_u64_be);
+        let deposit = |address, shares, offset| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(ether(shares)),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let withdraw = |address, shares, offset| {
+            Event::Withdrawal(Withdraw {
+                address,
+                shares: Shares(ether(shares)),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+
+        let mut state = GlobalState::new();
+        let mut pool_shares = PoolShares::new();
+        state
+            .process_events_with(
+                vec![
+                    deposit(bob, 1, 0),
+                    deposit(carol, 3, 10),
+                    withdraw(carol, 3, 30),
+                    withdraw(bob, 1, 40),
+                    deposit(dave, 1, 50),
+                ],
+                &mut pool_shares,
+            )
+            .unwrap();
+        let report = rewards_report(
+            &state,
+            block(70),
+            &Annotations::default(),
+            None,
+            (&SubAccounts::default(), &Checkpoints::new()),
+            false,
+        );
+
+        (state, pool_shares, report, [bob, carol, dave])
+    }
+
+    fn row<'a>(report: &'a mut Report, label: &str) -> &'a mut ReportRow {
+        report
+            .rows
+            .iter_mut()
+            .find(|row| row.label == label)
+            .unwrap()
+    }
+
+    #[test]
+    fn flags_rewards_above_what_the_shares_could_earn() {
+        let (state, pool_shares, mut report, [bob, carol, dave]) = replayed();
+        let check =
+            |report: &Report| sanity_check(report, &pool_shares, &state, block(70), None, BOUNDS);
+
+        // bob 10 + 5 + 10, carol 3/4 of 20 blocks, dave the 10 empty blocks and 20 more
+        assert_eq!(row(&mut report, &label(bob)).rewards, ether(25));
+        assert_eq!(row(&mut report, &label(carol)).rewards, ether(15));
+        assert_eq!(row(&mut report, &label(dave)).rewards, ether(30));
+        assert_eq!(check(&report), []);
+
+        // a wei past 3/4 of the 20 blocks carol held, and a payee that never held
+        row(&mut report, &label(carol)).rewards = ether(15) + 1;
+        report.rows.push(ReportRow {
+            label: "0x000000000000000000000000000000000000dead".to_string(),
+            rewards: ether(1),
+            bonus: U256::zero(),
+            note: None,
+            reason: None,
+        });
+
+        let flags = check(&report);
+        assert_eq!(flags.len(), 2);
+        assert_eq!(
+            flags[0].violation,
+            Violation::AboveTheoreticalMax(MaxRewards {
+                emitted: ether(20),
+                pool_share: 75.0,
+                rewards: ether(15),
+            })
+        );
+        assert_eq!(
+            flags[0].to_string(),
+            format!(
+                "{}: 15.000000000000000001 rewards exceed the theoretical maximum of \
+                 15.000000000000000000, 75.0000% of the pool at most over 20.000000000000000000 \
+                 emitted while it held shares",
+                label(carol)
+            )
+        );
+        assert_eq!(
+            flags[1].violation,
+            Violation::AboveTheoreticalMax(MaxRewards::default())
+        );
+    }
+
+    #[test]
+    fn flags_rewards_above_a_share_of_the_emission() {
+        let (state, pool_shares, report, [.., dave]) = replayed();
+        let bounds = SanityBounds {
+            max_emission_pct: 40.0,
+            ..BOUNDS
+        };
+
+        // 30 of the 70 emitted
+        let flags = sanity_check(&report, &pool_shares, &state, block(70), None, bounds);
+        assert_eq!(flags.len(), 1);
+        assert_eq!(flags[0].label, label(dave));
+        assert!(matches!(
+            flags[0].violation,
+            Violation::EmissionShare { total_emission, .. } if total_emission == ether(70)
+        ));
+        assert!(flags[0]
+            .to_string()
+            .ends_with("are 42.8571% of the 70.000000000000000000 emitted, more than 40%"));
+
+        let bounds = SanityBounds {
+            max_emission_pct: 45.0,
+            ..BOUNDS
+        };
+        assert_eq!(
+            sanity_check(&report, &pool_shares, &state, block(70), None, bounds),
+            []
+        );
+    }
+
+    #[test]
+    fn flags_rewards_changed_since_the_previous_report() {
+        let (state, pool_shares, report, [bob, carol, _]) = replayed();
+        // carol doubled, a payee dropped out, bob is unchanged and dave is new
+        let previous = RewardsFile::parse(&format!(
+            r#"{{"block": 17568990, "rows": [
+                {{"address": "{}", "rewards": "25000000000000000000", "pct": 50.0}},
+                {{"address": "{}", "rewards": "7500000000000000000", "pct": 15.0}},
+                {{"address": "0x000000000000000000000000000000000000dead", "rewards": "1"}}
+            ]}}"#,
+            label(bob),
+            label(carol)
+        ))
+        .unwrap();
+        let bounds = SanityBounds {
+            max_change_pct: 50.0,
+            ..BOUNDS
+        };
+
+        let flags = sanity_check(
+            &report,
+            &pool_shares,
+            &state,
+            block(70),
+            Some(&previous),
+            bounds,
+        );
+        let changes: Vec<_> = flags
+            .iter()
+            .map(|flag| match flag.violation {
+                Violation::Changed { previous, pct, .. } => (flag.label.as_str(), previous, pct),
+                _ => panic!("unexpected {}", flag),
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (
+                    "0x000000000000000000000000000000000000dead",
+                    U256::one(),
+                    -100.0
+                ),
+                (label(carol).as_str(), ether(15) / 2, 100.0),
+            ]
+        );
+
+        // the saved report reads back as the next run's previous one
+        let path = std::env::temp_dir().join(format!("sanity-{}.json", std::process::id()));
+        RewardsFile::new(&report, block(70)).save(&path).unwrap();
+        let saved = RewardsFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.rewards[&label(carol)], ether(15));
+        assert_eq!(
+            sanity_check(
+                &report,
+                &pool_shares,
+                &state,
+                block(70),
+                Some(&saved),
+                bounds
+            ),
+            []
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use crate::state::{
+    AccrualPolicy, Deposit, Event, GlobalState, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED,
+};
+use crate::units::Shares;
+use ethers::{
+    core::types::{Address, U256, U64},
+    utils::{format_ether, keccak256, parse_ether},
+};
+use eyre::{bail, eyre, Result, WrapErr};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Holding {
+    user: String,
+    shares: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Move {
+    from: String,
+    to: String,
+    shares: String,
+}
+
+/// One `[[step]]` as written, either an event at `block` or expectations at `at`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepSpec {
+    block: Option<u64>,
+    deposit: Option<Holding>,
+    withdraw: Option<Holding>,
+    transfer: Option<Move>,
+    at: Option<u64>,
+    expect: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioSpec {
+    #[serde(default)]
+    net_same_block: bool,
+    #[serde(default)]
+    accrual_policy: AccrualPolicy,
+    step: Vec<StepSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Event(Event),
+    /// Rewards of each user, or of everyone under `total`, at a block
+    Expect {
+        at: U64,
+        rewards: Vec<(String, U256)>,
+    },
+}
+
+/// Accounting test case written as a list of steps, with blocks counted from the vault
+/// deployment and users named freely:
+///
+/// ```toml
+/// [[step]]
+/// block = 0
+/// deposit = { user = "bob", shares = "1 ether" }
+///
+/// [[step]]
+/// at = 100
+/// expect = { bob = "100 ether", total = "100 ether" }
+/// ```
+#[derive(Debug)]
+pub struct Scenario {
+    net_same_block: bool,
+    accrual_policy: AccrualPolicy,
+    steps: Vec<Step>,
+}
+
+/// Address of a scenario user, either written out or derived from the name.
+fn user(name: &str) -> Address {
+    name.parse()
+        .unwrap_or_else(|_| Address::from_slice(&keccak256(name)[12..]))
+}
+
+/// Parses `1.5 ether`, `100 wei` or a bare number of wei.
+fn amount(value: &str) -> Result<U256> {
+    let parsed = match value.trim().split_once(' ') {
+        Some((number, "ether")) => parse_ether(number).ok(),
+        Some((number, "wei")) => U256::from_dec_str(number).ok(),
+        Some(_) => None,
+        None => U256::from_dec_str(value.trim()).ok(),
+    };
+
+    parsed.ok_or_else(|| eyre!("`{}` is not an amount", value))
+}
+
+fn block(offset: u64) -> U64 {
+    U64::from(BLOCK_CONTRACT_DEPLOYED + offset)
+}
+
+impl StepSpec {
+    fn build(self) -> Result<Step> {
+        let step = match self {
+            StepSpec {
+                block: Some(offset),
+                deposit: Some(holding),
+                withdraw: None,
+                transfer: None,
+                at: None,
+                expect: None,
+            } => Step::Event(Event::Deposit(Deposit {
+                address: user(&holding.user),
+                shares: Shares(amount(&holding.shares)?),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            StepSpec {
+                block: Some(offset),
+                deposit: None,
+                withdraw: Some(holding),
+                transfer: None,
+                at: None,
+                expect: None,
+            } => Step::Event(Event::Withdrawal(Withdraw {
+                address: user(&holding.user),
+                shares: Shares(amount(&holding.shares)?),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            StepSpec {
+                block: Some(offset),
+                deposit: None,
+                withdraw: None,
+                transfer: Some(transfer),
+                at: None,
+                expect: None,
+            } => Step::Event(Event::Transfer(Transfer {
+                from: user(&transfer.from),
+                to: user(&transfer.to),
+                shares: Shares(amount(&transfer.shares)?),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })),
+            StepSpec {
+                block: None,
+                deposit: None,
+                withdraw: None,
+                transfer: None,
+                at: Some(at),
+                expect: Some(expect),
+            } => Step::Expect {
+                at: block(at),
+                rewards: expect
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, amount(&value)?)))
+                    .collect::<Result<_>>()?,
+            },
+            _ => bail!("a step is either `block` with one of `deposit`, `withdraw` or `transfer`, or `at` with `expect`"),
+        };
+
+        Ok(step)
+    }
+}
+
+impl Scenario {
+    pub fn parse(contents: &str) -> Result<Scenario> {
+        let spec: ScenarioSpec = toml::from_str(contents)?;
+        let steps = spec
+            .step
+            .into_iter()
+            .enumerate()
+            .map(|(i, step)| step.build().wrap_err_with(|| format!("step {}", i + 1)))
+            .collect::<Result<_>>()?;
+
+        Ok(Scenario {
+            net_same_block: spec.net_same_block,
+            accrual_policy: spec.accrual_policy,
+            steps,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Scenario> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read scenario {}", path.display()))?;
+
+        Scenario::parse(&contents).wrap_err_with(|| format!("invalid scenario {}", path.display()))
+    }
+
+    /// Every event of the scenario, in step order.
+    #[cfg(test)]
+    pub(crate) fn events(&self) -> Vec<Event> {
+        self.steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Event(evt) => Some(evt.clone()),
+                Step::Expect { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Feeds the events to a `GlobalState` and checks every expectation, failing on the first
+    /// one that doesn't hold.
+    pub fn run(&self) -> Result<()> {
+        let mut global_state = GlobalState::new();
+        global_state.set_same_block_netting(self.net_same_block);
+        global_state.set_accrual_policy(self.accrual_policy);
+        let mut pending = vec![];
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let (at, rewards) = match step {
+                Step::Event(evt) => {
+                    pending.push(evt.clone());
+                    continue;
+                }
+                Step::Expect { at, rewards } => (*at, rewards),
+            };
+
+            global_state.process_events(std::mem::take(&mut pending))?;
+
+            for (name, expected) in rewards {
+                let actual = if name == "total" {
+                    global_state.get_all_rewards(at)
+                } else {
+                    global_state.preview_user_rewards(user(name), at)
+                };
+
+                if actual != *expected {
+                    bail!(
+                        "step {}: {} has {} ether at block +{}, expected {} ether",
+                        i + 1,
+                        name,
+                        format_ether(actual),
+                        at.as_u64() - BLOCK_CONTRACT_DEPLOYED,
+                        format_ether(*expected)
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the scenario at `path`, or every `.toml` scenario in it when it is a directory,
+/// returning the outcome of each file in name order. A panic in the accounting counts as a
+/// failure of its file.
+pub fn run_scenarios(path: &Path) -> Result<Vec<(PathBuf, Result<()>)>> {
+    let files = if path.is_dir() {
+        let mut files: Vec<_> = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        files.retain(|file| file.extension().is_some_and(|ext| ext == "toml"));
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let outcomes = files
+        .into_iter()
+        .map(|file| {
+            let outcome = Scenario::load(&file).and_then(|scenario| {
+                panic::catch_unwind(AssertUnwindSafe(|| scenario.run()))
+                    .unwrap_or_else(|_| Err(eyre!("accounting panicked")))
+            });
+            (file, outcome)
+        })
+        .collect();
+
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_scenarios_pass() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+        let outcomes = run_scenarios(&dir).unwrap();
+
+        assert!(outcomes.len() >= 4);
+        for (file, outcome) in outcomes {
+            if let Err(err) = outcome {
+                panic!("{}: {:#}", file.display(), err);
+            }
+        }
+    }
+
+    #[test]
+    fn reports_the_failing_expectation() {
+        let scenario = Scenario::parse(
+            r#"
+            [[step]]
+            block = 0
+            deposit = { user = "bob", shares = "1 ether" }
+
+            [[step]]
+            at = 10
+            expect = { bob = "11 ether" }
+            "#,
+        )
+        .unwrap();
+
+        let err = scenario.run().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "step 2: bob has 10.000000000000000000 ether at block +10, expected 11.000000000000000000 ether"
+        );
+
+        let err = Scenario::parse("[[step]]\nblock = 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "step 1");
+    }
+}
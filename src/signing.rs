@@ -0,0 +1,141 @@
+use crate::snapshot::parse_signing_key;
+use ethers::{
+    core::k256::ecdsa::{
+        signature::hazmat::{PrehashSigner, PrehashVerifier},
+        Signature, SigningKey, VerifyingKey,
+    },
+    utils::{hex, keccak256},
+};
+use eyre::{eyre, Result, WrapErr};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// The bytes a signature covers: JSON re-serialized compactly with sorted keys, so reformatting
+/// an export doesn't invalidate it, and anything else as written.
+pub fn canonical_bytes(contents: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(contents) {
+        Ok(value) => serde_json::to_vec(&value).expect("JSON values should serialize"),
+        Err(_) => contents.to_vec(),
+    }
+}
+
+/// secp256k1 signature, `r ‖ s`, of the keccak256 hash of the canonical `contents`.
+pub fn sign(contents: &[u8], key: &SigningKey) -> Result<Vec<u8>> {
+    let signature: Signature = key.sign_prehash(&keccak256(canonical_bytes(contents)))?;
+    Ok(signature.to_vec())
+}
+
+pub fn verify(contents: &[u8], signature: &[u8], key: &VerifyingKey) -> Result<()> {
+    let signature =
+        Signature::from_slice(signature).map_err(|err| eyre!("malformed signature: {}", err))?;
+    key.verify_prehash(&keccak256(canonical_bytes(contents)), &signature)
+        .map_err(|_| eyre!("signature doesn't match the contents and key"))
+}
+
+/// Where the detached signature of `path` is written: `report.json` is signed in
+/// `report.json.sig`.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// Signs the file at `path` into its detached signature file, returning that file's path.
+pub fn sign_file(path: &Path, key: &SigningKey) -> Result<PathBuf> {
+    let contents = fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let sig_path = signature_path(path);
+    fs::write(
+        &sig_path,
+        format!("0x{}\n", hex::encode(sign(&contents, key)?)),
+    )
+    .wrap_err_with(|| format!("failed to write signature {}", sig_path.display()))?;
+    Ok(sig_path)
+}
+
+pub fn verify_file(path: &Path, key: &VerifyingKey) -> Result<()> {
+    let contents = fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
+    let sig_path = signature_path(path);
+    let signature = fs::read_to_string(&sig_path)
+        .wrap_err_with(|| format!("failed to read signature {}", sig_path.display()))?;
+    let signature = hex::decode(signature.trim().trim_start_matches("0x"))
+        .map_err(|_| eyre!("signature {} is not hex", sig_path.display()))?;
+    verify(&contents, &signature, key)
+}
+
+/// Reads the hex secp256k1 private key held by the environment variable `var`. Errors name the
+/// variable only, never its value.
+pub fn signing_key_from_env(var: &str) -> Result<SigningKey> {
+    let value = env::var(var).map_err(|_| eyre!("{} is not set", var))?;
+    parse_signing_key(&value)
+        .ok_or_else(|| eyre!("{} does not hold a hex secp256k1 private key", var))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = r#"{
+  "schema_version": 1,
+  "rows": [{"address": "0xb0b", "rewards": "100"}]
+}"#;
+
+    #[test]
+    fn signatures_cover_the_canonical_contents_under_one_key() {
+        let key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let other = SigningKey::from_slice(&[8; 32]).unwrap();
+
+        let signature = sign(REPORT.as_bytes(), &key).unwrap();
+        verify(REPORT.as_bytes(), &signature, key.verifying_key()).unwrap();
+        let reformatted = r#"{"rows":[{"rewards":"100","address":"0xb0b"}],"schema_version":1}"#;
+        verify(reformatted.as_bytes(), &signature, key.verifying_key()).unwrap();
+
+        let tampered = REPORT.replace("100", "1000");
+        assert!(verify(tampered.as_bytes(), &signature, key.verifying_key()).is_err());
+        assert!(verify(REPORT.as_bytes(), &signature, other.verifying_key()).is_err());
+
+        let csv = b"address,rewards\n0xb0b,100\n";
+        let signature = sign(csv, &key).unwrap();
+        verify(csv, &signature, key.verifying_key()).unwrap();
+        assert!(verify(
+            b"address,rewards\n0xb0b,100 \n",
+            &signature,
+            key.verifying_key()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn detached_signature_files_round_trip() {
+        let dir = env::temp_dir().join(format!("oprtc-signing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proof.json");
+        fs::write(&path, REPORT).unwrap();
+        let key = SigningKey::from_slice(&[7; 32]).unwrap();
+
+        assert_eq!(sign_file(&path, &key).unwrap(), dir.join("proof.json.sig"));
+        verify_file(&path, key.verifying_key()).unwrap();
+
+        fs::write(&path, REPORT.replace("0xb0b", "0xa11ce")).unwrap();
+        assert!(verify_file(&path, key.verifying_key()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_errors_leave_out_the_value() {
+        let var = format!("OPRTC_TEST_SIGNING_KEY_{}", std::process::id());
+        env::set_var(&var, "0xnot-a-secret-key");
+        let err = signing_key_from_env(&var).unwrap_err().to_string();
+        assert!(err.contains(&var));
+        assert!(!err.contains("not-a-secret-key"));
+
+        env::set_var(&var, hex::encode([7; 32]));
+        assert_eq!(
+            signing_key_from_env(&var).unwrap(),
+            SigningKey::from_slice(&[7; 32]).unwrap()
+        );
+        env::remove_var(&var);
+    }
+}
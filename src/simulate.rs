@@ -0,0 +1,533 @@
+use crate::calls::call_uint;
+use crate::lineage::EmbeddedLineage;
+use crate::rpc::Client;
+use crate::stdio;
+use async_trait::async_trait;
+use ethers::{
+    core::{
+        abi::{decode, encode, ParamType, Token},
+        types::{Address, BlockId, Bytes, TransactionRequest, H256, U256, U64},
+    },
+    providers::{Middleware, ProviderError, RpcError},
+    utils::{hex, id, keccak256},
+};
+use eyre::{ensure, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+use tokio::task::JoinSet;
+
+const CLAIM: &str = "claim(uint256,address,uint256,bytes32[])";
+
+/// Selector of Solidity's `Error(string)` revert payload.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Claims file in the format of Uniswap's `merkle-distributor` generator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MerkleFile {
+    pub merkle_root: H256,
+    /// Sum of the amounts, zero when the file doesn't give it
+    #[serde(default)]
+    pub token_total: U256,
+    pub claims: BTreeMap<Address, MerkleClaim>,
+    /// Run that wrote the file and the files it was built from, left out of the tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleClaim {
+    pub index: u64,
+    pub amount: U256,
+    pub proof: Vec<H256>,
+}
+
+impl MerkleFile {
+    pub fn load(path: &Path) -> Result<MerkleFile> {
+        let contents = stdio::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read merkle file {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse merkle file {}", path.display()))
+    }
+
+    /// The tree the generator builds over `rewards`: zero amounts left out, indices given in
+    /// address order and leaves sorted by hash, so the same rewards always give the same
+    /// root. Every proof is checked against the root before the file is returned.
+    pub fn build(rewards: &[(Address, U256)]) -> Result<MerkleFile> {
+        let mut payees: Vec<(Address, U256)> = rewards
+            .iter()
+            .copied()
+            .filter(|(_, amount)| !amount.is_zero())
+            .collect();
+        payees.sort();
+        ensure!(
+            payees.windows(2).all(|pair| pair[0].0 != pair[1].0),
+            "an address is given rewards twice"
+        );
+        let token_total = payees
+            .iter()
+            .try_fold(U256::zero(), |total, (_, amount)| {
+                total.checked_add(*amount)
+            })
+            .ok_or_else(|| eyre::eyre!("the rewards overflow a uint256"))?;
+
+        let leaves: Vec<H256> = payees
+            .iter()
+            .enumerate()
+            .map(|(index, &(account, amount))| leaf(index as u64, account, amount))
+            .collect();
+        let mut layers = vec![leaves.clone()];
+        layers[0].sort();
+        while layers.last().unwrap().len() > 1 {
+            let next = layers
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match *pair {
+                    [a, b] => parent(a, b),
+                    [a] => a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            layers.push(next);
+        }
+        let merkle_root = layers.last().unwrap().first().copied().unwrap_or_default();
+
+        let mut claims = BTreeMap::new();
+        for (index, (&(account, amount), leaf)) in payees.iter().zip(leaves).enumerate() {
+            let mut position = layers[0].binary_search(&leaf).unwrap();
+            let mut proof = vec![];
+            for layer in &layers[..layers.len() - 1] {
+                if let Some(sibling) = layer.get(position ^ 1) {
+                    proof.push(*sibling);
+                }
+                position /= 2;
+            }
+            ensure!(
+                verify_proof(&proof, merkle_root, leaf),
+                "the proof of {:?} doesn't lead to the merkle root",
+                account
+            );
+            claims.insert(
+                account,
+                MerkleClaim {
+                    index: index as u64,
+                    amount,
+                    proof,
+                },
+            );
+        }
+
+        Ok(MerkleFile {
+            merkle_root,
+            token_total,
+            claims,
+            lineage: None,
+        })
+    }
+}
+
+/// `keccak256(abi.encodePacked(index, account, amount))`, the leaf the distributor checks.
+pub fn leaf(index: u64, account: Address, amount: U256) -> H256 {
+    let mut packed = [0u8; 84];
+    U256::from(index).to_big_endian(&mut packed[..32]);
+    packed[32..52].copy_from_slice(account.as_bytes());
+    amount.to_big_endian(&mut packed[52..]);
+    H256::from(keccak256(packed))
+}
+
+/// OpenZeppelin `MerkleProof.verify`: pairs are hashed in sorted order.
+pub fn verify_proof(proof: &[H256], root: H256, leaf: H256) -> bool {
+    proof
+        .iter()
+        .fold(leaf, |node, sibling| parent(node, *sibling))
+        == root
+}
+
+/// The node over two siblings, hashed in sorted order.
+fn parent(a: H256, b: H256) -> H256 {
+    let (a, b) = if a <= b { (a, b) } else { (b, a) };
+    H256::from(keccak256([a.as_bytes(), b.as_bytes()].concat()))
+}
+
+fn claim_calldata(index: u64, account: Address, claim: &MerkleClaim) -> Bytes {
+    let mut calldata = id(CLAIM).to_vec();
+    calldata.extend(encode(&[
+        Token::Uint(U256::from(index)),
+        Token::Address(account),
+        Token::Uint(claim.amount),
+        Token::Array(
+            claim
+                .proof
+                .iter()
+                .map(|node| Token::FixedBytes(node.as_bytes().to_vec()))
+                .collect(),
+        ),
+    ]));
+    Bytes::from(calldata)
+}
+
+/// The reason in a revert payload: the message of an `Error(string)`, the raw hex otherwise.
+pub fn revert_reason(data: &[u8]) -> String {
+    if data.starts_with(&ERROR_SELECTOR) {
+        if let Ok(tokens) = decode(&[ParamType::String], &data[4..]) {
+            if let Some(Token::String(reason)) = tokens.into_iter().next() {
+                return reason;
+            }
+        }
+    }
+    if data.is_empty() {
+        "reverted without a reason".to_string()
+    } else {
+        format!("reverted with 0x{}", hex::encode(data))
+    }
+}
+
+/// A node that can dry-run calls against a distributor at a fixed block.
+#[async_trait]
+pub trait ClaimSimulator: Clone + Send + Sync + 'static {
+    async fn merkle_root(&self, distributor: Address, block: U64) -> Result<H256>;
+    /// The gas `calldata` would use, or why it would revert.
+    async fn simulate(
+        &self,
+        distributor: Address,
+        calldata: Bytes,
+        block: U64,
+    ) -> Result<Result<U256, String>>;
+}
+
+#[async_trait]
+impl ClaimSimulator for Client {
+    async fn merkle_root(&self, distributor: Address, block: U64) -> Result<H256> {
+        let root = call_uint(self, distributor, "merkleRoot()", &[], block).await?;
+        let mut bytes = [0u8; 32];
+        root.to_big_endian(&mut bytes);
+        Ok(H256::from(bytes))
+    }
+
+    async fn simulate(
+        &self,
+        distributor: Address,
+        calldata: Bytes,
+        block: U64,
+    ) -> Result<Result<U256, String>> {
+        let tx = TransactionRequest::new()
+            .to(distributor)
+            .data(calldata)
+            .into();
+        let block = Some(BlockId::from(block));
+
+        match self.call(&tx, block).await {
+            Ok(_) => Ok(Ok(self.estimate_gas(&tx, block).await?)),
+            Err(err) => match reverted(&err) {
+                Some(reason) => Ok(Err(reason)),
+                None => Err(err.into()),
+            },
+        }
+    }
+}
+
+/// The revert reason of a failed `eth_call`, `None` when the request itself failed.
+fn reverted(err: &ProviderError) -> Option<String> {
+    let response = err.as_error_response()?;
+    let data = response
+        .data
+        .as_ref()
+        .and_then(|data| data.as_str())
+        .and_then(|data| hex::decode(data.trim_start_matches("0x")).ok());
+    Some(match data {
+        Some(data) => revert_reason(&data),
+        None => response.message.clone(),
+    })
+}
+
+/// How a leaf fared: the gas its claim would use, or why it would fail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafResult {
+    pub index: u64,
+    pub account: Address,
+    pub amount: U256,
+    pub outcome: Result<U256, String>,
+}
+
+/// Claims of every leaf of `file`, dry-run with up to `concurrency` calls in flight and
+/// ordered by index. Leaves sharing an index fail without a call, as every call runs against
+/// the same state and only a mined claim would make the other revert. So do leaves whose proof
+/// doesn't lead to the file's own root, which names the cause better than the revert.
+pub async fn simulate_claims<S: ClaimSimulator>(
+    simulator: &S,
+    distributor: Address,
+    file: &MerkleFile,
+    block: U64,
+    concurrency: usize,
+) -> Result<Vec<LeafResult>> {
+    let mut by_index: HashMap<u64, usize> = HashMap::new();
+    for claim in file.claims.values() {
+        *by_index.entry(claim.index).or_default() += 1;
+    }
+
+    let mut results = vec![];
+    let mut tasks = JoinSet::new();
+    let mut pending = file.claims.iter();
+
+    loop {
+        while tasks.len() < concurrency.max(1) {
+            let Some((&account, claim)) = pending.next() else {
+                break;
+            };
+            let result = |outcome| LeafResult {
+                index: claim.index,
+                account,
+                amount: claim.amount,
+                outcome,
+            };
+
+            if by_index[&claim.index] > 1 {
+                results.push(result(Err(format!(
+                    "index {} is shared by {} leaves",
+                    claim.index, by_index[&claim.index]
+                ))));
+            } else if !verify_proof(
+                &claim.proof,
+                file.merkle_root,
+                leaf(claim.index, account, claim.amount),
+            ) {
+                results.push(result(Err(
+                    "proof doesn't lead to the file's merkle root".to_string()
+                )));
+            } else {
+                let simulator = simulator.clone();
+                let calldata = claim_calldata(claim.index, account, claim);
+                let leaf = result(Ok(U256::zero()));
+                tasks.spawn(async move {
+                    let outcome = simulator.simulate(distributor, calldata, block).await?;
+                    Ok::<_, eyre::Report>(LeafResult { outcome, ..leaf })
+                });
+            }
+        }
+
+        match tasks.join_next().await {
+            Some(joined) => results.push(joined??),
+            None => break,
+        }
+    }
+
+    results.sort_by_key(|result| (result.index, result.account));
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// `MerkleDistributor` in Rust: checks the proof against its root, then transfers.
+    #[derive(Clone)]
+    struct ReferenceDistributor {
+        root: H256,
+    }
+
+    #[async_trait]
+    impl ClaimSimulator for ReferenceDistributor {
+        async fn merkle_root(&self, _distributor: Address, _block: U64) -> Result<H256> {
+            Ok(self.root)
+        }
+
+        async fn simulate(
+            &self,
+            _distributor: Address,
+            calldata: Bytes,
+            _block: U64,
+        ) -> Result<Result<U256, String>> {
+            assert_eq!(calldata[..4], id(CLAIM));
+            let params = [
+                ParamType::Uint(256),
+                ParamType::Address,
+                ParamType::Uint(256),
+                ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+            ];
+            let tokens = decode(&params, &calldata[4..])?;
+            let [Token::Uint(index), Token::Address(account), Token::Uint(amount), Token::Array(proof)] =
+                &tokens[..]
+            else {
+                panic!("claim decodes to its parameters");
+            };
+            let proof: Vec<H256> = proof
+                .iter()
+                .map(|node| H256::from_slice(&node.clone().into_fixed_bytes().unwrap()))
+                .collect();
+
+            if !verify_proof(&proof, self.root, leaf(index.as_u64(), *account, *amount)) {
+                let mut data = ERROR_SELECTOR.to_vec();
+                data.extend(encode(&[Token::String(
+                    "MerkleDistributor: Invalid proof.".to_string(),
+                )]));
+                return Ok(Err(revert_reason(&data)));
+            }
+            Ok(Ok(U256::from(80_000 + 1_000 * proof.len())))
+        }
+    }
+
+    /// Builds a merkle file over `claims` the way the generator does.
+    fn merkle_file(claims: &[(u64, Address, U256)]) -> MerkleFile {
+        let leaves: Vec<H256> = claims
+            .iter()
+            .map(|&(index, account, amount)| leaf(index, account, amount))
+            .collect();
+        let parent = |a: H256, b: H256| {
+            let (a, b) = if a <= b { (a, b) } else { (b, a) };
+            H256::from(keccak256([a.as_bytes(), b.as_bytes()].concat()))
+        };
+
+        let mut layers = vec![leaves.clone()];
+        while layers.last().unwrap().len() > 1 {
+            let layer = layers.last().unwrap();
+            let next = layer
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => parent(*a, *b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            layers.push(next);
+        }
+
+        let proof = |mut position: usize| {
+            let mut proof = vec![];
+            for layer in &layers[..layers.len() - 1] {
+                if let Some(sibling) = layer.get(position ^ 1) {
+                    proof.push(*sibling);
+                }
+                position /= 2;
+            }
+            proof
+        };
+
+        MerkleFile {
+            merkle_root: layers.last().unwrap()[0],
+            token_total: claims
+                .iter()
+                .map(|claim| claim.2)
+                .fold(U256::zero(), |a, b| a + b),
+            claims: claims
+                .iter()
+                .enumerate()
+                .map(|(i, &(index, account, amount))| {
+                    let proof = proof(i);
+                    (
+                        account,
+                        MerkleClaim {
+                            index,
+                            amount,
+                            proof,
+                        },
+                    )
+                })
+                .collect(),
+            lineage: None,
+        }
+    }
+
+    fn claims() -> Vec<(u64, Address, U256)> {
+        (0..5)
+            .map(|i| {
+                (
+                    i,
+                    Address::from_low_u64_be(0x100 + i),
+                    U256::from(1_000 * (i + 1)),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn every_leaf_of_a_good_file_claims() {
+        let file = merkle_file(&claims());
+        let distributor = ReferenceDistributor {
+            root: file.merkle_root,
+        };
+
+        let results = simulate_claims(&distributor, Address::zero(), &file, U64::one(), 2)
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(|result| result.outcome.is_ok()));
+        let indices: HashSet<_> = results.iter().map(|result| result.index).collect();
+        assert_eq!(indices.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn builds_a_file_every_leaf_claims_from() {
+        let mut rewards: Vec<(Address, U256)> = claims()
+            .into_iter()
+            .map(|(_, account, amount)| (account, amount))
+            .collect();
+        rewards.push((Address::from_low_u64_be(0x99), U256::zero()));
+        rewards.reverse();
+
+        let file = MerkleFile::build(&rewards).unwrap();
+        assert_eq!(file.claims.len(), 5);
+        assert_eq!(file.token_total, U256::from(15_000));
+        assert_eq!(file.claims[&Address::from_low_u64_be(0x100)].index, 0);
+        assert_eq!(file.claims[&Address::from_low_u64_be(0x104)].index, 4);
+
+        // the order rewards come in doesn't move the root, and the file reads back as written
+        rewards.sort_by_key(|&(_, amount)| amount);
+        assert_eq!(MerkleFile::build(&rewards).unwrap(), file);
+        let json = serde_json::to_string(&file).unwrap();
+        assert_eq!(serde_json::from_str::<MerkleFile>(&json).unwrap(), file);
+
+        let distributor = ReferenceDistributor {
+            root: file.merkle_root,
+        };
+        let results = simulate_claims(&distributor, Address::zero(), &file, U64::one(), 2)
+            .await
+            .unwrap();
+        assert!(results.iter().all(|result| result.outcome.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn names_the_leaves_of_a_corrupted_file() {
+        let mut file = merkle_file(&claims());
+        let distributor = ReferenceDistributor {
+            root: file.merkle_root,
+        };
+        let wrong_amount = Address::from_low_u64_be(0x101);
+        file.claims.get_mut(&wrong_amount).unwrap().amount += U256::one();
+        let repeated = Address::from_low_u64_be(0x103);
+        file.claims.get_mut(&repeated).unwrap().index = 4;
+
+        let results = simulate_claims(&distributor, Address::zero(), &file, U64::one(), 2)
+            .await
+            .unwrap();
+        let failures: Vec<_> = results
+            .iter()
+            .filter_map(|result| Some((result.account, result.outcome.clone().err()?)))
+            .collect();
+
+        assert_eq!(failures.len(), 3);
+        assert!(failures.contains(&(
+            wrong_amount,
+            "proof doesn't lead to the file's merkle root".to_string()
+        )));
+        assert!(failures
+            .iter()
+            .any(|(account, reason)| *account == repeated && reason.contains("shared by 2")));
+
+        // a file generated for another root passes its own checks and reverts on the distributor
+        let other = merkle_file(&[(1, wrong_amount, U256::from(2_001))]);
+        let results = simulate_claims(&distributor, Address::zero(), &other, U64::one(), 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            results[0].outcome,
+            Err("MerkleDistributor: Invalid proof.".to_string())
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use crate::lineage::EmbeddedLineage;
+use crate::state::{AccrualPolicy, Emission, GlobalState, StateSnapshot};
+use ethers::{
+    core::{
+        k256::ecdsa::{
+            signature::hazmat::{PrehashSigner, PrehashVerifier},
+            Signature, SigningKey, VerifyingKey,
+        },
+        types::{Address, Bytes, H256, U64},
+    },
+    utils::{hex, keccak256},
+};
+use eyre::{bail, ensure, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+/// Settings that change how events fold into the state, which a windowed run must share with
+/// the run that took its snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplaySettings {
+    pub net_same_block: bool,
+    pub emission: Emission,
+    #[serde(
+        default = "AccrualPolicy::legacy",
+        skip_serializing_if = "AccrualPolicy::is_legacy"
+    )]
+    pub accrual_policy: AccrualPolicy,
+}
+
+/// The accounting state of `vault` after every event up to `block_number`, which windowed runs
+/// resume from instead of replaying the history before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub vault: Address,
+    pub chain_id: u64,
+    pub block_number: U64,
+    pub settings: ReplaySettings,
+    pub state: StateSnapshot,
+    /// keccak256 over every field above
+    pub hash: H256,
+    /// secp256k1 signature of `hash`, `r ‖ s`
+    #[serde(default)]
+    pub signature: Option<Bytes>,
+    /// Run that wrote the snapshot and the files it was built from, left out of `hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lineage: Option<EmbeddedLineage>,
+}
+
+impl Snapshot {
+    pub fn new(
+        vault: Address,
+        chain_id: u64,
+        block_number: U64,
+        settings: ReplaySettings,
+        state: &GlobalState,
+    ) -> Snapshot {
+        let mut snapshot = Snapshot {
+            vault,
+            chain_id,
+            block_number,
+            settings,
+            state: state.snapshot(),
+            hash: H256::zero(),
+            signature: None,
+            lineage: None,
+        };
+        snapshot.hash = snapshot.digest();
+        snapshot
+    }
+
+    fn digest(&self) -> H256 {
+        let contents = (
+            self.vault,
+            self.chain_id,
+            self.block_number,
+            &self.settings,
+            &self.state,
+        );
+        H256::from(keccak256(
+            serde_json::to_vec(&contents).expect("snapshot should serialize"),
+        ))
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) -> Result<()> {
+        let signature: Signature = key.sign_prehash(self.hash.as_bytes())?;
+        self.signature = Some(signature.to_vec().into());
+        Ok(())
+    }
+
+    /// Checks the embedded hash against the contents and, given a key, the signature against
+    /// the hash.
+    pub fn verify(&self, key: Option<&VerifyingKey>) -> Result<()> {
+        ensure!(
+            self.digest() == self.hash,
+            "snapshot contents don't match its hash {:?}",
+            self.hash
+        );
+
+        if let Some(key) = key {
+            let Some(signature) = &self.signature else {
+                bail!("snapshot is unsigned");
+            };
+            let signature = Signature::from_slice(signature)
+                .map_err(|err| eyre!("malformed snapshot signature: {}", err))?;
+            key.verify_prehash(self.hash.as_bytes(), &signature)
+                .map_err(|_| eyre!("snapshot signature doesn't match the key"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Refuses blocks before the snapshot, whose events a windowed run never sees.
+    pub fn ensure_covers(&self, block_number: U64) -> Result<()> {
+        ensure!(
+            block_number >= self.block_number,
+            "block {} is before the snapshot at block {}, a windowed run only answers blocks from it on",
+            block_number,
+            self.block_number
+        );
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read snapshot {}", path.display()))?;
+
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse snapshot {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+            .wrap_err_with(|| format!("failed to write snapshot {}", path.display()))
+    }
+}
+
+/// Parses a hex SEC1 secp256k1 public key, compressed or not.
+pub fn parse_public_key(s: &str) -> Result<VerifyingKey, String> {
+    let bytes = hex::decode(s.trim_start_matches("0x")).map_err(|err| err.to_string())?;
+    VerifyingKey::from_sec1_bytes(&bytes).map_err(|err| err.to_string())
+}
+
+/// Parses a hex secp256k1 private key, surrounding whitespace allowed.
+pub fn parse_signing_key(s: &str) -> Option<SigningKey> {
+    let bytes = hex::decode(s.trim().trim_start_matches("0x")).ok()?;
+    SigningKey::from_slice(&bytes).ok()
+}
+
+/// Reads a hex secp256k1 private key from `path`.
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let contents = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read key {}", path.display()))?;
+    parse_signing_key(&contents).ok_or_else(|| eyre!("invalid key {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Event, Transfer, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::U256, utils::parse_ether};
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    fn history() -> Vec<Event> {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+
+        vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 3),
+                block_number: block(0),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Deposit(Deposit {
+                address: alice,
+                shares: Shares(one),
+                block_number: block(40),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Transfer(Transfer {
+                from: bob,
+                to: alice,
+                shares: Shares(one),
+                block_number: block(90),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: Shares(one * 2),
+                block_number: block(150),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(one * 5),
+                block_number: block(210),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        ]
+    }
+
+    fn snapshot_at(events: &[Event], block_number: U64) -> Snapshot {
+        let mut state = GlobalState::new();
+        state
+            .process_events(
+                events
+                    .iter()
+                    .filter(|evt| evt.block_number() <= block_number)
+                    .cloned()
+                    .collect(),
+            )
+            .unwrap();
+        let settings = ReplaySettings {
+            net_same_block: false,
+            emission: Emission::PerBlock,
+            accrual_policy: AccrualPolicy::BlockStart,
+        };
+        Snapshot::new(VAULT.parse().unwrap(), 1, block_number, settings, &state)
+    }
+
+    #[test]
+    fn windowed_replay_matches_the_full_history() {
+        let events = history();
+        let snapshot_block = U64::from(BLOCK_CONTRACT_DEPLOYED + 120);
+        let json = serde_json::to_string(&snapshot_at(&events, snapshot_block)).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        snapshot.verify(None).unwrap();
+
+        for offset in [120, 121, 150, 210, 400] {
+            let block = U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+            assert!(snapshot.ensure_covers(block).is_ok());
+            let up_to_block = events.iter().filter(|evt| evt.block_number() <= block);
+
+            let mut full = GlobalState::new();
+            full.process_events(up_to_block.clone().cloned().collect())
+                .unwrap();
+
+            let mut windowed = GlobalState::resume(snapshot.state.clone());
+            windowed
+                .process_events(
+                    up_to_block
+                        .filter(|evt| evt.block_number() > snapshot_block)
+                        .cloned()
+                        .collect(),
+                )
+                .unwrap();
+
+            assert_eq!(windowed.state_hash(), full.state_hash());
+            assert_eq!(windowed.total_share_blocks(), full.total_share_blocks());
+            assert_eq!(
+                windowed.get_user_rewards(block),
+                full.get_user_rewards(block)
+            );
+        }
+
+        let before = snapshot.ensure_covers(snapshot_block - 1).unwrap_err();
+        assert!(before.to_string().contains("before the snapshot"));
+    }
+
+    #[test]
+    fn rejects_tampered_or_foreign_snapshots() {
+        let key = SigningKey::from_slice(&[7; 32]).unwrap();
+        let other = SigningKey::from_slice(&[8; 32]).unwrap();
+        let mut snapshot = snapshot_at(&history(), U64::from(BLOCK_CONTRACT_DEPLOYED + 100));
+
+        assert!(snapshot.verify(Some(key.verifying_key())).is_err());
+        snapshot.sign(&key).unwrap();
+        snapshot.verify(Some(key.verifying_key())).unwrap();
+        assert!(snapshot.verify(Some(other.verifying_key())).is_err());
+
+        let public_key = hex::encode(key.verifying_key().to_sec1_bytes());
+        assert_eq!(&parse_public_key(&public_key).unwrap(), key.verifying_key());
+
+        snapshot.chain_id = 5;
+        assert!(snapshot.verify(None).is_err());
+        snapshot.chain_id = 1;
+        snapshot.settings.emission = Emission::PerSecond(U256::one());
+        assert!(snapshot.verify(Some(key.verifying_key())).is_err());
+    }
+}
@@ -1,86 +1,1155 @@
+use crate::accumulators::{Accumulator, Checkpoint, Checkpoints};
+use crate::coalesce::{coalesce, CoalescedDelta};
+use crate::error::{ensure, Error, Result};
+use crate::fetch::decode_logs;
+use crate::flavor::VaultFlavor;
+use crate::lots::Maturity;
+use crate::observer::{InvariantWarning, RecordChange, StateObserver, UserDelta, UserView};
+use crate::quarantine::{QuarantineReason, QuarantinedEvent};
+use crate::timestamps::TimestampCache;
+use crate::top_ups::{TopUpReconciliation, TopUps};
+use crate::units::{RewardPerShare, Rewards, Shares};
 use ethers::{
-    core::types::{Address, U256, U64},
-    utils::parse_ether,
+    core::{
+        abi::{encode, Token},
+        types::{Address, Log, H256, U256, U64},
+    },
+    utils::{keccak256, parse_ether},
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use tiny_keccak::{Hasher, Keccak};
 
 pub const BLOCK_CONTRACT_DEPLOYED: u64 = 17564663;
 
-#[derive(Debug)]
+fn is_zero(index: &u64) -> bool {
+    *index == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Deposit {
     pub address: Address,
-    pub shares: U256,
+    pub shares: Shares,
     pub block_number: U64,
+    /// Position of the transaction in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub transaction_index: u64,
+    /// Position of the log in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub log_index: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Withdraw {
     pub address: Address,
-    pub shares: U256,
+    pub shares: Shares,
     pub block_number: U64,
+    /// Position of the transaction in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub transaction_index: u64,
+    /// Position of the log in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub log_index: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transfer {
     pub from: Address,
     pub to: Address,
-    pub shares: U256,
+    pub shares: Shares,
     pub block_number: U64,
+    /// Position of the transaction in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub transaction_index: u64,
+    /// Position of the log in its block
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub log_index: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum Event {
     Deposit(Deposit),
     Withdrawal(Withdraw),
     Transfer(Transfer),
 }
 
-#[derive(Debug)]
+impl Event {
+    pub fn block_number(&self) -> U64 {
+        match self {
+            Event::Deposit(e) => e.block_number,
+            Event::Withdrawal(e) => e.block_number,
+            Event::Transfer(e) => e.block_number,
+        }
+    }
+
+    /// Where the event's log sits on chain, `(block, transaction, log)`: the order events are
+    /// replayed in. Events from before the indices were kept sort by block alone.
+    pub fn position(&self) -> (U64, u64, u64) {
+        match self {
+            Event::Deposit(e) => (e.block_number, e.transaction_index, e.log_index),
+            Event::Withdrawal(e) => (e.block_number, e.transaction_index, e.log_index),
+            Event::Transfer(e) => (e.block_number, e.transaction_index, e.log_index),
+        }
+    }
+
+    pub fn shares(&self) -> Shares {
+        match self {
+            Event::Deposit(e) => e.shares,
+            Event::Withdrawal(e) => e.shares,
+            Event::Transfer(e) => e.shares,
+        }
+    }
+
+    pub fn involves(&self, address: Address) -> bool {
+        match self {
+            Event::Deposit(e) => e.address == address,
+            Event::Withdrawal(e) => e.address == address,
+            Event::Transfer(e) => e.from == address || e.to == address,
+        }
+    }
+}
+
+/// Every event where `address` is the owner, sender or receiver, in the order given. A linear
+/// scan, kept as the reference `EventIndex` is checked against.
+#[cfg(test)]
+pub fn events_involving(events: &[Event], address: Address) -> Vec<&Event> {
+    events.iter().filter(|evt| evt.involves(address)).collect()
+}
+
+/// Hash over the event stream, in order, using the same encoding as `state_hash`.
+pub fn events_hash(events: &[Event]) -> H256 {
+    let tokens: Vec<_> = events.iter().map(event_token).collect();
+    H256::from(keccak256(encode(&tokens)))
+}
+
+fn event_token(evt: &Event) -> Token {
+    let (kind, from, to) = match evt {
+        Event::Deposit(e) => (0u8, e.address, e.address),
+        Event::Withdrawal(e) => (1, e.address, e.address),
+        Event::Transfer(e) => (2, e.from, e.to),
+    };
+    Token::Tuple(vec![
+        Token::Uint(U256::from(kind)),
+        Token::Address(from),
+        Token::Address(to),
+        Token::Uint(evt.shares().0),
+        Token::Uint(U256::from(evt.block_number().as_u64())),
+    ])
+}
+
+/// `events_hash` of a stream fed one event at a time. Every event encodes to a static tuple,
+/// so the encoding of the stream is that of its events one after another.
+#[derive(Clone)]
+pub(crate) struct EventsHasher(Keccak);
+
+impl EventsHasher {
+    pub fn new() -> EventsHasher {
+        EventsHasher(Keccak::v256())
+    }
+
+    pub fn update(&mut self, evt: &Event) {
+        self.0.update(&encode(&[event_token(evt)]));
+    }
+
+    pub fn finish(self) -> H256 {
+        let mut hash = [0; 32];
+        self.0.finalize(&mut hash);
+        H256::from(hash)
+    }
+}
+
+/// Rewards emitted between two blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Emission {
+    /// The state's reward rate every block, one token unless set with `GlobalState::with_rate`
+    PerBlock,
+    /// This many wei every second, measured between block timestamps
+    PerSecond(U256),
+    /// The top-ups set with `GlobalState::set_top_ups`, streaming between block timestamps
+    TopUps,
+}
+
+/// When a block's emission accrues relative to the events in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum AccrualPolicy {
+    /// Once, before any of the block's events, to the shares staked before it, like the vault
+    /// computes it
+    #[default]
+    BlockStart,
+    /// Before each event. Events of a block refilling an empty pool then see the deposits
+    /// before them, so their order decides who gets the emission of the empty blocks
+    PerEvent,
+}
+
+impl AccrualPolicy {
+    /// The policy of replays from before policies existed, which snapshots and proofs without
+    /// one were taken with. They leave it out when serialized, so their hashes still hold.
+    pub(crate) fn legacy() -> AccrualPolicy {
+        AccrualPolicy::PerEvent
+    }
+
+    pub(crate) fn is_legacy(&self) -> bool {
+        *self == AccrualPolicy::legacy()
+    }
+}
+
+/// How shares the vault holds of itself, such as minted fees, are rewarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum SelfHeldShares {
+    /// Left out of user rewards, while still diluting everyone else like on-chain
+    Exclude,
+    /// Rewarded like any other holder
+    Include,
+    /// Left out of user rewards and reported on a separate protocol line
+    Separate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct UserRecord {
-    shares_staked: U256,
-    rewards_per_share_snapshot: U256,
-    rewards_accumulated: U256,
+    shares_staked: Shares,
+    rewards_per_share_snapshot: RewardPerShare,
+    rewards_accumulated: Rewards,
+    first_deposit_block: U64,
+    /// Block the shares last dropped to zero, `None` while the user holds any
+    exit_block: Option<U64>,
+    /// Shares times accounted blocks, up to `share_blocks_snapshot`
+    share_blocks: U256,
+    share_blocks_snapshot: U64,
+    /// Block of the last deposit, withdrawal or transfer touching the user, unknown for
+    /// records of snapshots taken before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_activity_block: Option<U64>,
+    /// Staked shares short of the minimum staking duration, which earn nothing yet
+    #[serde(default, skip_serializing_if = "Shares::is_zero")]
+    immature_shares: Shares,
+}
+
+impl UserRecord {
+    /// Staked shares that earn rewards.
+    fn eligible_shares(&self) -> Shares {
+        self.shares_staked - self.immature_shares
+    }
+
+    /// Share-blocks up to `last_accounted_block`, which the eligible shares held since the
+    /// snapshot.
+    fn share_blocks_at(&self, last_accounted_block: U64) -> U256 {
+        let blocks = (last_accounted_block - self.share_blocks_snapshot).as_u64();
+        self.share_blocks + self.eligible_shares().0 * blocks
+    }
 }
 
-#[derive(Debug)]
+/// The vault's accounting, replayed from its events.
+///
+/// ```
+/// use ethers::{core::types::{Address, U64}, utils::parse_ether};
+/// use oprtc_calculator::prelude::*;
+///
+/// let bob = Address::from_low_u64_be(0xb0b);
+/// let alice = Address::from_low_u64_be(0xa11ce);
+/// let one = parse_ether("1").unwrap();
+/// let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+///
+/// let mut state = GlobalState::new();
+/// state.process_events(vec![
+///     Event::Deposit(Deposit {
+///         address: bob,
+///         shares: Shares(one * 4),
+///         block_number: block(0),
+///         transaction_index: 0,
+///         log_index: 0,
+///     }),
+///     Event::Transfer(Transfer {
+///         from: bob,
+///         to: alice,
+///         shares: Shares(one),
+///         block_number: block(100),
+///         transaction_index: 0,
+///         log_index: 0,
+///     }),
+///     Event::Withdrawal(Withdraw {
+///         address: bob,
+///         shares: Shares(one * 3),
+///         block_number: block(400),
+///         transaction_index: 0,
+///         log_index: 0,
+///     }),
+/// ])
+/// .unwrap();
+///
+/// // one token a block: bob's alone for 100 blocks, split 3:1 for 300, then alice's alone
+/// assert_eq!(state.preview_user_rewards(bob, block(500)), one * 325);
+/// assert_eq!(state.preview_user_rewards(alice, block(500)), one * 175);
+/// assert_eq!(state.shares_of(bob), 0.into());
+/// assert_eq!(state.user_view(alice).unwrap().shares, one);
+/// ```
+#[derive(Debug, Clone)]
 pub struct GlobalState {
     user_records: HashMap<Address, UserRecord>,
-    total_shares_staked: U256,
-    total_rewards_per_share: U256,
+    total_shares_staked: Shares,
+    total_rewards_per_share: RewardPerShare,
+    total_share_blocks: U256,
     last_accounted_block: U64,
+    net_same_block: bool,
+    coalesce: bool,
+    coalesced: Vec<CoalescedDelta>,
+    coalesced_events: usize,
+    accrual_policy: AccrualPolicy,
+    /// Block whose emission accrued at its first event, under `AccrualPolicy::BlockStart`
+    accrued_block: Option<U64>,
+    record_updates: usize,
+    max_share_multiple: Option<u64>,
+    suspicious_events: Vec<Event>,
+    best_effort: bool,
+    /// Withdrawals of users never seen depositing start them from zero instead of failing
+    lenient: bool,
+    skipped_events: Vec<Event>,
+    quarantined: Vec<QuarantinedEvent>,
+    events_processed: usize,
+    checkpoint_interval: Option<usize>,
+    checkpoints: Vec<InvariantCheckpoint>,
+    self_held: Option<(Address, SelfHeldShares)>,
+    emission: Emission,
+    timestamps: TimestampCache,
+    top_ups: TopUps,
+    /// Emission credited to staked shares since `attributed_from`
+    emission_attributed: U256,
+    attributed_from: U64,
+    distributions: Option<Vec<Distribution>>,
+    /// Block emission starts from
+    deployed_block: U64,
+    /// Wei emitted every block under `Emission::PerBlock`
+    rewards_per_block: U256,
+    /// Lots short of the minimum staking duration, when there is one
+    maturity: Option<Maturity>,
+    total_immature: Shares,
+    /// Accumulators every so many blocks, when enabled
+    accumulators: Option<Checkpoints>,
+}
+
+/// The accumulators and user records a replay resumes from, without the events behind them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    user_records: BTreeMap<Address, UserRecord>,
+    total_shares_staked: Shares,
+    total_rewards_per_share: RewardPerShare,
+    total_share_blocks: U256,
+    last_accounted_block: U64,
+    /// Left out for the default deploy block, so older snapshots keep their hash
+    #[serde(
+        default = "default_deployed_block",
+        skip_serializing_if = "is_default_deployed_block"
+    )]
+    deployed_block: U64,
+    /// Left out for the default rate, so older snapshots keep their hash
+    #[serde(
+        default = "default_rewards_per_block",
+        skip_serializing_if = "is_default_rewards_per_block"
+    )]
+    rewards_per_block: U256,
+    /// Left out without a minimum staking duration, so older snapshots keep their hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    maturity: Option<Maturity>,
+    #[serde(default, skip_serializing_if = "Shares::is_zero")]
+    total_immature: Shares,
+    /// Left out without accumulator checkpoints, so older snapshots keep their hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accumulators: Option<Checkpoints>,
+}
+
+fn default_deployed_block() -> U64 {
+    U64::from(BLOCK_CONTRACT_DEPLOYED)
+}
+
+fn is_default_deployed_block(block_number: &U64) -> bool {
+    *block_number == default_deployed_block()
+}
+
+fn default_rewards_per_block() -> U256 {
+    parse_ether("1").unwrap()
+}
+
+fn is_default_rewards_per_block(rate: &U256) -> bool {
+    *rate == default_rewards_per_block()
+}
+
+/// The emission of an accounted interval `(from_block, to_block]` and how much of it the staked
+/// shares were credited with, short of it by rounding or entirely when the pool was empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Distribution {
+    pub from_block: U64,
+    pub to_block: U64,
+    pub emitted: U256,
+    pub attributed: U256,
+}
+
+/// Global accumulators right after the `events_processed`-th event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InvariantCheckpoint {
+    pub events_processed: usize,
+    pub block_number: U64,
+    pub total_shares_staked: U256,
+    pub total_rewards_per_share: U256,
+}
+
+/// What `GlobalState::ingest_logs` did with the logs it was given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IngestSummary {
+    pub logs: usize,
+    /// Logs given again with the transaction hash and log index of an earlier one
+    pub duplicates: usize,
+    /// Logs the flavor doesn't account for, such as transfers to the zero address
+    pub skipped: usize,
+    /// Logs that didn't decode, skipped in best-effort mode
+    pub undecodable: usize,
+    pub applied: usize,
+    /// Events skipped in best-effort mode for moving more shares than the sender holds
+    pub quarantined: usize,
+}
+
+/// An event the accounting can't apply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StateError {
+    /// A withdrawal or transfer out of more shares than `address` holds, as when a log was
+    /// decoded from the wrong word or events arrive out of order
+    InsufficientShares {
+        address: Address,
+        have: U256,
+        want: U256,
+        block: U64,
+    },
+    /// A withdrawal or transfer out of an address never seen depositing, as when the events
+    /// start past its deposit
+    UnknownUser(Address),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InsufficientShares {
+                address,
+                have,
+                want,
+                block,
+            } => write!(
+                f,
+                "{:?} moves {} shares at block {} but holds {} (--best-effort skips it)",
+                address, want, block, have
+            ),
+            StateError::UnknownUser(address) => write!(
+                f,
+                "{:?} moves shares it was never seen depositing (a lenient state starts it from \
+                 zero)",
+                address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl Default for GlobalState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GlobalState {
+    /// An empty state of the vault deployed at `BLOCK_CONTRACT_DEPLOYED`.
     pub fn new() -> GlobalState {
+        GlobalState::deployed_at(BLOCK_CONTRACT_DEPLOYED)
+    }
+
+    /// An empty state of a vault deployed at `block_number`, whose emission starts there.
+    pub fn deployed_at(block_number: u64) -> GlobalState {
+        let deployed_block = U64::from(block_number);
         GlobalState {
             user_records: HashMap::new(),
-            total_shares_staked: U256::from(0),
-            total_rewards_per_share: U256::from(0),
-            last_accounted_block: U64::from(BLOCK_CONTRACT_DEPLOYED),
+            total_shares_staked: Shares::default(),
+            total_rewards_per_share: RewardPerShare::default(),
+            total_share_blocks: U256::zero(),
+            last_accounted_block: deployed_block,
+            net_same_block: false,
+            coalesce: false,
+            coalesced: vec![],
+            coalesced_events: 0,
+            accrual_policy: AccrualPolicy::default(),
+            accrued_block: None,
+            record_updates: 0,
+            max_share_multiple: None,
+            suspicious_events: vec![],
+            best_effort: false,
+            lenient: false,
+            skipped_events: vec![],
+            quarantined: vec![],
+            events_processed: 0,
+            checkpoint_interval: None,
+            checkpoints: vec![],
+            self_held: None,
+            emission: Emission::PerBlock,
+            timestamps: TimestampCache::new(),
+            top_ups: TopUps::default(),
+            emission_attributed: U256::zero(),
+            attributed_from: deployed_block,
+            distributions: None,
+            deployed_block,
+            rewards_per_block: default_rewards_per_block(),
+            maturity: None,
+            total_immature: Shares::default(),
+            accumulators: None,
+        }
+    }
+
+    /// An empty state of the vault deployed at `BLOCK_CONTRACT_DEPLOYED` whose events may start
+    /// past some deposits: a withdrawal of a user never seen depositing takes shares held
+    /// before the first event, so it leaves them at zero and the totals untouched.
+    pub fn new_lenient() -> GlobalState {
+        GlobalState {
+            lenient: true,
+            ..GlobalState::new()
+        }
+    }
+
+    /// An empty state of the vault deployed at `BLOCK_CONTRACT_DEPLOYED`, emitting `rate` wei
+    /// every block instead of one token.
+    pub fn with_rate(rate: U256) -> GlobalState {
+        GlobalState {
+            rewards_per_block: rate,
+            ..GlobalState::new()
+        }
+    }
+
+    /// A state resuming from `snapshot`, with default settings. Processing the events after the
+    /// snapshot leaves it identical to a replay of the whole history.
+    pub fn resume(snapshot: StateSnapshot) -> GlobalState {
+        GlobalState {
+            user_records: snapshot.user_records.into_iter().collect(),
+            total_shares_staked: snapshot.total_shares_staked,
+            total_rewards_per_share: snapshot.total_rewards_per_share,
+            total_share_blocks: snapshot.total_share_blocks,
+            last_accounted_block: snapshot.last_accounted_block,
+            attributed_from: snapshot.last_accounted_block,
+            rewards_per_block: snapshot.rewards_per_block,
+            maturity: snapshot.maturity,
+            total_immature: snapshot.total_immature,
+            accumulators: snapshot.accumulators,
+            ..GlobalState::deployed_at(snapshot.deployed_block.as_u64())
+        }
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            user_records: self
+                .user_records
+                .iter()
+                .map(|(address, record)| (*address, record.clone()))
+                .collect(),
+            total_shares_staked: self.total_shares_staked,
+            total_rewards_per_share: self.total_rewards_per_share,
+            total_share_blocks: self.total_share_blocks,
+            last_accounted_block: self.last_accounted_block,
+            deployed_block: self.deployed_block,
+            rewards_per_block: self.rewards_per_block,
+            maturity: self.maturity.clone(),
+            total_immature: self.total_immature,
+            accumulators: self.accumulators.clone(),
+        }
+    }
+
+    /// When enabled, a deposit that is withdrawn share for share by the same address in the
+    /// same block is skipped instead of being applied and reverted, only settling the record as
+    /// the pair would. That leaves the same state under `AccrualPolicy::BlockStart` and is
+    /// ignored under `PerEvent`, where the deposit may refill an empty pool.
+    pub fn set_same_block_netting(&mut self, enabled: bool) {
+        self.net_same_block = enabled;
+    }
+
+    /// When enabled, the events of each block are replayed as one net delta per address they
+    /// touch, which leaves the same state under `AccrualPolicy::BlockStart` and is ignored under
+    /// `PerEvent`. Observers, checkpoints and suspicious share checks see the deltas.
+    pub fn set_coalescing(&mut self, enabled: bool) {
+        self.coalesce = enabled;
+    }
+
+    /// Holds shares out of the rewards until `blocks` blocks after they were received, each
+    /// deposit or transfer in maturing on its own. Shares leave oldest first, so only those
+    /// leaving before they matured forfeit, and emission while no shares are eligible goes
+    /// unattributed. To be set before processing. Coalescing and same-block netting skip
+    /// receipts, so they don't go with it.
+    pub fn set_min_staking_blocks(&mut self, blocks: Option<u64>) {
+        self.maturity = blocks.map(Maturity::new);
+    }
+
+    /// Blocks shares are held out of the rewards after they were received.
+    pub fn min_staking_blocks(&self) -> Option<u64> {
+        self.maturity.as_ref().map(Maturity::min_blocks)
+    }
+
+    /// Checkpoints the accumulators every `interval` blocks, for `accumulator_at` to look up.
+    /// To be set before processing. Per-second emission needs the timestamp of every multiple of
+    /// the interval the events span.
+    pub fn set_accumulator_interval(&mut self, interval: Option<u64>) {
+        self.accumulators = interval
+            .filter(|interval| *interval > 0)
+            .map(|interval| Checkpoints::new(interval, self.last_accounted_block));
+    }
+
+    /// Blocks between accumulator checkpoints.
+    pub fn accumulator_interval(&self) -> Option<u64> {
+        self.accumulators.as_ref().map(Checkpoints::interval)
+    }
+
+    /// The accumulators a preview at `block_number` would have given, once the state moved
+    /// past it: the checkpoint at the block, or the one before it previewed forward while no
+    /// event came between. `None` without checkpoints, before the first one, or for a block
+    /// the checkpoints can't preview exactly, which those of the interval's multiples always
+    /// can.
+    pub fn accumulator_at(&self, block_number: U64) -> Option<Accumulator> {
+        let checkpoints = self.accumulators.as_ref()?;
+        if block_number >= checkpoints.live_from() {
+            return Some(self.preview_accumulator(block_number));
+        }
+
+        let checkpoint = checkpoints.at_or_before(block_number)?;
+        if checkpoint.block_number == block_number {
+            return Some(checkpoint.accumulator());
+        }
+        // lots maturing in between change the shares without an event
+        if block_number >= checkpoint.next_event_block || self.maturity.is_some() {
+            return None;
+        }
+        let shares = Shares(checkpoint.total_shares_staked);
+        let (_, per_share) = self.distribution(checkpoint.accounted_block, block_number, shares);
+        Some(Accumulator {
+            block_number,
+            total_rewards_per_share: checkpoint.accounted_rewards_per_share + per_share.0,
+            total_shares_staked: shares.0,
+            emission_to_date: self.total_emission(block_number),
+        })
+    }
+
+    /// The accumulators as previewed at `block_number`, past the last event.
+    fn preview_accumulator(&self, block_number: U64) -> Accumulator {
+        let mut total_rewards_per_share = self.total_rewards_per_share;
+        let mut shares = self.eligible_shares();
+        for step in self.pending_steps(block_number) {
+            total_rewards_per_share += step.per_share;
+            for (_, matured) in step.matured {
+                shares += Shares(matured);
+            }
+        }
+        Accumulator {
+            block_number,
+            total_rewards_per_share: total_rewards_per_share.0,
+            total_shares_staked: shares.0,
+            emission_to_date: self.total_emission(block_number),
+        }
+    }
+
+    /// Checkpoints the multiples of the interval an event at `block_number` moves past.
+    fn checkpoint_before(&mut self, block_number: U64) {
+        let Some(checkpoints) = &self.accumulators else {
+            return;
+        };
+        let taken = checkpoints
+            .due_before(block_number)
+            .map(|checkpoint_block| {
+                let accumulator = self.preview_accumulator(checkpoint_block);
+                Checkpoint {
+                    block_number: checkpoint_block,
+                    total_rewards_per_share: accumulator.total_rewards_per_share,
+                    total_shares_staked: accumulator.total_shares_staked,
+                    emission_to_date: accumulator.emission_to_date,
+                    accounted_block: self.last_accounted_block,
+                    accounted_rewards_per_share: self.total_rewards_per_share.0,
+                    next_event_block: block_number,
+                }
+            })
+            .collect();
+        if let Some(checkpoints) = &mut self.accumulators {
+            checkpoints.record(taken, block_number);
+        }
+    }
+
+    /// The deltas replayed in place of several events, with how many each stands for.
+    pub fn coalesced(&self) -> &[CoalescedDelta] {
+        &self.coalesced
+    }
+
+    /// Number of events replaced by the `coalesced` deltas.
+    pub fn coalesced_events(&self) -> usize {
+        self.coalesced_events
+    }
+
+    pub fn set_accrual_policy(&mut self, policy: AccrualPolicy) {
+        self.accrual_policy = policy;
+    }
+
+    /// Flags events moving more than `multiple` times the shares staked before them, which
+    /// usually means the amount was decoded from the wrong word.
+    pub fn set_max_share_multiple(&mut self, multiple: Option<u64>) {
+        self.max_share_multiple = multiple;
+    }
+
+    /// Events flagged by the `set_max_share_multiple` check, in processing order.
+    pub fn suspicious_events(&self) -> &[Event] {
+        &self.suspicious_events
+    }
+
+    /// When enabled, withdrawals and transfers moving more shares than the sender holds, such
+    /// as those of users never seen depositing, are skipped instead of failing the replay.
+    pub fn set_best_effort(&mut self, enabled: bool) {
+        self.best_effort = enabled;
+    }
+
+    /// Events skipped in best-effort mode, in processing order.
+    pub fn skipped_events(&self) -> &[Event] {
+        &self.skipped_events
+    }
+
+    /// The skipped events with why each was skipped.
+    pub fn quarantined(&self) -> &[QuarantinedEvent] {
+        &self.quarantined
+    }
+
+    fn quarantine(&mut self, event: Event, observer: &mut dyn StateObserver) {
+        let sender = match &event {
+            Event::Deposit(deposit) => deposit.address,
+            Event::Withdrawal(withdraw) => withdraw.address,
+            Event::Transfer(transfer) => transfer.from,
+        };
+        let reason = if self.user_records.contains_key(&sender) {
+            QuarantineReason::InsufficientShares
+        } else {
+            QuarantineReason::UnknownSender
+        };
+        let quarantined = QuarantinedEvent {
+            event: event.clone(),
+            reason,
+            held: self.shares_of(sender),
+        };
+        observer.on_invariant_warning(&InvariantWarning::Quarantined(quarantined.clone()));
+        self.quarantined.push(quarantined);
+        self.skipped_events.push(event);
+    }
+
+    fn overdraws(&self, evt: &Event) -> bool {
+        let (sender, shares) = match evt {
+            Event::Deposit(_) => return false,
+            Event::Withdrawal(withdraw) => (withdraw.address, withdraw.shares),
+            Event::Transfer(transfer) => (transfer.from, transfer.shares),
+        };
+        // a lenient state takes the shares of unknown senders as held before the first event,
+        // a strict one fails on them even when they move no shares
+        if !self.user_records.contains_key(&sender) {
+            return !self.lenient;
         }
+        self.shares_of(sender) < shares.0
+    }
+
+    /// Records an `InvariantCheckpoint` after every `interval` events.
+    pub fn set_checkpoint_interval(&mut self, interval: Option<usize>) {
+        self.checkpoint_interval = interval.filter(|interval| *interval > 0);
+    }
+
+    pub fn checkpoints(&self) -> &[InvariantCheckpoint] {
+        &self.checkpoints
+    }
+
+    /// Records a `Distribution` for every accounted interval, to be enabled before processing.
+    pub fn set_distribution_tracking(&mut self, enabled: bool) {
+        self.distributions = enabled.then(Vec::new);
+    }
+
+    /// Accounted intervals in order, empty unless tracking is enabled.
+    pub fn distributions(&self) -> &[Distribution] {
+        self.distributions.as_deref().unwrap_or_default()
+    }
+
+    /// The interval rewards previewed at `block_number` add past the last accounted block,
+    /// unattributed while the pool is empty.
+    pub fn pending_distribution(&self, block_number: U64) -> Distribution {
+        self.pending_steps(block_number).into_iter().fold(
+            Distribution {
+                from_block: self.last_accounted_block,
+                to_block: self.last_accounted_block,
+                emitted: U256::zero(),
+                attributed: U256::zero(),
+            },
+            |pending, step| Distribution {
+                to_block: step.distribution.to_block,
+                emitted: pending.emitted + step.distribution.emitted,
+                attributed: pending.attributed + step.distribution.attributed,
+                ..pending
+            },
+        )
     }
 
-    pub fn process_events(&mut self, evts: Vec<Event>) {
-        for evt in evts.into_iter() {
-            match evt {
-                Event::Deposit(deposit) => self.process_deposit(deposit),
-                Event::Withdrawal(withdrawal) => self.process_withdraw(withdrawal),
-                Event::Transfer(transfer) => self.process_transfer(transfer),
+    /// Emission and attribution over every block accounted by this state up to `block_number`:
+    /// since deployment, or since the snapshot it resumed from.
+    pub fn total_distribution(&self, block_number: U64) -> Distribution {
+        let pending = self.pending_distribution(block_number);
+        Distribution {
+            from_block: self.attributed_from,
+            to_block: pending.to_block,
+            emitted: self.emission_between(self.attributed_from, pending.to_block),
+            attributed: self.emission_attributed + pending.attributed,
+        }
+    }
+
+    /// Applies `policy` to the shares `vault` holds of itself. Its record is kept either way, so
+    /// recorded balances still reconcile with the chain.
+    pub fn set_self_held_shares(&mut self, vault: Address, policy: SelfHeldShares) {
+        self.self_held = Some((vault, policy));
+    }
+
+    pub(crate) fn is_payee(&self, address: Address) -> bool {
+        match self.self_held {
+            Some((vault, policy)) => vault != address || policy == SelfHeldShares::Include,
+            None => true,
+        }
+    }
+
+    /// Rewards accrued by the vault's own shares when they are left out of user rewards.
+    pub fn protocol_rewards(&self, block_number: U64) -> U256 {
+        match self.self_held {
+            Some((vault, policy)) if policy != SelfHeldShares::Include => {
+                self.preview_user_rewards(vault, block_number)
             }
+            _ => U256::zero(),
         }
     }
 
-    fn process_deposit(&mut self, deposit: Deposit) {
-        self.distribute_rewards(deposit.block_number);
+    /// Switches the emission schedule. Per-second and top-up emission need the timestamp of
+    /// every event block, of the deploy block and of every block rewards are previewed at.
+    ///
+    /// ```
+    /// use ethers::{core::types::{Address, U256, U64}, utils::parse_ether};
+    /// use oprtc_calculator::prelude::*;
+    ///
+    /// let bob = Address::from_low_u64_be(0xb0b);
+    /// let deployed = U64::from(BLOCK_CONTRACT_DEPLOYED);
+    /// let later = deployed + 10;
+    ///
+    /// let mut timestamps = TimestampCache::new();
+    /// timestamps.insert(deployed, 1_686_000_000);
+    /// timestamps.insert(later, 1_686_000_000 + 120);
+    ///
+    /// let mut state = GlobalState::new();
+    /// // 0.001 tokens a second
+    /// let rate = parse_ether("0.001").unwrap();
+    /// state.set_emission(Emission::PerSecond(rate), timestamps);
+    /// state.process_events(vec![Event::Deposit(Deposit {
+    ///     address: bob,
+    ///     shares: Shares(parse_ether("1").unwrap()),
+    ///     block_number: deployed,
+    ///     transaction_index: 0,
+    ///     log_index: 0,
+    /// })])
+    /// .unwrap();
+    ///
+    /// assert_eq!(state.total_emission(later), rate * U256::from(120));
+    /// assert_eq!(state.preview_user_rewards(bob, later), parse_ether("0.12").unwrap());
+    /// ```
+    pub fn set_emission(&mut self, emission: Emission, timestamps: TimestampCache) {
+        self.emission = emission;
+        self.timestamps = timestamps;
+    }
+
+    /// Streams of `Emission::TopUps`, whose blocks need their timestamps too.
+    pub fn set_top_ups(&mut self, top_ups: TopUps) {
+        self.top_ups = top_ups;
+    }
+
+    /// The top-ups added by `block_number` against the rewards attributed from them, under
+    /// `Emission::TopUps` only.
+    pub fn top_up_reconciliation(&self, block_number: U64) -> Option<TopUpReconciliation> {
+        if self.emission != Emission::TopUps {
+            return None;
+        }
+        let distribution = self.total_distribution(block_number);
+        let timestamp = self.timestamp(distribution.to_block);
+        let added = self.top_ups.added_by(timestamp);
+        Some(TopUpReconciliation {
+            from_block: distribution.from_block,
+            to_block: distribution.to_block,
+            added,
+            streaming: added - self.top_ups.streamed_by(timestamp),
+            streamed: distribution.emitted,
+            attributed: distribution.attributed,
+        })
+    }
+
+    fn timestamp(&self, block_number: U64) -> u64 {
+        self.timestamps
+            .get(block_number)
+            .unwrap_or_else(|| panic!("timestamp of block {} should be known", block_number))
+    }
+
+    /// Rewards emitted over `(from_block, to_block]`, none when the interval is empty.
+    fn emission_between(&self, from_block: U64, to_block: U64) -> U256 {
+        if to_block <= from_block {
+            return U256::zero();
+        }
+        match self.emission {
+            Emission::PerBlock => {
+                U256::from((to_block - from_block).as_u64()) * self.rewards_per_block
+            }
+            Emission::PerSecond(rate) => {
+                U256::from(self.timestamp(to_block) - self.timestamp(from_block)) * rate
+            }
+            Emission::TopUps => {
+                self.top_ups.streamed_by(self.timestamp(to_block))
+                    - self.top_ups.streamed_by(self.timestamp(from_block))
+            }
+        }
+    }
+
+    /// Rewards emitted from the deploy block up to `block_number`.
+    pub fn total_emission(&self, block_number: U64) -> U256 {
+        self.emission_between(self.deployed_block, block_number)
+    }
+
+    /// Block the vault was deployed at, from which rewards are emitted.
+    pub fn deployed_block(&self) -> U64 {
+        self.deployed_block
+    }
+
+    /// Wei emitted every block under `Emission::PerBlock`.
+    pub fn rewards_per_block(&self) -> U256 {
+        self.rewards_per_block
+    }
+
+    /// Number of user record writes performed so far.
+    pub fn record_updates(&self) -> usize {
+        self.record_updates
+    }
+
+    /// Block up to which emissions have been folded into the accumulators. Rewards previewed at
+    /// this block carry no pending term.
+    pub fn last_accounted_block(&self) -> U64 {
+        self.last_accounted_block
+    }
+
+    /// Applies `evts` in order. Unless best effort is on, an event moving more shares than its
+    /// sender holds stops the replay with an accounting error, leaving the events before it
+    /// applied.
+    pub fn process_events(&mut self, evts: Vec<Event>) -> Result<(), StateError> {
+        self.process_events_with(evts, &mut ())
+    }
+
+    /// Like `process_events`, with the crate's error type.
+    pub fn try_process_events(&mut self, evts: Vec<Event>) -> Result<()> {
+        Ok(self.process_events(evts)?)
+    }
+
+    /// Decodes the vault's raw logs with `flavor` and applies the events, in one call. The logs
+    /// are deduplicated on transaction hash and log index, where they have both, and sorted by
+    /// block, transaction and log index. They must all come from one contract, the
+    /// vault, since the flavor can't tell a vault's transfer from another token's.
+    ///
+    /// Unless best effort is on, an undecodable log fails the call before any event is applied.
+    pub fn ingest_logs(&mut self, logs: &[Log], flavor: &VaultFlavor) -> Result<IngestSummary> {
+        if let Some(other) = logs.iter().find(|log| log.address != logs[0].address) {
+            return Err(Error::decode(format!(
+                "logs of {:?} and {:?} given together, ingest only the vault's",
+                logs[0].address, other.address
+            )));
+        }
+
+        let mut summary = IngestSummary {
+            logs: logs.len(),
+            ..IngestSummary::default()
+        };
+        let mut seen = HashMap::new();
+        let mut unique = vec![];
+        for log in logs {
+            let (Some(transaction_hash), Some(log_index)) = (log.transaction_hash, log.log_index)
+            else {
+                unique.push(log.clone());
+                continue;
+            };
+            match seen.get(&(transaction_hash, log_index)) {
+                Some(first) => {
+                    ensure!(
+                        *first == log,
+                        Error::decode,
+                        "log {} of {:?} is given twice with different contents",
+                        log_index,
+                        transaction_hash
+                    );
+                    summary.duplicates += 1;
+                }
+                None => {
+                    seen.insert((transaction_hash, log_index), log);
+                    unique.push(log.clone());
+                }
+            }
+        }
+
+        let decoded = decode_logs(flavor, &unique);
+        summary.undecodable = decoded.undecodable;
+        summary.skipped = unique.len() - decoded.undecodable - decoded.events.len();
+        let events = if self.best_effort {
+            decoded.events
+        } else {
+            decoded.strict()?
+        };
+
+        let quarantined = self.quarantined.len();
+        let count = events.len();
+        self.try_process_events(events)?;
+        summary.quarantined = self.quarantined.len() - quarantined;
+        summary.applied = count - summary.quarantined;
+
+        Ok(summary)
+    }
+
+    /// Like `try_process_events`, telling `observer` about every step.
+    pub fn try_process_events_with(
+        &mut self,
+        evts: Vec<Event>,
+        observer: &mut impl StateObserver,
+    ) -> Result<()> {
+        Ok(self.process_events_with(evts, observer)?)
+    }
+
+    /// Like `process_events`, telling `observer` about every step.
+    pub fn process_events_with(
+        &mut self,
+        evts: Vec<Event>,
+        observer: &mut impl StateObserver,
+    ) -> Result<(), StateError> {
+        let evts = if self.coalesce && self.accrual_policy == AccrualPolicy::BlockStart {
+            let coalesced = coalesce(evts, |address| {
+                self.user_records
+                    .get(&address)
+                    .map(|record| record.shares_staked)
+            });
+            self.coalesced.extend(coalesced.deltas);
+            self.coalesced_events += coalesced.replaced;
+            coalesced.events
+        } else {
+            evts
+        };
+        let netted = if self.net_same_block && self.accrual_policy == AccrualPolicy::BlockStart {
+            same_block_pairs(&evts)
+        } else {
+            HashSet::new()
+        };
+
+        for (i, evt) in evts.into_iter().enumerate() {
+            if let Some(multiple) = self.max_share_multiple {
+                let total_shares = self.total_shares_staked.0;
+                if !total_shares.is_zero() && evt.shares().0 > total_shares * multiple {
+                    self.suspicious_events.push(evt.clone());
+                    observer.on_invariant_warning(&InvariantWarning::SuspiciousShares(evt.clone()));
+                }
+            }
+
+            let block_number = evt.block_number();
+
+            if netted.contains(&i) {
+                // still accrue up to this block so rounding matches sequential processing
+                self.accrue_for_event(block_number, observer)?;
+                if let Event::Deposit(deposit) = &evt {
+                    self.settle_netted(deposit.address, block_number);
+                }
+            } else if self.best_effort && self.overdraws(&evt) {
+                self.quarantine(evt, observer);
+            } else {
+                let touched = match &evt {
+                    Event::Deposit(deposit) => vec![deposit.address],
+                    Event::Withdrawal(withdraw) => vec![withdraw.address],
+                    Event::Transfer(transfer) => vec![transfer.from, transfer.to],
+                };
+                let before: Vec<_> = touched
+                    .iter()
+                    .map(|address| self.user_view(*address))
+                    .collect();
+
+                match evt.clone() {
+                    Event::Deposit(deposit) => self.process_deposit(deposit, observer)?,
+                    Event::Withdrawal(withdrawal) => self.process_withdraw(withdrawal, observer)?,
+                    Event::Transfer(transfer) => self.process_transfer(transfer, observer)?,
+                }
+
+                let changes = touched
+                    .into_iter()
+                    .zip(before)
+                    .map(|(address, before)| RecordChange {
+                        address,
+                        before,
+                        after: self.user_view(address).unwrap_or_default(),
+                    })
+                    .collect();
+                observer.on_event_applied(&evt, &UserDelta { changes });
+            }
+
+            self.events_processed += 1;
+
+            if let Some(interval) = self.checkpoint_interval {
+                if self.events_processed.is_multiple_of(interval) {
+                    self.checkpoints.push(InvariantCheckpoint {
+                        events_processed: self.events_processed,
+                        block_number,
+                        total_shares_staked: self.total_shares_staked.0,
+                        total_rewards_per_share: self.total_rewards_per_share.0,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The shares and settled rewards of `address`'s record, `None` before its first event.
+    pub fn user_view(&self, address: Address) -> Option<UserView> {
+        self.user_records.get(&address).map(|record| UserView {
+            shares: record.shares_staked.0,
+            rewards_accumulated: record.rewards_accumulated.to_wei().0,
+            rewards_per_share_snapshot: record.rewards_per_share_snapshot.0,
+        })
+    }
+
+    fn process_deposit(
+        &mut self,
+        deposit: Deposit,
+        observer: &mut dyn StateObserver,
+    ) -> Result<(), StateError> {
+        self.accrue_for_event(deposit.block_number, observer)?;
+        self.record_updates += 1;
+
+        let immature = match &mut self.maturity {
+            Some(maturity) => {
+                maturity.receive(deposit.address, deposit.shares.0, deposit.block_number);
+                deposit.shares
+            }
+            None => Shares::default(),
+        };
 
         if let Some(user) = self.user_records.get(&deposit.address) {
             let accrued_rewards = (self.total_rewards_per_share - user.rewards_per_share_snapshot)
-                * user.shares_staked;
+                * user.eligible_shares();
 
             let user_record = UserRecord {
                 shares_staked: user.shares_staked + deposit.shares,
                 rewards_accumulated: user.rewards_accumulated + accrued_rewards,
                 rewards_per_share_snapshot: self.total_rewards_per_share,
+                first_deposit_block: user.first_deposit_block,
+                exit_block: None,
+                share_blocks: user.share_blocks_at(self.last_accounted_block),
+                share_blocks_snapshot: self.last_accounted_block,
+                last_activity_block: Some(deposit.block_number),
+                immature_shares: user.immature_shares + immature,
             };
 
             self.user_records.insert(deposit.address, user_record);
@@ -89,49 +1158,151 @@ impl GlobalState {
                 deposit.address,
                 UserRecord {
                     shares_staked: deposit.shares,
-                    rewards_accumulated: U256::from(0),
+                    rewards_accumulated: Rewards::default(),
                     rewards_per_share_snapshot: self.total_rewards_per_share,
+                    first_deposit_block: deposit.block_number,
+                    exit_block: None,
+                    share_blocks: U256::zero(),
+                    share_blocks_snapshot: self.last_accounted_block,
+                    last_activity_block: Some(deposit.block_number),
+                    immature_shares: immature,
                 },
             );
         }
 
         self.total_shares_staked += deposit.shares;
+        self.total_immature += immature;
+        Ok(())
     }
 
-    fn process_withdraw(&mut self, withdraw: Withdraw) {
-        self.distribute_rewards(withdraw.block_number);
+    /// A record without shares, created and left at `block_number`.
+    fn empty_record(&self, block_number: U64) -> UserRecord {
+        UserRecord {
+            shares_staked: Shares::default(),
+            rewards_accumulated: Rewards::default(),
+            rewards_per_share_snapshot: self.total_rewards_per_share,
+            first_deposit_block: block_number,
+            exit_block: Some(block_number),
+            share_blocks: U256::zero(),
+            share_blocks_snapshot: self.last_accounted_block,
+            last_activity_block: Some(block_number),
+            immature_shares: Shares::default(),
+        }
+    }
+
+    /// Leaves the record of `address` as a netted deposit and withdrawal at `block_number`
+    /// would: settled, active at the block, and created there if it is new.
+    fn settle_netted(&mut self, address: Address, block_number: U64) {
+        self.record_updates += 1;
+        let Some(user_record) = self.user_records.get_mut(&address) else {
+            let record = self.empty_record(block_number);
+            self.user_records.insert(address, record);
+            return;
+        };
+
+        let rewards_accumulated = (self.total_rewards_per_share
+            - user_record.rewards_per_share_snapshot)
+            * user_record.eligible_shares();
+        user_record.rewards_accumulated += rewards_accumulated;
+        user_record.share_blocks = user_record.share_blocks_at(self.last_accounted_block);
+        user_record.share_blocks_snapshot = self.last_accounted_block;
+        user_record.rewards_per_share_snapshot = self.total_rewards_per_share;
+        user_record.last_activity_block = Some(block_number);
+        if user_record.shares_staked.is_zero() {
+            user_record.exit_block = Some(block_number);
+        }
+    }
+
+    /// Fails without touching the state when `withdraw` takes more shares than its sender holds,
+    /// or comes from a sender never seen depositing unless the state is lenient.
+    fn process_withdraw(
+        &mut self,
+        withdraw: Withdraw,
+        observer: &mut dyn StateObserver,
+    ) -> Result<(), StateError> {
+        if !self.user_records.contains_key(&withdraw.address) {
+            if !self.lenient {
+                return Err(StateError::UnknownUser(withdraw.address));
+            }
+            self.accrue_for_event(withdraw.block_number, observer)?;
+            self.record_updates += 1;
+            self.user_records
+                .insert(withdraw.address, self.empty_record(withdraw.block_number));
+            return Ok(());
+        }
+
+        let have = self.shares_of(withdraw.address);
+        if have < withdraw.shares.0 {
+            return Err(StateError::InsufficientShares {
+                address: withdraw.address,
+                have,
+                want: withdraw.shares.0,
+                block: withdraw.block_number,
+            });
+        }
+
+        self.accrue_for_event(withdraw.block_number, observer)?;
+        self.record_updates += 1;
 
         let user_record = self
             .user_records
             .get_mut(&withdraw.address)
-            .expect("user should exist");
+            .expect("checked above");
 
         let rewards_accumulated = (self.total_rewards_per_share
             - user_record.rewards_per_share_snapshot)
-            * user_record.shares_staked;
+            * user_record.eligible_shares();
+        // matured shares are the oldest, so they leave first
+        let forfeited = Shares(
+            withdraw
+                .shares
+                .0
+                .saturating_sub(user_record.eligible_shares().0),
+        );
 
         user_record.rewards_accumulated += rewards_accumulated;
+        user_record.share_blocks = user_record.share_blocks_at(self.last_accounted_block);
+        user_record.share_blocks_snapshot = self.last_accounted_block;
         user_record.shares_staked -= withdraw.shares;
+        user_record.immature_shares -= forfeited;
         user_record.rewards_per_share_snapshot = self.total_rewards_per_share;
+        user_record.last_activity_block = Some(withdraw.block_number);
+        if user_record.shares_staked.is_zero() {
+            user_record.exit_block = Some(withdraw.block_number);
+        }
 
         self.total_shares_staked -= withdraw.shares;
+        self.total_immature -= forfeited;
+        if let Some(maturity) = &mut self.maturity {
+            maturity.forfeit(withdraw.address, forfeited.0);
+        }
+
+        Ok(())
     }
 
-    fn process_transfer(&mut self, transfer: Transfer) {
+    fn process_transfer(
+        &mut self,
+        transfer: Transfer,
+        observer: &mut dyn StateObserver,
+    ) -> Result<(), StateError> {
         let withdrawal = Withdraw {
             address: transfer.from,
             shares: transfer.shares,
             block_number: transfer.block_number,
+            transaction_index: transfer.transaction_index,
+            log_index: transfer.log_index,
         };
 
         let deposit = Deposit {
             address: transfer.to,
             shares: transfer.shares,
             block_number: transfer.block_number,
+            transaction_index: transfer.transaction_index,
+            log_index: transfer.log_index,
         };
 
-        self.process_withdraw(withdrawal);
-        self.process_deposit(deposit);
+        self.process_withdraw(withdrawal, observer)?;
+        self.process_deposit(deposit, observer)
     }
 
     pub fn preview_user_rewards(&self, user: Address, block_number: U64) -> U256 {
@@ -142,34 +1313,124 @@ impl GlobalState {
         }
 
         let user_record = user_record.unwrap();
+        let mut rewards_accumulated = user_record.rewards_accumulated;
+        let mut rewards_per_share_snapshot = user_record.rewards_per_share_snapshot;
+        let mut eligible_shares = user_record.eligible_shares();
+        let mut total_rewards_per_share = self.total_rewards_per_share;
 
-        if self.total_shares_staked.is_zero() {
-            let accrued_rewards = (self.total_rewards_per_share
-                - user_record.rewards_per_share_snapshot)
-                * user_record.shares_staked;
-            let unclaimed_rewards = user_record.rewards_accumulated;
-            return (accrued_rewards + unclaimed_rewards) / parse_ether("1").unwrap();
+        for step in self.pending_steps(block_number) {
+            total_rewards_per_share += step.per_share;
+            for (address, shares) in step.matured {
+                if address == user {
+                    rewards_accumulated +=
+                        (total_rewards_per_share - rewards_per_share_snapshot) * eligible_shares;
+                    rewards_per_share_snapshot = total_rewards_per_share;
+                    eligible_shares += Shares(shares);
+                }
+            }
         }
 
-        let rewards_per_block = parse_ether("1").unwrap();
+        let user_rewards = (total_rewards_per_share - rewards_per_share_snapshot) * eligible_shares;
+        (user_rewards + rewards_accumulated).to_wei().0
+    }
+
+    pub fn total_shares_staked(&self) -> U256 {
+        self.total_shares_staked.0
+    }
 
-        let pending_rewards =
-            U256::from((block_number - self.last_accounted_block).as_u64()) * rewards_per_block;
+    /// The rewards accumulator, scaled by 1e18.
+    pub fn total_rewards_per_share(&self) -> U256 {
+        self.total_rewards_per_share.0
+    }
 
-        // increased by 1e18
-        let pending_rewards_per_share_staked =
-            pending_rewards * parse_ether("1").unwrap() / self.total_shares_staked;
+    pub fn users(&self) -> impl Iterator<Item = &Address> {
+        self.user_records.keys()
+    }
 
-        let user_rewards = (self.total_rewards_per_share + pending_rewards_per_share_staked
-            - user_record.rewards_per_share_snapshot)
-            * user_record.shares_staked;
+    pub fn shares_of(&self, user: Address) -> U256 {
+        self.user_records
+            .get(&user)
+            .map(|record| record.shares_staked.0)
+            .unwrap_or_default()
+    }
+
+    /// Block at which `user` first received shares, either by deposit or by transfer.
+    pub fn first_deposit_block(&self, user: Address) -> Option<U64> {
+        self.user_records
+            .get(&user)
+            .map(|record| record.first_deposit_block)
+    }
+
+    /// Block of the last deposit, withdrawal or transfer touching `user`, `None` for unknown
+    /// users and for records resumed from snapshots that didn't keep it.
+    pub fn last_activity_block(&self, user: Address) -> Option<U64> {
+        self.user_records
+            .get(&user)
+            .and_then(|record| record.last_activity_block)
+    }
+
+    /// Blocks from the first share receipt of `user` to the last block they held shares, the
+    /// last accounted block while they still do. Gaps after a full exit and re-entry are
+    /// inside the span.
+    pub fn user_active_span(&self, user: Address) -> Option<(U64, U64)> {
+        self.user_records.get(&user).map(|record| {
+            (
+                record.first_deposit_block,
+                record.exit_block.unwrap_or(self.last_accounted_block),
+            )
+        })
+    }
+
+    /// Staked shares times blocks, summed over the accounted blocks: the denominator rewards
+    /// are split by, counting only matured shares under a minimum staking duration. Blocks of
+    /// an empty pool are weighed with the shares that end the gap, like their emissions. While
+    /// the total is constant, a user's rewards under per-block emission are
+    /// `total_emission × user_share_blocks / total_share_blocks`.
+    pub fn total_share_blocks(&self) -> U256 {
+        self.total_share_blocks
+    }
+
+    /// Share-blocks of `user` over the accounted blocks, the numerator matching
+    /// `total_share_blocks`.
+    #[cfg(test)]
+    pub fn user_share_blocks(&self, user: Address) -> U256 {
+        self.user_records
+            .get(&user)
+            .map(|record| record.share_blocks_at(self.last_accounted_block))
+            .unwrap_or_default()
+    }
+
+    /// Hash over the global accumulators and every user record, independent of map order.
+    pub fn state_hash(&self) -> H256 {
+        let mut tokens = vec![
+            Token::Uint(self.total_shares_staked.0),
+            Token::Uint(self.total_rewards_per_share.0),
+            Token::Uint(U256::from(self.last_accounted_block.as_u64())),
+        ];
 
-        (user_rewards + user_record.rewards_accumulated) / parse_ether("1").unwrap()
+        let mut addresses: Vec<_> = self.user_records.keys().collect();
+        addresses.sort();
+
+        for address in addresses {
+            let record = &self.user_records[address];
+            tokens.extend([
+                Token::Address(*address),
+                Token::Uint(record.shares_staked.0),
+                Token::Uint(record.rewards_per_share_snapshot.0),
+                Token::Uint(record.rewards_accumulated.0),
+                Token::Uint(U256::from(record.first_deposit_block.as_u64())),
+            ]);
+        }
+
+        H256::from(keccak256(encode(&tokens)))
     }
 
     pub fn get_all_rewards(&self, block_number: U64) -> U256 {
         let mut rewards = U256::from(0);
         for address in self.user_records.keys() {
+            if !self.is_payee(*address) {
+                continue;
+            }
             let reward = self.preview_user_rewards(*address, block_number);
             rewards += reward;
         }
@@ -180,6 +1441,7 @@ impl GlobalState {
         let mut records: Vec<_> = self
             .user_records
             .keys()
+            .filter(|addr| self.is_payee(**addr))
             .map(|addr| {
                 let rewards = self.preview_user_rewards(*addr, block_number);
                 (*addr, rewards)
@@ -187,29 +1449,216 @@ impl GlobalState {
             .filter(|(_, r)| !r.is_zero())
             .collect();
 
-        records.sort_by_key(|&(_, num)| std::cmp::Reverse(num));
+        // ties ordered by address so the order doesn't depend on the map
+        records.sort_by_key(|&(addr, num)| (std::cmp::Reverse(num), addr));
 
         records
     }
 
-    fn distribute_rewards(&mut self, block_number: U64) {
-        if self.last_accounted_block >= block_number || self.total_shares_staked == U256::from(0) {
-            return;
+    /// Accrues before applying an event of `block_number`. With a staked pool only the block's
+    /// first event distributes either way, the rest find the block accounted. They differ on
+    /// a block refilling an empty pool, where per-event accrual distributes again as soon as
+    /// an event of the block has staked shares.
+    fn accrue_for_event(
+        &mut self,
+        block_number: U64,
+        observer: &mut dyn StateObserver,
+    ) -> Result<(), StateError> {
+        self.checkpoint_before(block_number);
+        match self.accrual_policy {
+            AccrualPolicy::BlockStart if self.accrued_block == Some(block_number) => {}
+            AccrualPolicy::BlockStart => {
+                self.distribute_rewards(block_number, observer)?;
+                self.accrued_block = Some(block_number);
+            }
+            AccrualPolicy::PerEvent => self.distribute_rewards(block_number, observer)?,
         }
+        Ok(())
+    }
 
-        let blocks_transcurred = U256::from((block_number - self.last_accounted_block).as_u64());
-        let rewards_per_block = parse_ether("1").unwrap();
+    /// Folds the emissions of `(last_accounted_block, block_number]` into the accumulator,
+    /// stopping at every block a lot matures at to let it earn from the next one.
+    fn distribute_rewards(
+        &mut self,
+        block_number: U64,
+        observer: &mut dyn StateObserver,
+    ) -> Result<(), StateError> {
+        while let Some(due) = self
+            .maturity
+            .as_ref()
+            .and_then(|maturity| maturity.next_due(block_number))
+        {
+            self.distribute_until(due, observer);
+            self.mature(due)?;
+        }
+        self.distribute_until(block_number, observer);
+        Ok(())
+    }
 
-        let pending_rewards = blocks_transcurred * rewards_per_block;
+    /// Folds the emissions of `(last_accounted_block, block_number]` into the accumulator. An
+    /// event at the deploy block, where accounting starts, has no prior blocks to distribute;
+    /// its shares earn from the next block on. Blocks of an empty pool wait for the shares
+    /// ending the gap, unless a minimum staking duration keeps those out.
+    fn distribute_until(&mut self, block_number: U64, observer: &mut dyn StateObserver) {
+        if self.last_accounted_block >= block_number
+            || (self.maturity.is_none() && self.total_shares_staked.is_zero())
+        {
+            return;
+        }
 
-        let pending_rewards_per_share =
-            pending_rewards * parse_ether("1").unwrap() / self.total_shares_staked;
+        let (distribution, pending_rewards_per_share) = self.distribution(
+            self.last_accounted_block,
+            block_number,
+            self.eligible_shares(),
+        );
+        self.emission_attributed += distribution.attributed;
+        if let Some(distributions) = &mut self.distributions {
+            distributions.push(distribution);
+        }
 
+        self.total_share_blocks +=
+            self.eligible_shares().0 * (block_number - self.last_accounted_block).as_u64();
         self.last_accounted_block = block_number;
         self.total_rewards_per_share += pending_rewards_per_share;
+        observer.on_rewards_distributed(
+            block_number,
+            distribution.emitted,
+            pending_rewards_per_share.0,
+        );
+    }
+
+    /// Staked shares that earn rewards, all of them without a minimum staking duration.
+    fn eligible_shares(&self) -> Shares {
+        self.total_shares_staked - self.total_immature
+    }
+
+    /// The emission of `(from_block, block_number]` and its increase per share when spread over
+    /// `shares`, nothing of it attributed without any.
+    fn distribution(
+        &self,
+        from_block: U64,
+        block_number: U64,
+        shares: Shares,
+    ) -> (Distribution, RewardPerShare) {
+        let emitted = self.emission_between(from_block, block_number);
+        let per_share = if shares.is_zero() {
+            RewardPerShare::default()
+        } else {
+            RewardPerShare::spread(emitted, shares)
+        };
+        let distribution = Distribution {
+            from_block,
+            to_block: block_number,
+            emitted,
+            attributed: per_share.0 * shares.0 / parse_ether("1").unwrap(),
+        };
+        (distribution, per_share)
+    }
+
+    /// The blocks past the last accounted one up to `block_number` as `distribute_rewards`
+    /// would account them, split where lots mature.
+    fn pending_steps(&self, block_number: U64) -> Vec<PendingStep> {
+        let mut due = self
+            .maturity
+            .as_ref()
+            .map(|maturity| maturity.due_by(block_number))
+            .unwrap_or_default();
+        due.entry(block_number.max(self.last_accounted_block))
+            .or_default();
+
+        let mut from_block = self.last_accounted_block;
+        let mut shares = self.eligible_shares();
+        due.into_iter()
+            .map(|(to_block, matured)| {
+                let (distribution, per_share) = self.distribution(from_block, to_block, shares);
+                if self.maturity.is_some() {
+                    from_block = to_block;
+                }
+                for (_, matured) in &matured {
+                    shares += Shares(*matured);
+                }
+                PendingStep {
+                    distribution,
+                    per_share,
+                    matured,
+                }
+            })
+            .collect()
+    }
+
+    /// Lets the lots maturing up to `block_number` earn from the next block on, settling their
+    /// holders first. A lot of an address without a record fails with `UnknownUser`.
+    fn mature(&mut self, block_number: U64) -> Result<(), StateError> {
+        let Some(maturity) = &mut self.maturity else {
+            return Ok(());
+        };
+
+        for (address, shares) in maturity.mature(block_number) {
+            let user_record = self
+                .user_records
+                .get_mut(&address)
+                .ok_or(StateError::UnknownUser(address))?;
+
+            let rewards_accumulated = (self.total_rewards_per_share
+                - user_record.rewards_per_share_snapshot)
+                * user_record.eligible_shares();
+
+            user_record.rewards_accumulated += rewards_accumulated;
+            user_record.share_blocks = user_record.share_blocks_at(self.last_accounted_block);
+            user_record.share_blocks_snapshot = self.last_accounted_block;
+            user_record.rewards_per_share_snapshot = self.total_rewards_per_share;
+            user_record.immature_shares -= Shares(shares);
+            self.total_immature -= Shares(shares);
+            self.record_updates += 1;
+        }
+        Ok(())
     }
 }
 
+/// Blocks about to be distributed up to `distribution.to_block`, and the lots maturing there.
+struct PendingStep {
+    distribution: Distribution,
+    per_share: RewardPerShare,
+    matured: Vec<(Address, U256)>,
+}
+
+/// Returns the indices of deposit/withdraw pairs that cancel out: same address, same block,
+/// same shares, and no other event touching the address in between.
+fn same_block_pairs(evts: &[Event]) -> HashSet<usize> {
+    let mut netted = HashSet::new();
+    let mut open_deposits: HashMap<Address, usize> = HashMap::new();
+    let mut current_block = None;
+
+    for (i, evt) in evts.iter().enumerate() {
+        if current_block != Some(evt.block_number()) {
+            open_deposits.clear();
+            current_block = Some(evt.block_number());
+        }
+
+        match evt {
+            Event::Deposit(deposit) => {
+                open_deposits.insert(deposit.address, i);
+            }
+            Event::Withdrawal(withdraw) => {
+                if let Some(j) = open_deposits.remove(&withdraw.address) {
+                    if let Event::Deposit(deposit) = &evts[j] {
+                        if deposit.shares == withdraw.shares {
+                            netted.insert(j);
+                            netted.insert(i);
+                        }
+                    }
+                }
+            }
+            Event::Transfer(transfer) => {
+                open_deposits.remove(&transfer.from);
+                open_deposits.remove(&transfer.to);
+            }
+        }
+    }
+
+    netted
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -223,14 +1672,18 @@ mod tests {
 
         let evt_one = Event::Deposit(Deposit {
             address: BOB.parse().unwrap(),
-            shares: parse_ether("1").unwrap(),
+            shares: Shares(parse_ether("1").unwrap()),
             block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+            transaction_index: 0,
+            log_index: 0,
         });
 
         let evt_two = Event::Deposit(Deposit {
             address: ALICE.parse().unwrap(),
-            shares: parse_ether("1").unwrap(),
+            shares: Shares(parse_ether("1").unwrap()),
             block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 100),
+            transaction_index: 0,
+            log_index: 0,
         });
 
         let events: Vec<Event> = vec![evt_one, evt_two];
@@ -244,7 +1697,7 @@ mod tests {
 
         let mut global_state = GlobalState::new();
 
-        global_state.process_events(events);
+        global_state.process_events(events).unwrap();
 
         let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 100);
 
@@ -257,4 +1710,997 @@ mod tests {
         let all_rewards = global_state.get_all_rewards(block_number);
         assert_eq!(all_rewards, parse_ether("100").unwrap());
     }
+
+    #[test]
+    fn withdrawing_more_than_deposited_fails_without_panicking() {
+        let bob: Address = BOB.parse().unwrap();
+        let mut global_state = GlobalState::new();
+        global_state.process_events(create_events()).unwrap();
+
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 200);
+        let err = global_state
+            .try_process_events(vec![Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: Shares(parse_ether("2").unwrap()),
+                block_number,
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Accounting(StateError::InsufficientShares { address, have, want, block })
+                if address == bob
+                    && have == parse_ether("1").unwrap()
+                    && want == parse_ether("2").unwrap()
+                    && block == block_number
+        ));
+
+        // the state is left as it was before the withdrawal
+        assert_eq!(global_state.shares_of(bob), parse_ether("1").unwrap());
+        assert_eq!(
+            global_state.last_accounted_block(),
+            U64::from(BLOCK_CONTRACT_DEPLOYED + 100)
+        );
+    }
+
+    #[test]
+    fn withdrawals_of_unknown_users_fail_unless_lenient() {
+        let carol = Address::from_low_u64_be(0xca201);
+        let withdrawal = Event::Withdrawal(Withdraw {
+            address: carol,
+            shares: Shares(parse_ether("5").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 200),
+            transaction_index: 0,
+            log_index: 0,
+        });
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 300);
+
+        let mut strict = GlobalState::new();
+        strict.process_events(create_events()).unwrap();
+        assert_eq!(
+            strict.process_events(vec![withdrawal.clone()]),
+            Err(StateError::UnknownUser(carol))
+        );
+        let err = strict
+            .try_process_events(vec![withdrawal.clone()])
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::Accounting(StateError::UnknownUser(address)) if address == carol)
+        );
+        assert_eq!(strict.total_shares_staked(), parse_ether("2").unwrap());
+
+        // the shares carol takes were held before the first event, so nobody else's rewards move
+        let mut lenient = GlobalState::new_lenient();
+        lenient.process_events(create_events()).unwrap();
+        lenient.try_process_events(vec![withdrawal]).unwrap();
+        assert_eq!(lenient.shares_of(carol), U256::zero());
+        assert_eq!(lenient.total_shares_staked(), parse_ether("2").unwrap());
+        assert_eq!(
+            lenient.preview_user_rewards(carol, block_number),
+            U256::zero()
+        );
+        assert_eq!(
+            lenient.get_user_rewards(block_number),
+            strict.get_user_rewards(block_number)
+        );
+    }
+
+    #[test]
+    fn maturing_lots_of_unknown_users_fail() {
+        let bob: Address = BOB.parse().unwrap();
+        let mut global_state = GlobalState::new();
+        global_state.set_min_staking_blocks(Some(50));
+        global_state
+            .process_events(create_events()[..1].to_vec())
+            .unwrap();
+
+        // a lot left behind by a record that is gone
+        global_state.user_records.remove(&bob);
+        assert_eq!(
+            global_state.process_events(create_events()[1..].to_vec()),
+            Err(StateError::UnknownUser(bob))
+        );
+    }
+
+    #[test]
+    fn emits_the_configured_rate_every_block() {
+        let mut global_state = GlobalState::with_rate(parse_ether("2").unwrap());
+        global_state.process_events(create_events()).unwrap();
+
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 100);
+        let bob_rewards = global_state.preview_user_rewards(BOB.parse().unwrap(), block_number);
+        assert_eq!(bob_rewards, parse_ether("200").unwrap());
+        assert_eq!(
+            global_state.get_all_rewards(block_number),
+            parse_ether("200").unwrap()
+        );
+
+        // the rate survives a snapshot, and the default one is left out of it
+        let resumed = GlobalState::resume(global_state.snapshot());
+        assert_eq!(resumed.rewards_per_block(), parse_ether("2").unwrap());
+        let default = serde_json::to_value(GlobalState::new().snapshot()).unwrap();
+        assert!(default.get("rewards_per_block").is_none());
+    }
+
+    #[test]
+    fn emission_starts_at_the_deploy_block() {
+        let deployed = BLOCK_CONTRACT_DEPLOYED + 1_000;
+        let mut state = GlobalState::deployed_at(deployed);
+        state
+            .process_events(vec![Event::Deposit(Deposit {
+                address: BOB.parse().unwrap(),
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: U64::from(deployed + 10),
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap();
+
+        let block_number = U64::from(deployed + 100);
+        assert_eq!(
+            state.total_emission(block_number),
+            parse_ether("100").unwrap()
+        );
+        assert_eq!(
+            state.get_all_rewards(block_number),
+            parse_ether("100").unwrap()
+        );
+        assert_eq!(state.total_emission(U64::from(deployed - 1)), U256::zero());
+
+        // a resumed state keeps the deploy block, which the default one leaves out
+        let resumed = GlobalState::resume(state.snapshot());
+        assert_eq!(resumed.deployed_block(), U64::from(deployed));
+        assert_eq!(
+            resumed.total_emission(block_number),
+            parse_ether("100").unwrap()
+        );
+        let default = serde_json::to_value(GlobalState::new().snapshot()).unwrap();
+        assert!(default.get("deployed_block").is_none());
+    }
+
+    #[test]
+    fn lists_events_where_the_address_receives_shares() {
+        let frank: Address = "0x000000000000000000000000000000000000F4a2"
+            .parse()
+            .unwrap();
+        let mut events = create_events();
+        let transfer = Event::Transfer(Transfer {
+            from: BOB.parse().unwrap(),
+            to: frank,
+            shares: Shares(parse_ether("0.5").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 120),
+            transaction_index: 0,
+            log_index: 0,
+        });
+        events.push(transfer.clone());
+
+        assert_eq!(events_involving(&events, frank), vec![&transfer]);
+        assert_eq!(
+            events_involving(&events, BOB.parse().unwrap()),
+            vec![&events[0], &transfer]
+        );
+    }
+
+    #[test]
+    fn flags_implausibly_large_events() {
+        let mut events = create_events();
+        // 2^128 shares, the kind of amount only a misaligned decode produces
+        let garbage = Event::Deposit(Deposit {
+            address: ALICE.parse().unwrap(),
+            shares: Shares(U256::from_dec_str("340282366920938463463374607431768211456").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 150),
+            transaction_index: 0,
+            log_index: 0,
+        });
+        events.push(garbage.clone());
+
+        let mut global_state = GlobalState::new();
+        global_state.set_max_share_multiple(Some(1_000));
+        global_state.process_events(events[..2].to_vec()).unwrap();
+        assert!(global_state.suspicious_events().is_empty());
+
+        global_state.process_events(events[2..].to_vec()).unwrap();
+        assert_eq!(global_state.suspicious_events(), &[garbage]);
+    }
+
+    #[test]
+    fn committed_rewards_exclude_pending_emissions() {
+        let mut events = create_events();
+        events.push(Event::Withdrawal(Withdraw {
+            address: BOB.parse().unwrap(),
+            shares: Shares(parse_ether("0.5").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + 160),
+            transaction_index: 0,
+            log_index: 0,
+        }));
+
+        let mut global_state = GlobalState::new();
+        global_state.process_events(events).unwrap();
+
+        let committed_block = global_state.last_accounted_block();
+        assert_eq!(committed_block, U64::from(BLOCK_CONTRACT_DEPLOYED + 160));
+
+        let head = U64::from(BLOCK_CONTRACT_DEPLOYED + 1_000);
+
+        for (address, record) in global_state.user_records.iter() {
+            let finalized = ((global_state.total_rewards_per_share
+                - record.rewards_per_share_snapshot)
+                * record.shares_staked
+                + record.rewards_accumulated)
+                .to_wei()
+                .0;
+            let committed = global_state.preview_user_rewards(*address, committed_block);
+
+            assert_eq!(committed, finalized);
+            assert!(committed <= global_state.preview_user_rewards(*address, head));
+        }
+
+        assert!(global_state.get_all_rewards(committed_block) < global_state.get_all_rewards(head));
+    }
+
+    #[test]
+    fn same_block_netting_matches_sequential_processing() {
+        let zap_block = U64::from(BLOCK_CONTRACT_DEPLOYED + 37);
+        let mut events = create_events();
+        events.insert(
+            1,
+            Event::Deposit(Deposit {
+                address: ALICE.parse().unwrap(),
+                shares: Shares(parse_ether("3").unwrap()),
+                block_number: zap_block,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        );
+        events.insert(
+            2,
+            Event::Withdrawal(Withdraw {
+                address: ALICE.parse().unwrap(),
+                shares: Shares(parse_ether("3").unwrap()),
+                block_number: zap_block,
+                transaction_index: 0,
+                log_index: 0,
+            }),
+        );
+
+        let mut sequential = GlobalState::new();
+        sequential.process_events(events.clone()).unwrap();
+
+        let mut netted = GlobalState::new();
+        netted.set_same_block_netting(true);
+        netted.process_events(events).unwrap();
+
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 250);
+        for user in [BOB, ALICE] {
+            let user = user.parse().unwrap();
+            assert_eq!(
+                netted.preview_user_rewards(user, block_number),
+                sequential.preview_user_rewards(user, block_number)
+            );
+        }
+        assert_eq!(
+            netted.get_all_rewards(block_number),
+            sequential.get_all_rewards(block_number)
+        );
+        // alice's record dates from the netted pair either way
+        assert_eq!(netted.state_hash(), sequential.state_hash());
+        let users = |state: &GlobalState| {
+            let mut users: Vec<Address> = state.users().copied().collect();
+            users.sort();
+            users
+        };
+        assert_eq!(users(&netted), users(&sequential));
+        assert_eq!(
+            netted.first_deposit_block(ALICE.parse().unwrap()),
+            Some(zap_block)
+        );
+        // the pair settles alice's record once instead of writing it twice
+        assert_eq!(netted.record_updates(), sequential.record_updates() - 1);
+    }
+
+    #[test]
+    fn per_event_accrual_replays_same_block_pairs() {
+        let bob: Address = BOB.parse().unwrap();
+        let zap = Address::from_low_u64_be(0x2a9);
+        let block = |offset| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = Shares(parse_ether("1").unwrap());
+        // the pool is empty from block 10 until the zap's deposit refills it at block 50
+        let events = vec![
+            Event::Deposit(Deposit {
+                address: bob,
+                shares: one,
+                block_number: block(0),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: bob,
+                shares: one,
+                block_number: block(10),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Deposit(Deposit {
+                address: zap,
+                shares: one,
+                block_number: block(50),
+                transaction_index: 0,
+                log_index: 0,
+            }),
+            Event::Withdrawal(Withdraw {
+                address: zap,
+                shares: one,
+                block_number: block(50),
+                transaction_index: 0,
+                log_index: 1,
+            }),
+        ];
+        let replay = |net_same_block| {
+            let mut state = GlobalState::new();
+            state.set_accrual_policy(AccrualPolicy::PerEvent);
+            state.set_same_block_netting(net_same_block);
+            state.process_events(events.clone()).unwrap();
+            state
+        };
+
+        let (sequential, netted) = (replay(false), replay(true));
+        assert!(!sequential.preview_user_rewards(zap, block(60)).is_zero());
+        for user in [bob, zap] {
+            assert_eq!(
+                netted.preview_user_rewards(user, block(60)),
+                sequential.preview_user_rewards(user, block(60))
+            );
+        }
+        assert_eq!(netted.state_hash(), sequential.state_hash());
+    }
+
+    #[test]
+    fn block_start_accrual_ignores_the_order_of_a_refilling_block() {
+        let carol = Address::from_low_u64_be(0xca401);
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let block = |offset| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = parse_ether("1").unwrap();
+        let deposit = |address, offset| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(one),
+                block_number: block(offset),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+
+        // carol empties the pool at 10, then alice and bob refill it in the same block
+        let events = |first, second| {
+            vec![
+                deposit(carol, 0),
+                Event::Withdrawal(Withdraw {
+                    address: carol,
+                    shares: Shares(one),
+                    block_number: block(10),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                deposit(first, 50),
+                deposit(second, 50),
+            ]
+        };
+        let rewards = |policy, first, second| {
+            let mut global_state = GlobalState::new();
+            global_state.set_accrual_policy(policy);
+            global_state.process_events(events(first, second)).unwrap();
+            [alice, bob].map(|user| global_state.preview_user_rewards(user, block(100)))
+        };
+
+        for (first, second) in [(alice, bob), (bob, alice)] {
+            assert_eq!(
+                rewards(AccrualPolicy::BlockStart, first, second),
+                [one * 45, one * 45]
+            );
+        }
+        // the first depositor alone takes the 40 blocks the pool stood empty
+        assert_eq!(
+            rewards(AccrualPolicy::PerEvent, alice, bob),
+            [one * 65, one * 25]
+        );
+        assert_eq!(
+            rewards(AccrualPolicy::PerEvent, bob, alice),
+            [one * 25, one * 65]
+        );
+    }
+
+    #[test]
+    fn applies_the_self_held_shares_policy() {
+        let bob: Address = BOB.parse().unwrap();
+        let vault: Address = "0xaF53431488E871D103baA0280b6360998F0F9926"
+            .parse()
+            .unwrap();
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 100);
+        let expected = parse_ether("100").unwrap();
+
+        for policy in [
+            SelfHeldShares::Exclude,
+            SelfHeldShares::Include,
+            SelfHeldShares::Separate,
+        ] {
+            let mut global_state = GlobalState::new();
+            global_state.set_self_held_shares(vault, policy);
+            global_state
+                .process_events(vec![
+                    Event::Deposit(Deposit {
+                        address: bob,
+                        shares: Shares(parse_ether("1").unwrap()),
+                        block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                    // fee shares minted to the vault, decoded as a deposit
+                    Event::Deposit(Deposit {
+                        address: vault,
+                        shares: Shares(parse_ether("1").unwrap()),
+                        block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                        transaction_index: 0,
+                        log_index: 0,
+                    }),
+                ])
+                .unwrap();
+
+            // the vault's shares dilute bob under every policy
+            assert_eq!(
+                global_state.preview_user_rewards(bob, block_number),
+                parse_ether("50").unwrap(),
+                "{:?}",
+                policy
+            );
+
+            let payees: Vec<_> = global_state
+                .get_user_rewards(block_number)
+                .into_iter()
+                .map(|(address, _)| address)
+                .collect();
+            let protocol = global_state.protocol_rewards(block_number);
+
+            if policy == SelfHeldShares::Include {
+                assert!(payees.contains(&vault));
+                assert!(protocol.is_zero());
+            } else {
+                assert_eq!(payees, vec![bob], "{:?}", policy);
+                assert_eq!(protocol, parse_ether("50").unwrap(), "{:?}", policy);
+            }
+            assert_eq!(
+                global_state.get_all_rewards(block_number) + protocol,
+                expected,
+                "{:?}",
+                policy
+            );
+
+            // recorded balances still match the chain
+            let on_chain = [
+                (bob, parse_ether("1").unwrap()),
+                (vault, parse_ether("1").unwrap()),
+            ];
+            assert!(crate::reconcile::reconcile_balances(&global_state, &on_chain).is_empty());
+        }
+    }
+
+    #[test]
+    fn ingests_raw_logs_in_one_call() {
+        use crate::fetch::mock::{deposit_log, transfer_log, withdraw_log};
+
+        let (bob, alice): (Address, Address) = (BOB.parse().unwrap(), ALICE.parse().unwrap());
+        let flavor = VaultFlavor::oprtc_v1();
+        let one = parse_ether("1").unwrap();
+        let keyed = |mut log: Log, key: u64| {
+            log.transaction_hash = Some(H256::from_low_u64_be(key));
+            log.log_index = Some(U256::zero());
+            log
+        };
+        let deploy = BLOCK_CONTRACT_DEPLOYED;
+
+        // out of block order, redelivered once, with a mint the deposit already covers
+        let alice_deposit = keyed(deposit_log(alice, one, deploy + 336), 2);
+        let logs = vec![
+            keyed(transfer_log(bob, alice, one, deploy + 1_336), 3),
+            alice_deposit.clone(),
+            keyed(deposit_log(bob, one * 4, deploy), 1),
+            transfer_log(Address::zero(), bob, one * 4, deploy),
+            alice_deposit,
+        ];
+
+        let mut ingested = GlobalState::new();
+        let summary = ingested.ingest_logs(&logs, &flavor).unwrap();
+        assert_eq!(
+            summary,
+            IngestSummary {
+                logs: 5,
+                duplicates: 1,
+                skipped: 1,
+                undecodable: 0,
+                applied: 3,
+                quarantined: 0,
+            }
+        );
+        let mut processed = GlobalState::new();
+        processed
+            .process_events(vec![
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(one * 4),
+                    block_number: U64::from(deploy),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Deposit(Deposit {
+                    address: alice,
+                    shares: Shares(one),
+                    block_number: U64::from(deploy + 336),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Transfer(Transfer {
+                    from: bob,
+                    to: alice,
+                    shares: Shares(one),
+                    block_number: U64::from(deploy + 1_336),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            ])
+            .unwrap();
+        assert_eq!(ingested.state_hash(), processed.state_hash());
+
+        // a withdrawal of shares never received and a log cut short
+        let mut truncated = withdraw_log(bob, one, deploy + 2_000);
+        truncated.data = truncated.data.0.slice(..32).into();
+        let bad = [withdraw_log(alice, one * 9, deploy + 2_000), truncated];
+
+        let err = ingested.ingest_logs(&bad, &flavor).unwrap_err();
+        assert!(matches!(err, Error::Decode { .. }), "{}", err);
+        assert_eq!(ingested.state_hash(), processed.state_hash());
+
+        ingested.set_best_effort(true);
+        let summary = ingested.ingest_logs(&bad, &flavor).unwrap();
+        assert_eq!((summary.undecodable, summary.quarantined), (1, 1));
+        assert_eq!(summary.applied, 0);
+
+        let mut foreign = deposit_log(bob, one, deploy + 3_000);
+        foreign.address = Address::from_low_u64_be(0xf00);
+        let err = ingested
+            .ingest_logs(&[deposit_log(bob, one, deploy + 3_000), foreign], &flavor)
+            .unwrap_err();
+        assert!(err.to_string().contains("ingest only the vault's"));
+
+        let conflicting = [
+            keyed(deposit_log(bob, one, deploy + 3_000), 4),
+            keyed(deposit_log(bob, one * 2, deploy + 3_000), 4),
+        ];
+        let err = ingested.ingest_logs(&conflicting, &flavor).unwrap_err();
+        assert!(err.to_string().contains("different contents"));
+    }
+
+    #[test]
+    fn spans_only_the_blocks_a_user_was_active() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+
+        let mut global_state = GlobalState::new();
+        global_state
+            .process_events(vec![
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: block(0),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Deposit(Deposit {
+                    address: alice,
+                    shares: Shares(parse_ether("2").unwrap()),
+                    block_number: block(40),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Withdrawal(Withdraw {
+                    address: alice,
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: block(50),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Withdrawal(Withdraw {
+                    address: alice,
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: block(60),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(parse_ether("1").unwrap()),
+                    block_number: block(100),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            global_state.user_active_span(alice),
+            Some((block(40), block(60)))
+        );
+        assert_eq!(
+            global_state.user_active_span(bob),
+            Some((block(0), block(100)))
+        );
+        assert_eq!(global_state.user_active_span(Address::zero()), None);
+
+        // alice is only credited for the 20 blocks she held shares
+        let alice_rewards = global_state.preview_user_rewards(alice, block(100));
+        assert_eq!(
+            alice_rewards,
+            parse_ether("10").unwrap() * 2 / 3 + parse_ether("5").unwrap()
+        );
+    }
+
+    #[test]
+    fn deposits_at_the_deploy_block_accrue_from_the_next_block() {
+        let bob: Address = BOB.parse().unwrap();
+        let deploy_block = U64::from(BLOCK_CONTRACT_DEPLOYED);
+
+        let mut global_state = GlobalState::new();
+        global_state
+            .process_events(vec![Event::Deposit(Deposit {
+                address: bob,
+                shares: Shares(parse_ether("1").unwrap()),
+                block_number: deploy_block,
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap();
+
+        assert_eq!(global_state.shares_of(bob), parse_ether("1").unwrap());
+        assert_eq!(global_state.last_accounted_block(), deploy_block);
+        assert!(global_state
+            .preview_user_rewards(bob, deploy_block)
+            .is_zero());
+        assert_eq!(
+            global_state.preview_user_rewards(bob, deploy_block + 1),
+            parse_ether("1").unwrap()
+        );
+    }
+
+    #[test]
+    fn per_second_emission_follows_block_timestamps() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = parse_ether("1").unwrap();
+
+        // blocks come every 10s, then every 2s once production speeds up
+        let mut timestamps = TimestampCache::new();
+        for offset in 0..=20 {
+            let timestamp = if offset <= 10 {
+                offset * 10
+            } else {
+                100 + (offset - 10) * 2
+            };
+            timestamps.insert(block(offset), 1_000 + timestamp);
+        }
+
+        let changes = [(0, bob, 1u64), (10, alice, 1), (15, bob, 2)];
+        let events: Vec<_> = changes
+            .iter()
+            .map(|(offset, address, shares)| {
+                Event::Deposit(Deposit {
+                    address: *address,
+                    shares: Shares(one * *shares),
+                    block_number: block(*offset),
+                    transaction_index: 0,
+                    log_index: 0,
+                })
+            })
+            .collect();
+
+        // emission split pro rata between every pair of consecutive points in time
+        let simulate = |user: Address, at: u64| {
+            let mut points: Vec<u64> = changes.iter().map(|(offset, ..)| *offset).collect();
+            points.push(at);
+            points
+                .windows(2)
+                .map(|pair| {
+                    let held = |address: Address| -> u64 {
+                        changes
+                            .iter()
+                            .filter(|(offset, a, _)| *offset <= pair[0] && *a == address)
+                            .map(|(.., shares)| shares)
+                            .sum()
+                    };
+                    let seconds = timestamps.get(block(pair[1])).unwrap()
+                        - timestamps.get(block(pair[0])).unwrap();
+                    one * seconds * held(user) / (held(bob) + held(alice))
+                })
+                .fold(U256::zero(), |acc, rewards| acc + rewards)
+        };
+
+        let mut per_second = GlobalState::new();
+        per_second.set_emission(Emission::PerSecond(one), timestamps.clone());
+        per_second.process_events(events.clone()).unwrap();
+
+        let mut per_block = GlobalState::new();
+        per_block.process_events(events).unwrap();
+
+        for user in [bob, alice] {
+            assert_eq!(
+                per_second.preview_user_rewards(user, block(20)),
+                simulate(user, 20)
+            );
+            assert_ne!(
+                per_block.preview_user_rewards(user, block(20)),
+                simulate(user, 20)
+            );
+        }
+        assert_eq!(per_second.total_emission(block(20)), one * 120);
+        assert_eq!(per_second.get_all_rewards(block(20)), one * 120);
+    }
+
+    #[test]
+    fn rewards_split_by_share_blocks() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let carol = Address::from_low_u64_be(0xca401);
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = parse_ether("1").unwrap();
+
+        // transfers keep the total at 4 shares until carol joins at the end
+        let mut global_state = GlobalState::new();
+        global_state
+            .process_events(vec![
+                Event::Deposit(Deposit {
+                    address: bob,
+                    shares: Shares(one * 3),
+                    block_number: block(0),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Deposit(Deposit {
+                    address: alice,
+                    shares: Shares(one),
+                    block_number: block(0),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Transfer(Transfer {
+                    from: bob,
+                    to: alice,
+                    shares: Shares(one),
+                    block_number: block(100),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+                Event::Deposit(Deposit {
+                    address: carol,
+                    shares: Shares(one),
+                    block_number: block(200),
+                    transaction_index: 0,
+                    log_index: 0,
+                }),
+            ])
+            .unwrap();
+
+        let total = global_state.total_share_blocks();
+        assert_eq!(total, one * 800);
+        assert_eq!(global_state.user_share_blocks(bob), one * 500);
+        assert_eq!(global_state.user_share_blocks(alice), one * 300);
+        assert!(global_state.user_share_blocks(carol).is_zero());
+
+        let emission = global_state.total_emission(block(200));
+        for user in [bob, alice, carol] {
+            assert_eq!(
+                global_state.preview_user_rewards(user, block(200)),
+                emission * global_state.user_share_blocks(user) / total
+            );
+        }
+    }
+
+    fn deposit_at(address: Address, shares: U256, offset: u64) -> Event {
+        Event::Deposit(Deposit {
+            address,
+            shares: Shares(shares),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn withdraw_at(address: Address, shares: U256, offset: u64) -> Event {
+        Event::Withdrawal(Withdraw {
+            address,
+            shares: Shares(shares),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    #[test]
+    fn lots_join_the_rewards_once_they_mature() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let carol = Address::from_low_u64_be(0xca401);
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = parse_ether("1").unwrap();
+
+        let mut state = GlobalState::new();
+        state.set_min_staking_blocks(Some(100));
+        state
+            .process_events(vec![deposit_at(bob, one, 0), deposit_at(alice, one, 50)])
+            .unwrap();
+
+        // nothing is eligible until bob's lot matures at 100, and bob earns alone until alice's
+        // does at 150, both inside the pending interval
+        let previews = |state: &GlobalState| {
+            [bob, alice, carol].map(|user| state.preview_user_rewards(user, block(300)))
+        };
+        assert_eq!(previews(&state), [one * 125, one * 75, U256::zero()]);
+        let total = state.total_distribution(block(300));
+        assert_eq!((total.emitted, total.attributed), (one * 300, one * 200));
+
+        // accounting up to a later event matures them the same way
+        state
+            .process_events(vec![deposit_at(carol, one, 300)])
+            .unwrap();
+        assert_eq!(previews(&state), [one * 125, one * 75, U256::zero()]);
+        assert_eq!(state.total_distribution(block(300)), total);
+        assert_eq!(state.total_share_blocks(), one * (200 + 150));
+    }
+
+    #[test]
+    fn shares_leaving_before_they_mature_forfeit() {
+        let bob: Address = BOB.parse().unwrap();
+        let carol = Address::from_low_u64_be(0xca401);
+        let block = |offset: u64| U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+        let one = parse_ether("1").unwrap();
+
+        let mut state = GlobalState::new();
+        state.set_min_staking_blocks(Some(100));
+        state
+            .process_events(vec![
+                deposit_at(bob, one * 2, 0),
+                // carol's lot never matures
+                deposit_at(carol, one, 10),
+                withdraw_at(carol, one, 50),
+                deposit_at(bob, one * 2, 150),
+                // the 2 matured shares leave first, then 1 of the lot maturing at 250
+                withdraw_at(bob, one * 3, 200),
+            ])
+            .unwrap();
+
+        // bob's 2 shares over (100, 200], nothing eligible until 250, then 1 share
+        assert_eq!(state.shares_of(bob), one);
+        assert_eq!(state.preview_user_rewards(bob, block(300)), one * 150);
+        assert!(state.preview_user_rewards(carol, block(300)).is_zero());
+        assert_eq!(state.total_distribution(block(300)).attributed, one * 150);
+
+        // the lots carry over a snapshot
+        let snapshot = serde_json::to_value(state.snapshot()).unwrap();
+        let resumed = GlobalState::resume(serde_json::from_value(snapshot).unwrap());
+        assert_eq!(resumed.min_staking_blocks(), Some(100));
+        assert_eq!(resumed.preview_user_rewards(bob, block(300)), one * 150);
+        let default = serde_json::to_value(GlobalState::new().snapshot()).unwrap();
+        assert!(default.get("maturity").is_none());
+    }
+
+    #[test]
+    fn accumulator_checkpoints_match_a_replay_up_to_every_block() {
+        let bob: Address = BOB.parse().unwrap();
+        let alice: Address = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let evts = vec![
+            deposit_at(bob, one * 3 + 1, 0),
+            deposit_at(alice, one * 7, 37),
+            withdraw_at(bob, one * 3 + 1, 120),
+            // the pool is empty over (160, 230]
+            withdraw_at(alice, one * 7, 160),
+            deposit_at(bob, one, 230),
+        ];
+        let mut state = GlobalState::new();
+        state.set_accumulator_interval(Some(50));
+        state.process_events(evts.clone()).unwrap();
+        let snapshot = serde_json::to_value(state.snapshot()).unwrap();
+        let resumed = GlobalState::resume(serde_json::from_value(snapshot).unwrap());
+
+        let mut previewed_forward = 0;
+        for offset in 0..=300 {
+            let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + offset);
+            let mut replay = GlobalState::new();
+            replay
+                .process_events(
+                    evts.iter()
+                        .filter(|evt| evt.block_number() <= block_number)
+                        .cloned()
+                        .collect(),
+                )
+                .unwrap();
+            let checkpoint_block = block_number - block_number % 50;
+
+            match state.accumulator_at(block_number) {
+                Some(accumulator) => {
+                    assert_eq!(
+                        accumulator,
+                        replay.preview_accumulator(block_number),
+                        "block +{}",
+                        offset
+                    );
+                    if checkpoint_block < block_number && offset < 230 {
+                        previewed_forward += 1;
+                    }
+                }
+                // only past an event after the checkpoint before the block
+                None => assert!(evts.iter().any(|evt| {
+                    evt.block_number() > checkpoint_block && evt.block_number() <= block_number
+                })),
+            }
+            assert_eq!(
+                resumed.accumulator_at(block_number),
+                state.accumulator_at(block_number)
+            );
+        }
+        assert!(previewed_forward > 100);
+    }
+
+    #[test]
+    fn a_minimum_staking_duration_conserves_the_emission() {
+        let holders: Vec<_> = (1..=4).map(Address::from_low_u64_be).collect();
+        let one = parse_ether("1").unwrap();
+
+        // a holder staked throughout keeps the pool from emptying, then churn every few blocks
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let mut balances = vec![U256::zero(); holders.len()];
+        let mut evts = vec![deposit_at(Address::from_low_u64_be(0xfee), one, 0)];
+        for offset in (1..600).step_by(7) {
+            let i = next() as usize % holders.len();
+            let amount = one * (next() % 5 + 1);
+            if next() % 3 == 0 && !balances[i].is_zero() {
+                let amount = amount.min(balances[i]);
+                balances[i] -= amount;
+                evts.push(withdraw_at(holders[i], amount, offset));
+            } else {
+                balances[i] += amount;
+                evts.push(deposit_at(holders[i], amount, offset));
+            }
+        }
+
+        let mut plain = GlobalState::new();
+        plain.process_events(evts.clone()).unwrap();
+        let mut immediate = GlobalState::new();
+        immediate.set_min_staking_blocks(Some(0));
+        immediate.process_events(evts.clone()).unwrap();
+        let end = U64::from(BLOCK_CONTRACT_DEPLOYED + 700);
+        assert_eq!(immediate.get_user_rewards(end), plain.get_user_rewards(end));
+
+        let mut state = GlobalState::new();
+        state.set_min_staking_blocks(Some(30));
+        for evt in evts {
+            // an event never changes the rewards up to its own block
+            let block_number = evt.block_number();
+            let before = state.get_user_rewards(block_number);
+            state.process_events(vec![evt]).unwrap();
+            assert_eq!(state.get_user_rewards(block_number), before);
+        }
+
+        // what isn't paid went unattributed, up to rounding
+        let total = state.total_distribution(end);
+        let paid = state.get_all_rewards(end);
+        assert!(total.attributed < total.emitted);
+        assert!(total.attributed.max(paid) - total.attributed.min(paid) < U256::from(1_000));
+        assert!(paid < plain.get_all_rewards(end));
+    }
 }
@@ -0,0 +1,178 @@
+//! `-` as a path: standard input where a file is read, standard output where one is written,
+//! so runs compose through pipes without temporary files.
+
+use crate::binary_cache;
+use crate::cache::EventCache;
+use crate::error::{bail, ensure, Error, Result};
+use crate::journal::read_journal;
+use crate::state::Event;
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// The bytes of `path`, or everything on standard input for `-`.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    if !is_stdio(path) {
+        return fs::read(path);
+    }
+    let mut bytes = vec![];
+    io::stdin().lock().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Buffered reads of `path`, or of standard input for `-`, for input too large to hold.
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdin().lock()));
+    }
+    Ok(Box::new(BufReader::new(File::open(path)?)))
+}
+
+pub fn read_to_string(path: &Path) -> io::Result<String> {
+    String::from_utf8(read(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Creates or truncates `path` for writing, or writes to standard output for `-`.
+pub fn create(path: &Path) -> io::Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        return Ok(Box::new(io::stdout().lock()));
+    }
+    Ok(Box::new(File::create(path)?))
+}
+
+/// Writes `bytes` to a temporary sibling of `path` and renames it over `path`, so a reader
+/// never sees half a file. Writes to standard output for `-`.
+pub fn replace(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if is_stdio(path) {
+        let mut stdout = io::stdout().lock();
+        return stdout.write_all(bytes).and_then(|()| stdout.flush());
+    }
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// How events piped in are read. Both are JSON, so `auto` takes a single cache document for a
+/// cache and anything else for a journal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+    #[default]
+    Auto,
+    /// A JSON cache, as `fetch` writes
+    Cache,
+    /// One event per line, as `prove --events` writes
+    Journal,
+}
+
+/// Events read from standard input.
+#[derive(Debug)]
+pub enum EventsInput {
+    Cache(EventCache),
+    Journal(Vec<Event>),
+}
+
+/// Fails on a binary cache, which is memory-mapped from its file and can't come through a pipe.
+pub(crate) fn ensure_not_binary(bytes: &[u8]) -> Result<()> {
+    ensure!(
+        !binary_cache::is_binary(bytes),
+        Error::config,
+        "binary caches can't be read from standard input, convert them to JSON with \
+         `cache convert --cache-format json` or pass the file"
+    );
+    Ok(())
+}
+
+/// Parses events piped in as `format`.
+pub fn parse_events(bytes: &[u8], format: InputFormat) -> Result<EventsInput> {
+    ensure_not_binary(bytes)?;
+    let cache = || -> Result<EventsInput> {
+        serde_json::from_slice(bytes)
+            .map(EventsInput::Cache)
+            .map_err(|err| Error::decode("standard input is not a JSON cache").caused_by(err))
+    };
+    let journal = || read_journal(bytes).map(EventsInput::Journal);
+
+    match format {
+        InputFormat::Cache => cache(),
+        InputFormat::Journal => journal(),
+        InputFormat::Auto => match cache().or_else(|_| journal()) {
+            Ok(events) => Ok(events),
+            Err(err) => bail!(
+                Error::decode,
+                "standard input is neither a JSON cache nor an events journal ({}), \
+                 --input-format tells which it should be",
+                err
+            ),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::write_journal;
+    use crate::state::{Deposit, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::core::types::{Address, U256, U64};
+
+    #[test]
+    fn tells_piped_caches_and_journals_apart() {
+        let deposit = Event::Deposit(Deposit {
+            address: Address::from_low_u64_be(0xb0b),
+            shares: Shares(U256::one()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+            transaction_index: 0,
+            log_index: 0,
+        });
+        let mut cache = EventCache::new(Address::from_low_u64_be(0x7a), 1, 100, 199, 100);
+        cache.events = vec![deposit.clone()];
+        let cache_json = serde_json::to_vec(&cache).unwrap();
+        let mut journal = vec![];
+        write_journal(&mut journal, &[deposit.clone(), deposit]).unwrap();
+
+        assert!(matches!(
+            parse_events(&cache_json, InputFormat::Auto).unwrap(),
+            EventsInput::Cache(parsed) if parsed == cache
+        ));
+        assert!(matches!(
+            parse_events(&journal, InputFormat::Auto).unwrap(),
+            EventsInput::Journal(events) if events.len() == 2
+        ));
+        assert!(matches!(
+            parse_events(b"", InputFormat::Auto).unwrap(),
+            EventsInput::Journal(events) if events.is_empty()
+        ));
+
+        // an explicit format reports why the input isn't that
+        let err = parse_events(&journal, InputFormat::Cache).unwrap_err();
+        assert!(err.to_string().contains("not a JSON cache"));
+        let err = parse_events(b"{\"not\": 1}", InputFormat::Auto).unwrap_err();
+        assert!(err.to_string().contains("--input-format"));
+
+        let mut binary = vec![];
+        binary_cache::encode(&cache, &mut binary).unwrap();
+        let err = parse_events(&binary, InputFormat::Auto).unwrap_err();
+        assert!(matches!(err, Error::Config { .. }));
+        assert!(err.to_string().contains("binary caches"));
+    }
+
+    #[test]
+    fn replaces_files_whole() {
+        let dir = std::env::temp_dir().join(format!("oprtc-stdio-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rewards.csv");
+
+        replace(&path, b"old").unwrap();
+        replace(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!dir.join("rewards.csv.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
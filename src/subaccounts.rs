@@ -0,0 +1,345 @@
+use crate::error::{ensure, Error, Result};
+use crate::observer::StateObserver;
+use crate::rounding::split;
+use crate::state::{Event, GlobalState};
+use crate::stdio;
+use ethers::core::types::{Address, U256, U64};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+/// Weights in force for one address from `from_block` up to, but excluding, `to_block`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SplitRange {
+    pub address: Address,
+    pub from_block: u64,
+    /// Open until the next range of the address when missing
+    #[serde(default)]
+    pub to_block: Option<u64>,
+    /// Percentage of the address' rewards per sub-account, summing to 100
+    pub weights: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    split: Vec<SplitRange>,
+}
+
+/// Cumulative rewards of split addresses at each block their weights change.
+pub type Checkpoints = HashMap<(Address, U64), U256>;
+
+/// Reports one on-chain address as several internal accounts, with weights that may change
+/// over time. Rewards of an interval go to the weights in force when it started, blocks not
+/// covered by any range stay with the address itself.
+///
+/// ```toml
+/// [[split]]
+/// address = "0x0000000000000000000000000000000000000B0b"
+/// from_block = 17564663
+/// weights = { clientA = 60, clientB = 40 }
+/// ```
+#[derive(Debug, Default)]
+pub struct SubAccounts {
+    ranges: BTreeMap<Address, Vec<SplitRange>>,
+}
+
+impl SubAccounts {
+    pub fn parse(contents: &str) -> Result<SubAccounts> {
+        let spec: SpecFile =
+            toml::from_str(contents).map_err(|err| Error::config(err.to_string()))?;
+        let mut ranges: BTreeMap<Address, Vec<SplitRange>> = BTreeMap::new();
+
+        for range in spec.split {
+            let total: u32 = range.weights.values().sum();
+            ensure!(
+                total == 100,
+                Error::config,
+                "weights of {:?} from block {} sum to {}%, expected 100%",
+                range.address,
+                range.from_block,
+                total
+            );
+            if let Some(to_block) = range.to_block {
+                ensure!(
+                    to_block > range.from_block,
+                    Error::config,
+                    "range of {:?} from block {} ends before it starts",
+                    range.address,
+                    range.from_block
+                );
+            }
+
+            ranges.entry(range.address).or_default().push(range);
+        }
+
+        for address_ranges in ranges.values_mut() {
+            address_ranges.sort_by_key(|range| range.from_block);
+
+            for pair in address_ranges.windows(2) {
+                let end = pair[0].to_block.unwrap_or(pair[1].from_block);
+                ensure!(
+                    pair[0].from_block < pair[1].from_block && end <= pair[1].from_block,
+                    Error::config,
+                    "ranges of {:?} from blocks {} and {} overlap",
+                    pair[0].address,
+                    pair[0].from_block,
+                    pair[1].from_block
+                );
+            }
+        }
+
+        Ok(SubAccounts { ranges })
+    }
+
+    pub fn load(path: &Path) -> Result<SubAccounts> {
+        let contents = stdio::read_to_string(path).map_err(|err| {
+            Error::io(
+                format!("failed to read sub-accounts {}", path.display()),
+                err,
+            )
+        })?;
+
+        SubAccounts::parse(&contents).map_err(|err| {
+            Error::config(format!("invalid sub-accounts {}", path.display())).caused_by(err)
+        })
+    }
+
+    pub fn is_split(&self, address: Address) -> bool {
+        self.ranges.contains_key(&address)
+    }
+
+    fn boundaries_of(&self, address: Address) -> BTreeSet<U64> {
+        self.ranges[&address]
+            .iter()
+            .flat_map(|range| [Some(range.from_block), range.to_block])
+            .flatten()
+            .map(U64::from)
+            .collect()
+    }
+
+    /// Every block at which the weights of some address change.
+    pub fn boundaries(&self) -> impl Iterator<Item = U64> + '_ {
+        self.ranges
+            .keys()
+            .flat_map(|address| self.boundaries_of(*address))
+    }
+
+    /// Processes `events` into `state`, pausing at every weight change to record the
+    /// cumulative rewards of the addresses it applies to. Stops at the first event the
+    /// accounting can't apply.
+    pub fn replay(&self, state: &mut GlobalState, events: Vec<Event>) -> Result<Checkpoints> {
+        self.replay_with(state, events, &mut ())
+    }
+
+    /// Like `replay`, telling `observer` about every step of the processing.
+    pub fn replay_with(
+        &self,
+        state: &mut GlobalState,
+        events: Vec<Event>,
+        observer: &mut impl StateObserver,
+    ) -> Result<Checkpoints> {
+        let mut by_block: BTreeMap<U64, Vec<Address>> = BTreeMap::new();
+        for address in self.ranges.keys() {
+            for boundary in self.boundaries_of(*address) {
+                by_block.entry(boundary).or_default().push(*address);
+            }
+        }
+
+        let mut checkpoints = HashMap::new();
+        let mut events = events.into_iter().peekable();
+
+        for (boundary, addresses) in by_block {
+            let mut segment = vec![];
+            while let Some(evt) = events.next_if(|evt| evt.block_number() < boundary) {
+                segment.push(evt);
+            }
+            state.process_events_with(segment, observer)?;
+
+            for address in addresses {
+                let rewards = if boundary > state.deployed_block() {
+                    state.preview_user_rewards(address, boundary)
+                } else {
+                    U256::zero()
+                };
+                checkpoints.insert((address, boundary), rewards);
+            }
+        }
+
+        state.process_events_with(events.collect(), observer)?;
+
+        Ok(checkpoints)
+    }
+
+    /// Splits the rewards `address` earned up to `block_number` into `0x..#name` rows, with a
+    /// plain `0x..` row for blocks outside every range. The rows add up to the wei.
+    pub fn split(
+        &self,
+        address: Address,
+        checkpoints: &Checkpoints,
+        state: &GlobalState,
+        block_number: U64,
+    ) -> Vec<(String, U256)> {
+        let start = state.deployed_block();
+        let mut timeline = vec![start];
+        timeline.extend(
+            self.boundaries_of(address)
+                .into_iter()
+                .filter(|boundary| *boundary > start && *boundary < block_number),
+        );
+        timeline.push(block_number);
+
+        let cumulative = |block: U64| {
+            if block == block_number {
+                state.preview_user_rewards(address, block_number)
+            } else if block == start {
+                U256::zero()
+            } else {
+                checkpoints[&(address, block)]
+            }
+        };
+
+        let mut totals: BTreeMap<String, U256> = BTreeMap::new();
+        let mut unassigned = U256::zero();
+
+        for interval in timeline.windows(2) {
+            let rewards = cumulative(interval[1]) - cumulative(interval[0]);
+            let begin = interval[0].as_u64();
+
+            let range = self.ranges[&address].iter().rev().find(|range| {
+                range.from_block <= begin && !matches!(range.to_block, Some(end) if end <= begin)
+            });
+
+            let Some(range) = range else {
+                unassigned += rewards;
+                continue;
+            };
+
+            let weights: Vec<_> = range.weights.values().map(|w| U256::from(*w)).collect();
+            for (name, share) in range.weights.keys().zip(split(rewards, &weights)) {
+                *totals.entry(name.clone()).or_default() += share;
+            }
+        }
+
+        let mut rows: Vec<_> = totals
+            .into_iter()
+            .map(|(name, rewards)| (format!("{:?}#{}", address, name), rewards))
+            .collect();
+        if !unassigned.is_zero() {
+            rows.push((format!("{:?}", address), unassigned));
+        }
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    fn deposit(address: &str, offset: u64) -> Event {
+        Event::Deposit(Deposit {
+            address: address.parse().unwrap(),
+            shares: Shares(parse_ether("1").unwrap()),
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + offset),
+            transaction_index: 0,
+            log_index: 0,
+        })
+    }
+
+    fn spec(ranges: &[(u64, Option<u64>, &str)]) -> String {
+        ranges
+            .iter()
+            .map(|(from, to, weights)| {
+                let to = to.map_or(String::new(), |to| {
+                    format!("to_block = {}\n", BLOCK_CONTRACT_DEPLOYED + to)
+                });
+                format!(
+                    "[[split]]\naddress = \"{}\"\nfrom_block = {}\n{}weights = {{ {} }}\n",
+                    BOB,
+                    BLOCK_CONTRACT_DEPLOYED + from,
+                    to,
+                    weights
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_at_a_weight_change_inside_an_accrual_interval() {
+        let bob: Address = BOB.parse().unwrap();
+        // bob's accrual interval runs from his deposit to alice's, the weights change halfway
+        let sub_accounts = SubAccounts::parse(&spec(&[
+            (0, None, "clientA = 60, clientB = 40"),
+            (50, None, "clientA = 50, clientB = 50"),
+        ]))
+        .unwrap();
+
+        let mut global_state = GlobalState::new();
+        let checkpoints = sub_accounts
+            .replay(
+                &mut global_state,
+                vec![deposit(BOB, 0), deposit(ALICE, 100)],
+            )
+            .unwrap();
+
+        let head = U64::from(BLOCK_CONTRACT_DEPLOYED + 200);
+        let rows = sub_accounts.split(bob, &checkpoints, &global_state, head);
+
+        assert_eq!(
+            rows,
+            vec![
+                (format!("{:?}#clientA", bob), parse_ether("80").unwrap()),
+                (format!("{:?}#clientB", bob), parse_ether("70").unwrap()),
+            ]
+        );
+
+        let total = rows.iter().fold(U256::zero(), |acc, (_, r)| acc + r);
+        assert_eq!(total, global_state.preview_user_rewards(bob, head));
+    }
+
+    #[test]
+    fn conserves_rewards_to_the_wei() {
+        let bob: Address = BOB.parse().unwrap();
+        let sub_accounts = SubAccounts::parse(&spec(&[
+            (10, Some(40), "a = 33, b = 33, c = 34"),
+            (77, None, "a = 1, b = 99"),
+        ]))
+        .unwrap();
+
+        let mut global_state = GlobalState::new();
+        let checkpoints = sub_accounts
+            .replay(
+                &mut global_state,
+                vec![deposit(BOB, 0), deposit(ALICE, 3), deposit(ALICE, 61)],
+            )
+            .unwrap();
+
+        let head = U64::from(BLOCK_CONTRACT_DEPLOYED + 97);
+        let rows = sub_accounts.split(bob, &checkpoints, &global_state, head);
+
+        // blocks 0..10 and 40..77 stay with the address itself
+        assert_eq!(rows.last().unwrap().0, format!("{:?}", bob));
+        let total = rows.iter().fold(U256::zero(), |acc, (_, r)| acc + r);
+        assert_eq!(total, global_state.preview_user_rewards(bob, head));
+    }
+
+    #[test]
+    fn rejects_bad_weights_and_overlaps() {
+        let err = SubAccounts::parse(&spec(&[(0, None, "a = 60, b = 30")])).unwrap_err();
+        assert!(err.to_string().contains("sum to 90%"));
+
+        let err = SubAccounts::parse(&spec(&[(0, Some(60), "a = 100"), (50, None, "b = 100")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("overlap"));
+    }
+}
@@ -0,0 +1,50 @@
+use crate::context::EvaluationContext;
+use crate::error::{Error, Result};
+use crate::rpc::Client;
+use ethers::{core::types::U64, providers::Middleware};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct TimestampCache {
+    timestamps: HashMap<U64, u64>,
+}
+
+impl TimestampCache {
+    pub fn new() -> TimestampCache {
+        TimestampCache {
+            timestamps: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, block_number: U64, timestamp: u64) {
+        self.timestamps.insert(block_number, timestamp);
+    }
+
+    pub fn get(&self, block_number: U64) -> Option<u64> {
+        self.timestamps.get(&block_number).copied()
+    }
+
+    /// Fetches the timestamp of every block not already cached, none of which may be past the
+    /// evaluation block.
+    pub async fn fetch(
+        &mut self,
+        client: &Client,
+        ctx: &EvaluationContext,
+        block_numbers: impl IntoIterator<Item = U64>,
+    ) -> Result<()> {
+        for block_number in block_numbers {
+            if self.timestamps.contains_key(&block_number) {
+                continue;
+            }
+
+            let block = client
+                .get_block(ctx.historical(block_number)?)
+                .await?
+                .ok_or_else(|| Error::rpc(format!("block {} not found", block_number)))?;
+
+            self.insert(block_number, block.timestamp.as_u64());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! Rewards paid in as discrete top-ups instead of a fixed schedule. The treasury sends reward
+//! tokens to the vault, which emits `RewardAdded(uint256 amount, uint256 duration)` and streams
+//! the amount linearly over the `duration` seconds after the block of the log. Top-ups running
+//! at the same time stack. A flavor's `[top_ups]` section names the event and where its words
+//! are, and switches the emission to `Emission::TopUps`.
+//!
+//! ```toml
+//! [top_ups]
+//! signature = "RewardAdded(uint256,uint256)"
+//! ```
+
+use crate::error::{ensure, Error, Result};
+use crate::fetch::{chunk_grid, LogSource, CHUNK_SIZE};
+use crate::output::format_ether_rounded;
+use crate::timestamps::TimestampCache;
+use ethers::core::types::{Address, Filter, Log, U256, U64};
+use serde::Deserialize;
+use std::fmt;
+
+pub const REWARD_ADDED_EVENT: &str = "RewardAdded(uint256,uint256)";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopUpConfig {
+    /// Event announcing a top-up, `RewardAdded(uint256,uint256)` unless given
+    #[serde(default = "default_signature")]
+    pub signature: String,
+    /// Index of the 32-byte data word holding the amount
+    #[serde(default)]
+    pub amount_word: usize,
+    /// Index of the 32-byte data word holding the duration in seconds
+    #[serde(default = "default_duration_word")]
+    pub duration_word: usize,
+}
+
+fn default_signature() -> String {
+    REWARD_ADDED_EVENT.to_string()
+}
+
+fn default_duration_word() -> usize {
+    1
+}
+
+impl Default for TopUpConfig {
+    fn default() -> Self {
+        TopUpConfig {
+            signature: default_signature(),
+            amount_word: 0,
+            duration_word: default_duration_word(),
+        }
+    }
+}
+
+impl TopUpConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        ensure!(
+            self.amount_word != self.duration_word,
+            Error::config,
+            "top_ups: amount_word and duration_word must differ"
+        );
+        Ok(())
+    }
+
+    fn word(&self, log: &Log, index: usize) -> Result<U256> {
+        log.data
+            .get(index * 32..index * 32 + 32)
+            .map(U256::from)
+            .ok_or_else(|| {
+                Error::decode(format!("{} log has no data word {}", self.signature, index))
+            })
+    }
+
+    fn decode(&self, log: &Log) -> Result<TopUp> {
+        let block_number = log
+            .block_number
+            .ok_or_else(|| Error::decode(format!("{} log has no block number", self.signature)))?;
+        let duration = self.word(log, self.duration_word)?;
+        ensure!(
+            duration <= U256::from(u64::MAX),
+            Error::decode,
+            "{} log at block {} streams over {} seconds",
+            self.signature,
+            block_number,
+            duration
+        );
+
+        Ok(TopUp {
+            block_number,
+            amount: self.word(log, self.amount_word)?,
+            duration: duration.as_u64(),
+        })
+    }
+
+    /// Every top-up of `vault` from `from_block`, the vault's deployment, up to `to_block`, in
+    /// block order.
+    pub async fn fetch<S: LogSource>(
+        &self,
+        source: &S,
+        vault: Address,
+        from_block: u64,
+        to_block: u64,
+    ) -> Result<Vec<TopUp>> {
+        let mut logs = vec![];
+        for (start, end) in chunk_grid(from_block, to_block, CHUNK_SIZE) {
+            let filter = Filter::new()
+                .address(vault)
+                .event(&self.signature)
+                .from_block(start)
+                .to_block(end);
+            logs.extend(source.fetch_logs(&filter).await?);
+        }
+        logs.retain(|log| log.address == vault);
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+        logs.iter().map(|log| self.decode(log)).collect()
+    }
+}
+
+/// `amount` streamed over the `duration` seconds after the timestamp of `block_number`, all at
+/// once for a duration of zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopUp {
+    pub block_number: U64,
+    pub amount: U256,
+    pub duration: u64,
+}
+
+/// A top-up over `[start, end)` in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stream {
+    start: u64,
+    end: u64,
+    amount: U256,
+}
+
+impl Stream {
+    /// The part of the amount streamed by `timestamp`, rounded down until the stream ends.
+    fn streamed_by(&self, timestamp: u64) -> U256 {
+        if timestamp >= self.end {
+            self.amount
+        } else if timestamp <= self.start {
+            U256::zero()
+        } else {
+            self.amount * (timestamp - self.start) / (self.end - self.start)
+        }
+    }
+}
+
+/// Top-ups as streams in time, indexed so that the amount streamed by a timestamp takes two
+/// binary searches and a pass over the streams that started less than the longest duration
+/// before it. Emission between two blocks is the difference of the amounts streamed by their
+/// timestamps, so the emission of consecutive intervals adds up to each amount exactly.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopUps {
+    /// By start
+    streams: Vec<Stream>,
+    /// Every end, in order, and the amounts of the streams ending up to each, from zero
+    ends: Vec<u64>,
+    ended: Vec<U256>,
+    longest: u64,
+}
+
+impl TopUps {
+    /// Starts every top-up at the timestamp of its block, which `timestamps` must hold.
+    pub fn new(top_ups: &[TopUp], timestamps: &TimestampCache) -> Result<TopUps> {
+        let mut streams = top_ups
+            .iter()
+            .map(|top_up| {
+                let start = timestamps.get(top_up.block_number).ok_or_else(|| {
+                    Error::config(format!(
+                        "the timestamp of top-up block {} is unknown",
+                        top_up.block_number
+                    ))
+                })?;
+                Ok(Stream {
+                    start,
+                    end: start.saturating_add(top_up.duration),
+                    amount: top_up.amount,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        streams.sort_by_key(|stream| stream.start);
+
+        let mut by_end: Vec<_> = streams
+            .iter()
+            .map(|stream| (stream.end, stream.amount))
+            .collect();
+        by_end.sort_by_key(|(end, _)| *end);
+        let mut ended = vec![U256::zero()];
+        for (_, amount) in &by_end {
+            ended.push(ended[ended.len() - 1] + amount);
+        }
+
+        Ok(TopUps {
+            longest: streams
+                .iter()
+                .map(|stream| stream.end - stream.start)
+                .max()
+                .unwrap_or_default(),
+            ends: by_end.into_iter().map(|(end, _)| end).collect(),
+            ended,
+            streams,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Amount of every top-up that started by `timestamp`, streamed or not.
+    pub fn added_by(&self, timestamp: u64) -> U256 {
+        self.streams
+            .iter()
+            .take_while(|stream| stream.start <= timestamp)
+            .fold(U256::zero(), |sum, stream| sum + stream.amount)
+    }
+
+    /// Amount streamed by `timestamp` across every top-up.
+    pub fn streamed_by(&self, timestamp: u64) -> U256 {
+        let ended = self.ended[self.ends.partition_point(|end| *end <= timestamp)];
+        // a stream still running started less than the longest duration before
+        let first = self
+            .streams
+            .partition_point(|stream| stream.start < timestamp.saturating_sub(self.longest));
+        let running = self.streams[first..]
+            .iter()
+            .take_while(|stream| stream.start < timestamp)
+            .filter(|stream| stream.end > timestamp)
+            .fold(U256::zero(), |sum, stream| {
+                sum + stream.streamed_by(timestamp)
+            });
+
+        ended + running
+    }
+}
+
+/// The top-ups added by an evaluation block against the rewards attributed from them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopUpReconciliation {
+    pub from_block: U64,
+    pub to_block: U64,
+    /// Amount of every top-up started by `to_block`
+    pub added: U256,
+    /// Part of `added` streamed after `to_block`
+    pub streaming: U256,
+    /// Streamed over `(from_block, to_block]`, the added amounts pro-rated to the block
+    pub streamed: U256,
+    /// Part of `streamed` credited to staked shares
+    pub attributed: U256,
+}
+
+impl TopUpReconciliation {
+    /// Streamed but credited to no one, for the blocks of an empty pool and rounding.
+    pub fn unattributed(&self) -> U256 {
+        self.streamed.saturating_sub(self.attributed)
+    }
+}
+
+impl fmt::Display for TopUpReconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ether = |amount| format_ether_rounded(amount, 18);
+        write!(
+            f,
+            "top-ups: {} added by block {}, {} of it still streaming; {} streamed over blocks \
+             {}-{}, {} attributed, {} unattributed",
+            ether(self.added),
+            self.to_block,
+            ether(self.streaming),
+            ether(self.streamed),
+            self.from_block,
+            self.to_block,
+            ether(self.attributed),
+            ether(self.unattributed())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::mock::{custom_log, deposit_log, MockSource};
+    use crate::fetch::{decode_logs, Decoded};
+    use crate::flavor::VaultFlavor;
+    use crate::state::{Emission, GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use ethers::utils::parse_ether;
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+
+    fn ether(value: &str) -> U256 {
+        parse_ether(value).unwrap()
+    }
+
+    /// Blocks of the deploy block plus `offset`, twelve seconds apart.
+    fn timestamps(offsets: impl IntoIterator<Item = u64>) -> TimestampCache {
+        let mut timestamps = TimestampCache::new();
+        for offset in offsets {
+            timestamps.insert(U64::from(BLOCK_CONTRACT_DEPLOYED + offset), offset * 12);
+        }
+        timestamps
+    }
+
+    #[test]
+    fn stacks_overlapping_streams_like_a_sum_over_each() {
+        let top_up = |offset: u64, amount: u64, duration: u64| TopUp {
+            block_number: U64::from(BLOCK_CONTRACT_DEPLOYED + offset),
+            amount: U256::from(amount),
+            duration,
+        };
+        // overlapping, nested, back to back, instant and far longer than the rest
+        let added = [
+            top_up(10, 1_000, 600),
+            top_up(20, 777, 120),
+            top_up(60, 500, 0),
+            top_up(60, 333, 7),
+            top_up(70, 10_000, 1_200),
+            top_up(200, 90, 10_000),
+        ];
+        let top_ups = TopUps::new(
+            &added,
+            &timestamps(added.map(|t| t.block_number.as_u64() - BLOCK_CONTRACT_DEPLOYED)),
+        )
+        .unwrap();
+        let streams: Vec<_> = added
+            .iter()
+            .map(|top_up| {
+                let start = (top_up.block_number.as_u64() - BLOCK_CONTRACT_DEPLOYED) * 12;
+                Stream {
+                    start,
+                    end: start + top_up.duration,
+                    amount: top_up.amount,
+                }
+            })
+            .collect();
+
+        for timestamp in 0..13_000 {
+            let naive = streams.iter().fold(U256::zero(), |sum, stream| {
+                sum + stream.streamed_by(timestamp)
+            });
+            assert_eq!(top_ups.streamed_by(timestamp), naive, "at {}", timestamp);
+        }
+        assert_eq!(top_ups.streamed_by(600), U256::from(800 + 777));
+        assert_eq!(top_ups.added_by(600), U256::from(1_000 + 777));
+        assert_eq!(top_ups.streamed_by(u64::MAX), U256::from(12_700));
+    }
+
+    #[tokio::test]
+    async fn emits_top_ups_streaming_past_the_evaluation_block_pro_rated() {
+        let vault: Address = VAULT.parse().unwrap();
+        let bob = Address::from_low_u64_be(0xb0b);
+        let block = |offset: u64| BLOCK_CONTRACT_DEPLOYED + offset;
+        // amount and duration swapped around by a custom signature
+        let signature = "RewardsTopUp(uint256,uint256,uint256)";
+        let reward = |amount, seconds: u64, offset| {
+            custom_log(
+                signature,
+                &[],
+                &[U256::from(seconds), U256::zero(), amount],
+                block(offset),
+            )
+        };
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, ether("1"), block(0)),
+                // 100 over 1200 seconds, 100 blocks, and 60 over the next 50 blocks
+                reward(ether("100"), 1_200, 0),
+                reward(ether("60"), 600, 50),
+                // half streamed by the evaluation block
+                reward(ether("40"), 2_400, 100),
+            ],
+        };
+        let flavor = VaultFlavor::from_toml(&format!(
+            "name = \"topped-up\"\n\
+             [[events]]\nsignature = \"Deposit(address,address,uint256,uint256)\"\n\
+             kind = \"deposit\"\naddress_topic = 2\nshares_word = 1\n\
+             [top_ups]\nsignature = \"{}\"\namount_word = 2\nduration_word = 0\n",
+            signature
+        ))
+        .unwrap();
+        assert_eq!(flavor.emission().unwrap(), Emission::TopUps);
+
+        let config = flavor.top_ups.as_ref().unwrap();
+        let added = config
+            .fetch(&source, vault, block(0), block(300))
+            .await
+            .unwrap();
+        assert_eq!(added.len(), 3);
+        let Decoded { events, .. } = decode_logs(&flavor, &source.logs);
+
+        let timestamps = timestamps([0, 50, 100, 200]);
+        let mut state = GlobalState::new();
+        state.set_top_ups(TopUps::new(&added, &timestamps).unwrap());
+        state.set_emission(Emission::TopUps, timestamps);
+        state.process_events(events).unwrap();
+
+        let at = U64::from(block(200));
+        // half of the first by block 50, the rest of it and all of the second by block 100
+        assert_eq!(state.total_emission(U64::from(block(50))), ether("50"));
+        assert_eq!(state.total_emission(U64::from(block(100))), ether("160"));
+        assert_eq!(state.total_emission(at), ether("180"));
+        assert_eq!(state.preview_user_rewards(bob, at), ether("180"));
+
+        let reconciliation = state.top_up_reconciliation(at).unwrap();
+        assert_eq!(
+            reconciliation,
+            TopUpReconciliation {
+                from_block: U64::from(block(0)),
+                to_block: at,
+                added: ether("200"),
+                streaming: ether("20"),
+                streamed: ether("180"),
+                attributed: ether("180"),
+            }
+        );
+        assert_eq!(reconciliation.unattributed(), U256::zero());
+    }
+}
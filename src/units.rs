@@ -0,0 +1,218 @@
+use ethers::{core::types::U256, utils::parse_ether};
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, AddAssign, Mul, Sub, SubAssign};
+
+/// A number of vault shares. Shares only add to shares, never to rewards:
+///
+/// ```compile_fail
+/// use ethers::core::types::U256;
+/// use oprtc_calculator::prelude::*;
+///
+/// let _ = Shares(U256::one()) + RewardWei(U256::one());
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Shares(pub U256);
+
+/// Rewards per share, scaled by 1e18.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RewardPerShare(pub U256);
+
+/// Rewards still carrying the 1e18 scale of `RewardPerShare`, which `to_wei` divides out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Rewards(pub U256);
+
+/// Rewards in whole wei of the reward token, as paid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RewardWei(pub U256);
+
+impl Shares {
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl RewardPerShare {
+    /// `rewards` wei spread evenly over `shares`.
+    pub fn spread(rewards: U256, shares: Shares) -> RewardPerShare {
+        RewardPerShare(rewards * parse_ether("1").unwrap() / shares.0)
+    }
+}
+
+impl Rewards {
+    pub fn to_wei(self) -> RewardWei {
+        RewardWei(self.0 / parse_ether("1").unwrap())
+    }
+}
+
+impl RewardWei {
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl Add for Shares {
+    type Output = Shares;
+
+    fn add(self, rhs: Shares) -> Shares {
+        Shares(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Shares {
+    type Output = Shares;
+
+    fn sub(self, rhs: Shares) -> Shares {
+        Shares(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for Shares {
+    fn add_assign(&mut self, rhs: Shares) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Shares {
+    fn sub_assign(&mut self, rhs: Shares) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Add for RewardPerShare {
+    type Output = RewardPerShare;
+
+    fn add(self, rhs: RewardPerShare) -> RewardPerShare {
+        RewardPerShare(self.0 + rhs.0)
+    }
+}
+
+impl Sub for RewardPerShare {
+    type Output = RewardPerShare;
+
+    fn sub(self, rhs: RewardPerShare) -> RewardPerShare {
+        RewardPerShare(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for RewardPerShare {
+    fn add_assign(&mut self, rhs: RewardPerShare) {
+        self.0 += rhs.0;
+    }
+}
+
+/// The only way to get rewards: a per-share increase held by some shares.
+impl Mul<Shares> for RewardPerShare {
+    type Output = Rewards;
+
+    fn mul(self, rhs: Shares) -> Rewards {
+        Rewards(self.0 * rhs.0)
+    }
+}
+
+impl Add for Rewards {
+    type Output = Rewards;
+
+    fn add(self, rhs: Rewards) -> Rewards {
+        Rewards(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Rewards {
+    fn add_assign(&mut self, rhs: Rewards) {
+        self.0 += rhs.0;
+    }
+}
+
+impl Add for RewardWei {
+    type Output = RewardWei;
+
+    fn add(self, rhs: RewardWei) -> RewardWei {
+        RewardWei(self.0 + rhs.0)
+    }
+}
+
+impl Sub for RewardWei {
+    type Output = RewardWei;
+
+    fn sub(self, rhs: RewardWei) -> RewardWei {
+        RewardWei(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for RewardWei {
+    fn add_assign(&mut self, rhs: RewardWei) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for RewardWei {
+    fn sub_assign(&mut self, rhs: RewardWei) {
+        self.0 -= rhs.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::marker::PhantomData;
+
+    // Autoref probe: `Probe<A, B>` has `can_add` from `Addable` when `A: Add<B>`, and method
+    // resolution only falls back to `&Probe`'s `NotAddable` otherwise.
+    struct Probe<A, B>(PhantomData<(A, B)>);
+
+    trait Addable {
+        fn can_add(&self) -> bool {
+            true
+        }
+    }
+
+    impl<A: Add<B>, B> Addable for Probe<A, B> {}
+
+    trait NotAddable {
+        fn can_add(&self) -> bool {
+            false
+        }
+    }
+
+    impl<A, B> NotAddable for &Probe<A, B> {}
+
+    macro_rules! can_add {
+        ($a:ty, $b:ty) => {
+            (&Probe::<$a, $b>(PhantomData)).can_add()
+        };
+    }
+
+    #[test]
+    fn units_only_combine_where_they_make_sense() {
+        assert!(can_add!(Shares, Shares));
+        assert!(can_add!(RewardPerShare, RewardPerShare));
+        assert!(can_add!(Rewards, Rewards));
+        assert!(can_add!(RewardWei, RewardWei));
+        assert!(can_add!(U256, U256));
+
+        assert!(!can_add!(Shares, RewardPerShare));
+        assert!(!can_add!(RewardPerShare, Shares));
+        assert!(!can_add!(Rewards, Shares));
+        assert!(!can_add!(Rewards, RewardPerShare));
+        assert!(!can_add!(Shares, U256));
+        assert!(!can_add!(Shares, RewardWei));
+        assert!(!can_add!(RewardWei, Rewards));
+    }
+
+    #[test]
+    fn accrual_matches_the_raw_arithmetic() {
+        let one = parse_ether("1").unwrap();
+        let emitted = parse_ether("7").unwrap();
+        let total = U256::from(3) * one;
+        let held = U256::from(2) * one;
+
+        let increase = RewardPerShare::spread(emitted, Shares(total));
+        assert_eq!(increase.0, emitted * one / total);
+
+        let earned = (RewardPerShare::default() + increase) * Shares(held) + Rewards(one);
+        assert_eq!(
+            earned.to_wei().0,
+            (emitted * one / total * held + one) / one
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use crate::fetch::chunk_grid;
+use crate::freshness::Freshness;
+use crate::output::{csv_preamble, markdown_preamble, serialize_u256, stamped_json, OutputFormat};
+use crate::state::{Distribution, GlobalState};
+use ethers::core::types::{U256, U64};
+use serde::Serialize;
+
+/// Blocks per bucket unless given.
+pub const BUCKET_SIZE: u64 = 1_000;
+
+/// Emission of the blocks `[from_block, to_block]` and how much of it staked shares received.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UtilizationBucket {
+    pub from_block: u64,
+    pub to_block: u64,
+    #[serde(serialize_with = "serialize_u256")]
+    pub emitted: U256,
+    #[serde(serialize_with = "serialize_u256")]
+    pub attributed: U256,
+}
+
+impl UtilizationBucket {
+    /// `attributed / emitted`, 1 for a bucket that emitted nothing.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.attributed, self.emitted)
+    }
+}
+
+pub fn ratio(attributed: U256, emitted: U256) -> f64 {
+    if emitted.is_zero() {
+        return 1.0;
+    }
+    let wei = |amount: U256| amount.to_string().parse::<f64>().unwrap();
+    wei(attributed) / wei(emitted)
+}
+
+/// Splits the emission the state accounted up to `block_number` into buckets of `bucket`
+/// blocks, aligned like the chunk grid. The state must have had distribution tracking enabled
+/// from the start. An interval spanning several buckets is shared between them by block count,
+/// which is exact under per-block emission. Blocks of an empty pool count as attributed once
+/// the shares ending the gap are credited with their emission, so only rounding and a pool
+/// still empty at `block_number` leave a bucket short.
+pub fn utilization_series(
+    state: &GlobalState,
+    block_number: U64,
+    bucket: u64,
+) -> Vec<UtilizationBucket> {
+    let pending = state.pending_distribution(block_number);
+    let intervals: Vec<Distribution> = state
+        .distributions()
+        .iter()
+        .copied()
+        .chain(Some(pending).filter(|pending| pending.to_block > pending.from_block))
+        .collect();
+    let Some(first) = intervals.first() else {
+        return vec![];
+    };
+
+    let mut buckets: Vec<UtilizationBucket> = chunk_grid(
+        first.from_block.as_u64() + 1,
+        pending.to_block.as_u64(),
+        bucket,
+    )
+    .into_iter()
+    .map(|(from_block, to_block)| UtilizationBucket {
+        from_block,
+        to_block,
+        emitted: U256::zero(),
+        attributed: U256::zero(),
+    })
+    .collect();
+
+    let mut current = 0;
+    for interval in intervals {
+        let (from_block, to_block) = (interval.from_block.as_u64(), interval.to_block.as_u64());
+        let blocks = U256::from(to_block - from_block);
+        let (mut emitted, mut attributed) = (interval.emitted, interval.attributed);
+
+        let mut start = from_block + 1;
+        while start <= to_block {
+            let bucket = &mut buckets[current];
+            let end = bucket.to_block.min(to_block);
+            if end == to_block {
+                // the last part takes what the divisions rounded off
+                bucket.emitted += emitted;
+                bucket.attributed += attributed;
+            } else {
+                let share = |amount: U256| amount * (end - start + 1) / blocks;
+                let (part_emitted, part_attributed) =
+                    (share(interval.emitted), share(interval.attributed));
+                bucket.emitted += part_emitted;
+                bucket.attributed += part_attributed;
+                emitted -= part_emitted;
+                attributed -= part_attributed;
+            }
+            if end == bucket.to_block {
+                current += 1;
+            }
+            start = end + 1;
+        }
+    }
+
+    buckets
+}
+
+/// The bucket with the lowest utilization, the earliest among equals.
+pub fn worst_bucket(buckets: &[UtilizationBucket]) -> Option<&UtilizationBucket> {
+    buckets.iter().reduce(|worst, bucket| {
+        if bucket.ratio() < worst.ratio() {
+            bucket
+        } else {
+            worst
+        }
+    })
+}
+
+pub fn render_utilization(
+    buckets: &[UtilizationBucket],
+    format: OutputFormat,
+    freshness: &Freshness,
+) -> String {
+    let columns = [
+        "from_block",
+        "to_block",
+        "emitted",
+        "attributed",
+        "utilization_pct",
+    ];
+
+    let cells = |bucket: &UtilizationBucket| {
+        [
+            bucket.from_block.to_string(),
+            bucket.to_block.to_string(),
+            bucket.emitted.to_string(),
+            bucket.attributed.to_string(),
+            format!("{:.4}", bucket.ratio() * 100.0),
+        ]
+    };
+
+    match format {
+        OutputFormat::Json => stamped_json(buckets, freshness),
+        OutputFormat::Csv => {
+            let mut out = csv_preamble(freshness);
+            out += &(columns.join(",") + "\n");
+            for bucket in buckets {
+                out += &(cells(bucket).join(",") + "\n");
+            }
+            out
+        }
+        OutputFormat::Markdown => {
+            let mut out = markdown_preamble(freshness);
+            out += &format!("| {} |\n", columns.join(" | "));
+            out += &format!("|{}\n", "---|".repeat(columns.len()));
+            for bucket in buckets {
+                out += &format!("| {} |\n", cells(bucket).join(" | "));
+            }
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Deposit, Event, Withdraw, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::{core::types::Address, utils::parse_ether};
+
+    #[test]
+    fn carries_a_mid_program_gap_and_leaves_a_trailing_one_unallocated() {
+        let bob = Address::from_low_u64_be(0xb0b);
+        let alice = Address::from_low_u64_be(0xa11ce);
+        let one = parse_ether("1").unwrap();
+        let deposit = |address, block: u64| {
+            Event::Deposit(Deposit {
+                address,
+                shares: Shares(one),
+                block_number: U64::from(block),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+        let withdraw = |address, block: u64| {
+            Event::Withdrawal(Withdraw {
+                address,
+                shares: Shares(one),
+                block_number: U64::from(block),
+                transaction_index: 0,
+                log_index: 0,
+            })
+        };
+
+        // the pool is empty over (17565500, 17566500] and again from 17567500 on
+        let mut state = GlobalState::new();
+        state.set_distribution_tracking(true);
+        state
+            .process_events(vec![
+                deposit(bob, BLOCK_CONTRACT_DEPLOYED),
+                withdraw(bob, 17_565_500),
+                deposit(alice, 17_566_500),
+                withdraw(alice, 17_567_500),
+            ])
+            .unwrap();
+        let buckets = utilization_series(&state, U64::from(17_568_999), 1_000);
+
+        let rows: Vec<_> = buckets
+            .iter()
+            .map(|bucket| (bucket.from_block, bucket.emitted / one, bucket.ratio()))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (BLOCK_CONTRACT_DEPLOYED + 1, U256::from(336), 1.0),
+                (17_565_000, U256::from(1_000), 1.0),
+                (17_566_000, U256::from(1_000), 1.0),
+                (17_567_000, U256::from(1_000), 0.501),
+                (17_568_000, U256::from(1_000), 0.0),
+            ]
+        );
+        assert_eq!(worst_bucket(&buckets).unwrap().from_block, 17_568_000);
+
+        let total = state.total_distribution(U64::from(17_568_999));
+        assert_eq!(total.emitted, one * 4_336);
+        assert_eq!(total.attributed, one * 2_837);
+        let summed = buckets
+            .iter()
+            .fold(U256::zero(), |sum, bucket| sum + bucket.attributed);
+        assert_eq!(summed, total.attributed);
+
+        let csv = render_utilization(&buckets, OutputFormat::Csv, &Freshness::offline(17_568_999));
+        assert!(csv.contains(&format!(
+            "17567000,17567999,{},{},50.1000\n",
+            one * 1_000,
+            one * 501
+        )));
+    }
+
+    #[test]
+    fn rounding_leaves_dust_unattributed() {
+        let mut state = GlobalState::new();
+        state.set_distribution_tracking(true);
+        // 3 wei of shares can't take an even split of whole ether
+        state
+            .process_events(vec![Event::Deposit(Deposit {
+                address: Address::from_low_u64_be(0xb0b),
+                shares: Shares(U256::from(3)),
+                block_number: U64::from(BLOCK_CONTRACT_DEPLOYED),
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap();
+
+        let total = state.total_distribution(U64::from(BLOCK_CONTRACT_DEPLOYED + 1));
+        assert_eq!(total.emitted, parse_ether("1").unwrap());
+        assert_eq!(total.emitted - total.attributed, U256::one());
+    }
+}
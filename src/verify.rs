@@ -0,0 +1,281 @@
+use crate::clock::Clock;
+use crate::fetch::{fetch_events, LogSource};
+use crate::flavor::VaultFlavor;
+use crate::state::{Event, GlobalState};
+use ethers::core::types::{Address, U64};
+use eyre::Result;
+use std::collections::BTreeSet;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+use std::time::Duration;
+
+/// Accounting state shared with readers, paired with the block it is synced to. Writers replace
+/// both under the write lock, so readers never observe a half-applied update.
+#[derive(Debug)]
+pub struct LiveState {
+    pub state: GlobalState,
+    pub synced_block: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    /// `None` when only the global accumulators differ
+    pub address: Option<Address>,
+    /// Last block at which the clean replay saw an event touching the address
+    pub block_number: U64,
+}
+
+/// Finds the lowest address whose shares differ between the two states, falling back to the
+/// lowest address whose rewards at `block_number` differ.
+pub fn find_divergence(
+    live: &GlobalState,
+    expected: &GlobalState,
+    events: &[Event],
+    block_number: U64,
+) -> Divergence {
+    let addresses: BTreeSet<Address> = live.users().chain(expected.users()).copied().collect();
+
+    let address = addresses
+        .iter()
+        .find(|address| live.shares_of(**address) != expected.shares_of(**address))
+        .or_else(|| {
+            addresses.iter().find(|address| {
+                live.preview_user_rewards(**address, block_number)
+                    != expected.preview_user_rewards(**address, block_number)
+            })
+        })
+        .copied();
+
+    let last_touched = address.and_then(|address| {
+        events
+            .iter()
+            .rev()
+            .find(|evt| evt.involves(address))
+            .map(|evt| evt.block_number())
+    });
+
+    Divergence {
+        address,
+        block_number: last_touched.unwrap_or(block_number),
+    }
+}
+
+/// Periodically replays a clean fetch of the vault history and checks it against the live
+/// state, optionally swapping the clean replay in when they disagree.
+pub struct Verifier<S> {
+    source: S,
+    flavor: VaultFlavor,
+    vault: Address,
+    from_block: u64,
+    live: Arc<RwLock<LiveState>>,
+    repair: bool,
+    mismatches: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: LogSource> Verifier<S> {
+    pub fn new(
+        source: S,
+        flavor: VaultFlavor,
+        vault: Address,
+        from_block: u64,
+        live: Arc<RwLock<LiveState>>,
+        repair: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Verifier<S> {
+        Verifier {
+            source,
+            flavor,
+            vault,
+            from_block,
+            live,
+            repair,
+            mismatches: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    pub async fn verify_once(&self) -> Result<Option<Divergence>> {
+        let synced_block = self.live.read().unwrap().synced_block;
+
+        let events = fetch_events(
+            &self.source,
+            &self.flavor,
+            self.vault,
+            self.from_block,
+            synced_block,
+        )
+        .await?
+        .strict()?;
+        let mut expected = GlobalState::deployed_at(self.from_block);
+        expected.process_events(events.clone())?;
+
+        let divergence = {
+            let live = self.live.read().unwrap();
+
+            // the live state moved on while we were fetching, check again next round
+            if live.synced_block != synced_block || live.state.state_hash() == expected.state_hash()
+            {
+                return Ok(None);
+            }
+
+            find_divergence(&live.state, &expected, &events, U64::from(synced_block))
+        };
+
+        let mismatches = self.mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "live state diverged from a clean replay up to block {}: address {:?}, last touched at block {} (mismatches: {})",
+            synced_block, divergence.address, divergence.block_number, mismatches
+        );
+
+        if self.repair {
+            let mut live = self.live.write().unwrap();
+            if live.synced_block == synced_block {
+                live.state = expected;
+                eprintln!("replaced live state with the clean replay");
+            }
+        }
+
+        Ok(Some(divergence))
+    }
+
+    /// Verifies every `interval` of the clock until the task is dropped. Fetch errors are logged
+    /// and retried on the next round.
+    pub async fn run(&self, interval: Duration) {
+        loop {
+            if let Err(err) = self.verify_once().await {
+                eprintln!("verification failed: {:?}", err);
+            }
+
+            self.clock.sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{manual::ManualClock, TokioClock};
+    use crate::fetch::mock::{deposit_log, transfer_log, MockSource};
+    use crate::state::{Deposit, BLOCK_CONTRACT_DEPLOYED};
+    use crate::units::Shares;
+    use ethers::utils::parse_ether;
+
+    const VAULT: &str = "0xaF53431488E871D103baA0280b6360998F0F9926";
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    /// A verifier of a live state crediting alice with shares that were never minted.
+    async fn corrupted_verifier(
+        repair: bool,
+        clock: Arc<dyn Clock>,
+    ) -> (Verifier<MockSource>, Arc<RwLock<LiveState>>) {
+        let vault = VAULT.parse().unwrap();
+        let bob = BOB.parse().unwrap();
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let flavor = VaultFlavor::oprtc_v1();
+        let from_block = BLOCK_CONTRACT_DEPLOYED;
+        let synced_block = from_block + 50;
+
+        let source = MockSource {
+            logs: vec![
+                deposit_log(bob, one * 4, from_block),
+                deposit_log(alice, one, from_block + 10),
+                transfer_log(bob, alice, one, from_block + 20),
+            ],
+        };
+
+        let mut state = GlobalState::new();
+        state
+            .process_events(
+                fetch_events(&source, &flavor, vault, from_block, synced_block)
+                    .await
+                    .unwrap()
+                    .events,
+            )
+            .unwrap();
+
+        state
+            .process_events(vec![Event::Deposit(Deposit {
+                address: alice,
+                shares: Shares(one),
+                block_number: U64::from(from_block + 30),
+                transaction_index: 0,
+                log_index: 0,
+            })])
+            .unwrap();
+
+        let live = Arc::new(RwLock::new(LiveState {
+            state,
+            synced_block,
+        }));
+        let verifier = Verifier::new(
+            source,
+            flavor,
+            vault,
+            from_block,
+            live.clone(),
+            repair,
+            clock,
+        );
+        (verifier, live)
+    }
+
+    #[tokio::test]
+    async fn detects_and_repairs_corrupted_live_state() {
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let (verifier, live) = corrupted_verifier(true, Arc::new(TokioClock)).await;
+
+        let divergence = verifier.verify_once().await.unwrap().unwrap();
+        assert_eq!(divergence.address, Some(alice));
+        assert_eq!(
+            divergence.block_number,
+            U64::from(BLOCK_CONTRACT_DEPLOYED + 20)
+        );
+        assert_eq!(verifier.mismatches.load(Ordering::Relaxed), 1);
+
+        assert_eq!(live.read().unwrap().state.shares_of(alice), one * 2);
+        assert_eq!(verifier.verify_once().await.unwrap(), None);
+        assert_eq!(verifier.mismatches.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn verifies_once_per_interval_of_the_injected_clock() {
+        const INTERVAL: Duration = Duration::from_secs(3600);
+        let clock = ManualClock::default();
+        let (verifier, _) = corrupted_verifier(false, Arc::new(clock.clone())).await;
+        let verifier = Arc::new(verifier);
+        let rounds = || verifier.mismatches.load(Ordering::Relaxed);
+        // lets the spawned loop run until it waits on the clock again
+        let settle = || async {
+            for _ in 0..100 {
+                tokio::task::yield_now().await;
+            }
+        };
+
+        let running = tokio::spawn({
+            let verifier = verifier.clone();
+            async move { verifier.run(INTERVAL).await }
+        });
+        settle().await;
+        assert_eq!(rounds(), 1);
+
+        clock.advance(INTERVAL - Duration::from_secs(1));
+        settle().await;
+        assert_eq!(rounds(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        settle().await;
+        assert_eq!(rounds(), 2);
+
+        clock.advance(INTERVAL * 2);
+        settle().await;
+        // a late tick runs one round, not the ones it missed
+        assert_eq!(rounds(), 3);
+        running.abort();
+    }
+}
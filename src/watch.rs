@@ -0,0 +1,250 @@
+use crate::clock::{Clock, Head, HeadSource};
+use crate::fetch::{fetch_events, LogSource};
+use crate::flavor::VaultFlavor;
+use crate::verify::LiveState;
+use ethers::core::types::Address;
+use eyre::Result;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Longest wait between retries of a failing sync.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Keeps a live state up with the chain head, applying the events of every block after the one
+/// it is synced to. Each sync starts right after the last one ended, so no block is skipped or
+/// applied twice, whenever the head is polled.
+pub struct Watcher<S, H> {
+    source: S,
+    head: H,
+    flavor: VaultFlavor,
+    vault: Address,
+    live: Arc<RwLock<LiveState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: LogSource, H: HeadSource> Watcher<S, H> {
+    pub fn new(
+        source: S,
+        head: H,
+        flavor: VaultFlavor,
+        vault: Address,
+        live: Arc<RwLock<LiveState>>,
+        clock: Arc<dyn Clock>,
+    ) -> Watcher<S, H> {
+        Watcher {
+            source,
+            head,
+            flavor,
+            vault,
+            live,
+            clock,
+        }
+    }
+
+    /// Applies the events up to the current head, returning the block synced to when it moved.
+    /// A failed sync leaves the live state as it was.
+    pub async fn sync_once(&self) -> Result<Option<u64>> {
+        let (mut state, synced_block) = {
+            let live = self.live.read().unwrap();
+            (live.state.clone(), live.synced_block)
+        };
+        let head = self.head.head(Head::Latest).await?;
+        // a node behind the last sync has nothing new
+        if head <= synced_block {
+            return Ok(None);
+        }
+
+        let events = fetch_events(
+            &self.source,
+            &self.flavor,
+            self.vault,
+            synced_block + 1,
+            head,
+        )
+        .await?
+        .strict()?;
+        state.try_process_events(events)?;
+
+        let mut live = self.live.write().unwrap();
+        eyre::ensure!(
+            live.synced_block == synced_block,
+            "the live state moved past block {} during the sync",
+            synced_block
+        );
+        *live = LiveState {
+            state,
+            synced_block: head,
+        };
+        Ok(Some(head))
+    }
+
+    /// Syncs every `interval` until the task is dropped, calling `on_update` after each sync
+    /// that moved the state. A failed sync is logged and retried after twice the last wait, up
+    /// to `MAX_BACKOFF`, and the interval is back once one succeeds.
+    pub async fn run(&self, interval: Duration, mut on_update: impl FnMut(&LiveState)) {
+        let mut wait = interval;
+        loop {
+            self.clock.sleep(wait).await;
+
+            match self.sync_once().await {
+                Ok(synced) => {
+                    wait = interval;
+                    if synced.is_some() {
+                        on_update(&self.live.read().unwrap());
+                    }
+                }
+                Err(err) => {
+                    wait = (wait * 2).min(MAX_BACKOFF.max(interval));
+                    eprintln!("sync failed, retrying in {:?}: {:?}", wait, err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::manual::{ManualClock, ManualHead};
+    use crate::fetch::mock::{deposit_log, transfer_log, MockSource, VAULT};
+    use crate::state::{GlobalState, BLOCK_CONTRACT_DEPLOYED};
+    use ethers::core::types::{Filter, Log, U64};
+    use ethers::utils::parse_ether;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    const BOB: &str = "0x0000000000000000000000000000000000000B0b";
+    const ALICE: &str = "0x00000000000000000000000000000000000A11cE";
+
+    /// Logs tests add as blocks are mined, failing every call while down.
+    #[derive(Clone, Default)]
+    struct Chain {
+        logs: Arc<Mutex<Vec<Log>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl LogSource for Chain {
+        async fn fetch_logs(&self, filter: &Filter) -> crate::error::Result<Vec<Log>> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(crate::error::Error::rpc("provider is down"));
+            }
+            let source = MockSource {
+                logs: self.logs.lock().unwrap().clone(),
+            };
+            source.fetch_logs(filter).await
+        }
+    }
+
+    /// Lets the spawned watcher run until it waits on the clock again.
+    async fn settle() {
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Moves the clock a second at a time, letting the watcher run after each step.
+    async fn elapse(clock: &ManualClock, seconds: u64) {
+        for _ in 0..seconds {
+            clock.advance(Duration::from_secs(1));
+            settle().await;
+        }
+    }
+
+    fn watcher(
+        chain: &Chain,
+        head: &ManualHead,
+        clock: &ManualClock,
+    ) -> Watcher<Chain, ManualHead> {
+        let live = Arc::new(RwLock::new(LiveState {
+            state: GlobalState::new(),
+            synced_block: BLOCK_CONTRACT_DEPLOYED,
+        }));
+        Watcher::new(
+            chain.clone(),
+            head.clone(),
+            VaultFlavor::oprtc_v1(),
+            VAULT.parse().unwrap(),
+            live,
+            Arc::new(clock.clone()),
+        )
+    }
+
+    #[tokio::test]
+    async fn applies_every_block_once() {
+        let (chain, head, clock) = (
+            Chain::default(),
+            ManualHead::default(),
+            ManualClock::default(),
+        );
+        let bob = BOB.parse().unwrap();
+        let alice = ALICE.parse().unwrap();
+        let one = parse_ether("1").unwrap();
+        let from = BLOCK_CONTRACT_DEPLOYED;
+        let watcher = watcher(&chain, &head, &clock);
+
+        // the block the live state is synced to is never fetched again
+        chain.logs.lock().unwrap().extend([
+            deposit_log(bob, one * 9, from),
+            deposit_log(bob, one * 2, from + 5),
+        ]);
+        head.set(from + 5, from + 5, from + 5);
+        assert_eq!(watcher.sync_once().await.unwrap(), Some(from + 5));
+        assert_eq!(watcher.sync_once().await.unwrap(), None);
+
+        chain
+            .logs
+            .lock()
+            .unwrap()
+            .push(transfer_log(bob, alice, one, from + 8));
+        head.advance(3);
+        assert_eq!(watcher.sync_once().await.unwrap(), Some(from + 8));
+
+        let live = watcher.live.read().unwrap();
+        assert_eq!(live.state.shares_of(bob), one);
+        assert_eq!(live.state.shares_of(alice), one);
+        assert_eq!(live.state.last_accounted_block(), U64::from(from + 8));
+    }
+
+    #[tokio::test]
+    async fn backs_off_while_the_provider_is_down() {
+        let (chain, head, clock) = (
+            Chain::default(),
+            ManualHead::default(),
+            ManualClock::default(),
+        );
+        let bob = BOB.parse().unwrap();
+        let from = BLOCK_CONTRACT_DEPLOYED;
+        let watcher = watcher(&chain, &head, &clock);
+
+        chain
+            .logs
+            .lock()
+            .unwrap()
+            .push(deposit_log(bob, parse_ether("1").unwrap(), from + 1));
+        head.set(from + 1, from + 1, from + 1);
+        chain.down.store(true, Ordering::Relaxed);
+
+        let updates = Arc::new(Mutex::new(vec![]));
+        let seen = updates.clone();
+        let interval = Duration::from_secs(12);
+        let run = tokio::spawn(async move {
+            watcher
+                .run(interval, |live| {
+                    seen.lock().unwrap().push(live.synced_block)
+                })
+                .await
+        });
+
+        // fails at 12s and waits 24s, fails again at 36s and waits 48s
+        settle().await;
+        elapse(&clock, 36).await;
+        chain.down.store(false, Ordering::Relaxed);
+        elapse(&clock, 47).await;
+        assert!(updates.lock().unwrap().is_empty());
+
+        elapse(&clock, 1).await;
+        assert_eq!(*updates.lock().unwrap(), vec![from + 1]);
+        run.abort();
+    }
+}
@@ -0,0 +1,63 @@
+//! The JSON dump of the command line, which scripts and wrappers read instead of `--help`.
+
+use serde_json::Value;
+use std::process::Command;
+
+fn help_json() -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_oprtc_calculator"))
+        .arg("--help-json")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn arg<'a>(command: &'a Value, long: &str) -> &'a Value {
+    command["args"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|arg| arg["long"] == long)
+        .unwrap_or_else(|| panic!("no --{}", long))
+}
+
+fn subcommand<'a>(command: &'a Value, name: &str) -> &'a Value {
+    command["subcommands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|subcommand| subcommand["name"] == name)
+        .unwrap_or_else(|| panic!("no {} command", name))
+}
+
+#[test]
+fn lists_flags_with_their_defaults() {
+    let cli = help_json();
+
+    let rpc_url = arg(&cli, "rpc-url");
+    assert_eq!(rpc_url["default"][0], "https://rpc.flashbots.net");
+    assert_eq!(rpc_url["env"], "OPRTC_RPC_URL");
+    assert_eq!(rpc_url["global"], true);
+    assert_eq!(rpc_url["kind"], "list");
+    assert_eq!(arg(&cli, "chunk-size")["default"][0], "5000");
+    assert_eq!(arg(&cli, "no-cache")["kind"], "flag");
+
+    let policies = arg(&cli, "accrual-policy")["possible_values"]
+        .as_array()
+        .unwrap();
+    assert!(policies.contains(&Value::from("block-start")));
+}
+
+#[test]
+fn lists_subcommands() {
+    let cli = help_json();
+
+    assert_eq!(
+        arg(subcommand(&cli, "watch"), "interval")["default"][0],
+        "12"
+    );
+    let shells = subcommand(&cli, "completions")["args"][0]["possible_values"]
+        .as_array()
+        .unwrap();
+    assert!(shells.contains(&Value::from("zsh")));
+}
@@ -0,0 +1,97 @@
+//! Runs connected by pipes, `-` standing for standard input and output.
+
+use ethers::core::types::{Address, U256, U64};
+use oprtc_calculator::prelude::*;
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn oprtc() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_oprtc_calculator"))
+}
+
+fn json_cache() -> Vec<u8> {
+    let holder = Address::from_low_u64_be(0xb0b);
+    let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 10);
+    let mut cache = EventCache::new(
+        Address::from_low_u64_be(0x7a),
+        1,
+        BLOCK_CONTRACT_DEPLOYED,
+        BLOCK_CONTRACT_DEPLOYED + 99,
+        100,
+    );
+    cache.completed_chunks = chunk_grid(cache.from_block, cache.to_block, cache.chunk_size)
+        .into_iter()
+        .map(|(start, _)| start)
+        .collect();
+    cache.events = vec![
+        Event::Deposit(Deposit {
+            address: holder,
+            shares: Shares(U256::exp10(18)),
+            block_number,
+            transaction_index: 0,
+            log_index: 0,
+        }),
+        Event::Transfer(Transfer {
+            from: holder,
+            to: Address::from_low_u64_be(0xa11ce),
+            shares: Shares(U256::exp10(17)),
+            block_number: block_number + 5,
+            transaction_index: 0,
+            log_index: 0,
+        }),
+    ];
+    serde_json::to_vec(&cache).unwrap()
+}
+
+/// Feeds `input` to `producer` and its standard output to `consumer`, with no file in between.
+fn pipe(producer: &mut Command, input: &[u8], consumer: &mut Command) -> (Output, Output) {
+    let mut producer = producer
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = producer.stdin.take().unwrap();
+    stdin.write_all(input).unwrap();
+    drop(stdin);
+
+    let consumer = consumer
+        .stdin(producer.stdout.take().unwrap())
+        .output()
+        .unwrap();
+    (producer.wait_with_output().unwrap(), consumer)
+}
+
+#[test]
+fn converted_cache_pipes_into_bisect() {
+    let (convert, bisect) = pipe(
+        oprtc().args(["cache", "convert", "-", "-"]),
+        &json_cache(),
+        oprtc().args(["bisect", "-"]),
+    );
+
+    assert!(convert.status.success(), "{:?}", convert);
+    assert!(bisect.status.success(), "{:?}", bisect);
+    let stdout = String::from_utf8(bisect.stdout).unwrap();
+    assert!(
+        stdout.contains("the replays agree after every block, 2 and 2 events"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn binary_cache_is_refused_on_stdin() {
+    let (convert, bisect) = pipe(
+        oprtc().args(["--cache-format", "binary", "cache", "convert", "-", "-"]),
+        &json_cache(),
+        oprtc().args(["bisect", "-"]),
+    );
+
+    assert!(convert.status.success(), "{:?}", convert);
+    assert!(!bisect.status.success());
+    let stderr = String::from_utf8(bisect.stderr).unwrap();
+    assert!(stderr.contains("binary caches"), "{}", stderr);
+}
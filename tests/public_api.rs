@@ -0,0 +1,561 @@
+//! Snapshot of the public API. Every prelude item is named here with its signature, so a
+//! change that would break a dependent fails to compile in review. Extend it when the API
+//! grows, and treat any other edit as a breaking change.
+
+use ethers::core::types::{Address, Log, H256, U256, U64};
+use oprtc_calculator::{prelude::*, Result};
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    ops::{Add, Mul},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+#[test]
+fn replay_api() {
+    let _: fn() -> GlobalState = GlobalState::new;
+    let _: fn(u64) -> GlobalState = GlobalState::deployed_at;
+    let _: fn(&GlobalState) -> U64 = GlobalState::deployed_block;
+    let _: fn(U256) -> GlobalState = GlobalState::with_rate;
+    let _: fn() -> GlobalState = GlobalState::new_lenient;
+    let _: fn(&GlobalState) -> U256 = GlobalState::rewards_per_block;
+    let _: fn(&mut GlobalState, Vec<Event>) -> Result<(), StateError> = GlobalState::process_events;
+    let _: fn(&mut GlobalState, Vec<Event>) -> Result<()> = GlobalState::try_process_events;
+    let _: fn(&mut GlobalState, &[Log], &VaultFlavor) -> Result<IngestSummary> =
+        GlobalState::ingest_logs;
+    let _ = IngestSummary {
+        logs: 0,
+        duplicates: 0,
+        skipped: 0,
+        undecodable: 0,
+        applied: 0,
+        quarantined: 0,
+    };
+    let _: fn(&mut GlobalState, bool) = GlobalState::set_same_block_netting;
+    let _: fn(&mut GlobalState, bool) = GlobalState::set_coalescing;
+    let _: fn(&mut GlobalState, Option<u64>) = GlobalState::set_min_staking_blocks;
+    let _: fn(&GlobalState) -> Option<u64> = GlobalState::min_staking_blocks;
+    let _: fn(&mut GlobalState, Option<u64>) = GlobalState::set_accumulator_interval;
+    let _: fn(&GlobalState) -> Option<u64> = GlobalState::accumulator_interval;
+    let _: fn(&GlobalState, U64) -> Option<Accumulator> = GlobalState::accumulator_at;
+    let Accumulator {
+        block_number,
+        total_rewards_per_share,
+        total_shares_staked,
+        emission_to_date,
+    } = GlobalState::new()
+        .accumulator_at(U64::zero())
+        .unwrap_or(Accumulator {
+            block_number: U64::zero(),
+            total_rewards_per_share: U256::zero(),
+            total_shares_staked: U256::zero(),
+            emission_to_date: U256::zero(),
+        });
+    let _: (U64, U256, U256, U256) = (
+        block_number,
+        total_rewards_per_share,
+        total_shares_staked,
+        emission_to_date,
+    );
+    let _: fn(U64, U64, usize, u64) -> Vec<U64> = series_blocks;
+    let _: u64 = CHECKPOINT_BLOCKS;
+    let _: fn(&GlobalState) -> &[CoalescedDelta] = GlobalState::coalesced;
+    let _: fn(&GlobalState) -> usize = GlobalState::coalesced_events;
+    let _ = CoalescedDelta {
+        address: Address::zero(),
+        block_number: U64::zero(),
+        events: 0,
+    };
+    let _: fn(&mut GlobalState, AccrualPolicy) = GlobalState::set_accrual_policy;
+    let _: fn(&mut GlobalState, Option<u64>) = GlobalState::set_max_share_multiple;
+    let _: fn(&mut GlobalState, bool) = GlobalState::set_best_effort;
+    let _: fn(&mut GlobalState, Option<usize>) = GlobalState::set_checkpoint_interval;
+    let _: fn(&mut GlobalState, Address, SelfHeldShares) = GlobalState::set_self_held_shares;
+    let _: fn(&mut GlobalState, Emission, TimestampCache) = GlobalState::set_emission;
+    let _: fn(&mut GlobalState, TopUps) = GlobalState::set_top_ups;
+    let _: fn(&GlobalState, U64) -> Option<TopUpReconciliation> =
+        GlobalState::top_up_reconciliation;
+
+    let _: fn(&GlobalState, Address, U64) -> U256 = GlobalState::preview_user_rewards;
+    let _: fn(&GlobalState, U64) -> Vec<(Address, U256)> = GlobalState::get_user_rewards;
+    let _: fn(&GlobalState, U64) -> U256 = GlobalState::get_all_rewards;
+    let _: fn(&GlobalState, U64) -> U256 = GlobalState::protocol_rewards;
+    let _: fn(&GlobalState, U64) -> U256 = GlobalState::total_emission;
+    let _: fn(&GlobalState) -> U256 = GlobalState::total_share_blocks;
+    let _: fn(&GlobalState) -> U256 = GlobalState::total_shares_staked;
+    let _: fn(&GlobalState) -> U256 = GlobalState::total_rewards_per_share;
+    let _: fn(&GlobalState, Address) -> U256 = GlobalState::shares_of;
+    let _: fn(&GlobalState, Address) -> Option<UserView> = GlobalState::user_view;
+    let _: fn(&GlobalState, Address) -> Option<U64> = GlobalState::first_deposit_block;
+    let _: fn(&GlobalState, Address) -> Option<(U64, U64)> = GlobalState::user_active_span;
+    let _: fn(&GlobalState) -> U64 = GlobalState::last_accounted_block;
+    let _: fn(&GlobalState) -> &[Event] = GlobalState::suspicious_events;
+    let _: fn(&GlobalState) -> &[Event] = GlobalState::skipped_events;
+    let _: fn(&GlobalState) -> &[QuarantinedEvent] = GlobalState::quarantined;
+    let _: fn(&mut GlobalState, Vec<Event>, &mut ()) -> Result<(), StateError> =
+        GlobalState::process_events_with;
+    let _: fn(&mut GlobalState, Vec<Event>, &mut ()) -> Result<()> =
+        GlobalState::try_process_events_with;
+    let _: fn(&SubAccounts, &mut GlobalState, Vec<Event>, &mut ()) -> Result<Checkpoints> =
+        SubAccounts::replay_with;
+
+    struct Observer;
+    impl StateObserver for Observer {
+        fn on_event_applied(&mut self, _: &Event, delta: &UserDelta) {
+            for RecordChange {
+                address: _,
+                before,
+                after,
+            } in &delta.changes
+            {
+                let _: Option<&UserView> = before.as_ref();
+                let UserView {
+                    shares: _,
+                    rewards_accumulated: _,
+                    rewards_per_share_snapshot: _,
+                } = after;
+            }
+        }
+
+        fn on_rewards_distributed(&mut self, _: U64, _: U256, _: U256) {}
+
+        fn on_invariant_warning(&mut self, warning: &InvariantWarning) {
+            match warning {
+                InvariantWarning::SuspiciousShares(_) | InvariantWarning::Quarantined(_) => {}
+            }
+        }
+    }
+    GlobalState::new()
+        .process_events_with(vec![], &mut Observer)
+        .unwrap();
+    let _: fn(&[QuarantinedEvent], usize) -> QuarantineSummary = QuarantineSummary::new;
+    let _: fn(&QuarantineSummary) -> bool = QuarantineSummary::is_empty;
+    let quarantined = |quarantined: QuarantinedEvent| {
+        let QuarantinedEvent {
+            event: _,
+            reason,
+            held: _,
+        } = quarantined;
+        let _ = [
+            reason,
+            QuarantineReason::UnknownSender,
+            QuarantineReason::InsufficientShares,
+            QuarantineReason::Undecodable,
+        ];
+    };
+    let _ = quarantined;
+    let _ = QuarantineSummary {
+        events: 0,
+        shares: U256::zero(),
+        undecodable_logs: 0,
+    };
+    let _: fn(&[QuarantinedEvent], &[u64]) -> Option<UnknownSenderBurst> = unknown_sender_burst;
+    let _ = UnknownSenderBurst {
+        from_block: 0,
+        events: 0,
+        total: 0,
+        boundary: None,
+    };
+    let _: fn(&GlobalState) -> &[InvariantCheckpoint] = GlobalState::checkpoints;
+    let _: fn(&GlobalState) -> H256 = GlobalState::state_hash;
+    let _: fn(&mut GlobalState, bool) = GlobalState::set_distribution_tracking;
+    let _: fn(&GlobalState) -> &[Distribution] = GlobalState::distributions;
+    let _: fn(&GlobalState, U64) -> Distribution = GlobalState::pending_distribution;
+    let _: fn(&GlobalState, U64) -> Distribution = GlobalState::total_distribution;
+    let _ = Distribution {
+        from_block: U64::zero(),
+        to_block: U64::one(),
+        emitted: U256::one(),
+        attributed: U256::zero(),
+    };
+
+    let _: fn(&Event) -> U64 = Event::block_number;
+    let _: fn(&Event) -> (U64, u64, u64) = Event::position;
+    let _: fn(&Event) -> Shares = Event::shares;
+    let _: fn(&Event, Address) -> bool = Event::involves;
+    let _: u64 = BLOCK_CONTRACT_DEPLOYED;
+
+    let deposit = Deposit {
+        address: Address::zero(),
+        shares: Shares(U256::one()),
+        block_number: U64::zero(),
+        transaction_index: 0,
+        log_index: 0,
+    };
+    let withdraw = Withdraw {
+        address: Address::zero(),
+        shares: Shares(U256::one()),
+        block_number: U64::zero(),
+        transaction_index: 0,
+        log_index: 0,
+    };
+    let transfer = Transfer {
+        from: Address::zero(),
+        to: Address::zero(),
+        shares: Shares(U256::one()),
+        block_number: U64::zero(),
+        transaction_index: 0,
+        log_index: 0,
+    };
+    let _ = [
+        Event::Deposit(deposit),
+        Event::Withdrawal(withdraw),
+        Event::Transfer(transfer),
+    ];
+    let _ = [
+        Emission::PerBlock,
+        Emission::PerSecond(U256::one()),
+        Emission::TopUps,
+    ];
+    let _ = [
+        SelfHeldShares::Exclude,
+        SelfHeldShares::Include,
+        SelfHeldShares::Separate,
+    ];
+    let _ = [AccrualPolicy::BlockStart, AccrualPolicy::PerEvent];
+
+    let _: fn() -> TimestampCache = TimestampCache::new;
+    let _: fn(&mut TimestampCache, U64, u64) = TimestampCache::insert;
+    let _: fn(&TimestampCache, U64) -> Option<u64> = TimestampCache::get;
+
+    let _: fn(Cursor<Vec<u8>>) -> Result<Vec<Event>> = read_journal;
+    let _: fn(Vec<u8>, &[Event]) -> Result<()> = write_journal;
+    let mut events: JournalEvents<Cursor<Vec<u8>>> = journal_events(Cursor::new(vec![]));
+    let _: Option<Result<(usize, Event)>> = events.next();
+
+    let _: fn(RewardPerShare, Shares) -> Rewards = <RewardPerShare as Mul<Shares>>::mul;
+    let _: fn(U256, Shares) -> RewardPerShare = RewardPerShare::spread;
+    let _: fn(Rewards) -> RewardWei = Rewards::to_wei;
+    let _: fn(Shares, Shares) -> Shares = <Shares as Add>::add;
+    let _: fn(RewardWei, RewardWei) -> RewardWei = <RewardWei as Add>::add;
+}
+
+type SanityCheck =
+    fn(&Report, &PoolShares, &GlobalState, U64, Option<&RewardsFile>, SanityBounds) -> Vec<RedFlag>;
+
+type LayoutCounts = fn(&VaultFlavor, &[Event]) -> Vec<(String, usize)>;
+
+type ChunkFields<'a> = (u32, Address, u64, &'a [H256], u64);
+
+type MissingChunks = fn(&ChunkStore, &[(u64, u64)]) -> Vec<(u64, u64)>;
+
+type RewardsReport =
+    fn(&GlobalState, U64, &Annotations, Option<&str>, (&SubAccounts, &Checkpoints), bool) -> Report;
+
+#[test]
+fn report_api() {
+    let _: RewardsReport = rewards_report;
+    let _: fn(&Report) -> H256 = Report::hash;
+    let _: fn(&Report, U256) -> f64 = Report::pct;
+    let _: fn(&Report, U64) -> String = Report::to_json;
+    let _: fn(&GlobalState, U64, Vec<u8>) -> Result<()> = write_rewards_csv::<Vec<u8>>;
+
+    let report = |report: Report| {
+        let Report {
+            total_rewards_expected: _,
+            total_rewards_given: _,
+            protocol_rewards: _,
+            protocol_row: _,
+            decimals: _,
+            rounding,
+            rows,
+            withheld,
+            export_chain,
+            quarantine,
+            freshness,
+            bonus_budget,
+            ..
+        } = report;
+        let _: Option<U256> = bonus_budget;
+        let _: Option<QuarantineSummary> = quarantine;
+        let _: Option<Freshness> = freshness;
+        let _: Rounding = rounding;
+        let _: Vec<ReportRow> = rows;
+        let _: Vec<ReportRow> = withheld;
+        let _: Option<ExportChain> = export_chain;
+    };
+    let _ = report;
+    let _ = ReportRow {
+        label: String::new(),
+        rewards: U256::zero(),
+        note: None,
+        bonus: U256::zero(),
+        reason: None,
+    };
+    let _: fn(WithholdReason) -> &'static str = WithholdReason::code;
+    let _ = [WithholdReason::Unmapped, WithholdReason::Dormant];
+
+    let _: fn(&str) -> Result<Annotations> = Annotations::parse;
+    let _: fn(&Path) -> Result<Annotations> = Annotations::load;
+    let _: fn(&Annotations, Address) -> Option<&Annotation> = Annotations::get;
+    let _: fn(&str) -> Result<SubAccounts> = SubAccounts::parse;
+    let _: fn(&Path) -> Result<SubAccounts> = SubAccounts::load;
+    let _: fn(&SubAccounts, &mut GlobalState, Vec<Event>) -> Result<Checkpoints> =
+        SubAccounts::replay;
+
+    let _: fn(&str) -> Result<AddressMap> = AddressMap::parse;
+    let _: fn(&Path) -> Result<AddressMap> = AddressMap::load;
+    let _: fn(&AddressMap, Address, ExportChain) -> Option<&str> = AddressMap::get;
+    let _: fn(&mut Report, &AddressMap, ExportChain) -> Result<()> = map_report;
+    let _: fn(ExportChain, &str) -> std::result::Result<(), String> = ExportChain::validate;
+    let _ = [ExportChain::L2, ExportChain::Cosmos];
+
+    let _: fn(Rounding, U256, U256) -> U256 = Rounding::div;
+    let _: fn(Rounding, &[U256], U256) -> Vec<U256> = Rounding::round_to_unit;
+    let _ = [Rounding::Floor, Rounding::HalfEven, Rounding::Ceil];
+
+    let _: fn(&EvaluationContext, &EvaluationContext) -> Freshness = Freshness::online;
+    let _: fn(u64) -> Freshness = Freshness::offline;
+    let _: fn(&Freshness, std::time::Duration) -> Result<()> = Freshness::ensure_within;
+
+    let _: SanityCheck = sanity_check;
+    let _: fn() -> PoolShares = PoolShares::new;
+    let _: fn(u64) -> PoolShares = PoolShares::deployed_at;
+
+    let _: fn(u64) -> LoyaltyBonus = LoyaltyBonus::new;
+    let _: fn(&LoyaltyBonus, Address) -> Option<U64> = LoyaltyBonus::holding_since;
+    let _: fn(&LoyaltyBonus, Address, U64) -> U256 = LoyaltyBonus::eligible_share_blocks;
+    let _: fn(&LoyaltyBonus, U256, U64, &GlobalState) -> BTreeMap<Address, U256> =
+        LoyaltyBonus::bonuses;
+    let _: u64 = MIN_AGE_BLOCKS;
+    fn _observes(loyalty: LoyaltyBonus) -> impl StateObserver {
+        loyalty
+    }
+    let _: fn(&PoolShares, Address, &GlobalState, U64) -> MaxRewards = PoolShares::max_rewards;
+    let _: fn(&Report, U64) -> RewardsFile = RewardsFile::new;
+    let _: fn(&str) -> Result<RewardsFile> = RewardsFile::parse;
+    let _: fn(&Path) -> Result<RewardsFile> = RewardsFile::load;
+    let _: fn(&RewardsFile, &Path) -> Result<()> = RewardsFile::save;
+    let _ = SanityBounds {
+        max_emission_pct: 25.0,
+        max_change_pct: 50.0,
+    };
+    let flag = |flag: RedFlag| {
+        let RedFlag {
+            label,
+            rewards,
+            violation,
+        } = flag;
+        let _: (String, U256) = (label, rewards);
+        match violation {
+            Violation::AboveTheoreticalMax(MaxRewards {
+                emitted,
+                pool_share,
+                rewards,
+            }) => {
+                let _: (U256, f64, U256) = (emitted, pool_share, rewards);
+            }
+            Violation::EmissionShare {
+                total_emission,
+                pct,
+                max_pct,
+            } => {
+                let _: (U256, f64, f64) = (total_emission, pct, max_pct);
+            }
+            Violation::Changed {
+                previous,
+                pct,
+                max_pct,
+            } => {
+                let _: (U256, f64, f64) = (previous, pct, max_pct);
+            }
+        }
+    };
+    let _ = flag;
+}
+
+#[test]
+fn proof_api() {
+    let _: fn(Address, U64) -> ProofConfig = ProofConfig::new;
+    let _: fn(&ProofConfig) -> H256 = ProofConfig::digest;
+    let _: fn(ProofConfig, &[Event], &GlobalState) -> Proof = Proof::new;
+    let _: fn(&Path) -> Result<Proof> = Proof::load;
+    let _: fn(&Proof, &Path) -> Result<()> = Proof::save;
+    let _: fn(&Proof, &[Event]) -> Result<(), ProofError> = Proof::verify;
+    let _: fn(ProofConfig, Cursor<Vec<u8>>) -> Result<Proof> = Proof::from_journal;
+    let _: fn(&Proof, Cursor<Vec<u8>>) -> Result<usize> = Proof::verify_journal;
+    let _: usize = CHECKPOINT_INTERVAL;
+
+    let _ = Amount {
+        address: Address::zero(),
+        amount: U256::zero(),
+    };
+    let error: &dyn std::error::Error = &ProofError::EventsHash;
+    let _ = error;
+    let _: fn(StateError) -> ProofError = ProofError::Replay;
+}
+
+#[test]
+fn error_api() {
+    let _: fn(&Error) -> i32 = Error::exit_code;
+    let error: Error = ProofError::EventsHash.into();
+    let _: &(dyn std::error::Error + Send + Sync) = &error;
+    let error: Error = StateError::InsufficientShares {
+        address: Address::zero(),
+        have: U256::zero(),
+        want: U256::one(),
+        block: U64::zero(),
+    }
+    .into();
+    let _ = error;
+    let _: Error = StateError::UnknownUser(Address::zero()).into();
+}
+
+#[test]
+fn fetch_api() {
+    let _: fn(Address, u64, u64, u64, u64) -> EventCache = EventCache::new;
+    let _: fn(&EventCache) -> bool = EventCache::is_complete;
+    let _: fn(&Path) -> Result<EventCache> = EventCache::load;
+    let _: fn(&EventCache, &Path) -> Result<()> = EventCache::save;
+    let _: fn(&Path, u64) -> Result<EventCache> = EventCache::load_after;
+    let _: fn(&EventCache, &Path, CacheFormat) -> Result<()> = EventCache::save_as;
+    let _ = [CacheFormat::Json, CacheFormat::Binary];
+    let _: fn(&EventCache) -> H256 = EventCache::hash;
+    let _: fn(&EventCache, Address) -> Result<()> = EventCache::ensure_vault;
+    let _: fn(Vec<EventCache>) -> Result<EventCache> = EventCache::merge;
+    let meta: fn(Address, u64, &VaultFlavor, u64) -> ChunkMeta = ChunkMeta::new;
+    let _: fn(&ChunkMeta) -> ChunkFields = |meta| {
+        (
+            meta.schema,
+            meta.vault,
+            meta.chain_id,
+            &meta.topics,
+            meta.chunk_size,
+        )
+    };
+    let _: fn(&Path, ChunkMeta) -> Result<ChunkStore> = ChunkStore::open;
+    let _: fn(&ChunkStore) -> &ChunkMeta = ChunkStore::meta;
+    let _: MissingChunks = ChunkStore::missing;
+    let _: u32 = CHUNK_SCHEMA_VERSION;
+    async fn fill_from(
+        cache: &mut EventCache,
+        chunks: &mut ChunkStore,
+        client: &Client,
+        flavor: &VaultFlavor,
+    ) -> Result<Vec<Vec<Log>>> {
+        cache.fill_from(chunks, client, flavor, 1).await?;
+        chunks.fetch(client, flavor, &[], 1).await
+    }
+    let _ = (meta, fill_from);
+
+    let _: fn(u64, u64, u64) -> Vec<(u64, u64)> = chunk_grid;
+    let _: u64 = CHUNK_SIZE;
+    let _: fn(Vec<Decoded>) -> Decoded = Decoded::concat;
+    let _: fn(Decoded) -> Result<Vec<Event>> = Decoded::strict;
+    let _: fn(&Log, &VaultFlavor) -> Result<Option<Event>> = decode_vault_log;
+    let _ = PipelineTimings {
+        fetch: Duration::ZERO,
+        decode: Duration::ZERO,
+        process: Duration::ZERO,
+        wall: Duration::ZERO,
+        chunks: 0,
+    };
+
+    let _: fn() -> VaultFlavor = VaultFlavor::erc4626;
+    let _: fn() -> VaultFlavor = VaultFlavor::oprtc_v1;
+    let _: fn(&str) -> Option<VaultFlavor> = VaultFlavor::builtin;
+    let _: fn(&str) -> Result<VaultFlavor> = VaultFlavor::from_toml;
+    let _: fn(&Path) -> Result<VaultFlavor> = VaultFlavor::load;
+    let _: fn(&VaultFlavor) -> Result<Emission> = VaultFlavor::emission;
+    let _: fn(&VaultFlavor, &Log) -> Result<Option<Event>> = VaultFlavor::decode;
+    let _: fn(&VaultFlavor) -> &Option<RebaseConfig> = |flavor| &flavor.rebase;
+    let _: fn(&VaultFlavor) -> &[LayoutSegment] = |flavor| &flavor.segments;
+    let _: fn(&LayoutSegment) -> (u64, &Option<String>) =
+        |segment| (segment.from_block, &segment.name);
+    let _: fn(&VaultFlavor) -> Vec<u64> = VaultFlavor::boundaries;
+    let _: fn(&VaultFlavor) -> Vec<&str> = VaultFlavor::signatures;
+    let _: LayoutCounts = VaultFlavor::layout_counts;
+
+    let _: fn(&RebaseConfig) -> Result<U256> = RebaseConfig::initial_index;
+    let _: fn(&RebaseConfig, Vec<Event>, &IndexSeries, Address) -> Normalized =
+        RebaseConfig::normalize;
+    let _: fn(U256, Vec<(u64, U256)>) -> Result<IndexSeries> = IndexSeries::new;
+    let _: fn(U256, &str) -> Result<IndexSeries> = IndexSeries::parse_csv;
+    let _: fn(U256, &Path) -> Result<IndexSeries> = IndexSeries::load_csv;
+    let _: fn(&IndexSeries, u64) -> U256 = IndexSeries::index_at;
+    let _: fn(&IndexSeries, U256, u64) -> (U256, U256) = IndexSeries::to_shares;
+    let _: fn(&IndexSeries, U256, u64) -> U256 = IndexSeries::to_rebased;
+    let _: fn(&VaultFlavor) -> &Option<TopUpConfig> = |flavor| &flavor.top_ups;
+    let _: &str = REWARD_ADDED_EVENT;
+    let _: fn(&TopUpConfig) -> (&str, usize, usize) =
+        |config| (&config.signature, config.amount_word, config.duration_word);
+    let _ = TopUpConfig::default();
+    let top_up = TopUp {
+        block_number: U64::zero(),
+        amount: U256::one(),
+        duration: 60,
+    };
+    let _: fn(&[TopUp], &TimestampCache) -> Result<TopUps> = TopUps::new;
+    let _: fn(&TopUps, u64) -> U256 = TopUps::streamed_by;
+    let _: fn(&TopUps, u64) -> U256 = TopUps::added_by;
+    let _: fn(&TopUps) -> usize = TopUps::len;
+    let _ = top_up;
+    let TopUpReconciliation {
+        from_block: _,
+        to_block: _,
+        added: _,
+        streaming: _,
+        streamed: _,
+        attributed: _,
+    } = TopUpReconciliation {
+        from_block: U64::zero(),
+        to_block: U64::one(),
+        added: U256::one(),
+        streaming: U256::zero(),
+        streamed: U256::one(),
+        attributed: U256::one(),
+    };
+
+    let Normalized { events, dust } = Normalized {
+        events: vec![],
+        dust: U256::zero(),
+    };
+    let _: (Vec<Event>, U256) = (events, dust);
+
+    fn is_log_source<S: LogSource>() {}
+    is_log_source::<Client>();
+    let _: fn(&ethers::providers::ProviderError) -> bool = is_retryable;
+    let RetryPolicy {
+        max_attempts,
+        base_delay,
+        max_delay,
+    } = RetryPolicy::none();
+    let _: (usize, Duration, Duration) = (max_attempts, base_delay, max_delay);
+    let _: fn(Instrumented<ethers::providers::Http>, RetryPolicy) -> Instrumented<_> =
+        Instrumented::with_retry;
+    let _: fn(
+        Instrumented<ethers::providers::Http>,
+        &str,
+        ethers::providers::Http,
+    ) -> Instrumented<_> = Instrumented::with_fallback;
+
+    let _: fn(&EvaluationContext, U64) -> Result<U64> = EvaluationContext::historical;
+}
+
+#[test]
+fn oracle_api() {
+    let _: fn(Address) -> OracleConfig = OracleConfig::new;
+    let _: fn(Client, OracleConfig) -> RewardsOracle = RewardsOracle::new;
+    let _: fn(Client, OracleConfig, Arc<dyn Clock>) -> RewardsOracle = RewardsOracle::with_clock;
+    let _: fn(&RewardsOracle) -> OracleMetrics = RewardsOracle::metrics;
+    let _ = [StalePolicy::Wait, StalePolicy::ServeStale];
+
+    fn is_oracle_source<S: OracleSource>() {}
+    is_oracle_source::<Client>();
+
+    fn is_clock<C: Clock>() {}
+    is_clock::<TokioClock>();
+    fn is_head_source<S: HeadSource>() {}
+    is_head_source::<Client>();
+    let _ = [Head::Latest, Head::Safe, Head::Finalized];
+    let config = OracleConfig::new(Address::zero());
+    let _: Head = config.follow;
+    let _: u64 = config.deployed_block;
+
+    async fn rewards_of(oracle: &RewardsOracle, address: Address) -> Result<RewardView> {
+        oracle.rewards_of(address).await
+    }
+    let _ = rewards_of;
+}
@@ -0,0 +1,165 @@
+//! A rewards report run with `--sign-key-env`, against a stub node that serves the evaluation
+//! block of a cached history.
+
+use ethers::core::types::{Address, U256, U64};
+use oprtc_calculator::prelude::*;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{self, Command},
+    thread,
+};
+
+const VAULT: u64 = 0x7a;
+
+fn cache() -> EventCache {
+    let holder = Address::from_low_u64_be(0xb0b);
+    let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + 10);
+    let mut cache = EventCache::new(
+        Address::from_low_u64_be(VAULT),
+        1,
+        BLOCK_CONTRACT_DEPLOYED,
+        BLOCK_CONTRACT_DEPLOYED + 99,
+        100,
+    );
+    cache.completed_chunks = chunk_grid(cache.from_block, cache.to_block, cache.chunk_size)
+        .into_iter()
+        .map(|(start, _)| start)
+        .collect();
+    cache.events = vec![
+        Event::Deposit(Deposit {
+            address: holder,
+            shares: Shares(U256::exp10(18)),
+            block_number,
+            transaction_index: 0,
+            log_index: 0,
+        }),
+        Event::Transfer(Transfer {
+            from: holder,
+            to: Address::from_low_u64_be(0xa11ce),
+            shares: Shares(U256::exp10(17)),
+            block_number: block_number + 5,
+            transaction_index: 0,
+            log_index: 0,
+        }),
+    ];
+    cache
+}
+
+/// Answers the chain id, head and block requests of a run, the head being `head`, one request
+/// per connection.
+fn serve_node(head: u64) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || answer(stream, head));
+        }
+    });
+    url
+}
+
+fn answer(stream: TcpStream, head: u64) {
+    let mut reader = BufReader::new(stream);
+    let mut length = 0;
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            length = value.trim().parse().unwrap();
+        }
+        line.clear();
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+    let request: Value = serde_json::from_slice(&body).unwrap();
+
+    let response = match request["method"].as_str().unwrap() {
+        "eth_chainId" => json!({ "result": "0x1" }),
+        "eth_blockNumber" => json!({ "result": format!("{:#x}", head) }),
+        "eth_getBlockByNumber" => json!({
+            "result": {
+                "number": request["params"][0],
+                "hash": format!("0x{:064x}", head),
+                "parentHash": format!("0x{:064x}", 0),
+                "timestamp": "0x64000000",
+                "gasUsed": "0x0",
+                "gasLimit": "0x1c9c380",
+                "difficulty": "0x0",
+                "extraData": "0x",
+                "transactions": [],
+                "uncles": [],
+            }
+        }),
+        method => {
+            json!({ "error": { "code": -32601, "message": format!("{} unsupported", method) } })
+        }
+    };
+    let mut response = response;
+    response["jsonrpc"] = json!("2.0");
+    response["id"] = request["id"].clone();
+    let response = response.to_string();
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.len(),
+        response
+    )
+    .unwrap();
+}
+
+#[test]
+fn every_written_output_has_a_signature() {
+    let cache = cache();
+    let url = serve_node(cache.to_block);
+    let dir = std::env::temp_dir().join(format!("oprtc-signed-run-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let cache_path = dir.join("events.cache");
+    fs::write(&cache_path, serde_json::to_vec(&cache).unwrap()).unwrap();
+    let outputs = dir.join("outputs");
+    fs::create_dir_all(&outputs).unwrap();
+    let out = |name: &str| outputs.join(name);
+
+    let run = Command::new(env!("CARGO_BIN_EXE_oprtc_calculator"))
+        .env("OPRTC_TEST_SIGN_KEY", format!("0x{}", "07".repeat(32)))
+        .args(["--rpc-url", &url])
+        .args(["--vault", &format!("{:?}", Address::from_low_u64_be(VAULT))])
+        .args(["--from-block", &BLOCK_CONTRACT_DEPLOYED.to_string()])
+        .args(["--sign-key-env", "OPRTC_TEST_SIGN_KEY"])
+        .arg("--cache")
+        .arg(&cache_path)
+        .arg("--output")
+        .arg(out("report.txt"))
+        .arg("--csv")
+        .arg(out("rewards.csv"))
+        .arg("--merkle-out")
+        .arg(out("merkle.json"))
+        .arg("--quarantine")
+        .arg(out("quarantine.csv"))
+        .output()
+        .unwrap();
+    assert!(run.status.success(), "{:?}", run);
+
+    let written: Vec<_> = fs::read_dir(&outputs)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !is_metadata(path))
+        .collect();
+    assert_eq!(written.len(), 4, "{:?}", written);
+    for path in written {
+        let mut sig = path.clone().into_os_string();
+        sig.push(".sig");
+        assert!(Path::new(&sig).exists(), "{} is unsigned", path.display());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Signatures and lineage sidecars, which describe an output rather than being one.
+fn is_metadata(path: &Path) -> bool {
+    let name = path.file_name().unwrap().to_string_lossy();
+    name.ends_with(".sig") || name.ends_with(".lineage.json")
+}
@@ -0,0 +1,166 @@
+//! Proves and verifies a journal generated as it is read, far larger than the memory the
+//! replay may use, counting every allocation. Set `OPRTC_STREAM_EVENTS` to stream more, about
+//! 7.5M events to the gigabyte.
+
+use ethers::core::types::{Address, U256, U64};
+use oprtc_calculator::prelude::*;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    env,
+    io::{self, BufReader, Read},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const EVENTS: u64 = 100_000;
+const MEMORY_BUDGET: usize = 512 << 10;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+            let live = LIVE.fetch_add(new_size, Ordering::SeqCst) + new_size;
+            PEAK.fetch_max(live, Ordering::SeqCst);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Journal lines of `events` events, three to a block: a holder staked throughout, and a
+/// hundred others depositing and withdrawing in turn. Each line is encoded as it is read.
+struct SyntheticJournal {
+    events: u64,
+    next: u64,
+    line: Vec<u8>,
+    read: usize,
+    bytes: u64,
+}
+
+impl SyntheticJournal {
+    fn new(events: u64) -> SyntheticJournal {
+        SyntheticJournal {
+            events,
+            next: 0,
+            line: Vec::with_capacity(512),
+            read: 0,
+            bytes: 0,
+        }
+    }
+
+    fn event(i: u64) -> Event {
+        let block_number = U64::from(BLOCK_CONTRACT_DEPLOYED + i / 3);
+        let log_index = i % 3;
+        let shares = Shares(U256::exp10(18));
+        if i == 0 {
+            return Event::Deposit(Deposit {
+                address: Address::from_low_u64_be(0xfee),
+                shares,
+                block_number,
+                transaction_index: 0,
+                log_index,
+            });
+        }
+        let address = Address::from_low_u64_be((i - 1) / 2 % 100 + 1);
+        if i % 2 == 1 {
+            Event::Deposit(Deposit {
+                address,
+                shares,
+                block_number,
+                transaction_index: 0,
+                log_index,
+            })
+        } else {
+            Event::Withdrawal(Withdraw {
+                address,
+                shares,
+                block_number,
+                transaction_index: 0,
+                log_index,
+            })
+        }
+    }
+}
+
+impl Read for SyntheticJournal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.line.len() {
+            if self.next == self.events {
+                return Ok(0);
+            }
+            self.line.clear();
+            serde_json::to_writer(&mut self.line, &SyntheticJournal::event(self.next))?;
+            self.line.push(b'\n');
+            self.read = 0;
+            self.next += 1;
+        }
+        let len = buf.len().min(self.line.len() - self.read);
+        buf[..len].copy_from_slice(&self.line[self.read..self.read + len]);
+        self.read += len;
+        self.bytes += len as u64;
+        Ok(len)
+    }
+}
+
+/// Peak bytes allocated on top of those live before `f` ran.
+fn peak_during<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = LIVE.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let out = f();
+    (out, PEAK.load(Ordering::SeqCst) - before)
+}
+
+#[test]
+fn verifies_a_journal_larger_than_memory_in_flat_memory() {
+    let events = env::var("OPRTC_STREAM_EVENTS")
+        .map(|events| events.parse().expect("event count"))
+        .unwrap_or(EVENTS);
+    let config = ProofConfig::new(
+        Address::from_low_u64_be(0x7a),
+        U64::from(BLOCK_CONTRACT_DEPLOYED + events / 3 + 100),
+    );
+
+    let (proof, proving) = peak_during(|| {
+        Proof::from_journal(config, BufReader::new(SyntheticJournal::new(events))).unwrap()
+    });
+    assert_eq!(proof.amounts.len(), 101);
+
+    let mut journal = SyntheticJournal::new(events);
+    let (verified, verifying) = peak_during(|| proof.verify_journal(BufReader::new(&mut journal)));
+    assert_eq!(verified.unwrap(), events as usize);
+
+    assert!(
+        journal.bytes > 20 * MEMORY_BUDGET as u64,
+        "{} bytes streamed",
+        journal.bytes
+    );
+    for peak in [proving, verifying] {
+        assert!(
+            peak < MEMORY_BUDGET,
+            "{} bytes at peak for a {} byte journal",
+            peak,
+            journal.bytes
+        );
+    }
+}